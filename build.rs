@@ -0,0 +1,32 @@
+// Embeds the git commit of the build, shown by `--version` as `0.x.y (abc1234)`.
+use std::process::Command;
+
+fn main() {
+    // builds without a git checkout (e.g. Docker with .git excluded) can pass it in
+    println!("cargo:rerun-if-env-changed=GIT_HASH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    let git_hash = std::env::var("GIT_HASH")
+        .ok()
+        .filter(|hash| !hash.is_empty())
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(["rev-parse", "--short", "HEAD"])
+                .output()
+                .ok()?;
+            if !output.status.success() {
+                return None;
+            }
+            let hash = String::from_utf8(output.stdout).ok()?;
+            Some(hash.trim().to_string()).filter(|hash| !hash.is_empty())
+        });
+
+    let version = env!("CARGO_PKG_VERSION");
+    let long_version = match &git_hash {
+        Some(hash) => format!("{} ({})", version, hash),
+        None => version.to_string(),
+    };
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash.unwrap_or_default());
+    println!("cargo:rustc-env=JPKSJ_VERSION={}", long_version);
+}
@@ -1,4 +1,9 @@
-CREATE EXTENSION IF NOT EXISTS "postgis";
+-- Migrations are applied in order and recorded in `schema_migrations`.
+-- Each one must be idempotent (`IF NOT EXISTS`): databases created before the
+-- migrations existed already have these tables, and docker-compose runs this file on init.
+
+-- in public, so that it is found from every `--pg-schema`
+CREATE EXTENSION IF NOT EXISTS "postgis" SCHEMA public;
 
 CREATE TABLE IF NOT EXISTS "admini_boundary_cd" (
     "行政区域コード" VARCHAR(5) PRIMARY KEY NOT NULL,
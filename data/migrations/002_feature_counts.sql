@@ -0,0 +1,7 @@
+-- The number of features loaded into each table, written after every load.
+-- A table with 0 features usually means the shapefile matching or the encoding went wrong.
+CREATE TABLE IF NOT EXISTS "feature_counts" (
+    "table_name" TEXT PRIMARY KEY NOT NULL,
+    "feature_count" BIGINT NOT NULL,
+    "counted_at" TIMESTAMPTZ NOT NULL DEFAULT now()
+);
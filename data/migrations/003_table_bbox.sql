@@ -0,0 +1,2 @@
+-- The WGS84 bounding box of each table, `[minx, miny, maxx, maxy]` (NULL without geometries).
+ALTER TABLE "feature_counts" ADD COLUMN IF NOT EXISTS "bbox" DOUBLE PRECISION[];
@@ -0,0 +1,186 @@
+// A run-wide circuit breaker for requests to the MLIT servers.
+// When too many requests fail within a time window (errors, rate limiting, ...),
+// the breaker opens and every worker pauses for a cool-down before resuming,
+// instead of each task burning through its own retries.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::logging;
+
+#[derive(Clone, Debug)]
+pub struct BreakerConfig {
+    /// この件数の失敗が `window` 内に発生したらブレーカーを開く
+    pub failure_threshold: usize,
+    pub window: Duration,
+    pub cooldown: Duration,
+    /// 再試行までの待ち時間。再試行のたびに2倍になります
+    pub retry_delay: Duration,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 10,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(300),
+            retry_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+/// The backoff stops growing after this many doublings.
+const MAX_RETRY_DOUBLINGS: u32 = 5;
+
+#[derive(Default)]
+struct State {
+    failures: VecDeque<Instant>,
+    open_until: Option<Instant>,
+}
+
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Waits until the breaker is closed. Returns immediately when it is not open.
+    pub async fn wait_until_closed(&self) {
+        while let Some(remaining) = self.remaining_cooldown_at(Instant::now()) {
+            tokio::time::sleep(remaining).await;
+        }
+    }
+
+    /// Records a failed request. Returns `true` if this failure opened the breaker.
+    pub fn record_failure(&self) -> bool {
+        self.record_failure_at(Instant::now())
+    }
+
+    /// [`CircuitBreaker::record_failure`], telling the user when it opened the breaker.
+    pub fn report_failure(&self) {
+        if self.record_failure() {
+            eprintln!(
+                "リクエストの失敗が続いているため、{}秒間すべてのダウンロードと取得を停止します。",
+                self.cooldown().as_secs()
+            );
+            logging::event("circuit breaker opened");
+        }
+    }
+
+    /// How long to wait before retrying after the `attempt`th (1-based) failed attempt.
+    pub fn retry_delay(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(MAX_RETRY_DOUBLINGS);
+        self.config.retry_delay * 2u32.pow(doublings)
+    }
+
+    fn record_failure_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.open_until.is_some_and(|until| until > now) {
+            // already open; failures of in-flight requests don't extend the cool-down
+            return false;
+        }
+        state.open_until = None;
+        state.failures.push_back(now);
+        while state
+            .failures
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > self.config.window)
+        {
+            state.failures.pop_front();
+        }
+        if state.failures.len() >= self.config.failure_threshold {
+            state.failures.clear();
+            state.open_until = Some(now + self.config.cooldown);
+            return true;
+        }
+        false
+    }
+
+    fn remaining_cooldown_at(&self, now: Instant) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        state
+            .open_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.config.cooldown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breaker_opens_and_closes() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 3,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+            ..Default::default()
+        });
+        let start = Instant::now();
+
+        assert!(!breaker.record_failure_at(start));
+        assert!(!breaker.record_failure_at(start + Duration::from_secs(1)));
+        assert!(breaker
+            .remaining_cooldown_at(start + Duration::from_secs(1))
+            .is_none());
+
+        // third failure within the window trips the breaker
+        assert!(breaker.record_failure_at(start + Duration::from_secs(2)));
+        assert_eq!(
+            breaker.remaining_cooldown_at(start + Duration::from_secs(2)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            breaker.remaining_cooldown_at(start + Duration::from_secs(12)),
+            Some(Duration::from_secs(20))
+        );
+
+        // failures while open don't extend the cool-down
+        assert!(!breaker.record_failure_at(start + Duration::from_secs(20)));
+
+        // after the cool-down, the breaker is closed again
+        assert!(breaker
+            .remaining_cooldown_at(start + Duration::from_secs(32))
+            .is_none());
+        assert!(!breaker.record_failure_at(start + Duration::from_secs(33)));
+    }
+
+    #[test]
+    fn test_breaker_ignores_failures_outside_window() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            failure_threshold: 2,
+            window: Duration::from_secs(5),
+            cooldown: Duration::from_secs(30),
+            ..Default::default()
+        });
+        let start = Instant::now();
+        assert!(!breaker.record_failure_at(start));
+        assert!(!breaker.record_failure_at(start + Duration::from_secs(10)));
+        assert!(breaker.record_failure_at(start + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn test_retry_delay_backs_off() {
+        let breaker = CircuitBreaker::new(BreakerConfig {
+            retry_delay: Duration::from_secs(2),
+            ..Default::default()
+        });
+        assert_eq!(breaker.retry_delay(1), Duration::from_secs(2));
+        assert_eq!(breaker.retry_delay(2), Duration::from_secs(4));
+        assert_eq!(breaker.retry_delay(3), Duration::from_secs(8));
+        assert_eq!(breaker.retry_delay(100), Duration::from_secs(64));
+    }
+}
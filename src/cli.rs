@@ -1,10 +1,22 @@
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+
+use bytesize::ByteSize;
+use jpksj_to_sql::loader::transform::FieldTransform;
+use jpksj_to_sql::loader::{self, Compression, GeometryFilter};
+use jpksj_to_sql::plan::PlanFormat;
+use jpksj_to_sql::scraper;
 
 #[derive(Parser)]
-#[command(version)]
+// `0.x.y (abc1234)`, see build.rs
+#[command(version = env!("JPKSJ_VERSION"))]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// 出力フォーマット（GDAL driver 名、または PostgreSQL を示す文字列）
     /// 指定しない場合は postgresql が使用されます
     #[arg(
@@ -15,36 +27,897 @@ pub struct Cli {
     pub output_format: String,
 
     /// 出力先（PostgreSQL の場合は接続文字列、その他は出力ディレクトリ）
+    /// PostgreSQL の場合は `--postgres-url-file` または環境変数 `JPKSJ_POSTGRES_URL` / `DATABASE_URL` でも指定できます
     #[arg(value_name = "OUTPUT_DESTINATION")]
-    pub output_destination: String,
+    pub output_destination: Option<String>,
+
+    /// PostgreSQL の接続文字列を読み込むファイル
+    /// 接続文字列（パスワード）がシェル履歴やプロセス一覧に残らないようにするために使います
+    #[arg(long, value_name = "FILE")]
+    pub postgres_url_file: Option<PathBuf>,
+
+    /// ファイル出力を圧縮します（例: `.geojson.gz`）
+    /// gzip の場合、GeoJSON/GeoJSONSeq/CSV/GML/KML/GPX は GDAL が直接 `/vsigzip/` に書き出します
+    /// それ以外のフォーマットと zstd は、書き出した後に圧縮します
+    #[arg(long, value_name = "COMPRESSION")]
+    pub compress_output: Option<Compression>,
+
+    /// PostgreSQL の書き込み先スキーマ（例: jpksj_2024）。存在しない場合は作成します
+    /// `datasets` などのメタデータのテーブルもこのスキーマに作成されます。指定しない場合は `public` です
+    #[arg(long, value_name = "SCHEMA", value_parser = parse_sql_identifier)]
+    pub pg_schema: Option<String>,
+
+    /// テーブル名の前に付けるプレフィックス（例: `ksj_` で `ksj_n03`）。既存のテーブルとの衝突を避けるために使います
+    /// 63バイトを超えるテーブル名は、カラム名と同様に先頭部分とハッシュに短縮されます
+    #[arg(long, value_name = "PREFIX", value_parser = parse_sql_identifier)]
+    pub table_prefix: Option<String>,
+
+    /// PostgreSQL に取り込む際のジオメトリのカラム名（例: geometry, the_geom）
+    #[arg(long, value_name = "NAME", default_value = "geom", value_parser = parse_sql_identifier)]
+    pub geometry_column_name: String,
+
+    /// ogr2ogr の GDAL ブロックキャッシュの上限（`--config GDAL_CACHEMAX`）
+    /// MB 単位の数値か `10%` のような割合で指定します。未指定の場合は GDAL のデフォルト（物理メモリの 5%）
+    #[arg(long, value_name = "SIZE")]
+    pub gdal_cache_max: Option<String>,
+
+    /// ogr2ogr の1トランザクションあたりの地物数（`-gt`）
+    #[arg(long, visible_alias = "ogr-transaction-size", value_name = "N", default_value_t = loader::DEFAULT_GROUP_TRANSACTIONS)]
+    pub ogr_group_transactions: u64,
+
+    /// PostgreSQL への書き込みに COPY を使わず、INSERT で取り込みます（`--config PG_USE_COPY NO`）
+    /// 遅くなりますが、メモリの使用量が減ります
+    #[arg(long)]
+    pub no_copy: bool,
+
+    /// ogr2ogr にそのまま渡す追加の引数（例: `"--config OGR_TRUNCATE YES"`）
+    /// シェルを介さずに、空白と引用符で区切って渡します
+    #[arg(long, value_name = "ARGS", allow_hyphen_values = true, value_parser = parse_extra_args)]
+    pub ogr2ogr_extra: Option<ExtraArgs>,
+
+    /// ogrinfo（文字コードや属性の判定、取り込み結果の確認）に渡す追加の引数（例: `"--config SHAPE_RESTORE_SHX YES"`）
+    #[arg(long, value_name = "ARGS", allow_hyphen_values = true, value_parser = parse_extra_args)]
+    pub ogrinfo_extra: Option<ExtraArgs>,
+
+    /// 文字コードの判定結果を表示します（判定に使ったフィーチャ数と、文字コードごとのデコードエラーの位置）
+    #[arg(long)]
+    pub debug_encoding: bool,
+
+    /// 出力の座標参照系（例: EPSG:3857）。ogr2ogr の `-t_srs` に渡されます
+    /// 指定しない場合はリプロジェクションせず、元の座標参照系（JGD2011 など）のまま出力します
+    #[arg(long, value_name = "SRS")]
+    pub target_srs: Option<String>,
+
+    /// 入力の座標参照系（例: EPSG:6668）。`.prj` から読めない場合に指定します
+    /// `--target-srs` と併用した場合は `-s_srs`、それ以外は `-a_srs` として ogr2ogr に渡されます
+    #[arg(long, value_name = "SRS")]
+    pub source_srs: Option<String>,
+
+    /// 不正なジオメトリなど、書き込めないフィーチャをスキップして取り込みを続行します（ogr2ogr の `-skipfailures`）
+    /// スキップされたフィーチャ数はテーブルごとに表示されます。1件ずつコミットするため、取り込みは遅くなります
+    #[arg(long)]
+    pub skip_invalid_geometries: bool,
+
+    /// 取り込み後、ST_IsValid で不正と判定されたジオメトリを ST_MakeValid で修復します（PostgreSQL のみ）
+    /// 修復でポリゴンがジオメトリコレクションになる場合は、ポリゴンの部分のみを残します
+    #[arg(long)]
+    pub fix_geometries: bool,
+
+    /// 取り込み後、重複するフィーチャを削除します（PostgreSQL のみ）
+    /// 主キーのカラムがあればそのカラムとジオメトリ、なければジオメトリ（ST_AsBinary のハッシュ）で判定します
+    #[arg(long)]
+    pub dedupe: bool,
 
     /// 中間ファイルの保存先 (Zip等)
     /// デフォルトはシステムのtmpディレクトリを利用します
     #[arg(long)]
     pub tmp_dir: Option<PathBuf>,
 
+    /// 前回取得したデータセット一覧（`<tmp>/initial.json`）を再利用し、API へのリクエストを省略します
+    /// キャッシュが `--index-cache-max-age` より古い場合は取得し直します
+    #[arg(long)]
+    pub use_cached_index: bool,
+
+    /// `--use-cached-index` のキャッシュの有効期間（時間、デフォルト: 24）
+    #[arg(long, value_name = "HOURS", requires = "use_cached_index", value_parser = parse_hours)]
+    pub index_cache_max_age: Option<f64>,
+
     /// データのダウンロードをスキップします
     /// データが存在しない場合はスキップされます
     #[arg(long, default_value = "false")]
     pub skip_download: bool,
 
+    /// すべてのダウンロードが終わってから取り込みを始めます
+    /// 指定しない場合は、ファイルのダウンロードが終わったデータセットから順に取り込みます
+    #[arg(long)]
+    pub sequential: bool,
+
+    /// 取り込みに成功したデータセットを tmp の `checkpoint.json` に記録し、記録済みのデータセットをスキップします
+    /// 失敗したデータセットは記録されないため、途中で失敗した実行を再開できます
+    #[arg(long)]
+    pub resume: bool,
+
+    /// `--resume` で、既存のチェックポイントを使わずに全てのデータセットを取り込み直します
+    #[arg(long, requires = "resume")]
+    pub force: bool,
+
+    /// 取り込みが終わったデータセットの、ZIP から展開したシェープファイル（tmp の `shp/` 以下）を削除せずに残します
+    #[arg(long)]
+    pub keep_intermediate: bool,
+
+    /// 取り込みに成功したデータセットの ZIP ファイルを削除します（ストレージの少ない環境向け）
+    /// 次回の実行では再ダウンロードが必要になります
+    #[arg(long)]
+    pub delete_zips_after_load: bool,
+
+    /// ZIP 内の ZIP を展開する深さの上限（ダウンロードした ZIP が 0 段目）
+    #[arg(long, default_value_t = loader::DEFAULT_MAX_ZIP_DEPTH)]
+    pub max_zip_depth: usize,
+
+    /// ZIP 1つあたりの展開後の合計サイズの上限（ネストした ZIP を含む。例: 20GiB）
+    /// 上限を超えた場合、そのデータセットの取り込みはエラーになります（zip bomb 対策）
+    #[arg(long, value_name = "SIZE", default_value = "20GiB")]
+    pub max_extracted_size: ByteSize,
+
+    /// ZIP 内で展開しないファイルのパターンを識別子ごとに書いた JSON ファイル
+    /// 例: `{"N08": ["^N08-21_GML/utf8/"]}`（パスは `/` 区切りの正規表現。組み込みのルールに追加されます）
+    #[arg(long, value_name = "FILE")]
+    pub skip_rules: Option<PathBuf>,
+
     /// 既に存在する出力をスキップします
     /// プロセスが途中で中断された場合、出力が中途半端な状態にある可能性があります
     #[arg(long, alias = "skip-sql-if-exists")]
     pub skip_if_exists: bool,
 
+    /// ZIP ファイルのダウンロードの同時実行数（1〜64）
+    /// 回線が細い場合や、サーバーに接続を拒否される場合は小さくしてください
+    #[arg(long, value_name = "N", default_value_t = scraper::DEFAULT_DOWNLOAD_CONCURRENCY)]
+    pub download_concurrency: usize,
+
+    /// 同じホスト（nlftp.mlit.go.jp など）への同時接続数の上限
+    /// `--download-concurrency` のワーカーのうち、この数だけが同時に接続します（例: 2〜3）
+    /// 指定しない場合は上限を設けません
+    #[arg(long, value_name = "N")]
+    pub parallel_downloads_per_host: Option<usize>,
+
+    /// ダウンロードの帯域の上限（1秒あたり。例: 5MB）
+    /// 並列ダウンロード全体の合計に対する上限です。指定しない場合は上限を設けません
+    #[arg(long, value_name = "RATE")]
+    pub max_download_rate: Option<ByteSize>,
+
+    /// ダウンロード済みのファイルを再利用する前のサイズの検証を無効にします
+    /// 検証では、前回のダウンロード完了時に記録したサイズと一致しないファイルを破損とみなして再ダウンロードします
+    #[arg(long)]
+    pub no_verify: bool,
+
+    /// ダウンロードを許可するホスト（カンマ区切りで複数指定可。サブドメインも含みます）
+    /// デフォルトは nlftp.mlit.go.jp のみで、それ以外のホストへのリンクはダウンロードせずに失敗として扱います
+    /// `*` を指定するとすべてのホストを許可します
+    #[arg(long, value_name = "HOSTS", value_delimiter = ',')]
+    pub allowed_hosts: Option<Vec<String>>,
+
+    /// HTTP リクエストの User-Agent（デフォルト: `jpksj-to-sql/<バージョン>`）
+    /// プロキシは環境変数 `HTTPS_PROXY` で指定します（`NO_PROXY` の対象は除く）
+    #[arg(long, value_name = "USER_AGENT")]
+    pub user_agent: Option<String>,
+
     /// 読み込むデータセットの識別子
     /// 指定しない場合は全てのデータセットが読み込まれます
     /// 複数指定する場合は `,` で区切ってください
     #[arg(long, value_delimiter = ',')]
     pub filter_identifiers: Option<Vec<String>>,
 
-    /// 取得するデータセットの年（例: 2019）
+    /// カテゴリ1の名前で絞り込みます（カンマ区切りで複数指定可。例: `--filter-category1 "3. 地域"` または `地域`）
+    /// `--filter-identifiers` などと併用した場合は、すべての条件に一致するデータセットのみが対象になります
+    #[arg(long, value_name = "CATEGORY", value_delimiter = ',')]
+    pub filter_category1: Option<Vec<String>>,
+
+    /// カテゴリ2の名前で絞り込みます（カンマ区切りで複数指定可）
+    #[arg(long, value_name = "CATEGORY", value_delimiter = ',')]
+    pub filter_category2: Option<Vec<String>>,
+
+    /// 利用区分が「非商用」のデータセットもダウンロード・取り込みの対象にします（研究目的など）
+    /// 指定しない場合はスキップします。取り込んだテーブルのメタデータの license に利用区分が記録されます
+    #[arg(long)]
+    pub include_non_commercial: bool,
+
+    /// 指定したデータセット1件のみを、詳細なログ付きでダウンロード・取り込みします（例: `--test-one A38`）
+    /// `--filter-identifiers <識別子> --verbose --row-counts` と同じです。不具合の報告にはこのオプションの出力を添付してください
+    #[arg(long, value_name = "IDENTIFIER", conflicts_with_all = ["filter_identifiers", "retry_failed"])]
+    pub test_one: Option<String>,
+
+    /// 詳細な診断メッセージ（ZIP ごとに一致したシェープファイル、matcher のフォールバックの警告など）を出力します
+    #[arg(short, long)]
+    pub verbose: bool,
+
+    /// 取り込み後、テーブルごとの行数を表示します
+    #[arg(long)]
+    pub row_counts: bool,
+
+    /// 同時に取り込むデータセットの数（ワーカー数）。指定しない場合は CPU 数 - 1 です
+    /// ogr2ogr や PostgreSQL の I/O が詰まる環境では、CPU 数より少ない方が速いことがあります
+    #[arg(long, value_name = "N")]
+    pub load_concurrency: Option<usize>,
+
+    /// PostgreSQL へ同時に書き込む ogr2ogr の数。指定しない場合は `--load-concurrency` と同じです
+    /// ZIP の展開と VRT の作成は `--load-concurrency` の並列度のまま、書き込みだけを絞ります
+    #[arg(long, value_name = "N")]
+    pub db_write_concurrency: Option<usize>,
+
+    /// 取り込み完了後、取り込んだ全テーブルに `ANALYZE` を実行してプランナ統計を更新します（PostgreSQL のみ）
+    #[arg(long)]
+    pub analyze: bool,
+
+    /// `--analyze` の同時実行数（大きなテーブルが多い場合は小さくしてください）
+    #[arg(long, value_name = "N", default_value_t = loader::DEFAULT_ANALYZE_CONCURRENCY, requires = "analyze")]
+    pub analyze_concurrency: usize,
+
+    /// 取得するデータセットの年（例: 2019）。カンマ区切りで複数指定できます（例: 2011,2015,2019）
     /// 指定しない場合は最新のデータセットが使用されます
+    #[arg(long, value_delimiter = ',')]
+    pub year: Option<Vec<u32>>,
+
+    /// 取得するデータセットの年の範囲（両端を含む。例: 2011-2019）
+    /// `--year` と併用した場合は両方の年が対象になります
+    #[arg(long, value_name = "START-END", value_parser = parse_year_range)]
+    pub year_range: Option<RangeInclusive<u32>>,
+
+    /// 最新版（`--year` 指定時はその版）に含まれない地域（行政区域コード単位）を、古い版のデータで補完します
+    /// 古い版もすべてダウンロードします。補完した行は `source_year` カラムに元の版の年が入ります（PostgreSQL のみ）
+    #[arg(long)]
+    pub fill_gaps: bool,
+
+    /// 年版ごとに別のテーブルに取り込み、テーブル名に年を付けます（例: `n03_2019`、`n03_2024`）
+    /// `--year` で複数年を指定した場合に、同じテーブルにまとめずに分けるためのものです
+    #[arg(long, conflicts_with = "fill_gaps")]
+    pub suffix_year: bool,
+
+    /// ダウンロードやデータセット情報の取得の失敗がこの件数に達したら、全体を一時停止します（サーキットブレーカー）
+    #[arg(long, default_value = "10")]
+    pub breaker_threshold: usize,
+
+    /// サーキットブレーカーが失敗を数える時間幅（秒）
+    #[arg(long, value_name = "SECONDS", default_value = "60")]
+    pub breaker_window: u64,
+
+    /// サーキットブレーカーが開いたときの停止時間（秒）
+    #[arg(long, value_name = "SECONDS", default_value = "300")]
+    pub breaker_cooldown: u64,
+
+    /// shapefile 以外に ZIP から展開して保持するファイルの拡張子（例: csv,txt）
+    /// ファイル出力の場合は `<出力先>/<識別子>_files/<ZIP 名>/` に ZIP 内のディレクトリ構成のままコピーされます
+    /// ogr2ogr には渡されません
+    #[arg(long, value_delimiter = ',')]
+    pub extra_extensions: Option<Vec<String>>,
+
+    /// ZIP 内に `sjis/` と `utf8/` の重複ディレクトリがある場合、`utf8/` 側を展開します
+    /// 指定しない場合は `sjis/` 側が使用されます
+    #[arg(long)]
+    pub prefer_utf8_dirs: bool,
+
+    /// スクレイプした全データセットの属性一覧を JSON Lines で書き出すファイル
+    /// 1行ごとに `{identifier, attribute_id, name, description, type, ref}` を出力します
+    #[arg(long, value_name = "FILE")]
+    pub export_attributes: Option<PathBuf>,
+
+    /// データセット・属性・コードリストのメタデータを SQLite のカタログとして書き出すファイル
+    /// 出力先（PostgreSQL やファイル出力）とは独立して作成されます
+    #[arg(long, value_name = "FILE")]
+    pub catalog_sqlite: Option<PathBuf>,
+
+    /// 取り込んだテーブルの STAC カタログ（catalog.json と、データセットごとのコレクション・テーブルごとのアイテム）を書き出すディレクトリ
+    /// 範囲（bbox）を取得するため、テーブルごとに追加のクエリ（ファイル出力の場合は ogrinfo）を実行します
+    #[arg(long, value_name = "DIR")]
+    pub stac: Option<PathBuf>,
+
+    /// 前回 `--catalog-sqlite` で書き出したカタログを使い、一覧から変更があったデータセットのみ詳細を取得します
+    /// 変更のないデータセットはカタログに保存されたメタデータを再利用します
+    #[arg(long, value_name = "CATALOG")]
+    pub incremental_from: Option<PathBuf>,
+
+    /// データセットの主キーとするカラム（例: A27=学校コード）
+    /// 組み込みのヒントより優先されます。複数指定する場合は `,` で区切ってください
+    #[arg(long, value_name = "IDENTIFIER=COLUMN", value_delimiter = ',', value_parser = parse_key_value)]
+    pub primary_key: Vec<(String, String)>,
+
+    /// データセットのうち条件に一致するフィーチャのみ取り込みます（例: `A31=A31_001='27100'`）。繰り返し指定できます
+    /// 条件は元のシェープファイルの属性名で記述する SQLite の WHERE 句です。条件はメタデータにも記録されます
+    #[arg(long = "where", value_name = "IDENTIFIER=CONDITION", value_parser = parse_key_value)]
+    pub where_filter: Vec<(String, String)>,
+
+    /// カラムの値に適用する変換（例: `行政区域コード=trim,zero_pad:5`）。繰り返し指定できます
+    /// 利用できる変換: trim, upper, zero_pad:N。`none` で組み込みの変換を無効にします
+    /// 組み込みでは 行政区域コード を5桁にゼロ埋めします
+    #[arg(long, value_name = "COLUMN=TRANSFORMS", value_parser = parse_field_transform)]
+    pub field_transform: Vec<(String, Vec<FieldTransform>)>,
+
+    /// 指定した種別（point, line, polygon）のジオメトリのテーブルのみ取り込みます
+    /// 展開後、テーブルごとに最初のシェープファイルを ogrinfo で調べるため、その分の時間がかかります
+    #[arg(long, value_name = "GEOMETRY_TYPE")]
+    pub geometry_filter: Option<GeometryFilter>,
+
+    /// シェープファイルの文字コードを自動検出せずに指定します（例: `CP932`、`A38=CP932`）。繰り返し指定できます
+    /// `識別子=文字コード` の指定はそのデータセット（またはテーブル）のみ、識別子なしの指定は全体に適用され、
+    /// 識別子の指定 > 全体の指定 > 自動検出 の順に優先されます
+    #[arg(long, value_name = "[IDENTIFIER=]ENCODING", value_parser = parse_force_encoding)]
+    pub force_encoding: Vec<(Option<String>, String)>,
+
+    /// マッピングにない属性も、元の属性名（例: `A27_009`）のカラムとして取り込みます
+    /// マッピングのある属性は通常どおり日本語のカラム名になります
     #[arg(long)]
-    pub year: Option<u32>,
+    pub keep_all_fields: bool,
+
+    /// 取り込み後のインデックス作成を行いません
+    /// 既定では、PostgreSQL に取り込んだテーブルのジオメトリカラムに GiST の空間インデックスを作成し、
+    /// 主キーのカラムに重複や NULL がなければ一意制約を追加します
+    #[arg(long)]
+    pub no_index: bool,
+
+    /// ダウンロード・取り込みを行わず、データセット → バージョン → ZIP → テーブル → マッチャーの計画を表示します
+    /// `--print-plan=json` で JSON として出力します
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "tree"
+    )]
+    pub print_plan: Option<PlanFormat>,
+
+    /// ダウンロード・取り込みを行わず、データセットごとの版、ダウンロードする ZIP の件数と合計サイズ、作成されるテーブル名を表示して終了します
+    /// データベースやファイルへの書き込みは一切行いません
+    #[arg(long, conflicts_with = "print_plan")]
+    pub dry_run: bool,
+
+    /// 指定したデータセットの取り込みで作成されるテーブル名を1行ずつ出力して終了します（例: `--print-tables A38`）
+    /// ZIP のダウンロードや取り込みは行いません
+    #[arg(long, value_name = "IDENTIFIER")]
+    pub print_tables: Option<String>,
+
+    /// 実行結果（成功・失敗したデータセットと実行時の設定）を JSON で書き出すファイル
+    #[arg(long, value_name = "FILE")]
+    pub summary: Option<PathBuf>,
+
+    /// 失敗したデータセットの一覧（識別子・テーブル・段階・エラーの原因・日時）を書き出すファイル
+    /// 拡張子が `.csv` の場合は CSV、それ以外は JSON で書き出します
+    #[arg(long, value_name = "FILE")]
+    pub error_report: Option<PathBuf>,
+
+    /// 取得・ダウンロード・取り込みの失敗が1件でもあれば、終了コードを非ゼロにします
+    /// 指定しない場合は、失敗したデータセットをスキップして続行し、0 で終了します
+    #[arg(long)]
+    pub fail_on_error: bool,
+
+    /// 失敗のなかったデータセットの割合がこの値（0〜1、例: 0.9）を下回った場合、終了コードを非ゼロにします
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    pub min_success_rate: Option<f64>,
+
+    /// 以前の `--summary` で失敗したデータセットのみを再実行します
+    /// `--year` を指定しない場合は、サマリーに記録された年が使用されます
+    #[arg(
+        long,
+        value_name = "SUMMARY_FILE",
+        conflicts_with = "filter_identifiers"
+    )]
+    pub retry_failed: Option<PathBuf>,
+
+    /// ダウンロード・取り込みのログと最後の実行結果のサマリを書き出すファイル
+    /// プログレスバーはターミナルに表示されたままです
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+
+    /// ログファイルに追記します
+    /// 指定しない場合、既存のログファイルは `<log_file>.1` にローテーションされます
+    #[arg(long, requires = "log_file")]
+    pub log_append: bool,
+}
+
+/// PostgreSQL の接続文字列を読み込む環境変数（先に見つかったものが使われます）
+const POSTGRES_URL_ENV_VARS: &[&str] = &["JPKSJ_POSTGRES_URL", "DATABASE_URL"];
+
+impl Cli {
+    /// The years selected with `--year` and `--year-range`, sorted and deduplicated.
+    /// `None` when neither is given (the latest version is used).
+    pub fn years(&self) -> Option<Vec<u32>> {
+        if self.year.is_none() && self.year_range.is_none() {
+            return None;
+        }
+        let mut years: Vec<u32> = self.year.iter().flatten().copied().collect();
+        years.extend(self.year_range.clone().into_iter().flatten());
+        years.sort_unstable();
+        years.dedup();
+        Some(years)
+    }
+
+    /// 出力先を解決します。優先順位は 引数 > `--postgres-url-file` > 環境変数 です。
+    /// ファイル・環境変数は PostgreSQL 出力の場合のみ参照されます。
+    pub fn resolve_output_destination(&self, is_postgres: bool) -> Result<String> {
+        self.resolve_output_destination_with_env(is_postgres, |key| std::env::var(key).ok())
+    }
+
+    fn resolve_output_destination_with_env(
+        &self,
+        is_postgres: bool,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<String> {
+        if let Some(destination) = &self.output_destination {
+            return Ok(destination.clone());
+        }
+        if !is_postgres {
+            anyhow::bail!("OUTPUT_DESTINATION is required");
+        }
+        if let Some(path) = &self.postgres_url_file {
+            let url = std::fs::read_to_string(path)
+                .with_context(|| format!("when reading {}", path.display()))?;
+            let url = url.trim();
+            if url.is_empty() {
+                anyhow::bail!("{} is empty", path.display());
+            }
+            return Ok(url.to_string());
+        }
+        POSTGRES_URL_ENV_VARS
+            .iter()
+            .filter_map(|key| env(key))
+            .find(|url| !url.trim().is_empty())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "OUTPUT_DESTINATION, --postgres-url-file, or one of {} is required",
+                    POSTGRES_URL_ENV_VARS.join(", ")
+                )
+            })
+    }
+}
+
+/// Accepts only plain SQL identifiers (`[A-Za-z_][A-Za-z0-9_]*`, up to 63 bytes).
+fn parse_sql_identifier(s: &str) -> Result<String, String> {
+    let mut chars = s.chars();
+    let valid_start = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    if !valid_start || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_') || s.len() > 63 {
+        return Err(format!(
+            "英数字と `_` のみ（先頭は数字以外、63文字以内）で指定してください: {}",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
+/// Parses a rate between 0 and 1 (inclusive).
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("0 から 1 の数値で指定してください: {}", s)),
+    }
+}
+
+/// Parses a non-negative number of hours.
+fn parse_hours(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(hours) if hours.is_finite() && hours >= 0.0 => Ok(hours),
+        _ => Err(format!("0 以上の時間数で指定してください: {}", s)),
+    }
+}
+
+/// `--ogr2ogr-extra` / `--ogrinfo-extra`: the arguments, split like a shell would but never run through one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtraArgs(pub Vec<String>);
+
+fn parse_extra_args(s: &str) -> Result<ExtraArgs, String> {
+    shlex::split(s)
+        .map(ExtraArgs)
+        .ok_or_else(|| format!("引用符が閉じられていません: {}", s))
+}
+
+/// Parses `START-END` year ranges (inclusive).
+fn parse_year_range(s: &str) -> Result<RangeInclusive<u32>, String> {
+    let invalid = || format!("`2011-2019` の形式で指定してください: {}", s);
+    let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+    let start: u32 = start.trim().parse().map_err(|_| invalid())?;
+    let end: u32 = end.trim().parse().map_err(|_| invalid())?;
+    if start > end {
+        return Err(format!("開始年が終了年より後になっています: {}", s));
+    }
+    Ok(start..=end)
+}
+
+#[derive(Subcommand, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// 実行前の確認: API と MLIT のエンドポイント、GDAL、PostgreSQL（接続先が指定されている場合）をチェックします
+    /// いずれかのチェックが失敗した場合は 0 以外で終了します
+    Doctor,
+    /// 利用可能なデータセットの一覧（識別子、カテゴリ、名称、利用区分）を表示します
+    /// 非商用のデータセットには [非商用] が付きます
+    List {
+        /// JSON で出力します
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// Parses `COLUMN=TRANSFORM[,TRANSFORM...]` arguments.
+fn parse_field_transform(s: &str) -> Result<(String, Vec<FieldTransform>), String> {
+    let (column, transforms) = parse_key_value(s)?;
+    let transforms = FieldTransform::parse_list(&transforms).map_err(|e| e.to_string())?;
+    Ok((column, transforms))
+}
+
+/// Parses `ENCODING` (for every dataset) or `IDENTIFIER=ENCODING`.
+fn parse_force_encoding(s: &str) -> Result<(Option<String>, String), String> {
+    if s.contains('=') {
+        let (identifier, encoding) = parse_key_value(s)?;
+        return Ok((Some(identifier), encoding));
+    }
+    let encoding = s.trim();
+    if encoding.is_empty() {
+        return Err("文字コードを指定してください".to_string());
+    }
+    Ok((None, encoding.to_string()))
+}
+
+/// Parses `KEY=VALUE` arguments.
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("`KEY=VALUE` の形式で指定してください: {}", s))?;
+    let (key, value) = (key.trim(), value.trim());
+    if key.is_empty() || value.is_empty() {
+        return Err(format!("`KEY=VALUE` の形式で指定してください: {}", s));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
+impl Cli {
+    /// Expands `--test-one` into the options it implies.
+    fn apply_test_one(&mut self) {
+        if let Some(identifier) = &self.test_one {
+            self.filter_identifiers = Some(vec![identifier.clone()]);
+            self.verbose = true;
+            self.row_counts = true;
+        }
+    }
 }
 
 pub fn main() -> Cli {
-    Cli::parse()
+    let mut cli = Cli::parse();
+    cli.apply_test_one();
+    cli
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env<'a>(vars: &'a [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        |key| {
+            vars.iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    #[test]
+    fn test_resolve_output_destination() {
+        let path = PathBuf::from("./tmp/test_cli/postgres_url");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "host=from-file dbname=jpksj\n").unwrap();
+        let vars = [
+            ("DATABASE_URL", "host=from-database-url"),
+            ("JPKSJ_POSTGRES_URL", "host=from-jpksj-env"),
+        ];
+
+        // env only; JPKSJ_POSTGRES_URL takes precedence over DATABASE_URL
+        let cli = Cli::try_parse_from(["jpksj-to-sql"]).unwrap();
+        assert_eq!(
+            cli.resolve_output_destination_with_env(true, env(&vars))
+                .unwrap(),
+            "host=from-jpksj-env"
+        );
+        assert_eq!(
+            cli.resolve_output_destination_with_env(true, env(&vars[..1]))
+                .unwrap(),
+            "host=from-database-url"
+        );
+        assert!(cli
+            .resolve_output_destination_with_env(true, env(&[]))
+            .is_err());
+
+        // file > env
+        let cli = Cli::try_parse_from([
+            "jpksj-to-sql",
+            "--postgres-url-file",
+            path.to_str().unwrap(),
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.resolve_output_destination_with_env(true, env(&vars))
+                .unwrap(),
+            "host=from-file dbname=jpksj"
+        );
+
+        // CLI > file > env
+        let cli = Cli::try_parse_from([
+            "jpksj-to-sql",
+            "--postgres-url-file",
+            path.to_str().unwrap(),
+            "host=from-cli",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.resolve_output_destination_with_env(true, env(&vars))
+                .unwrap(),
+            "host=from-cli"
+        );
+
+        // file outputs never fall back to the Postgres sources
+        let cli = Cli::try_parse_from(["jpksj-to-sql", "--format", "GeoJSON"]).unwrap();
+        assert!(cli
+            .resolve_output_destination_with_env(false, env(&vars))
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_sql_identifier() {
+        assert_eq!(parse_sql_identifier("the_geom").unwrap(), "the_geom");
+        assert_eq!(parse_sql_identifier("_geom2").unwrap(), "_geom2");
+        assert!(parse_sql_identifier("2geom").is_err());
+        assert!(parse_sql_identifier("geom; DROP TABLE x").is_err());
+        assert!(parse_sql_identifier("\"geom\"").is_err());
+        assert!(parse_sql_identifier("").is_err());
+        assert!(parse_sql_identifier(&"g".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn test_doctor_subcommand() {
+        let cli = Cli::try_parse_from(["jpksj-to-sql", "doctor"]).unwrap();
+        assert_eq!(cli.command, Some(Command::Doctor));
+        assert_eq!(cli.output_destination, None);
+
+        let cli = Cli::try_parse_from(["jpksj-to-sql", "list", "--json"]).unwrap();
+        assert_eq!(cli.command, Some(Command::List { json: true }));
+
+        let cli = Cli::try_parse_from(["jpksj-to-sql", "postgresql://localhost/jpksj"]).unwrap();
+        assert_eq!(cli.command, None);
+        assert_eq!(
+            cli.output_destination.as_deref(),
+            Some("postgresql://localhost/jpksj")
+        );
+    }
+
+    #[test]
+    fn test_test_one() {
+        let mut cli =
+            Cli::try_parse_from(["jpksj-to-sql", "--test-one", "A38", "host=localhost"]).unwrap();
+        cli.apply_test_one();
+        assert_eq!(cli.filter_identifiers, Some(vec!["A38".to_string()]));
+        assert!(cli.verbose);
+        assert!(cli.row_counts);
+        assert_eq!(cli.output_destination.as_deref(), Some("host=localhost"));
+
+        let mut cli = Cli::try_parse_from(["jpksj-to-sql", "host=localhost"]).unwrap();
+        cli.apply_test_one();
+        assert_eq!(cli.filter_identifiers, None);
+        assert!(!cli.verbose);
+        assert!(!cli.row_counts);
+
+        assert!(Cli::try_parse_from([
+            "jpksj-to-sql",
+            "--test-one",
+            "A38",
+            "--filter-identifiers",
+            "N03"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_years() {
+        let cli = Cli::try_parse_from(["jpksj-to-sql"]).unwrap();
+        assert_eq!(cli.years(), None);
+
+        let cli = Cli::try_parse_from(["jpksj-to-sql", "--year", "2019"]).unwrap();
+        assert_eq!(cli.years(), Some(vec![2019]));
+
+        let cli = Cli::try_parse_from(["jpksj-to-sql", "--year", "2019,2011,2015"]).unwrap();
+        assert_eq!(cli.years(), Some(vec![2011, 2015, 2019]));
+
+        let cli = Cli::try_parse_from([
+            "jpksj-to-sql",
+            "--year-range",
+            "2011-2014",
+            "--year",
+            "2013,2020",
+        ])
+        .unwrap();
+        assert_eq!(cli.years(), Some(vec![2011, 2012, 2013, 2014, 2020]));
+
+        assert!(Cli::try_parse_from(["jpksj-to-sql", "--year-range", "2019-2011"]).is_err());
+        assert!(Cli::try_parse_from(["jpksj-to-sql", "--year-range", "2019"]).is_err());
+    }
+
+    #[test]
+    fn test_extract_limits() {
+        let cli = Cli::try_parse_from(["jpksj-to-sql"]).unwrap();
+        assert_eq!(cli.max_zip_depth, 5);
+        assert_eq!(
+            cli.max_extracted_size.as_u64(),
+            loader::ExtractLimits::default().max_total_bytes
+        );
+
+        let cli = Cli::try_parse_from(["jpksj-to-sql", "--max-extracted-size", "512MiB"]).unwrap();
+        assert_eq!(cli.max_extracted_size.as_u64(), 512 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_max_download_rate() {
+        let cli = Cli::try_parse_from(["jpksj-to-sql"]).unwrap();
+        assert!(cli.max_download_rate.is_none());
+        let cli = Cli::try_parse_from(["jpksj-to-sql", "--max-download-rate", "5MB"]).unwrap();
+        assert_eq!(cli.max_download_rate.unwrap().as_u64(), 5_000_000);
+        assert!(Cli::try_parse_from(["jpksj-to-sql", "--max-download-rate", "fast"]).is_err());
+    }
+
+    #[test]
+    fn test_resume() {
+        let cli = Cli::try_parse_from(["jpksj-to-sql", "--resume"]).unwrap();
+        assert!(cli.resume);
+        assert!(!cli.force);
+        let cli = Cli::try_parse_from(["jpksj-to-sql", "--resume", "--force"]).unwrap();
+        assert!(cli.force);
+        assert!(Cli::try_parse_from(["jpksj-to-sql", "--force"]).is_err());
+    }
+
+    #[test]
+    fn test_suffix_year() {
+        let cli =
+            Cli::try_parse_from(["jpksj-to-sql", "--year", "2019,2024", "--suffix-year"]).unwrap();
+        assert!(cli.suffix_year);
+        assert!(Cli::try_parse_from(["jpksj-to-sql", "--suffix-year", "--fill-gaps"]).is_err());
+    }
+
+    #[test]
+    fn test_where() {
+        let cli = Cli::try_parse_from([
+            "jpksj-to-sql",
+            "--where",
+            "A31=A31_001='27100'",
+            "--where",
+            "N03=N03_001 IN ('東京都', '大阪府')",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.where_filter,
+            vec![
+                ("A31".to_string(), "A31_001='27100'".to_string()),
+                (
+                    "N03".to_string(),
+                    "N03_001 IN ('東京都', '大阪府')".to_string()
+                ),
+            ]
+        );
+        assert!(Cli::try_parse_from(["jpksj-to-sql", "--where", "A31_001"]).is_err());
+    }
+
+    #[test]
+    fn test_ogr_transaction_size() {
+        let cli = Cli::try_parse_from(["jpksj-to-sql"]).unwrap();
+        assert_eq!(
+            cli.ogr_group_transactions,
+            loader::DEFAULT_GROUP_TRANSACTIONS
+        );
+        assert!(!cli.no_copy);
+        let cli = Cli::try_parse_from([
+            "jpksj-to-sql",
+            "--ogr-transaction-size",
+            "1000",
+            "--no-copy",
+        ])
+        .unwrap();
+        assert_eq!(cli.ogr_group_transactions, 1000);
+        assert!(cli.no_copy);
+    }
+
+    #[test]
+    fn test_extra_args() {
+        let cli = Cli::try_parse_from([
+            "jpksj-to-sql",
+            "--ogr2ogr-extra",
+            "--config OGR_TRUNCATE YES -lco 'DESCRIPTION=国土 数値情報'",
+            "--ogrinfo-extra=--config SHAPE_RESTORE_SHX YES",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.ogr2ogr_extra.unwrap().0,
+            vec![
+                "--config",
+                "OGR_TRUNCATE",
+                "YES",
+                "-lco",
+                "DESCRIPTION=国土 数値情報"
+            ]
+        );
+        assert_eq!(
+            cli.ogrinfo_extra.unwrap().0,
+            vec!["--config", "SHAPE_RESTORE_SHX", "YES"]
+        );
+        // no shell: `;` is just an argument
+        assert_eq!(
+            parse_extra_args("-q; rm -rf /").unwrap().0,
+            vec!["-q;", "rm", "-rf", "/"]
+        );
+        assert!(parse_extra_args("--config 'A B").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("0.9").unwrap(), 0.9);
+        assert_eq!(parse_rate("1").unwrap(), 1.0);
+        assert!(parse_rate("1.5").is_err());
+        assert!(parse_rate("-0.1").is_err());
+        assert!(parse_rate("NaN").is_err());
+        assert!(parse_rate("90%").is_err());
+    }
+
+    #[test]
+    fn test_analyze() {
+        let cli = Cli::try_parse_from(["jpksj-to-sql", "--analyze"]).unwrap();
+        assert!(cli.analyze);
+        assert_eq!(cli.analyze_concurrency, loader::DEFAULT_ANALYZE_CONCURRENCY);
+
+        // the concurrency is only meaningful with --analyze
+        assert!(Cli::try_parse_from(["jpksj-to-sql", "--analyze-concurrency", "4"]).is_err());
+    }
+
+    #[test]
+    fn test_load_concurrency() {
+        let cli = Cli::try_parse_from(["jpksj-to-sql"]).unwrap();
+        assert_eq!(cli.load_concurrency, None);
+        assert_eq!(cli.db_write_concurrency, None);
+        let cli = Cli::try_parse_from([
+            "jpksj-to-sql",
+            "--load-concurrency",
+            "8",
+            "--db-write-concurrency",
+            "2",
+        ])
+        .unwrap();
+        assert_eq!(cli.load_concurrency, Some(8));
+        assert_eq!(cli.db_write_concurrency, Some(2));
+    }
+
+    #[test]
+    fn test_force_encoding() {
+        let cli = Cli::try_parse_from([
+            "jpksj-to-sql",
+            "--force-encoding",
+            "cp932",
+            "--force-encoding",
+            "A38=UTF-8",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.force_encoding,
+            vec![
+                (None, "cp932".to_string()),
+                (Some("A38".to_string()), "UTF-8".to_string())
+            ]
+        );
+        assert!(Cli::try_parse_from(["jpksj-to-sql", "--force-encoding", "A38="]).is_err());
+        assert!(Cli::try_parse_from(["jpksj-to-sql", "--force-encoding", " "]).is_err());
+    }
+
+    #[test]
+    fn test_version() {
+        use clap::CommandFactory;
+
+        let version = Cli::command().get_version().unwrap().to_string();
+        assert!(version.starts_with(env!("CARGO_PKG_VERSION")));
+        let git_hash = env!("GIT_HASH");
+        if !git_hash.is_empty() {
+            assert_eq!(
+                version,
+                format!("{} ({})", env!("CARGO_PKG_VERSION"), git_hash)
+            );
+        }
+    }
 }
@@ -1,13 +1,166 @@
-use std::{path::PathBuf, sync::OnceLock};
+use anyhow::{Context, Result};
+use std::{
+    path::PathBuf,
+    sync::{PoisonError, RwLock},
+};
 
 fn default_tmp() -> PathBuf {
     PathBuf::from("./tmp")
 }
 
-static TMP: OnceLock<PathBuf> = OnceLock::new();
+static TMP: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Sets the working directory. It can be set more than once (and after [`tmp`] was read),
+/// but paths already taken from [`tmp`] keep pointing to the previous directory.
 pub fn set_tmp(tmp: PathBuf) {
-    TMP.set(tmp).unwrap();
+    let mut current = TMP.write().unwrap_or_else(PoisonError::into_inner);
+    if let Some(previous) = current.as_ref().filter(|previous| **previous != tmp) {
+        eprintln!(
+            "作業ディレクトリを {} から {} に変更します",
+            previous.display(),
+            tmp.display()
+        );
+    }
+    *current = Some(tmp);
+}
+
+pub fn tmp() -> PathBuf {
+    if let Some(tmp) = TMP.read().unwrap_or_else(PoisonError::into_inner).as_ref() {
+        return tmp.clone();
+    }
+    TMP.write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get_or_insert_with(default_tmp)
+        .clone()
+}
+
+/// User-Agent sent when `--user-agent` isn't given.
+pub const DEFAULT_USER_AGENT: &str = concat!("jpksj-to-sql/", env!("CARGO_PKG_VERSION"));
+
+/// The `HTTPS_PROXY` (or `https_proxy`) environment variable, if set.
+pub fn https_proxy_from_env() -> Option<String> {
+    ["HTTPS_PROXY", "https_proxy"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty())
+}
+
+/// Builds the client shared by every request. `NO_PROXY` is honoured for the proxy.
+pub fn build_http_client(
+    user_agent: Option<&str>,
+    https_proxy: Option<&str>,
+) -> Result<reqwest::Client> {
+    let mut builder =
+        reqwest::Client::builder().user_agent(user_agent.unwrap_or(DEFAULT_USER_AGENT));
+    if let Some(https_proxy) = https_proxy {
+        let proxy = reqwest::Proxy::https(https_proxy)
+            .with_context(|| format!("invalid HTTPS_PROXY: {}", https_proxy))?
+            .no_proxy(reqwest::NoProxy::from_env());
+        builder = builder.proxy(proxy);
+    }
+    builder.build().context("when building HTTP client")
+}
+
+static HTTP_CLIENT: RwLock<Option<reqwest::Client>> = RwLock::new(None);
+/// Sets the client of the following requests. It can be set more than once (and after
+/// [`http_client`] was used).
+pub fn set_http_client(client: reqwest::Client) {
+    *HTTP_CLIENT.write().unwrap_or_else(PoisonError::into_inner) = Some(client);
 }
-pub fn tmp() -> &'static PathBuf {
-    TMP.get_or_init(|| default_tmp())
+/// The shared client (a cheap handle to the same connection pool).
+pub fn http_client() -> reqwest::Client {
+    if let Some(client) = HTTP_CLIENT
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+    {
+        return client.clone();
+    }
+    HTTP_CLIENT
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get_or_insert_with(|| {
+            build_http_client(None, https_proxy_from_env().as_deref())
+                .expect("failed to build the default HTTP client")
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Accepts one connection and returns the request head it received.
+    async fn capture_request() -> (String, tokio::task::JoinHandle<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let _ = stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await;
+            String::from_utf8_lossy(&request).to_lowercase()
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn test_set_tmp_more_than_once() {
+        // other tests use the default directory concurrently, so it is only set to itself
+        let current = tmp();
+        set_tmp(current.clone());
+        set_tmp(current);
+        assert_eq!(tmp(), default_tmp());
+    }
+
+    #[test]
+    fn test_set_http_client_more_than_once() {
+        // set to the client in use, like test_set_tmp_more_than_once
+        let client = http_client();
+        set_http_client(client.clone());
+        set_http_client(client);
+    }
+
+    #[tokio::test]
+    async fn test_user_agent() {
+        let (addr, request) = capture_request().await;
+        let client = build_http_client(None, None).unwrap();
+        client.get(&addr).send().await.unwrap();
+        let request = request.await.unwrap();
+        assert!(request.contains(&format!(
+            "user-agent: {}",
+            DEFAULT_USER_AGENT.to_lowercase()
+        )));
+
+        let (addr, request) = capture_request().await;
+        let client = build_http_client(Some("my-crawler/1.0"), None).unwrap();
+        client.get(&addr).send().await.unwrap();
+        assert!(request
+            .await
+            .unwrap()
+            .contains("user-agent: my-crawler/1.0"));
+    }
+
+    #[tokio::test]
+    async fn test_https_proxy() {
+        let (proxy, request) = capture_request().await;
+        let client = build_http_client(None, Some(&proxy)).unwrap();
+        // the proxy only answers the CONNECT, so the TLS handshake after it fails
+        let _ = client.get("https://nlftp.mlit.go.jp/").send().await;
+        assert!(request
+            .await
+            .unwrap()
+            .starts_with("connect nlftp.mlit.go.jp:443"));
+
+        assert!(build_http_client(None, Some("not a url")).is_err());
+    }
 }
@@ -0,0 +1,190 @@
+// `doctor`: a fast preflight of everything a run depends on (the JPKSJ API, the MLIT
+// code list, GDAL, and optionally PostgreSQL), printed as a pass/fail checklist.
+// The attribute mappings come from the JPKSJ API, so there is no separate mapping file to check.
+
+use anyhow::{Context, Result};
+use url::Url;
+
+use crate::context;
+use crate::loader;
+use crate::scraper::api;
+
+/// 動作確認に使うデータセット
+const SAMPLE_DATASET: &str = "N03";
+
+/// ZIP (xlsx) files start with a local file header.
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Status {
+    Pass(String),
+    Fail(String),
+    Skip(String),
+}
+
+impl Status {
+    fn from_result(result: Result<String>) -> Self {
+        match result {
+            Ok(detail) => Self::Pass(detail),
+            Err(e) => Self::Fail(format!("{:#}", e)),
+        }
+    }
+}
+
+async fn check_dataset_list() -> Result<String> {
+    let list = api::fetch_dataset_list().await?;
+    if list.is_empty() {
+        anyhow::bail!("the dataset list is empty");
+    }
+    Ok(format!("{} 件", list.len()))
+}
+
+async fn check_sample_dataset() -> Result<String> {
+    let detail = api::fetch_dataset_detail(SAMPLE_DATASET).await?;
+    let version = detail
+        .versions
+        .iter()
+        .find(|v| v.most_recent)
+        .or_else(|| detail.versions.first())
+        .with_context(|| format!("{} has no versions", SAMPLE_DATASET))?;
+    let files = api::fetch_dataset_version(SAMPLE_DATASET, &version.id)
+        .await?
+        .files;
+    if files.is_empty() {
+        anyhow::bail!("{} {} has no files", SAMPLE_DATASET, version.id);
+    }
+    Ok(format!(
+        "{} {} ({} ファイル)",
+        SAMPLE_DATASET,
+        version.id,
+        files.len()
+    ))
+}
+
+/// Checks that `url` serves an xlsx file, and not e.g. an HTML rate-limit page.
+async fn check_xlsx(url: &Url) -> Result<String> {
+    let body = context::http_client()
+        .get(url.clone())
+        .send()
+        .await
+        .with_context(|| format!("when requesting {}", url))?
+        .error_for_status()
+        .with_context(|| format!("when checking response from {}", url))?
+        .bytes()
+        .await
+        .with_context(|| format!("when reading {}", url))?;
+    if !body.starts_with(ZIP_MAGIC) {
+        anyhow::bail!(
+            "{} did not return an xlsx file (possibly a rate-limit page)",
+            url
+        );
+    }
+    Ok(format!("{} bytes", body.len()))
+}
+
+async fn check_postgres(postgres_url: &str) -> Result<String> {
+    let (client, connection) = tokio_postgres::connect(postgres_url, tokio_postgres::NoTls)
+        .await
+        .context("when connecting to PostgreSQL")?;
+    tokio::spawn(connection);
+    let row = client
+        .query_one("SELECT version()", &[])
+        .await
+        .context("when querying PostgreSQL")?;
+    Ok(row.get::<_, String>(0))
+}
+
+fn render(checks: &[(&str, Status)]) -> String {
+    let mut out = String::new();
+    for (name, status) in checks {
+        let (label, detail) = match status {
+            Status::Pass(detail) => ("OK", detail),
+            Status::Fail(detail) => ("NG", detail),
+            Status::Skip(detail) => ("SKIP", detail),
+        };
+        out.push_str(&format!("[{:<4}] {}: {}\n", label, name, detail));
+    }
+    out
+}
+
+/// チェックを実行して結果を表示します。失敗したチェックがある場合はエラーを返します
+pub async fn run(postgres_url: Option<&str>) -> Result<()> {
+    let admini_boundary_url = Url::parse(loader::ADMINI_BOUNDARY_URL)?;
+    let mut checks = vec![
+        (
+            "JPKSJ API (データセット一覧)",
+            Status::from_result(check_dataset_list().await),
+        ),
+        (
+            "JPKSJ API (データページ)",
+            Status::from_result(check_sample_dataset().await),
+        ),
+        (
+            "AdminiBoundary_CD.xlsx",
+            Status::from_result(check_xlsx(&admini_boundary_url).await),
+        ),
+        (
+            "GDAL (ogr2ogr, ogrinfo)",
+            Status::from_result(
+                loader::check_gdal_tools(postgres_url.is_some())
+                    .await
+                    .map(|info| info.summary()),
+            ),
+        ),
+    ];
+    checks.push((
+        "PostgreSQL",
+        match postgres_url {
+            Some(url) => Status::from_result(check_postgres(url).await),
+            None => Status::Skip("接続先が指定されていません".to_string()),
+        },
+    ));
+
+    print!("{}", render(&checks));
+    let failed = checks
+        .iter()
+        .filter(|(_, status)| matches!(status, Status::Fail(_)))
+        .count();
+    if failed > 0 {
+        anyhow::bail!("{} 件のチェックが失敗しました", failed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::spawn_http_server;
+
+    #[tokio::test]
+    async fn test_check_xlsx() {
+        let base = spawn_http_server(vec![
+            ("/ok.xlsx", 200, b"PK\x03\x04rest of the workbook".to_vec()),
+            (
+                "/limited.xlsx",
+                200,
+                b"<html>Too many requests</html>".to_vec(),
+            ),
+        ])
+        .await;
+
+        assert!(check_xlsx(&base.join("ok.xlsx").unwrap()).await.is_ok());
+        let err = check_xlsx(&base.join("limited.xlsx").unwrap())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("rate-limit"));
+        assert!(check_xlsx(&base.join("missing.xlsx").unwrap())
+            .await
+            .is_err());
+
+        let checks = [
+            ("a", Status::Pass("1 件".to_string())),
+            ("b", Status::Fail("timeout".to_string())),
+            ("c", Status::Skip("-".to_string())),
+        ];
+        assert_eq!(
+            render(&checks),
+            "[OK  ] a: 1 件\n[NG  ] b: timeout\n[SKIP] c: -\n"
+        );
+    }
+}
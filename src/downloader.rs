@@ -1,9 +1,13 @@
 use anyhow::Result;
 use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
 use tokio::fs::{self, File};
 use tokio::io::AsyncWriteExt;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use url::Url;
 
 use crate::context;
@@ -12,10 +16,18 @@ use crate::context;
 struct Metadata {
     last_modified: Option<String>,
     etag: Option<String>,
+    /// 期待するファイルサイズ（`Content-Length`）。ダウンロード完了後は実際のサイズ
+    #[serde(default)]
+    content_length: Option<u64>,
+    /// ダウンロード完了時の SHA-256（記録のみで、再利用時には検証しません）
+    #[serde(default)]
+    sha256: Option<String>,
 }
 
 pub struct DownloadedFile {
     pub path: PathBuf,
+    /// ファイルのサイズ（再利用した場合も含む）
+    pub bytes: u64,
 }
 
 pub fn path_for_url(url: &Url) -> (PathBuf, PathBuf) {
@@ -30,22 +42,309 @@ pub fn path_for_url(url: &Url) -> (PathBuf, PathBuf) {
     )
 }
 
+/// Caps the number of simultaneous connections to each host (`--parallel-downloads-per-host`),
+/// independently of how many download workers there are.
+pub struct HostLimiter {
+    limit: Option<usize>,
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl HostLimiter {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit: limit.map(|limit| limit.max(1)),
+            semaphores: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits for a free connection slot for the host of `url`. `None` when there is no limit.
+    pub async fn acquire(&self, url: &Url) -> Option<OwnedSemaphorePermit> {
+        let limit = self.limit?;
+        let host = format!(
+            "{}:{}",
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or_default()
+        );
+        let semaphore = self
+            .semaphores
+            .lock()
+            .unwrap()
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+            .clone();
+        semaphore.acquire_owned().await.ok()
+    }
+}
+
+static HOST_LIMITER: RwLock<Option<Arc<HostLimiter>>> = RwLock::new(None);
+/// The per-host connection limit. It can be set more than once; the downloads already
+/// running keep their connection slots of the previous limit.
+pub fn set_per_host_limit(limit: Option<usize>) {
+    *HOST_LIMITER.write().unwrap_or_else(PoisonError::into_inner) =
+        Some(Arc::new(HostLimiter::new(limit)));
+}
+fn host_limiter() -> Arc<HostLimiter> {
+    HOST_LIMITER
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get_or_insert_with(|| Arc::new(HostLimiter::new(None)))
+        .clone()
+}
+
+/// Caps the combined bandwidth of all downloads (`--max-download-rate`).
+/// A token bucket holding at most one second's worth of bytes, shared by every worker.
+pub struct RateLimiter {
+    bytes_per_second: f64,
+    bucket: tokio::sync::Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Negative while the bytes already let through are ahead of the rate
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let bytes_per_second = bytes_per_second.max(1) as f64;
+        Self {
+            bytes_per_second,
+            bucket: tokio::sync::Mutex::new(Bucket {
+                tokens: bytes_per_second,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until `bytes` more may be written.
+    pub async fn acquire(&self, bytes: usize) {
+        // held while sleeping, so that the waiting workers are let through one at a time
+        let mut bucket = self.bucket.lock().await;
+        let now = Instant::now();
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * self.bytes_per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(self.bytes_per_second) - bytes as f64;
+        bucket.updated = now;
+        if bucket.tokens < 0.0 {
+            let wait = Duration::from_secs_f64(-bucket.tokens / self.bytes_per_second);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+static RATE_LIMITER: RwLock<Option<Arc<RateLimiter>>> = RwLock::new(None);
+/// The total download rate in bytes per second, `None` for no limit. It can be set more
+/// than once; the downloads already running keep the previous limit.
+pub fn set_max_rate(bytes_per_second: Option<u64>) {
+    *RATE_LIMITER.write().unwrap_or_else(PoisonError::into_inner) =
+        bytes_per_second.map(|rate| Arc::new(RateLimiter::new(rate)));
+}
+fn rate_limiter() -> Option<Arc<RateLimiter>> {
+    RATE_LIMITER
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+static VERIFY: RwLock<bool> = RwLock::new(true);
+/// Whether to check the size of previously downloaded files before reusing them (`--no-verify` disables it).
+/// It can be set more than once.
+pub fn set_verify(verify: bool) {
+    *VERIFY.write().unwrap_or_else(PoisonError::into_inner) = verify;
+}
+fn verify() -> bool {
+    *VERIFY.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The hosts the datasets are downloaded from, unless `--allowed-hosts` says otherwise.
+pub const DEFAULT_ALLOWED_HOSTS: &[&str] = &["nlftp.mlit.go.jp"];
+
+static ALLOWED_HOSTS: RwLock<Option<Vec<String>>> = RwLock::new(None);
+/// The hosts files may be downloaded from (`--allowed-hosts`), including their subdomains.
+/// `*` allows any host. It can be set more than once.
+pub fn set_allowed_hosts(hosts: Vec<String>) {
+    *ALLOWED_HOSTS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = Some(hosts);
+}
+/// The hosts set by [`set_allowed_hosts`], or [`DEFAULT_ALLOWED_HOSTS`].
+pub fn allowed_hosts() -> Vec<String> {
+    ALLOWED_HOSTS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .unwrap_or_else(|| {
+            DEFAULT_ALLOWED_HOSTS
+                .iter()
+                .map(|host| host.to_string())
+                .collect()
+        })
+}
+
+fn is_allowed_host(url: &Url, allowed: &[String]) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let host = host.to_ascii_lowercase();
+    allowed.iter().any(|allowed| {
+        let allowed = allowed.to_ascii_lowercase();
+        allowed == "*"
+            || host == allowed
+            || host
+                .strip_suffix(&allowed)
+                .is_some_and(|sub| sub.ends_with('.'))
+    })
+}
+
+/// Fails for a URL outside of `allowed_hosts`, e.g. a link to another site in the listing.
+pub fn ensure_allowed_host(url: &Url, allowed_hosts: &[String]) -> Result<()> {
+    if !is_allowed_host(url, allowed_hosts) {
+        anyhow::bail!(
+            "{} は許可されていないホストのため、ダウンロードしません（--allowed-hosts で許可できます）",
+            url
+        );
+    }
+    Ok(())
+}
+
 pub async fn download_to_tmp(url: &Url) -> Result<DownloadedFile> {
+    download_to_tmp_with_progress(url, &|_| {}).await
+}
+
+/// [`download_to_tmp`], calling `on_chunk` with the number of bytes of each chunk written.
+/// A resumed download only reports the bytes written this time.
+pub async fn download_to_tmp_with_progress(
+    url: &Url,
+    on_chunk: &(dyn Fn(u64) + Sync),
+) -> Result<DownloadedFile> {
+    download_to_tmp_from(url, &allowed_hosts(), on_chunk).await
+}
+
+/// [`download_to_tmp_with_progress`] from `allowed_hosts` instead of [`allowed_hosts`].
+pub async fn download_to_tmp_from(
+    url: &Url,
+    allowed_hosts: &[String],
+    on_chunk: &(dyn Fn(u64) + Sync),
+) -> Result<DownloadedFile> {
+    download_to_tmp_with(
+        url,
+        allowed_hosts,
+        &host_limiter(),
+        rate_limiter().as_deref(),
+        verify(),
+        on_chunk,
+    )
+    .await
+}
+
+/// The file an unfinished download is written to, and its metadata.
+fn part_paths(file_path: &Path) -> (PathBuf, PathBuf) {
+    let mut part = file_path.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    let mut part_meta = part.as_os_str().to_owned();
+    part_meta.push(".meta.json");
+    (part, PathBuf::from(part_meta))
+}
+
+async fn read_metadata(path: &Path) -> Option<Metadata> {
+    let content = fs::read_to_string(path).await.ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+fn header_string(
+    response: &reqwest::Response,
+    name: reqwest::header::HeaderName,
+) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string())
+}
+
+/// The total length from a `Content-Range: bytes 100-999/1000` header.
+fn content_range_total(response: &reqwest::Response) -> Option<u64> {
+    header_string(response, reqwest::header::CONTENT_RANGE)?
+        .rsplit_once('/')?
+        .1
+        .parse()
+        .ok()
+}
+
+/// Whether the downloaded file still has the size recorded when it was completed.
+async fn has_recorded_size(file_path: &Path, metadata: &Metadata) -> bool {
+    match (metadata.content_length, fs::metadata(file_path).await) {
+        (Some(expected), Ok(file)) => file.len() == expected,
+        // nothing recorded (downloaded by an older version), or the file is missing
+        (None, _) | (_, Err(_)) => true,
+    }
+}
+
+async fn sha256_of(path: &Path) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || -> Result<String> {
+        let mut file = std::fs::File::open(&path)?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut file, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await?
+}
+
+async fn download_to_tmp_with(
+    url: &Url,
+    allowed_hosts: &[String],
+    limiter: &HostLimiter,
+    rate_limiter: Option<&RateLimiter>,
+    verify: bool,
+    on_chunk: &(dyn Fn(u64) + Sync),
+) -> Result<DownloadedFile> {
+    ensure_allowed_host(url, allowed_hosts)?;
+    // held until the body has been written
+    let _permit = limiter.acquire(url).await;
     let (file_path, meta_path) = path_for_url(&url);
+    let (part_path, part_meta_path) = part_paths(&file_path);
 
     // Try to read existing metadata if it exists.
-    let metadata: Option<Metadata> = if let Ok(meta_content) = fs::read_to_string(&meta_path).await
-    {
-        serde_json::from_str(&meta_content).ok()
-    } else {
-        None
+    let mut metadata = read_metadata(&meta_path).await;
+    if let (true, Some(meta)) = (verify, &metadata) {
+        if !has_recorded_size(&file_path, meta).await {
+            // e.g. an earlier write was cut short: download it again instead of reusing a broken ZIP
+            eprintln!(
+                "{} のサイズが記録と一致しないため、再ダウンロードします",
+                file_path.display()
+            );
+            let _ = fs::remove_file(&file_path).await;
+            let _ = fs::remove_file(&meta_path).await;
+            metadata = None;
+        }
+    }
+    // An interrupted download, which is resumed if the server still has the same file.
+    let part_offset = match fs::metadata(&part_path).await {
+        Ok(part) if part.len() > 0 => Some(part.len()),
+        _ => None,
+    };
+    let part_metadata = match part_offset {
+        Some(_) => read_metadata(&part_meta_path).await,
+        None => None,
     };
 
-    let client = reqwest::Client::new();
-    let mut request = client.get(url.clone());
+    let mut request = context::http_client().get(url.clone());
 
-    // Add conditional headers if metadata is available.
-    if let Some(meta) = &metadata {
+    // without If-Range, a file that changed on the server would be appended to the old one
+    let resume_validator = part_metadata
+        .as_ref()
+        .and_then(|m| m.etag.as_ref().or(m.last_modified.as_ref()));
+    if let (Some(offset), Some(validator)) = (part_offset, resume_validator) {
+        request = request
+            .header(reqwest::header::RANGE, format!("bytes={}-", offset))
+            .header(reqwest::header::IF_RANGE, validator);
+    } else if let Some(meta) = &metadata {
+        // Add conditional headers if metadata is available.
         if let Some(etag) = &meta.etag {
             request = request.header(reqwest::header::IF_NONE_MATCH, etag);
         }
@@ -55,6 +354,8 @@ pub async fn download_to_tmp(url: &Url) -> Result<DownloadedFile> {
     }
 
     let response = request.send().await?;
+    // a redirect may have led to another host
+    ensure_allowed_host(response.url(), allowed_hosts)?;
 
     // If the server indicates the file has not changed, return the existing file.
     if response.status() == reqwest::StatusCode::NOT_MODIFIED {
@@ -64,44 +365,424 @@ pub async fn download_to_tmp(url: &Url) -> Result<DownloadedFile> {
                 "Server returned 304 Not Modified, but file is missing"
             ));
         }
-        return Ok(DownloadedFile { path: file_path });
+        let bytes = fs::metadata(&file_path).await?.len();
+        return Ok(DownloadedFile {
+            path: file_path,
+            bytes,
+        });
+    }
+
+    if response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        // the part file is already complete, or longer than the file on the server
+        let expected = part_metadata.as_ref().and_then(|m| m.content_length);
+        if expected.is_none() || expected != part_offset {
+            fs::remove_file(&part_path).await?;
+            anyhow::bail!("could not resume {}, restarting the download", url);
+        }
+        let part_meta = part_metadata.expect("checked above");
+        return finish_download(
+            &file_path,
+            &meta_path,
+            &part_path,
+            &part_meta_path,
+            part_meta,
+        )
+        .await;
     }
 
     // Ensure the response is successful (will error on 4xx or 5xx responses).
     let response = response.error_for_status()?;
 
-    // Create (or overwrite) the target file.
-    let mut file = File::create(&file_path).await?;
-
-    // Extract metadata from response headers.
-    let last_modified = response
-        .headers()
-        .get(reqwest::header::LAST_MODIFIED)
-        .and_then(|v| v.to_str().ok())
-        .map(|s| s.to_string());
-    let etag = response
-        .headers()
-        .get(reqwest::header::ETAG)
-        .and_then(|v| v.to_str().ok())
-        .map(|s| s.to_string());
-
-    let new_metadata = Metadata {
-        last_modified,
-        etag,
+    let resumed = response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+    let (mut file, new_metadata) = if resumed {
+        let part_meta = part_metadata.expect("a range is only requested with part metadata");
+        let offset = part_offset.unwrap_or_default();
+        let content_length = content_range_total(&response)
+            .or_else(|| response.content_length().map(|len| len + offset))
+            .or(part_meta.content_length);
+        let file = fs::OpenOptions::new().append(true).open(&part_path).await?;
+        (
+            file,
+            Metadata {
+                content_length,
+                ..part_meta
+            },
+        )
+    } else {
+        // Extract metadata from response headers.
+        let new_metadata = Metadata {
+            last_modified: header_string(&response, reqwest::header::LAST_MODIFIED),
+            etag: header_string(&response, reqwest::header::ETAG),
+            content_length: response.content_length(),
+            sha256: None,
+        };
+        // Create (or overwrite) the part file.
+        let file = File::create(&part_path).await?;
+        fs::write(
+            &part_meta_path,
+            serde_json::to_string_pretty(&new_metadata)?,
+        )
+        .await?;
+        (file, new_metadata)
     };
 
     // Stream the response body and write it chunk by chunk.
     let mut stream = response.bytes_stream();
     while let Some(chunk) = stream.next().await {
         let chunk = chunk?;
+        if let Some(rate_limiter) = rate_limiter {
+            rate_limiter.acquire(chunk.len()).await;
+        }
         file.write_all(&chunk).await?;
+        on_chunk(chunk.len() as u64);
     }
     file.flush().await?;
 
+    finish_download(
+        &file_path,
+        &meta_path,
+        &part_path,
+        &part_meta_path,
+        new_metadata,
+    )
+    .await
+}
+
+/// Checks the length of the part file, and moves it to its final name.
+async fn finish_download(
+    file_path: &Path,
+    meta_path: &Path,
+    part_path: &Path,
+    part_meta_path: &Path,
+    mut metadata: Metadata,
+) -> Result<DownloadedFile> {
+    let len = fs::metadata(part_path).await?.len();
+    if let Some(expected) = metadata.content_length {
+        if len != expected {
+            if len > expected {
+                // cannot be resumed
+                fs::remove_file(part_path).await?;
+            }
+            anyhow::bail!(
+                "incomplete download of {}: {} of {} bytes",
+                file_path.display(),
+                len,
+                expected
+            );
+        }
+    }
+    metadata.content_length = Some(len);
+    metadata.sha256 = Some(sha256_of(part_path).await?);
+    fs::rename(part_path, file_path).await?;
+
     // Serialize and write the metadata to a {filename}.meta.json file.
-    let meta_json = serde_json::to_string_pretty(&new_metadata)?;
-    fs::write(&meta_path, meta_json).await?;
+    let meta_json = serde_json::to_string_pretty(&metadata)?;
+    fs::write(meta_path, meta_json).await?;
     // Note that this is set after the file is completely written. That way, if the process crashed or was interrupted, we won't have a partial file.
+    let _ = fs::remove_file(part_meta_path).await;
 
-    Ok(DownloadedFile { path: file_path })
+    Ok(DownloadedFile {
+        path: file_path.to_path_buf(),
+        bytes: len,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use tokio::io::AsyncReadExt;
+
+    /// The allowed hosts of the tests' local servers.
+    fn local() -> Vec<String> {
+        vec!["127.0.0.1".to_string()]
+    }
+
+    /// A server that answers slowly and records the peak number of simultaneous connections.
+    async fn spawn_slow_server(peak: Arc<AtomicUsize>) -> Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let active = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let active = active.clone();
+                let peak = peak.clone();
+                tokio::spawn(async move {
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\ndata")
+                        .await;
+                    active.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        Url::parse(&format!("http://{}/", addr)).unwrap()
+    }
+
+    #[test]
+    fn test_is_allowed_host() {
+        let allowed = vec!["nlftp.mlit.go.jp".to_string()];
+        let url = |s: &str| Url::parse(s).unwrap();
+        assert!(is_allowed_host(
+            &url("https://nlftp.mlit.go.jp/ksj/gml/data/N03/N03-2024.zip"),
+            &allowed
+        ));
+        assert!(is_allowed_host(
+            &url("http://NLFTP.mlit.go.jp/a.zip"),
+            &allowed
+        ));
+        assert!(is_allowed_host(
+            &url("https://www.nlftp.mlit.go.jp/a.zip"),
+            &allowed
+        ));
+        assert!(!is_allowed_host(
+            &url("https://example.com/a.zip"),
+            &allowed
+        ));
+        assert!(!is_allowed_host(
+            &url("https://evilnlftp.mlit.go.jp/a.zip"),
+            &allowed
+        ));
+        assert!(!is_allowed_host(
+            &url("https://nlftp.mlit.go.jp.example.com/a.zip"),
+            &allowed
+        ));
+        assert!(!is_allowed_host(&url("file:///etc/passwd"), &allowed));
+        assert!(is_allowed_host(
+            &url("https://example.com/a.zip"),
+            &["*".to_string()]
+        ));
+    }
+
+    #[test]
+    fn test_settings_can_be_set_again() {
+        // e.g. by a library caller after a download already used the defaults
+        assert!(host_limiter().limit.is_none());
+        set_per_host_limit(Some(2));
+        set_per_host_limit(Some(3));
+        assert_eq!(host_limiter().limit, Some(3));
+        set_per_host_limit(None);
+
+        set_max_rate(Some(1_000));
+        set_max_rate(Some(2_000));
+        assert_eq!(rate_limiter().unwrap().bytes_per_second, 2_000.0);
+        set_max_rate(None);
+        assert!(rate_limiter().is_none());
+
+        assert!(verify());
+        set_verify(false);
+        set_verify(true);
+        assert!(verify());
+
+        let defaults = allowed_hosts();
+        set_allowed_hosts(local());
+        set_allowed_hosts(defaults.clone());
+        assert_eq!(allowed_hosts(), defaults);
+    }
+
+    #[tokio::test]
+    async fn test_disallowed_host_is_not_downloaded() {
+        let peak = Arc::new(AtomicUsize::new(0));
+        let base = spawn_slow_server(peak.clone()).await;
+        let url = base.join("disallowed.zip").unwrap();
+        let defaults: Vec<String> = DEFAULT_ALLOWED_HOSTS
+            .iter()
+            .map(|h| h.to_string())
+            .collect();
+        let Err(error) = download_to_tmp_from(&url, &defaults, &|_| {}).await else {
+            panic!("downloaded from a host that isn't allowed");
+        };
+        assert!(error.to_string().contains("許可されていないホスト"));
+        assert_eq!(peak.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_is_shared() {
+        let limiter = Arc::new(RateLimiter::new(100_000));
+        let start = Instant::now();
+        let mut set = tokio::task::JoinSet::new();
+        for _ in 0..2 {
+            let limiter = limiter.clone();
+            set.spawn(async move {
+                for _ in 0..4 {
+                    limiter.acquire(16_250).await;
+                }
+            });
+        }
+        while let Some(result) = set.join_next().await {
+            result.unwrap();
+        }
+        // 130,000 bytes in total: the first 100,000 at once, the rest at 100,000 bytes/s.
+        // Each worker alone stays within the first second's worth.
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(250), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_per_host_limit() {
+        fs::create_dir_all(context::tmp()).await.unwrap();
+        let peak = Arc::new(AtomicUsize::new(0));
+        let base = spawn_slow_server(peak.clone()).await;
+        let limiter = Arc::new(HostLimiter::new(Some(2)));
+
+        let mut set = tokio::task::JoinSet::new();
+        for i in 0..6 {
+            let url = base.join(&format!("per_host_limit_{}.zip", i)).unwrap();
+            let limiter = limiter.clone();
+            set.spawn(async move {
+                download_to_tmp_with(&url, &local(), &limiter, None, true, &|_| {}).await
+            });
+        }
+        while let Some(result) = set.join_next().await {
+            result.unwrap().unwrap();
+        }
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+        assert!(peak.load(Ordering::SeqCst) >= 1);
+    }
+
+    /// A server for `body` that honours `Range` when `If-Range` matches `etag`,
+    /// and answers 304 when `If-None-Match` matches it.
+    async fn spawn_range_server(body: &'static [u8], etag: &'static str) -> Url {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request).to_ascii_lowercase();
+                    let header = |name: &str| {
+                        request
+                            .lines()
+                            .find_map(|line| line.strip_prefix(&format!("{}: ", name)))
+                            .map(str::to_string)
+                    };
+                    let offset = header("range")
+                        .filter(|_| header("if-range").as_deref() == Some(etag))
+                        .and_then(|range| {
+                            range
+                                .strip_prefix("bytes=")?
+                                .strip_suffix('-')?
+                                .parse::<usize>()
+                                .ok()
+                        });
+                    if header("if-none-match").as_deref() == Some(etag) {
+                        let _ = stream
+                            .write_all(b"HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n")
+                            .await;
+                        return;
+                    }
+                    let head = match offset {
+                        Some(offset) => format!(
+                            "HTTP/1.1 206 Partial Content\r\nETag: {}\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            etag,
+                            offset,
+                            body.len() - 1,
+                            body.len(),
+                            body.len() - offset
+                        ),
+                        None => format!(
+                            "HTTP/1.1 200 OK\r\nETag: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                            etag,
+                            body.len()
+                        ),
+                    };
+                    let _ = stream.write_all(head.as_bytes()).await;
+                    let _ = stream.write_all(&body[offset.unwrap_or(0)..]).await;
+                });
+            }
+        });
+        Url::parse(&format!("http://{}/", addr)).unwrap()
+    }
+
+    async fn write_part(url: &Url, content: &[u8], etag: &str) {
+        let (file_path, meta_path) = path_for_url(url);
+        let (part_path, part_meta_path) = part_paths(&file_path);
+        let _ = fs::remove_file(&file_path).await;
+        let _ = fs::remove_file(&meta_path).await;
+        fs::write(&part_path, content).await.unwrap();
+        let meta = Metadata {
+            last_modified: None,
+            etag: Some(etag.to_string()),
+            content_length: Some(10),
+            sha256: None,
+        };
+        fs::write(&part_meta_path, serde_json::to_string(&meta).unwrap())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_part_download() {
+        fs::create_dir_all(context::tmp()).await.unwrap();
+        let base = spawn_range_server(b"0123456789", "\"v1\"").await;
+
+        // the server still has the same file: only the rest is fetched and appended
+        // (the part is written in upper case to tell it apart from a full download)
+        let url = base.join("resume_same.zip").unwrap();
+        write_part(&url, b"ABCDE", "\"v1\"").await;
+        let written = AtomicU64::new(0);
+        let file = download_to_tmp_from(&url, &local(), &|bytes| {
+            written.fetch_add(bytes, Ordering::SeqCst);
+        })
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&file.path).await.unwrap(), b"ABCDE56789");
+        assert_eq!(file.bytes, 10);
+        assert_eq!(written.load(Ordering::SeqCst), 5);
+        let (part_path, part_meta_path) = part_paths(&file.path);
+        assert!(!part_path.exists());
+        assert!(!part_meta_path.exists());
+        let meta = read_metadata(&path_for_url(&url).1).await.unwrap();
+        assert_eq!(meta.content_length, Some(10));
+
+        // the file changed on the server: it is downloaded from the start
+        let url = base.join("resume_changed.zip").unwrap();
+        write_part(&url, b"abcde", "\"v0\"").await;
+        let file = download_to_tmp_from(&url, &local(), &|_| {}).await.unwrap();
+        assert_eq!(fs::read(&file.path).await.unwrap(), b"0123456789");
+    }
+
+    #[tokio::test]
+    async fn test_truncated_download_is_fetched_again() {
+        fs::create_dir_all(context::tmp()).await.unwrap();
+        let base = spawn_range_server(b"0123456789", "\"v1\"").await;
+        let limiter = HostLimiter::new(None);
+
+        let url = base.join("verify_truncated.zip").unwrap();
+        let file = download_to_tmp_with(&url, &local(), &limiter, None, true, &|_| {})
+            .await
+            .unwrap();
+        let meta = read_metadata(&path_for_url(&url).1).await.unwrap();
+        assert_eq!(meta.content_length, Some(10));
+        assert_eq!(
+            meta.sha256.as_deref(),
+            Some("84d89877f0d4041efb6bf91a16f0248f2fd573e6af05c19f96bedb9f882f7882")
+        );
+
+        // unchanged on the server (304), but the local copy was cut short
+        fs::write(&file.path, b"01234").await.unwrap();
+        let file = download_to_tmp_with(&url, &local(), &limiter, None, true, &|_| {})
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&file.path).await.unwrap(), b"0123456789");
+
+        // --no-verify reuses whatever is there
+        fs::write(&file.path, b"01234").await.unwrap();
+        let file = download_to_tmp_with(&url, &local(), &limiter, None, false, &|_| {})
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&file.path).await.unwrap(), b"01234");
+    }
 }
@@ -0,0 +1,347 @@
+// Exports of the scraped metadata that don't depend on the output target.
+
+use crate::scraper::data_page::RefType;
+use crate::scraper::{CachedPage, Dataset};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug, Serialize)]
+pub struct AttributeRecord<'a> {
+    pub identifier: &'a str,
+    pub attribute_id: &'a str,
+    pub name: &'a str,
+    pub description: &'a str,
+    #[serde(rename = "type")]
+    pub attr_type: &'a str,
+    pub r#ref: Option<&'a RefType>,
+}
+
+/// 全データセットの属性を1行1レコードで列挙します（識別子・属性ID順）
+pub fn attribute_records(datasets: &[Dataset]) -> Vec<AttributeRecord<'_>> {
+    let mut records = Vec::new();
+    for dataset in datasets {
+        let mut attributes = dataset.page.metadata.attribute.iter().collect::<Vec<_>>();
+        attributes.sort_by(|a, b| a.0.cmp(b.0));
+        for (attribute_id, attr) in attributes {
+            records.push(AttributeRecord {
+                identifier: &dataset.initial_item.identifier,
+                attribute_id,
+                name: &attr.name,
+                description: &attr.description,
+                attr_type: &attr.attr_type,
+                r#ref: attr.r#ref.as_ref(),
+            });
+        }
+    }
+    records
+}
+
+/// Writes the attribute catalog as newline-delimited JSON.
+pub fn write_attribute_catalog(datasets: &[Dataset], path: &Path) -> Result<()> {
+    let file =
+        std::fs::File::create(path).with_context(|| format!("when creating {}", path.display()))?;
+    let mut writer = std::io::BufWriter::new(file);
+    for record in attribute_records(datasets) {
+        serde_json::to_writer(&mut writer, &record)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+const CATALOG_SCHEMA: &str = r#"
+CREATE TABLE datasets (
+    identifier TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    category1 TEXT NOT NULL,
+    category2 TEXT NOT NULL,
+    usage TEXT NOT NULL,
+    source_url TEXT NOT NULL,
+    version_id TEXT NOT NULL,
+    start_year INTEGER NOT NULL,
+    end_year INTEGER NOT NULL,
+    description TEXT,
+    metadata TEXT NOT NULL,
+    listing_fingerprint TEXT NOT NULL,
+    page TEXT NOT NULL
+);
+CREATE TABLE attributes (
+    identifier TEXT NOT NULL REFERENCES datasets (identifier),
+    attribute_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    description TEXT NOT NULL,
+    type TEXT NOT NULL,
+    ref_url TEXT,
+    PRIMARY KEY (identifier, attribute_id)
+);
+CREATE TABLE codelists (
+    identifier TEXT NOT NULL,
+    attribute_id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    code TEXT NOT NULL,
+    label TEXT NOT NULL,
+    FOREIGN KEY (identifier, attribute_id) REFERENCES attributes (identifier, attribute_id)
+);
+CREATE INDEX codelists_attribute_idx ON codelists (identifier, attribute_id);
+"#;
+
+/// スクレイプしたメタデータ（データセット・属性・コードリスト）を SQLite のカタログとして書き出します。
+/// 既存のファイルは置き換えられます。
+pub fn write_sqlite_catalog(datasets: &[Dataset], path: &Path) -> Result<()> {
+    if path.exists() {
+        std::fs::remove_file(path)
+            .with_context(|| format!("when removing existing catalog {}", path.display()))?;
+    }
+    let mut conn = rusqlite::Connection::open(path)
+        .with_context(|| format!("when opening {}", path.display()))?;
+    let tx = conn.transaction()?;
+    tx.execute_batch(CATALOG_SCHEMA)?;
+    {
+        let mut insert_dataset = tx.prepare(
+            "INSERT INTO datasets VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+        )?;
+        let mut insert_attribute =
+            tx.prepare("INSERT INTO attributes VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+        let mut insert_code = tx.prepare("INSERT INTO codelists VALUES (?1, ?2, ?3, ?4, ?5)")?;
+
+        for dataset in datasets {
+            let item = &dataset.initial_item;
+            let page = &dataset.page;
+            insert_dataset.execute(rusqlite::params![
+                item.identifier,
+                item.name,
+                item.category1_name,
+                item.category2_name,
+                item.usage,
+                page.url.as_str(),
+                page.version.id,
+                page.version.start_year,
+                page.version.end_year,
+                page.metadata.fundamental.get("内容"),
+                serde_json::to_string(&page.metadata)?,
+                dataset.listing_fingerprint,
+                serde_json::to_string(page.as_ref())?,
+            ])?;
+        }
+
+        for record in attribute_records(datasets) {
+            let ref_url = datasets
+                .iter()
+                .find(|d| d.initial_item.identifier == record.identifier)
+                .and_then(|d| d.page.metadata.attribute.get(record.attribute_id))
+                .and_then(|attr| attr.ref_url.as_ref())
+                .map(|url| url.to_string());
+            insert_attribute.execute(rusqlite::params![
+                record.identifier,
+                record.attribute_id,
+                record.name,
+                record.description,
+                record.attr_type,
+                ref_url,
+            ])?;
+            match record.r#ref {
+                Some(RefType::Enum(values)) => {
+                    for value in values {
+                        insert_code.execute(rusqlite::params![
+                            record.identifier,
+                            record.attribute_id,
+                            "enum",
+                            value,
+                            value,
+                        ])?;
+                    }
+                }
+                Some(codes) => {
+                    let mut codes = codes
+                        .flat_codes()
+                        .unwrap_or_default()
+                        .into_iter()
+                        .collect::<Vec<_>>();
+                    codes.sort();
+                    for (code, label) in codes {
+                        insert_code.execute(rusqlite::params![
+                            record.identifier,
+                            record.attribute_id,
+                            "code",
+                            code,
+                            label,
+                        ])?;
+                    }
+                }
+                None => {}
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// 前回の SQLite カタログからデータページを読み込みます（インクリメンタル取得用）
+pub fn read_cached_pages(path: &Path) -> Result<HashMap<String, CachedPage>> {
+    let conn =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("when opening {}", path.display()))?;
+    let mut stmt = conn
+        .prepare("SELECT identifier, listing_fingerprint, page FROM datasets")
+        .with_context(|| format!("when reading datasets from {}", path.display()))?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+    let mut pages = HashMap::new();
+    for row in rows {
+        let (identifier, listing_fingerprint, page) = row?;
+        let page = serde_json::from_str(&page)
+            .with_context(|| format!("when parsing cached page for {}", identifier))?;
+        pages.insert(
+            identifier,
+            CachedPage {
+                listing_fingerprint,
+                page,
+            },
+        );
+    }
+    Ok(pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::fixture_dataset;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_write_attribute_catalog() {
+        let mut codes = HashMap::new();
+        codes.insert("1".to_string(), "国".to_string());
+        let mut a27 = fixture_dataset(
+            "A27",
+            vec![
+                ("A27_002", "設置主体", "コードリスト"),
+                ("A27_001", "行政区域コード", "コードリスト"),
+            ],
+        );
+        let mut page = Arc::try_unwrap(a27.page).unwrap();
+        page.metadata.attribute.get_mut("A27_002").unwrap().r#ref = Some(RefType::Code(codes));
+        a27.page = Arc::new(page);
+        let datasets = vec![
+            fixture_dataset("N03", vec![("N03_001", "都道府県名", "文字列型")]),
+            a27,
+        ];
+
+        let dir = PathBuf::from("./tmp/test_export");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("attributes.jsonl");
+        write_attribute_catalog(&datasets, &path).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let rows = contents
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0]["identifier"], "N03");
+        assert_eq!(rows[0]["attribute_id"], "N03_001");
+        assert_eq!(rows[0]["name"], "都道府県名");
+        assert_eq!(rows[0]["type"], "文字列型");
+        assert!(rows[0]["ref"].is_null());
+        assert_eq!(rows[1]["attribute_id"], "A27_001");
+        assert_eq!(rows[2]["attribute_id"], "A27_002");
+        assert_eq!(rows[2]["description"], "設置主体の説明");
+        assert_eq!(rows[2]["ref"]["Code"]["1"], "国");
+    }
+
+    #[test]
+    fn test_write_sqlite_catalog() {
+        let mut codes = HashMap::new();
+        codes.insert("1".to_string(), "国".to_string());
+        codes.insert("2".to_string(), "都道府県".to_string());
+        let mut a27 = fixture_dataset(
+            "A27",
+            vec![
+                ("A27_001", "行政区域コード", "コードリスト"),
+                ("A27_002", "設置主体", "コードリスト"),
+            ],
+        );
+        let mut page = Arc::try_unwrap(a27.page).unwrap();
+        page.metadata.attribute.get_mut("A27_002").unwrap().r#ref = Some(RefType::Code(codes));
+        a27.page = Arc::new(page);
+        let datasets = vec![
+            a27,
+            fixture_dataset("N03", vec![("N03_001", "都道府県名", "文字列型")]),
+        ];
+
+        let dir = PathBuf::from("./tmp/test_export");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("catalog.sqlite");
+        write_sqlite_catalog(&datasets, &path).unwrap();
+        // writing again replaces the previous catalog
+        write_sqlite_catalog(&datasets, &path).unwrap();
+
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let mut tables = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get::<_, String>(0))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        tables.sort();
+        assert_eq!(tables, vec!["attributes", "codelists", "datasets"]);
+
+        let (name, version_id): (String, String) = conn
+            .query_row(
+                "SELECT name, version_id FROM datasets WHERE identifier = 'N03'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(name, "N03");
+        assert_eq!(version_id, "2024");
+
+        // the catalog can be read back for incremental scraping
+        let cached = read_cached_pages(&path).unwrap();
+        assert_eq!(cached.len(), 2);
+        assert_eq!(
+            cached["A27"].listing_fingerprint,
+            datasets[0].listing_fingerprint
+        );
+        assert_eq!(cached["A27"].page.metadata.attribute.len(), 2);
+
+        let attr_name: String = conn
+            .query_row(
+                "SELECT name FROM attributes WHERE identifier = 'A27' AND attribute_id = 'A27_002'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(attr_name, "設置主体");
+
+        let labels = conn
+            .prepare(
+                "SELECT code, label FROM codelists WHERE attribute_id = 'A27_002' ORDER BY code",
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            labels,
+            vec![
+                ("1".to_string(), "国".to_string()),
+                ("2".to_string(), "都道府県".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,114 @@
+// jpksj-to-sql as a library: the CLI (`main.rs`, `cli.rs`) is built on the same modules.
+// `run` covers the common case of scraping, downloading and loading a set of datasets;
+// `scraper::Scraper` and `loader::Loader` can be assembled directly for anything else.
+#![warn(unused_extern_crates)]
+
+use anyhow::{Context, Result};
+use derive_builder::Builder;
+use std::path::PathBuf;
+
+pub mod circuit_breaker;
+pub mod context;
+pub mod doctor;
+pub mod downloader;
+pub mod export;
+pub mod list;
+pub mod loader;
+pub mod logging;
+pub mod metadata;
+pub mod plan;
+pub mod report;
+pub mod scraper;
+pub mod stac;
+#[cfg(test)]
+mod test_helpers;
+
+pub use loader::{LoadReport, LoadedTable, Loader, LoaderBuilder, OutputTarget};
+pub use metadata::MetadataConnection;
+pub use report::{Failure, Stage};
+pub use scraper::{Dataset, Scraper, ScraperBuilder};
+
+/// Settings of [`run`]. Everything but the output has the CLI's defaults.
+#[derive(Builder)]
+pub struct Config {
+    output: OutputTarget,
+    /// 取り込むデータセットの識別子（例: `["N03"]`）。`None` の場合は全てのデータセット
+    #[builder(default)]
+    identifiers: Option<Vec<String>>,
+    /// 取得する年（複数可）。`None` の場合は最新
+    #[builder(default)]
+    year: Option<Vec<u32>>,
+    /// ダウンロードと中間ファイルの作業ディレクトリ（デフォルト: `./tmp`）
+    #[builder(default)]
+    tmp_dir: Option<PathBuf>,
+    /// 既に存在するテーブル（出力ファイル）は取り込みません
+    #[builder(default)]
+    skip_if_exists: bool,
+}
+
+/// Scrapes and downloads the selected datasets, and loads them into the output.
+/// The failures of the scrape and download stages are included in the report.
+///
+/// The settings are process-wide: `tmp_dir` is applied with [`context::set_tmp`], and the
+/// HTTP client, download limits and verbosity come from [`context::set_http_client`],
+/// [`downloader::set_per_host_limit`], [`logging::set_verbose`] and the like. Concurrent `run`s
+/// in one process share them, so they should use the same `tmp_dir`.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use jpksj_to_sql::{ConfigBuilder, OutputTarget};
+///
+/// let config = ConfigBuilder::default()
+///     .output(OutputTarget::Postgres {
+///         postgres_url: "host=127.0.0.1 dbname=jpksj".to_string(),
+///         schema: None,
+///     })
+///     .identifiers(Some(vec!["N03".to_string()]))
+///     .build()?;
+/// let report = jpksj_to_sql::run(config).await?;
+/// println!("{:?}", report.succeeded);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn run(config: Config) -> Result<LoadReport> {
+    let Config {
+        output,
+        identifiers,
+        year,
+        tmp_dir,
+        skip_if_exists,
+    } = config;
+
+    loader::check_gdal_tools(output.postgres_url().is_some())
+        .await
+        .context("while checking GDAL tools")?;
+    if let Some(tmp) = tmp_dir {
+        context::set_tmp(tmp);
+    }
+    tokio::fs::create_dir_all(context::tmp()).await?;
+
+    let scraper::Scraped {
+        datasets, failures, ..
+    } = ScraperBuilder::default()
+        .skip_dl(false)
+        .filter_identifiers(identifiers)
+        .year(year)
+        .build()
+        .context("while building scraper")?
+        .download_all()
+        .await
+        .context("while downloading datasets")?;
+
+    let mut report = LoaderBuilder::default()
+        .datasets(datasets)
+        .output(output)
+        .skip_if_exists(skip_if_exists)
+        .add_primary_keys(true)
+        .build()
+        .context("while building loader")?
+        .load_all()
+        .await
+        .context("while loading datasets")?;
+    report.failed.splice(0..0, failures);
+    Ok(report)
+}
@@ -0,0 +1,123 @@
+// `list`: the datasets available from the JPKSJ API, as an aligned table or JSON.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::scraper::{initial, NON_COMMERCIAL};
+
+#[derive(Debug, Serialize)]
+struct ListEntry<'a> {
+    identifier: &'a str,
+    category1_name: &'a str,
+    category2_name: &'a str,
+    name: &'a str,
+    usage: &'a str,
+    non_commercial: bool,
+}
+
+impl<'a> From<&'a initial::DataItem> for ListEntry<'a> {
+    fn from(item: &'a initial::DataItem) -> Self {
+        ListEntry {
+            identifier: &item.identifier,
+            category1_name: &item.category1_name,
+            category2_name: &item.category2_name,
+            name: &item.name,
+            usage: &item.usage,
+            non_commercial: item.usage == NON_COMMERCIAL,
+        }
+    }
+}
+
+/// Terminal columns of a string: full-width (non-ASCII) characters take two.
+fn display_width(s: &str) -> usize {
+    s.chars().map(|c| if c.is_ascii() { 1 } else { 2 }).sum()
+}
+
+fn render_table(entries: &[ListEntry]) -> String {
+    let header = ["識別子", "カテゴリ1", "カテゴリ2", "名称", "利用区分"];
+    let mut rows: Vec<[String; 5]> = vec![header.map(str::to_string)];
+    for entry in entries {
+        let name = if entry.non_commercial {
+            format!("[非商用] {}", entry.name)
+        } else {
+            entry.name.to_string()
+        };
+        let usage = if entry.usage.is_empty() {
+            "-"
+        } else {
+            entry.usage
+        };
+        rows.push([
+            entry.identifier.to_string(),
+            entry.category1_name.to_string(),
+            entry.category2_name.to_string(),
+            name,
+            usage.to_string(),
+        ]);
+    }
+
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(display_width(cell));
+        }
+    }
+    let mut out = String::new();
+    for row in &rows {
+        let mut line = String::new();
+        for (i, cell) in row.iter().enumerate() {
+            line.push_str(cell);
+            if i + 1 < row.len() {
+                line.push_str(&" ".repeat(widths[i] - display_width(cell) + 2));
+            }
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+pub async fn run(json: bool) -> Result<()> {
+    let listing = initial::scrape()
+        .await
+        .context("while fetching the dataset list")?;
+    let entries: Vec<ListEntry> = listing.data.iter().map(ListEntry::from).collect();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        print!("{}", render_table(&entries));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::fixture_dataset;
+
+    #[test]
+    fn test_render_table() {
+        let mut n03 = fixture_dataset("N03", vec![]).initial_item;
+        n03.category1_name = "政策区域".to_string();
+        n03.category2_name = "行政地域".to_string();
+        n03.name = "行政区域".to_string();
+        let mut a99 = fixture_dataset("A99", vec![]).initial_item;
+        a99.name = "テスト".to_string();
+        a99.usage = NON_COMMERCIAL.to_string();
+        let items = [n03, a99];
+        let entries: Vec<ListEntry> = items.iter().map(ListEntry::from).collect();
+
+        assert_eq!(
+            render_table(&entries),
+            "識別子  カテゴリ1  カテゴリ2  名称             利用区分
+N03     政策区域   行政地域   行政区域         -
+A99     cat1       cat2       [非商用] テスト  非商用
+"
+        );
+
+        let json = serde_json::to_value(&entries).unwrap();
+        assert_eq!(json[0]["identifier"], "N03");
+        assert_eq!(json[0]["non_commercial"], false);
+        assert_eq!(json[1]["non_commercial"], true);
+    }
+}
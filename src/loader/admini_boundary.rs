@@ -1,10 +1,14 @@
 //! Loader for AdminiBoundary_CD.xslx
 //! This module is responsible for loading the AdminiBoundary_CD.xslx file into the database.
 
-use crate::{downloader, metadata::MetadataConnection};
+use crate::{
+    downloader,
+    metadata::{self, MetadataConnection, KSJ_LICENSE_URL},
+};
 use anyhow::{Context, Result};
 use calamine::{Reader, Xlsx};
 use km_to_sql::metadata::{ColumnMetadata, TableMetadata};
+use std::path::Path;
 use std::vec;
 use tokio_postgres::{types::ToSql, NoTls};
 use unicode_normalization::UnicodeNormalization;
@@ -12,9 +16,20 @@ use url::Url;
 
 use super::xslx_helpers::data_to_string;
 
-async fn download_admini_boundary_file() -> Result<downloader::DownloadedFile> {
-    let url = Url::parse("https://nlftp.mlit.go.jp/ksj/gml/codelist/AdminiBoundary_CD.xlsx")?;
-    downloader::download_to_tmp(&url).await
+pub const ADMINI_BOUNDARY_URL: &str =
+    "https://nlftp.mlit.go.jp/ksj/gml/codelist/AdminiBoundary_CD.xlsx";
+
+fn admini_boundary_url() -> Result<Url> {
+    Ok(Url::parse(ADMINI_BOUNDARY_URL)?)
+}
+
+async fn download_admini_boundary_file(
+    url: &Url,
+    allowed_hosts: &[String],
+) -> Result<downloader::DownloadedFile> {
+    downloader::download_to_tmp_from(url, allowed_hosts, &|_| {})
+        .await
+        .with_context(|| format!("when downloading {}", url))
 }
 
 #[derive(Debug)]
@@ -22,10 +37,14 @@ struct ParsedFile {
     rows: Vec<Vec<Option<String>>>,
 }
 
-async fn parse() -> Result<ParsedFile> {
-    let file = download_admini_boundary_file().await?;
-    let path = file.path;
-    let mut workbook: Xlsx<_> = calamine::open_workbook(&path)?;
+/// Downloads the code list from `url` (normally [`ADMINI_BOUNDARY_URL`]) and parses it.
+async fn parse(url: &Url, allowed_hosts: &[String]) -> Result<ParsedFile> {
+    let file = download_admini_boundary_file(url, allowed_hosts).await?;
+    parse_file(&file.path)
+}
+
+fn parse_file(path: &Path) -> Result<ParsedFile> {
+    let mut workbook: Xlsx<_> = calamine::open_workbook(path)?;
     let sheet = workbook.worksheet_range("行政区域コード")?;
     let mut data_started = false;
 
@@ -63,7 +82,7 @@ async fn parse() -> Result<ParsedFile> {
     Ok(ParsedFile { rows: out })
 }
 
-async fn load(postgres_url: &str, parsed: &ParsedFile) -> Result<()> {
+async fn load(postgres_url: &str, schema: Option<&str>, parsed: &ParsedFile) -> Result<()> {
     let (client, connection) = tokio_postgres::connect(postgres_url, NoTls)
         .await
         .with_context(|| "when connecting to PostgreSQL")?;
@@ -73,6 +92,7 @@ async fn load(postgres_url: &str, parsed: &ParsedFile) -> Result<()> {
             eprintln!("Connection error: {}", e);
         }
     });
+    metadata::set_search_path(&client, schema).await?;
 
     client
         .execute(
@@ -107,18 +127,16 @@ async fn load(postgres_url: &str, parsed: &ParsedFile) -> Result<()> {
     Ok(())
 }
 
-async fn create_admini_boundary_metadata(postgres_url: &str) -> Result<()> {
-    let metadata_conn = MetadataConnection::new(postgres_url).await?;
+async fn create_admini_boundary_metadata(postgres_url: &str, schema: Option<&str>) -> Result<()> {
+    let metadata_conn = MetadataConnection::new(postgres_url, schema).await?;
 
     let metadata = TableMetadata {
         name: "行政区域コード".to_string(),
         desc: None,
         source: Some("国土数値情報".to_string()),
-        source_url: Some(
-            Url::parse("https://nlftp.mlit.go.jp/ksj/gml/codelist/AdminiBoundary_CD.xlsx").unwrap(),
-        ),
+        source_url: Some(Url::parse(ADMINI_BOUNDARY_URL).unwrap()),
         license: None,
-        license_url: None,
+        license_url: Some(Url::parse(KSJ_LICENSE_URL).unwrap()),
         primary_key: Some("行政区域コード".to_string()),
         columns: vec![
             ColumnMetadata {
@@ -209,26 +227,73 @@ async fn create_admini_boundary_metadata(postgres_url: &str) -> Result<()> {
     Ok(())
 }
 
-pub async fn load_admini_boundary(postgres_url: &str) -> Result<()> {
-    let parsed = parse().await?;
-    load(postgres_url, &parsed).await?;
-    create_admini_boundary_metadata(postgres_url).await?;
+pub async fn load_admini_boundary(postgres_url: &str, schema: Option<&str>) -> Result<()> {
+    let parsed = parse(&admini_boundary_url()?, &downloader::allowed_hosts()).await?;
+    load(postgres_url, schema, &parsed).await?;
+    create_admini_boundary_metadata(postgres_url, schema).await?;
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_helpers::spawn_http_server;
+
+    #[test]
+    fn test_parse_file() {
+        let parsed_file = parse_file(Path::new("./test_data/xlsx/AdminiBoundary_CD.xlsx")).unwrap();
+        // the title, the header and the empty rows are skipped
+        assert_eq!(parsed_file.rows.len(), 4);
+        assert_eq!(parsed_file.rows[0].len(), 11);
+        assert_eq!(parsed_file.rows[0][0], Some("01000".to_string()));
+        assert_eq!(parsed_file.rows[0][1], Some("北海道".to_string()));
+        assert_eq!(parsed_file.rows[0][2], None);
+        // 半角カナは NFKC で全角になります
+        assert_eq!(parsed_file.rows[0][3], Some("ホッカイドウ".to_string()));
+        assert_eq!(parsed_file.rows[3][0], Some("02201".to_string()));
+        assert_eq!(parsed_file.rows[3][10], Some("浪岡町と合併".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_parse_downloaded() {
+        let xlsx = std::fs::read("./test_data/xlsx/AdminiBoundary_CD.xlsx").unwrap();
+        // not the real file name, so the cached download of the real list is left alone
+        let base = spawn_http_server(vec![("/admini_boundary_test.xlsx", 200, xlsx)]).await;
+        tokio::fs::create_dir_all(crate::context::tmp())
+            .await
+            .unwrap();
+        let url = base.join("admini_boundary_test.xlsx").unwrap();
+        let local = vec!["127.0.0.1".to_string()];
+        let parsed_file = parse(&url, &local).await.unwrap();
+        assert_eq!(parsed_file.rows.len(), 4);
+        assert_eq!(parsed_file.rows[1][2], Some("札幌市".to_string()));
+
+        let missing = base.join("missing.xlsx").unwrap();
+        let error = parse(&missing, &local).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("missing.xlsx"));
+    }
 
     #[tokio::test]
+    #[ignore = "requires network"]
     async fn test_download_admini_boundary_file() {
-        let file = download_admini_boundary_file().await.unwrap();
+        let file = download_admini_boundary_file(
+            &admini_boundary_url().unwrap(),
+            &downloader::allowed_hosts(),
+        )
+        .await
+        .unwrap();
         assert!(file.path.exists());
     }
 
     #[tokio::test]
+    #[ignore = "requires network"]
     async fn test_parse_admini() {
-        let parsed_file = parse().await.unwrap();
+        let parsed_file = parse(
+            &admini_boundary_url().unwrap(),
+            &downloader::allowed_hosts(),
+        )
+        .await
+        .unwrap();
         assert!(!parsed_file.rows.is_empty());
         assert_eq!(parsed_file.rows[0].len(), 11);
         assert_eq!(parsed_file.rows[0][0], Some("01000".to_string()));
@@ -0,0 +1,164 @@
+// `--resume`: the datasets loaded so far, so that a rerun after a failure skips them
+// instead of loading (or checking with ogrinfo) every table again.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::context;
+use crate::scraper::Dataset;
+
+use super::OutputTarget;
+
+/// `<tmp>/checkpoint.json`
+pub fn default_checkpoint_path() -> PathBuf {
+    context::tmp().join("checkpoint.json")
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct State {
+    /// 出力先のハッシュ（接続文字列をそのまま保存しないため）。別の出力先のチェックポイントは使いません
+    target: String,
+    /// 取り込みに成功したデータセットの識別子と、その時の一覧のフィンガープリント
+    completed: BTreeMap<String, String>,
+}
+
+pub struct Checkpoint {
+    path: PathBuf,
+    state: Mutex<State>,
+}
+
+/// Identifies where the tables were written: the output and the table prefix.
+pub fn target_key(output: &OutputTarget, table_prefix: &str) -> String {
+    let hash = Sha256::digest(format!("{:?}\t{}", output, table_prefix));
+    format!("{:x}", hash)
+}
+
+/// The identifier, and the year with `--suffix-year` (each year is a dataset of its own).
+fn key(dataset: &Dataset) -> String {
+    match dataset.year_suffix {
+        Some(year) => format!("{}_{}", dataset.initial_item.identifier, year),
+        None => dataset.initial_item.identifier.clone(),
+    }
+}
+
+impl Checkpoint {
+    /// Reads the checkpoint at `path`. It starts empty if there is none, if `fresh` (`--force`),
+    /// or if it was written for another target.
+    pub fn open(path: &Path, target: &str, fresh: bool) -> Result<Self> {
+        let previous = match std::fs::read_to_string(path) {
+            Ok(_) if fresh => None,
+            Ok(json) => Some(
+                serde_json::from_str::<State>(&json)
+                    .with_context(|| format!("when parsing {}", path.display()))?,
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).with_context(|| format!("when reading {}", path.display())),
+        };
+        let state = match previous {
+            Some(state) if state.target == target => state,
+            Some(_) => {
+                eprintln!(
+                    "{} は別の出力先のチェックポイントのため、使用しません",
+                    path.display()
+                );
+                State {
+                    target: target.to_string(),
+                    ..Default::default()
+                }
+            }
+            None => State {
+                target: target.to_string(),
+                ..Default::default()
+            },
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Whether the dataset was loaded by an earlier run, with the same listing entry and years.
+    pub fn is_completed(&self, dataset: &Dataset) -> bool {
+        self.state.lock().unwrap().completed.get(&key(dataset))
+            == Some(&dataset.listing_fingerprint)
+    }
+
+    /// Records a loaded dataset, and writes the checkpoint right away.
+    pub fn record(&self, dataset: &Dataset) -> Result<()> {
+        // held while writing, so that the workers don't write over each other
+        let mut state = self.state.lock().unwrap();
+        state
+            .completed
+            .insert(key(dataset), dataset.listing_fingerprint.clone());
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // an interrupted write leaves the previous checkpoint
+        let mut part = self.path.as_os_str().to_owned();
+        part.push(".part");
+        std::fs::write(&part, serde_json::to_string_pretty(&*state)?)
+            .with_context(|| format!("when writing {}", self.path.display()))?;
+        std::fs::rename(&part, &self.path)
+            .with_context(|| format!("when writing {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::fixture_dataset;
+
+    #[test]
+    fn test_checkpoint() {
+        let path = context::tmp().join("test_checkpoint.json");
+        let _ = std::fs::remove_file(&path);
+        let n03 = fixture_dataset("N03", vec![]);
+        let a27 = fixture_dataset("A27", vec![]);
+
+        let checkpoint = Checkpoint::open(&path, "db1", false).unwrap();
+        assert!(!checkpoint.is_completed(&n03));
+        checkpoint.record(&n03).unwrap();
+        assert!(checkpoint.is_completed(&n03));
+
+        // the next run skips N03 only
+        let checkpoint = Checkpoint::open(&path, "db1", false).unwrap();
+        assert!(checkpoint.is_completed(&n03));
+        assert!(!checkpoint.is_completed(&a27));
+        // another year (or a changed listing entry) is loaded again
+        let mut n03_2011 = n03.clone();
+        n03_2011.listing_fingerprint =
+            crate::scraper::listing_fingerprint(&n03.initial_item, &[], Some(&[2011]));
+        assert!(!checkpoint.is_completed(&n03_2011));
+        // --suffix-year
+        let mut n03_2024 = n03.clone();
+        n03_2024.year_suffix = Some(2024);
+        assert!(!checkpoint.is_completed(&n03_2024));
+
+        // --force, or another output
+        assert!(!Checkpoint::open(&path, "db1", true)
+            .unwrap()
+            .is_completed(&n03));
+        assert!(!Checkpoint::open(&path, "db2", false)
+            .unwrap()
+            .is_completed(&n03));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_target_key() {
+        let postgres = |schema: Option<&str>| OutputTarget::Postgres {
+            postgres_url: "host=127.0.0.1 password=secret".to_string(),
+            schema: schema.map(str::to_string),
+        };
+        let key = target_key(&postgres(None), "");
+        assert!(!key.contains("secret"));
+        assert_eq!(key, target_key(&postgres(None), ""));
+        assert_ne!(key, target_key(&postgres(Some("ksj")), ""));
+        assert_ne!(key, target_key(&postgres(None), "ksj_"));
+    }
+}
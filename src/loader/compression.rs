@@ -0,0 +1,157 @@
+// Optional compression of file outputs (`--compress-output`).
+// gzip output of the streaming (sequential write) drivers is written by GDAL directly
+// through `/vsigzip/`; everything else is written uncompressed first and then compressed.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// 出力ファイル名に付与する拡張子
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gz",
+            Self::Zstd => "zst",
+        }
+    }
+}
+
+/// Drivers that write sequentially, so GDAL can stream them into `/vsigzip/`.
+const VSIGZIP_DRIVERS: &[&str] = &["geojson", "geojsonseq", "csv", "gml", "kml", "gpx"];
+
+pub fn writes_through_vsigzip(driver: &str) -> bool {
+    let driver = driver.trim().to_ascii_lowercase();
+    VSIGZIP_DRIVERS.contains(&driver.as_str())
+}
+
+/// `/vsigzip/<path>`
+pub fn vsigzip_path(path: &Path) -> PathBuf {
+    let mut vsi = OsString::from("/vsigzip/");
+    vsi.push(path.as_os_str());
+    PathBuf::from(vsi)
+}
+
+/// The path GDAL should use to read a (possibly compressed) output, `None` if GDAL can't
+/// read it (zstd).
+pub fn gdal_readable_path(path: &Path, compression: Option<Compression>) -> Option<PathBuf> {
+    match compression {
+        None => Some(path.to_path_buf()),
+        Some(Compression::Gzip) => Some(vsigzip_path(path)),
+        Some(Compression::Zstd) => None,
+    }
+}
+
+/// `src` を圧縮して `dest` に書き出します
+pub async fn compress_file(src: &Path, dest: &Path, compression: Compression) -> Result<()> {
+    let context = format!("when compressing {}", src.display());
+    let src = src.to_path_buf();
+    let dest = dest.to_path_buf();
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut reader = BufReader::new(
+            File::open(&src).with_context(|| format!("when opening {}", src.display()))?,
+        );
+        let writer = BufWriter::new(
+            File::create(&dest).with_context(|| format!("when creating {}", dest.display()))?,
+        );
+        match compression {
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+                std::io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?.flush()?;
+            }
+            Compression::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(writer, 0)?;
+                std::io::copy(&mut reader, &mut encoder)?;
+                encoder.finish()?.flush()?;
+            }
+        }
+        Ok(())
+    })
+    .await?
+    .context(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::OutputTarget;
+    use std::io::Read;
+
+    fn geojson_output(compression: Option<Compression>) -> OutputTarget {
+        OutputTarget::File {
+            output_dir: PathBuf::from("./tmp/test_compression"),
+            gdal_driver: "GeoJSON".to_string(),
+            file_extension: "geojson".to_string(),
+            compression,
+            single_file: None,
+        }
+    }
+
+    #[test]
+    fn test_compressed_output_path() {
+        assert_eq!(
+            geojson_output(None).output_path("N03").unwrap(),
+            PathBuf::from("./tmp/test_compression/N03.geojson")
+        );
+        let gzip = geojson_output(Some(Compression::Gzip));
+        assert_eq!(
+            gzip.output_path("N03").unwrap(),
+            PathBuf::from("./tmp/test_compression/N03.geojson.gz")
+        );
+        assert_eq!(
+            gzip.metadata_path("N03").unwrap(),
+            PathBuf::from("./tmp/test_compression/N03.metadata.json")
+        );
+        assert!(writes_through_vsigzip("GeoJSON"));
+        assert!(!writes_through_vsigzip("FlatGeobuf"));
+        assert_eq!(
+            vsigzip_path(Path::new("out/N03.geojson.gz")),
+            PathBuf::from("/vsigzip/out/N03.geojson.gz")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compress_geojson() {
+        let dir = PathBuf::from("./tmp/test_compression");
+        std::fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("N03.geojson");
+        std::fs::write(
+            &src,
+            r#"{"type":"FeatureCollection","features":[{"type":"Feature","properties":{"N03_001":"北海道"},"geometry":null}]}"#,
+        )
+        .unwrap();
+
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let dest = dir.join(format!("N03.geojson.{}", compression.suffix()));
+            compress_file(&src, &dest, compression).await.unwrap();
+
+            let mut json = String::new();
+            let file = File::open(&dest).unwrap();
+            match compression {
+                Compression::Gzip => {
+                    flate2::read::GzDecoder::new(file)
+                        .read_to_string(&mut json)
+                        .unwrap();
+                }
+                Compression::Zstd => {
+                    zstd::stream::read::Decoder::new(file)
+                        .unwrap()
+                        .read_to_string(&mut json)
+                        .unwrap();
+                }
+            }
+            let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(value["features"][0]["properties"]["N03_001"], "北海道");
+        }
+    }
+}
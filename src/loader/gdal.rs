@@ -1,8 +1,16 @@
+use super::compression::{self, Compression};
 use super::mapping::ShapefileMetadata;
+use super::transform;
+use crate::logging;
+use crate::metadata::shorten_identifier;
 use anyhow::{anyhow, Context, Result};
-use encoding_rs::{Encoding, SHIFT_JIS, UTF_8};
+use clap::ValueEnum;
+use encoding_rs::{DecoderResult, Encoding, EUC_JP, SHIFT_JIS, UTF_8};
 use serde_json::Value;
+use std::collections::HashMap;
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError, RwLock};
 use tokio::process::Command;
 
 #[derive(Debug, Clone)]
@@ -16,23 +24,135 @@ pub struct LayerSchema {
     pub fields: Vec<FieldSchema>,
     pub geometry_type: Option<String>,
     pub geometry_srid: Option<i32>,
+    /// `[minx, miny, maxx, maxy]`
+    pub extent: Option<[f64; 4]>,
+    pub feature_count: Option<u64>,
 }
 
-pub async fn check_gdal_tools() -> Result<()> {
-    let output = Command::new("ogrinfo")
-        .arg("--version")
-        .output()
-        .await
-        .context("running ogrinfo --version")?;
+/// 必要な GDAL のバージョン（`ogrinfo -limit` を使うため）
+pub const MIN_GDAL_VERSION: (u32, u32) = (3, 9);
+
+const GDAL_INSTALL_GUIDE: &str = "GDAL をインストールしてください（例: Ubuntu/Debian は `sudo apt install gdal-bin`、macOS は `brew install gdal`）。Docker 環境を使うこともできます（README 参照）";
+
+/// The GDAL installation found by [`check_gdal_tools`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GdalInfo {
+    /// `ogrinfo --version` の出力（例: `GDAL 3.9.2, released 2024/08/13`）
+    pub version_line: String,
+    pub version: Option<(u32, u32, u32)>,
+    pub postgres_driver: bool,
+}
+
+impl GdalInfo {
+    /// A warning when the version is older than [`MIN_GDAL_VERSION`].
+    pub fn version_warning(&self) -> Option<String> {
+        let (major, minor, _) = self.version?;
+        if (major, minor) >= MIN_GDAL_VERSION {
+            return None;
+        }
+        Some(format!(
+            "GDAL {}.{} は古いため、一部の処理が失敗する可能性があります（{}.{} 以上が必要です）",
+            major, minor, MIN_GDAL_VERSION.0, MIN_GDAL_VERSION.1
+        ))
+    }
+
+    pub fn summary(&self) -> String {
+        let driver = if self.postgres_driver {
+            "あり"
+        } else {
+            "なし"
+        };
+        let mut summary = format!("{}（PostgreSQL ドライバ: {}）", self.version_line, driver);
+        if let Some(warning) = self.version_warning() {
+            summary.push_str(&format!(" 警告: {}", warning));
+        }
+        summary
+    }
+}
+
+/// Parses `GDAL 3.9.2, released 2024/08/13` (or `GDAL 3.10.0dev-...`) into `(3, 9, 2)`.
+fn parse_gdal_version(version_line: &str) -> Option<(u32, u32, u32)> {
+    let version = version_line
+        .trim()
+        .strip_prefix("GDAL ")?
+        .split([',', ' '])
+        .next()?;
+    let mut parts = version.split('.').map(|part| {
+        let digits = part
+            .chars()
+            .take_while(char::is_ascii_digit)
+            .collect::<String>();
+        digits.parse::<u32>().ok()
+    });
+    let major = parts.next()??;
+    let minor = parts.next()??;
+    let patch = parts.next().flatten().unwrap_or(0);
+    Some((major, minor, patch))
+}
+
+/// Whether `ogrinfo --formats` lists the PostgreSQL driver, e.g.
+/// `  PostgreSQL -vector- (rw+): PostgreSQL/PostGIS`.
+fn has_postgres_driver(formats: &str) -> bool {
+    formats
+        .lines()
+        .any(|line| line.trim_start().starts_with("PostgreSQL "))
+}
+
+async fn run_gdal_tool(tool: &str, arg: &str) -> Result<String> {
+    let output = match Command::new(tool).arg(arg).output().await {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            anyhow::bail!("{} が見つかりません（PATH を確認してください）", tool)
+        }
+        Err(e) => return Err(e).with_context(|| format!("running {} {}", tool, arg)),
+    };
     if !output.status.success() {
         let stderr = String::from_utf8_lossy(&output.stderr);
         let stderr = stderr.trim();
         if stderr.is_empty() {
-            anyhow::bail!("ogrinfo --version failed with status {}", output.status);
+            anyhow::bail!("{} {} failed with status {}", tool, arg, output.status);
         }
-        anyhow::bail!("ogrinfo --version failed: {}", stderr);
+        anyhow::bail!("{} {} failed: {}", tool, arg, stderr);
     }
-    Ok(())
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Checks that both ogr2ogr and ogrinfo can be run, and (with `require_postgres`)
+/// that GDAL was built with the PostgreSQL driver.
+pub async fn check_gdal_tools(require_postgres: bool) -> Result<GdalInfo> {
+    let (ogr2ogr, ogrinfo) = tokio::join!(
+        run_gdal_tool("ogr2ogr", "--version"),
+        run_gdal_tool("ogrinfo", "--version")
+    );
+    let errors = [&ogr2ogr, &ogrinfo]
+        .into_iter()
+        .filter_map(|result| result.as_ref().err())
+        .map(|e| format!("{:#}", e))
+        .collect::<Vec<_>>();
+    if !errors.is_empty() {
+        anyhow::bail!("{}\n{}", errors.join("\n"), GDAL_INSTALL_GUIDE);
+    }
+    let version_line = ogrinfo?.trim().to_string();
+
+    let formats = run_gdal_tool("ogrinfo", "--formats").await?;
+    let info = GdalInfo {
+        version: parse_gdal_version(&version_line),
+        version_line,
+        postgres_driver: has_postgres_driver(&formats),
+    };
+    if require_postgres && !info.postgres_driver {
+        anyhow::bail!(
+            "{} に PostgreSQL ドライバがありません（`ogrinfo --formats` に PostgreSQL が含まれていません）。PostgreSQL ドライバを含む GDAL をインストールしてください",
+            info.version_line
+        );
+    }
+    Ok(info)
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
 }
 
 pub async fn create_vrt(
@@ -48,6 +168,19 @@ pub async fn create_vrt(
     let layer_name = bare_vrt.file_name().unwrap().to_str().unwrap();
     // let vrt_path = shape.with_extension("vrt");
 
+    for (field_name, _) in metadata.field_mappings.iter() {
+        let column_name = shorten_identifier(field_name);
+        if column_name != field_name.as_str() {
+            eprintln!(
+                "{}: カラム名が {} バイトを超えるため、{} に短縮します（元のカラム名: {}）",
+                metadata.identifier,
+                crate::metadata::MAX_IDENTIFIER_BYTES,
+                column_name,
+                field_name
+            );
+        }
+    }
+
     let mut layers = String::new();
     let mut any_fields = false;
     for shape in shapes {
@@ -57,34 +190,67 @@ pub async fn create_vrt(
             .await
             .with_context(|| format!("when getting attribute list for {}", &shape.display()))?;
         let mut fields = String::new();
+        let mut transformed = Vec::new();
         for (field_name, shape_name) in metadata.field_mappings.iter() {
             // ignore attributes in the mapping that are not in the shapefile
             if attributes.iter().find(|&attr| attr == shape_name).is_none() {
                 continue;
             }
-            fields.push_str(&format!(
-                r#"<Field name="{}" src="{}" />"#,
-                field_name, shape_name
+            let transforms = metadata.transforms_for(field_name);
+            let src = if transforms.is_empty() {
+                shape_name.clone()
+            } else {
+                transformed.push((shape_name.as_str(), transforms));
+                transform::transformed_column(shape_name)
+            };
+            fields.push_str(&vrt_field(
+                &shorten_identifier(field_name),
+                &src,
+                metadata.field_type(field_name),
             ));
         }
+        if metadata.keep_all_fields {
+            // `--keep-all-fields`: the attributes missing from the mapping keep their names
+            for attr in unmapped_attributes(&attributes, metadata) {
+                fields.push_str(&vrt_field(&shorten_identifier(attr), attr, None));
+            }
+        }
         if fields.is_empty() {
             continue;
         }
+        // `--where` filters the features in the same SQL as the transforms
+        let src_sql = if transformed.is_empty() && metadata.where_clause.is_none() {
+            String::new()
+        } else {
+            format!(
+                r#"<SrcSQL dialect="SQLite">{}</SrcSQL>"#,
+                xml_escape(&transform::src_sql(
+                    shape_filename,
+                    &transformed,
+                    metadata.where_clause.as_deref()
+                ))
+            )
+        };
         any_fields = true;
-        let encoding = detect_encoding(shape)
-            .await
-            .with_context(|| format!("when detecting encoding for {}", &shape.display()))?;
+        let encoding = match &metadata.encoding {
+            Some(encoding) => encoding.clone(),
+            None => detect_encoding(shape)
+                .await
+                .with_context(|| format!("when detecting encoding for {}", &shape.display()))?,
+        };
         layers.push_str(&format!(
             r#"
                 <OGRVRTLayer name="{}">
                 <SrcDataSource>{}</SrcDataSource>
                 <OpenOptions><OOI key="ENCODING">{}</OOI></OpenOptions>
                 {}
+                {}
                 </OGRVRTLayer>
             "#,
             shape_filename,
             shape.canonicalize().unwrap().to_str().unwrap(),
-            encoding,
+            canonical_encoding(&encoding),
+            src_sql,
             fields
         ));
     }
@@ -92,15 +258,22 @@ pub async fn create_vrt(
         anyhow::bail!("No fields found in shapefiles");
     }
 
+    // the unmapped attributes can differ between the shapefiles
+    let field_strategy = if metadata.keep_all_fields {
+        "<FieldStrategy>Union</FieldStrategy>"
+    } else {
+        ""
+    };
     let vrt = format!(
         r#"
         <OGRVRTDataSource>
         <OGRVRTUnionLayer name="{}">
         {}
+        {}
         </OGRVRTUnionLayer>
         </OGRVRTDataSource>
     "#,
-        layer_name, layers
+        layer_name, field_strategy, layers
     );
 
     tokio::fs::write(&out, vrt)
@@ -110,24 +283,266 @@ pub async fn create_vrt(
     Ok(())
 }
 
-pub async fn load_to_postgres(vrt: &Path, postgres_url: &str) -> Result<()> {
+/// The attributes of a shapefile that aren't in the mapping, and whose names don't collide
+/// with a mapped column.
+fn unmapped_attributes<'a>(
+    attributes: &'a [String],
+    metadata: &ShapefileMetadata,
+) -> Vec<&'a String> {
+    attributes
+        .iter()
+        .filter(|attr| {
+            !metadata
+                .field_mappings
+                .iter()
+                .any(|(field_name, shape_name)| {
+                    shape_name == *attr
+                        || shorten_identifier(field_name) == shorten_identifier(attr)
+                })
+        })
+        .collect()
+}
+
+/// A `<Field>` of a VRT layer, with its type when it is declared.
+fn vrt_field(name: &str, src: &str, field_type: Option<&str>) -> String {
+    match field_type {
+        Some(field_type) => format!(
+            r#"<Field name="{}" src="{}" type="{}" />"#,
+            name, src, field_type
+        ),
+        None => format!(r#"<Field name="{}" src="{}" />"#, name, src),
+    }
+}
+
+/// `--geometry-filter`: the kind of geometry to load.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum GeometryFilter {
+    Point,
+    Line,
+    Polygon,
+}
+
+impl GeometryFilter {
+    /// Whether an OGR geometry type name (e.g. `Point`, `Multi Line String`, `3D Polygon`) is of this kind.
+    pub fn matches(&self, ogr_geometry_type: &str) -> bool {
+        let normalized = ogr_geometry_type.to_ascii_lowercase();
+        match self {
+            Self::Point => normalized.contains("point"),
+            Self::Line => normalized.contains("line"),
+            Self::Polygon => normalized.contains("polygon") || normalized.contains("surface"),
+        }
+    }
+}
+
+/// ジオメトリ種別を ogrinfo で調べ、フィルタに一致するかどうかと、その種別を返します
+pub async fn matches_geometry_filter(
+    shape: &Path,
+    filter: GeometryFilter,
+) -> Result<(bool, String)> {
+    let geometry_type = shape_info(shape)
+        .await?
+        .schema
+        .geometry_type
+        .clone()
+        .unwrap_or_else(|| "None".to_string());
+    Ok((filter.matches(&geometry_type), geometry_type))
+}
+
+/// Options applied to every ogr2ogr invocation.
+#[derive(Debug, Clone)]
+pub struct Ogr2OgrOptions {
+    /// ジオメトリのカラム名（`-lco GEOMETRY_NAME=...`）
+    pub geometry_column: String,
+    /// GDAL のブロックキャッシュの上限（`--config GDAL_CACHEMAX ...`）
+    /// 指定がない場合は GDAL のデフォルト（物理メモリの 5%）になります
+    pub gdal_cache_max: Option<String>,
+    /// 1トランザクションあたりの地物数（`-gt ...`）
+    pub group_transactions: u64,
+    /// 出力の座標参照系（`-t_srs ...`）。指定がない場合はリプロジェクションしません
+    pub target_srs: Option<String>,
+    /// 入力の座標参照系（`.prj` から読めない場合の上書き）
+    pub source_srs: Option<String>,
+    /// 書き込めないフィーチャ（不正なジオメトリなど）をスキップして続行します（`-skipfailures`）
+    pub skip_failures: bool,
+    /// PostgreSQL への書き込みに COPY を使います（`--config PG_USE_COPY YES`）
+    /// 使わない場合は INSERT になり、遅くなりますがメモリの使用量が減ります
+    pub use_copy: bool,
+    /// そのまま ogr2ogr に渡す追加の引数（`--ogr2ogr-extra`）
+    pub extra_args: Vec<String>,
+}
+
+/// Features per transaction used when `--ogr-group-transactions` isn't given.
+/// ogr2ogr's own default (100 without COPY, 20000 with it) commits far too
+/// often for bulk loads.
+pub const DEFAULT_GROUP_TRANSACTIONS: u64 = 65536;
+
+impl Default for Ogr2OgrOptions {
+    fn default() -> Self {
+        Self {
+            geometry_column: "geom".to_string(),
+            gdal_cache_max: None,
+            group_transactions: DEFAULT_GROUP_TRANSACTIONS,
+            target_srs: None,
+            source_srs: None,
+            skip_failures: false,
+            use_copy: true,
+            extra_args: vec![],
+        }
+    }
+}
+
+impl Ogr2OgrOptions {
+    /// Memory, transaction and failure handling arguments shared by every output format.
+    fn tuning_args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> =
+            vec!["-gt".into(), self.group_transactions.to_string().into()];
+        if self.skip_failures {
+            // after -gt: ogr2ogr then commits every feature on its own, so a failure only loses that feature
+            args.push("-skipfailures".into());
+        }
+        if let Some(cache_max) = &self.gdal_cache_max {
+            args.push("--config".into());
+            args.push("GDAL_CACHEMAX".into());
+            args.push(cache_max.into());
+        }
+        args
+    }
+
+    /// Reprojection arguments. `-s_srs` only has an effect together with `-t_srs`,
+    /// so without a target the source SRS is assigned with `-a_srs` instead.
+    fn srs_args(&self) -> Vec<OsString> {
+        match (&self.source_srs, &self.target_srs) {
+            (Some(source), Some(target)) => vec![
+                "-s_srs".into(),
+                source.into(),
+                "-t_srs".into(),
+                target.into(),
+            ],
+            (None, Some(target)) => vec!["-t_srs".into(), target.into()],
+            (Some(source), None) => vec!["-a_srs".into(), source.into()],
+            (None, None) => vec![],
+        }
+    }
+
+    /// The SRID the output is written in, when it is set explicitly as `EPSG:<code>`.
+    pub fn output_srid(&self) -> Option<i32> {
+        let srs = self.target_srs.as_ref().or(self.source_srs.as_ref())?;
+        let (authority, code) = srs.split_once(':')?;
+        if !authority.eq_ignore_ascii_case("EPSG") {
+            return None;
+        }
+        code.trim().parse().ok()
+    }
+}
+
+/// `-nlt` for the kind of geometry the mapping expects. Shapefiles don't tell single and
+/// multi lines or polygons apart, so those (and unknown kinds) are promoted to multi;
+/// points are kept as they are.
+fn geometry_type_args(expected: Option<GeometryFilter>) -> Vec<OsString> {
+    match expected {
+        Some(GeometryFilter::Point) => vec![],
+        _ => vec!["-nlt".into(), "PROMOTE_TO_MULTI".into()],
+    }
+}
+
+/// The geometry type the output column gets from the source `geom_type`, see [`geometry_type_args`].
+pub fn output_geometry_type(geom_type: &str, expected: Option<GeometryFilter>) -> String {
+    match expected {
+        Some(GeometryFilter::Point) if !geom_type.trim().is_empty() => {
+            geom_type.trim().to_ascii_uppercase()
+        }
+        _ => promote_geometry_type(geom_type),
+    }
+}
+
+/// The geometry types found in a table that are not of the `expected` kind. Without an
+/// expected kind, all of them when they are of more than one kind (e.g. points and polygons).
+pub fn unexpected_geometry_types(
+    expected: Option<GeometryFilter>,
+    actual: &[String],
+) -> Vec<String> {
+    match expected {
+        Some(expected) => actual
+            .iter()
+            .filter(|geometry_type| !expected.matches(geometry_type))
+            .cloned()
+            .collect(),
+        None => {
+            let kinds = [
+                GeometryFilter::Point,
+                GeometryFilter::Line,
+                GeometryFilter::Polygon,
+            ]
+            .into_iter()
+            .filter(|kind| {
+                actual
+                    .iter()
+                    .any(|geometry_type| kind.matches(geometry_type))
+            })
+            .count();
+            if kinds > 1 {
+                actual.to_vec()
+            } else {
+                vec![]
+            }
+        }
+    }
+}
+
+fn postgres_args(
+    vrt: &Path,
+    postgres_url: &str,
+    schema: Option<&str>,
+    expected_geometry: Option<GeometryFilter>,
+    options: &Ogr2OgrOptions,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![
+        "-f".into(),
+        "PostgreSQL".into(),
+        format!("PG:{}", postgres_url).into(),
+        "-lco".into(),
+        "GEOM_TYPE=geometry".into(),
+        "-lco".into(),
+        "OVERWRITE=YES".into(),
+        "-lco".into(),
+        format!("GEOMETRY_NAME={}", options.geometry_column).into(),
+        // created after the load (and `--fill-gaps`), unless `--no-index`
+        "-lco".into(),
+        "SPATIAL_INDEX=NONE".into(),
+        "--config".into(),
+        if options.use_copy {
+            "PG_USE_COPY=YES".into()
+        } else {
+            "PG_USE_COPY=NO".into()
+        },
+    ];
+    if let Some(schema) = schema {
+        args.extend(["-lco".into(), format!("SCHEMA={}", schema).into()]);
+    }
+    args.extend(geometry_type_args(expected_geometry));
+    args.extend(options.srs_args());
+    args.extend(options.tuning_args());
+    args.extend(options.extra_args.iter().map(OsString::from));
+    args.push(vrt.into());
+    args
+}
+
+pub async fn load_to_postgres(
+    vrt: &Path,
+    postgres_url: &str,
+    schema: Option<&str>,
+    expected_geometry: Option<GeometryFilter>,
+    options: &Ogr2OgrOptions,
+) -> Result<()> {
     let mut cmd = Command::new("ogr2ogr");
     let output = cmd
-        .arg("-f")
-        .arg("PostgreSQL")
-        .arg(format!("PG:{}", postgres_url))
-        // .arg("-skipfailures")
-        .arg("-lco")
-        .arg("GEOM_TYPE=geometry")
-        .arg("-lco")
-        .arg("OVERWRITE=YES")
-        .arg("-lco")
-        .arg("GEOMETRY_NAME=geom")
-        .arg("-nlt")
-        .arg("PROMOTE_TO_MULTI")
-        .arg("--config")
-        .arg("PG_USE_COPY=YES")
-        .arg(vrt)
+        .args(postgres_args(
+            vrt,
+            postgres_url,
+            schema,
+            expected_geometry,
+            options,
+        ))
         .output()
         .await?;
 
@@ -140,21 +555,77 @@ pub async fn load_to_postgres(vrt: &Path, postgres_url: &str) -> Result<()> {
     Ok(())
 }
 
-pub async fn load_to_file(vrt: &Path, output_path: &Path, driver: &str) -> Result<()> {
-    if output_path.exists() {
+fn file_args(
+    vrt: &Path,
+    target: &Path,
+    driver: &str,
+    layer: Option<&str>,
+    expected_geometry: Option<GeometryFilter>,
+    options: &Ogr2OgrOptions,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec!["-f".into(), driver.into()];
+    args.extend(geometry_type_args(expected_geometry));
+    if let Some(layer) = layer {
+        // replaces only this layer, and creates the file if it doesn't exist yet
+        args.extend(["-overwrite".into(), "-nln".into(), layer.into()]);
+    }
+    args.extend(options.srs_args());
+    args.extend(options.tuning_args());
+    args.extend(options.extra_args.iter().map(OsString::from));
+    args.push(target.into());
+    args.push(vrt.into());
+    args
+}
+
+/// Writes the VRT to `output_path`. With `layer`, the file holds several tables and
+/// only that layer is replaced.
+pub async fn load_to_file(
+    vrt: &Path,
+    output_path: &Path,
+    driver: &str,
+    compression: Option<Compression>,
+    layer: Option<&str>,
+    expected_geometry: Option<GeometryFilter>,
+    options: &Ogr2OgrOptions,
+) -> Result<()> {
+    if layer.is_some() && compression.is_some() {
+        anyhow::bail!("compression is not supported when writing layers to a single file");
+    }
+    if layer.is_none() && output_path.exists() {
         tokio::fs::remove_file(output_path)
             .await
             .with_context(|| format!("when removing {}", output_path.display()))?;
     }
 
+    // when the driver can't stream into /vsigzip/, write the uncompressed file
+    // (output path without the compression suffix) and compress it afterwards
+    let (target, uncompressed) = match compression {
+        None => (output_path.to_path_buf(), None),
+        Some(Compression::Gzip) if compression::writes_through_vsigzip(driver) => {
+            (compression::vsigzip_path(output_path), None)
+        }
+        Some(compression) => {
+            let uncompressed = output_path.with_extension("");
+            (uncompressed.clone(), Some((uncompressed, compression)))
+        }
+    };
+
+    if uncompressed.is_some() && target.exists() {
+        tokio::fs::remove_file(&target)
+            .await
+            .with_context(|| format!("when removing {}", target.display()))?;
+    }
+
     let mut cmd = Command::new("ogr2ogr");
     let output = cmd
-        .arg("-f")
-        .arg(driver)
-        .arg("-nlt")
-        .arg("PROMOTE_TO_MULTI")
-        .arg(output_path)
-        .arg(vrt)
+        .args(file_args(
+            vrt,
+            &target,
+            driver,
+            layer,
+            expected_geometry,
+            options,
+        ))
         .output()
         .await?;
 
@@ -163,20 +634,109 @@ pub async fn load_to_file(vrt: &Path, output_path: &Path, driver: &str) -> Resul
         anyhow::bail!("ogr2ogr failed: {}", stderr);
     }
 
+    if let Some((uncompressed, compression)) = uncompressed {
+        compression::compress_file(&uncompressed, output_path, compression).await?;
+        tokio::fs::remove_file(&uncompressed)
+            .await
+            .with_context(|| format!("when removing {}", uncompressed.display()))?;
+    }
+
     Ok(())
 }
 
+static OGRINFO_EXTRA_ARGS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+/// Arguments passed to every `ogrinfo` run, e.g. `--config` options (`--ogrinfo-extra`).
+/// It can be set more than once.
+pub fn set_ogrinfo_extra_args(args: Vec<String>) {
+    *OGRINFO_EXTRA_ARGS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = args;
+}
+
+/// `ogrinfo` with the arguments of [`set_ogrinfo_extra_args`].
+fn ogrinfo_command() -> Command {
+    let mut command = Command::new("ogrinfo");
+    command.args(
+        OGRINFO_EXTRA_ARGS
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter(),
+    );
+    command
+}
+
 pub async fn layer_schema(path: &Path) -> Result<LayerSchema> {
-    let ogrinfo = Command::new("ogrinfo").arg("-json").arg(path).output().await?;
+    layer_schema_of(path, None).await
+}
+
+/// The schema of `layer`, or of the first layer when `layer` is `None`.
+pub async fn layer_schema_of(path: &Path, layer: Option<&str>) -> Result<LayerSchema> {
+    let json = ogrinfo_json(path, layer).await?;
+    parse_layer_schema(&json)
+}
+
+/// `ogrinfo -json` of `layer` (the first layer when `None`).
+async fn ogrinfo_json(path: &Path, layer: Option<&str>) -> Result<Value> {
+    let ogrinfo = ogrinfo_command()
+        .arg("-json")
+        .arg(path)
+        .args(layer)
+        .output()
+        .await?;
 
     if !ogrinfo.status.success() {
         let stderr = String::from_utf8_lossy(&ogrinfo.stderr);
         anyhow::bail!("ogrinfo failed: {}", stderr);
     }
 
-    let json: Value =
-        serde_json::from_slice(&ogrinfo.stdout).with_context(|| "when parsing ogrinfo JSON")?;
+    serde_json::from_slice(&ogrinfo.stdout).with_context(|| "when parsing ogrinfo JSON")
+}
+
+/// What a single `ogrinfo -json` run tells about a shapefile: the VRT needs its attributes
+/// and encoding, and `--geometry-filter` its geometry type.
+#[derive(Debug)]
+pub struct ShapeInfo {
+    pub schema: LayerSchema,
+    /// `SOURCE_ENCODING` (from the `.cpg` file), if there is one
+    pub source_encoding: Option<String>,
+}
+
+/// Identifies an extracted shapefile: the same path may be extracted again with other contents.
+type ShapeKey = (PathBuf, u64, Option<std::time::SystemTime>);
+
+static SHAPE_INFO: OnceLock<Mutex<HashMap<ShapeKey, Arc<ShapeInfo>>>> = OnceLock::new();
+
+/// The [`ShapeInfo`] of a shapefile. ogrinfo is run once per shapefile, and the result
+/// is reused by the later lookups (e.g. the geometry filter, then the VRT).
+pub async fn shape_info(shape: &Path) -> Result<Arc<ShapeInfo>> {
+    let metadata = tokio::fs::metadata(shape)
+        .await
+        .with_context(|| format!("when reading {}", shape.display()))?;
+    let key = (
+        shape.to_path_buf(),
+        metadata.len(),
+        metadata.modified().ok(),
+    );
+    let cache = SHAPE_INFO.get_or_init(Default::default);
+    if let Some(info) = cache.lock().unwrap().get(&key) {
+        return Ok(info.clone());
+    }
+
+    let json = ogrinfo_json(shape, None).await?;
+    let source_encoding = json
+        .pointer("/layers/0/metadata/SHAPEFILE/SOURCE_ENCODING")
+        .and_then(Value::as_str)
+        .filter(|encoding| !encoding.is_empty())
+        .map(str::to_string);
+    let info = Arc::new(ShapeInfo {
+        schema: parse_layer_schema(&json)?,
+        source_encoding,
+    });
+    cache.lock().unwrap().insert(key, info.clone());
+    Ok(info)
+}
 
+fn parse_layer_schema(json: &Value) -> Result<LayerSchema> {
     let fields = json
         .pointer("/layers/0/fields")
         .and_then(Value::as_array)
@@ -202,12 +762,18 @@ pub async fn layer_schema(path: &Path) -> Result<LayerSchema> {
         .and_then(Value::as_str)
         .map(|s| s.to_string());
 
-    let geometry_srid = extract_geometry_srid(&json);
+    let geometry_srid = extract_geometry_srid(json);
+    let extent = extract_extent(json);
+    let feature_count = json
+        .pointer("/layers/0/featureCount")
+        .and_then(Value::as_u64);
 
     Ok(LayerSchema {
         fields: out_fields,
         geometry_type,
         geometry_srid,
+        extent,
+        feature_count,
     })
 }
 
@@ -250,20 +816,107 @@ pub fn promote_geometry_type(geom_type: &str) -> String {
     }
 }
 
-pub async fn has_layer(postgres_url: &str, layer_name: &str) -> Result<bool> {
+/// Whether the layer exists in a file holding several tables (e.g. a GeoPackage).
+pub async fn has_file_layer(path: &Path, layer_name: &str) -> Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    let output = ogrinfo_command()
+        .arg("-so")
+        .arg("-q")
+        .arg(path)
+        .arg(layer_name)
+        .output()
+        .await?;
+
+    Ok(output.status.success())
+}
+
+pub async fn has_layer(postgres_url: &str, schema: Option<&str>, layer_name: &str) -> Result<bool> {
     let layer_name_lower = layer_name.to_lowercase();
-    let output = Command::new("ogrinfo")
+    let table = match schema {
+        Some(schema) => format!("\"{}\".\"{}\"", schema, layer_name_lower),
+        None => format!("\"{}\"", layer_name_lower),
+    };
+    let output = ogrinfo_command()
         .arg("-if")
         .arg("postgresql")
         .arg(format!("PG:{}", postgres_url))
         .arg("-sql")
-        .arg(&format!("SELECT 1 FROM \"{}\" LIMIT 1", layer_name_lower))
+        .arg(format!("SELECT 1 FROM {} LIMIT 1", table))
         .output()
         .await?;
 
     Ok(output.status.success())
 }
 
+/// Converts an extent in `EPSG:srid` to WGS84 (EPSG:4326), with `gdaltransform`.
+/// A projected extent is not a rectangle in longitude and latitude, so this is the
+/// bounding box of its transformed corners.
+pub async fn transform_extent(extent: [f64; 4], srid: i32) -> Result<[f64; 4]> {
+    use tokio::io::AsyncWriteExt;
+
+    let [minx, miny, maxx, maxy] = extent;
+    let corners = format!(
+        "{minx} {miny}\n{minx} {maxy}\n{maxx} {miny}\n{maxx} {maxy}\n",
+        minx = minx,
+        miny = miny,
+        maxx = maxx,
+        maxy = maxy
+    );
+    let mut child = Command::new("gdaltransform")
+        .arg("-s_srs")
+        .arg(format!("EPSG:{}", srid))
+        .arg("-t_srs")
+        .arg("EPSG:4326")
+        .arg("-output_xy")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("when running gdaltransform")?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin.write_all(corners.as_bytes()).await?;
+    drop(stdin);
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("gdaltransform failed: {}", stderr);
+    }
+    bbox_of_points(&String::from_utf8_lossy(&output.stdout))
+        .ok_or_else(|| anyhow!("unexpected gdaltransform output"))
+}
+
+/// The bounding box of `x y` lines (the output of `gdaltransform -output_xy`).
+fn bbox_of_points(text: &str) -> Option<[f64; 4]> {
+    let mut bbox: Option<[f64; 4]> = None;
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let mut values = line.split_whitespace().map(str::parse::<f64>);
+        let (Some(Ok(x)), Some(Ok(y))) = (values.next(), values.next()) else {
+            return None;
+        };
+        // gdaltransform prints `inf` for points it can't transform
+        if !x.is_finite() || !y.is_finite() {
+            return None;
+        }
+        bbox = Some(match bbox {
+            Some([minx, miny, maxx, maxy]) => [minx.min(x), miny.min(y), maxx.max(x), maxy.max(y)],
+            None => [x, y, x, y],
+        });
+    }
+    bbox
+}
+
+fn extract_extent(json: &Value) -> Option<[f64; 4]> {
+    let extent = json
+        .pointer("/layers/0/geometryFields/0/extent")?
+        .as_array()?
+        .iter()
+        .map(Value::as_f64)
+        .collect::<Option<Vec<_>>>()?;
+    extent.try_into().ok()
+}
+
 fn extract_geometry_srid(json: &Value) -> Option<i32> {
     let id = json
         .pointer("/layers/0/geometryFields/0/coordinateSystem/projjson/id")
@@ -289,41 +942,19 @@ fn extract_geometry_srid(json: &Value) -> Option<i32> {
 }
 
 async fn get_attribute_list(shape: &Path) -> Result<Vec<String>> {
-    let ogrinfo = Command::new("ogrinfo")
-        .arg("-json")
-        .arg(shape)
-        .output()
-        .await?;
-
-    if !ogrinfo.status.success() {
-        let stderr = String::from_utf8_lossy(&ogrinfo.stderr);
-        anyhow::bail!("ogrinfo failed: {}", stderr);
-    }
-
-    let stdout_str = String::from_utf8_lossy(&ogrinfo.stdout);
-    let json: Value =
-        serde_json::from_str(&stdout_str).with_context(|| "when parsing ogrinfo JSON output")?;
-
-    let fields = json
-        .pointer("/layers/0/fields")
-        .and_then(Value::as_array)
-        .ok_or_else(|| anyhow!("missing fields array"))?;
-
-    let attributes: Vec<String> = fields
+    let info = shape_info(shape).await?;
+    Ok(info
+        .schema
+        .fields
         .iter()
-        .filter_map(|field| {
-            field
-                .pointer("/name")
-                .and_then(Value::as_str)
-                .map(String::from)
-        })
-        .collect();
-
-    Ok(attributes)
+        .map(|field| field.name.clone())
+        .collect())
 }
 
 // PC932 is almost the same as Shift-JIS, but most GIS software outputs as CP932 when using Shift-JIS
-static ENCODINGS: &[(&str, &Encoding)] = &[("CP932", SHIFT_JIS), ("UTF-8", UTF_8)];
+// tried in order: EUC-JP (CP51932) is rare, and last since its bytes often decode as CP932 too
+static ENCODINGS: &[(&str, &Encoding)] =
+    &[("CP932", SHIFT_JIS), ("UTF-8", UTF_8), ("EUC-JP", EUC_JP)];
 
 // We get the bytes from the ogrinfo output after "successful"
 // this is because before "successful" is the filename, and the filename
@@ -335,69 +966,241 @@ fn bytes_after_successful(data: &Vec<u8>) -> Option<&[u8]> {
         .map(|pos| &data[pos + needle.len()..])
 }
 
+static DEBUG_ENCODING: RwLock<bool> = RwLock::new(false);
+/// Whether to print how the encoding of each shapefile was detected (`--debug-encoding`).
+/// It can be set more than once.
+pub fn set_debug_encoding(debug: bool) {
+    *DEBUG_ENCODING
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = debug;
+}
+fn debug_encoding() -> bool {
+    *DEBUG_ENCODING
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The number of features the fallback detection reads, tried in order while the sample
+/// is ASCII only (which decodes the same in every encoding). The output of ogrinfo is read
+/// into memory, so even a shapefile of codes only is never read whole: after the last one,
+/// the first of [`ENCODINGS`] is used.
+const ENCODING_SAMPLE_LIMITS: &[u64] = &[100, 1_000, 10_000, 100_000];
+
+/// The sample the fallback detection decided on, for `--debug-encoding`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodingSample {
+    /// 読み込んだフィーチャ数
+    pub features: usize,
+    pub bytes: usize,
+    /// エンコーディングごとの最初のデコードエラーの位置（エラーがなければ `None`）
+    pub errors: Vec<(&'static str, Option<usize>)>,
+    pub detected: Option<&'static str>,
+}
+
+impl EncodingSample {
+    fn new(data: &[u8], source: &str) -> Self {
+        let needle = b"OGRFeature(";
+        Self {
+            features: data
+                .windows(needle.len())
+                .filter(|window| window == needle)
+                .count(),
+            bytes: data.len(),
+            errors: ENCODINGS
+                .iter()
+                .map(|(name, encoding)| (*name, first_decode_error(encoding, data)))
+                .collect(),
+            detected: encoding_of_bytes(data, source),
+        }
+    }
+
+    /// Whether the sample tells the encodings apart: it has non-ASCII bytes, or it is
+    /// every feature there is.
+    fn is_conclusive(&self, data: &[u8], limit: u64) -> bool {
+        !data.is_ascii() || (self.features as u64) < limit
+    }
+
+    fn describe(&self, source: &str) -> String {
+        let errors = self
+            .errors
+            .iter()
+            .map(|(name, error)| match error {
+                Some(position) => format!("{}: {} バイト目でエラー", name, position),
+                None => format!("{}: エラーなし", name),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(
+            "{}: {} 件（{} バイト）のフィーチャで判定 [{}] → {}",
+            source,
+            self.features,
+            self.bytes,
+            errors,
+            self.detected.unwrap_or("判定できません")
+        )
+    }
+}
+
 async fn detect_encoding_fallback(shape: &Path) -> Result<Option<String>> {
-    let ogrinfo = Command::new("ogrinfo")
-        .arg("-al")
-        .arg("-geom=NO")
-        .arg("-limit")
-        .arg("100")
-        .arg(shape)
-        .output()
-        .await?;
+    let source = shape.display().to_string();
+    for (i, limit) in ENCODING_SAMPLE_LIMITS.iter().enumerate() {
+        let ogrinfo = ogrinfo_command()
+            .arg("-al")
+            .arg("-geom=NO")
+            .arg("-limit")
+            .arg(limit.to_string())
+            .arg(shape)
+            .output()
+            .await?;
 
-    if !ogrinfo.status.success() {
-        let stderr = String::from_utf8_lossy(&ogrinfo.stderr);
-        anyhow::bail!("ogrinfo failed: {}", stderr);
+        if !ogrinfo.status.success() {
+            let stderr = String::from_utf8_lossy(&ogrinfo.stderr);
+            anyhow::bail!("ogrinfo failed: {}", stderr);
+        }
+
+        let Some(data) = bytes_after_successful(&ogrinfo.stdout) else {
+            anyhow::bail!("ogrinfo failed to open {}", shape.display());
+        };
+        let sample = EncodingSample::new(data, &source);
+        let conclusive = sample.is_conclusive(data, *limit);
+        if conclusive || i + 1 == ENCODING_SAMPLE_LIMITS.len() {
+            if debug_encoding() {
+                eprintln!("{}", sample.describe(&source));
+            }
+            if !conclusive {
+                let message = format!(
+                    "{}: 先頭 {} 件が ASCII のみのため判定できません。{} を使用します",
+                    source,
+                    sample.features,
+                    sample.detected.unwrap_or_default()
+                );
+                if debug_encoding() {
+                    eprintln!("{}", message);
+                } else {
+                    logging::detail(message);
+                }
+            }
+            return Ok(sample.detected.map(str::to_string));
+        }
+        logging::detail(format!(
+            "{}: 先頭 {} 件が ASCII のみのため、さらに読み込みます",
+            source, sample.features
+        ));
     }
+    unreachable!("ENCODING_SAMPLE_LIMITS is not empty")
+}
 
-    let Some(data) = bytes_after_successful(&ogrinfo.stdout) else {
-        anyhow::bail!("ogrinfo failed to open {}", shape.display());
-    };
+/// The first of [`ENCODINGS`] that decodes `data` without errors.
+/// Every attempt is logged with `--verbose`, to debug wrong guesses.
+fn encoding_of_bytes(data: &[u8], source: &str) -> Option<&'static str> {
     for (name, encoding) in ENCODINGS {
-        // decode() returns a tuple: (decoded string, bytes read, had_errors)
-        let (_decoded, _, had_errors) = encoding.decode(data);
-        if !had_errors {
-            return Ok(Some(name.to_string()));
+        match first_decode_error(encoding, data) {
+            None => {
+                logging::detail(format!(
+                    "{}: {} として {} バイトをエラーなく読み込みました",
+                    source,
+                    name,
+                    data.len()
+                ));
+                return Some(name);
+            }
+            Some(position) => logging::detail(format!(
+                "{}: {} として {} バイト中 {} バイトまで読み込み、デコードエラーになりました",
+                source,
+                name,
+                data.len(),
+                position
+            )),
         }
     }
-
-    Ok(None)
+    None
 }
 
-async fn detect_encoding_ogrinfo(shape: &Path) -> Result<Option<String>> {
-    let ogrinfo = Command::new("ogrinfo")
-        .arg("-json")
-        .arg(shape)
-        .output()
-        .await?;
-
-    if !ogrinfo.status.success() {
-        let stderr = String::from_utf8_lossy(&ogrinfo.stderr);
-        anyhow::bail!("ogrinfo failed: {}", stderr);
+/// The offset of the first malformed byte sequence, if `data` isn't valid in `encoding`.
+fn first_decode_error(encoding: &'static Encoding, data: &[u8]) -> Option<usize> {
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let capacity = decoder
+        .max_utf8_buffer_length_without_replacement(data.len())
+        .unwrap_or(data.len() * 3);
+    let mut decoded = String::with_capacity(capacity);
+    let (result, read) = decoder.decode_to_string_without_replacement(data, &mut decoded, true);
+    match result {
+        // (length of the malformed sequence, bytes read after it)
+        DecoderResult::Malformed(malformed, after) => {
+            Some(read - malformed as usize - after as usize)
+        }
+        _ => None,
     }
+}
 
-    let stdout_str = String::from_utf8_lossy(&ogrinfo.stdout);
-    let json: Value =
-        serde_json::from_str(&stdout_str).with_context(|| "when parsing ogrinfo JSON output")?;
+/// `--force-encoding`: encodings used instead of the detected ones.
+///
+/// The order of precedence for a table is:
+/// 1. the encoding forced for its identifier (the table's, e.g. `A38a`, then the dataset's, e.g. `A38`)
+/// 2. the encoding forced for every dataset
+/// 3. the detected encoding: `SOURCE_ENCODING` reported by ogrinfo (from the `.cpg` file),
+///    then the first of CP932, UTF-8 and EUC-JP that decodes the attributes without errors
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ForcedEncodings {
+    pub global: Option<String>,
+    pub by_identifier: HashMap<String, String>,
+}
 
-    let source_encoding = json
-        .pointer("/layers/0/metadata/SHAPEFILE/SOURCE_ENCODING")
-        .and_then(Value::as_str);
+impl ForcedEncodings {
+    pub fn for_mapping(&self, mapping: &ShapefileMetadata) -> Option<&str> {
+        self.by_identifier
+            .iter()
+            .find(|(identifier, _)| identifier.eq_ignore_ascii_case(&mapping.identifier))
+            .or_else(|| {
+                self.by_identifier.iter().find(|(identifier, _)| {
+                    identifier.eq_ignore_ascii_case(&mapping.original_identifier)
+                })
+            })
+            .map(|(_, encoding)| encoding.as_str())
+            .or(self.global.as_deref())
+    }
+}
 
-    if let Some(encoding) = source_encoding {
-        if encoding == "" {
-            return Ok(None);
+impl FromIterator<(Option<String>, String)> for ForcedEncodings {
+    fn from_iter<I: IntoIterator<Item = (Option<String>, String)>>(iter: I) -> Self {
+        let mut forced = Self::default();
+        for (identifier, encoding) in iter {
+            let encoding = canonical_encoding(&encoding);
+            match identifier {
+                Some(identifier) => {
+                    forced.by_identifier.insert(identifier, encoding);
+                }
+                None => forced.global = Some(encoding),
+            }
         }
-        return Ok(Some(encoding.to_string()));
+        forced
     }
+}
 
-    Ok(None)
+/// Normalizes an encoding name reported by GDAL (which varies between versions, e.g.
+/// `SHIFT_JIS`, `Shift-JIS`, `CP932`) to a spelling the ENCODING open option accepts.
+pub fn canonical_encoding(name: &str) -> String {
+    let normalized = name.trim().to_ascii_uppercase().replace('_', "-");
+    match normalized.as_str() {
+        "CP932" | "SHIFT-JIS" | "SJIS" | "SHIFTJIS" | "MS932" | "WINDOWS-31J" | "CP-932" => {
+            "CP932".to_string()
+        }
+        "UTF-8" | "UTF8" => "UTF-8".to_string(),
+        "EUC-JP" | "EUCJP" | "EUCJP-MS" | "EUC-JP-MS" => "EUC-JP".to_string(),
+        _ => normalized,
+    }
 }
 
 pub async fn detect_encoding(shape: &Path) -> Result<String> {
-    let encoding = detect_encoding_ogrinfo(shape).await?;
-    if let Some(encoding) = encoding {
-        return Ok(encoding);
+    if let Some(encoding) = &shape_info(shape).await?.source_encoding {
+        if debug_encoding() {
+            eprintln!(
+                "{}: SOURCE_ENCODING（.cpg）の {} を使用",
+                shape.display(),
+                encoding
+            );
+        }
+        return Ok(encoding.clone());
     }
 
     if let Some(encoding) = detect_encoding_fallback(shape).await? {
@@ -409,6 +1212,318 @@ pub async fn detect_encoding(shape: &Path) -> Result<String> {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_ogrinfo_extra_args_more_than_once() {
+        // other tests run with the default concurrently, so it is only set to it
+        set_ogrinfo_extra_args(Vec::new());
+        set_ogrinfo_extra_args(Vec::new());
+        assert_eq!(ogrinfo_command().as_std().get_args().count(), 0);
+    }
+
+    #[test]
+    fn test_postgres_args_geometry_column() {
+        let vrt = Path::new("./tmp/vrt/n03.vrt");
+        let args = postgres_args(
+            vrt,
+            "host=localhost",
+            None,
+            None,
+            &Ogr2OgrOptions::default(),
+        );
+        assert!(args.contains(&OsString::from("GEOMETRY_NAME=geom")));
+        assert!(args.contains(&OsString::from("SPATIAL_INDEX=NONE")));
+        assert!(!args
+            .iter()
+            .any(|a| a.to_string_lossy().starts_with("SCHEMA=")));
+        let args = postgres_args(
+            vrt,
+            "host=localhost",
+            Some("jpksj_2024"),
+            None,
+            &Ogr2OgrOptions::default(),
+        );
+        assert!(args.contains(&OsString::from("SCHEMA=jpksj_2024")));
+
+        let options = Ogr2OgrOptions {
+            geometry_column: "the_geom".to_string(),
+            ..Default::default()
+        };
+        let args = postgres_args(vrt, "host=localhost", None, None, &options);
+        let pos = args
+            .iter()
+            .position(|a| a == "GEOMETRY_NAME=the_geom")
+            .unwrap();
+        assert_eq!(args[pos - 1], "-lco");
+        assert!(!args.contains(&OsString::from("GEOMETRY_NAME=geom")));
+        assert_eq!(args.last().unwrap(), vrt.as_os_str());
+    }
+
+    #[test]
+    fn test_geometry_type_args() {
+        let vrt = Path::new("./tmp/vrt/p04.vrt");
+        let options = Ogr2OgrOptions::default();
+        let promoted = |args: &[OsString]| {
+            args.windows(2)
+                .any(|pair| pair[0] == "-nlt" && pair[1] == "PROMOTE_TO_MULTI")
+        };
+        assert!(promoted(&postgres_args(
+            vrt,
+            "host=localhost",
+            None,
+            None,
+            &options
+        )));
+        assert!(promoted(&postgres_args(
+            vrt,
+            "host=localhost",
+            None,
+            Some(GeometryFilter::Polygon),
+            &options
+        )));
+        assert!(!promoted(&postgres_args(
+            vrt,
+            "host=localhost",
+            None,
+            Some(GeometryFilter::Point),
+            &options
+        )));
+        assert!(!promoted(&file_args(
+            vrt,
+            Path::new("p04.fgb"),
+            "FlatGeobuf",
+            None,
+            Some(GeometryFilter::Point),
+            &options
+        )));
+
+        assert_eq!(
+            output_geometry_type("Point", Some(GeometryFilter::Point)),
+            "POINT"
+        );
+        assert_eq!(output_geometry_type("Point", None), "MULTIPOINT");
+        assert_eq!(
+            output_geometry_type("Polygon", Some(GeometryFilter::Polygon)),
+            "MULTIPOLYGON"
+        );
+    }
+
+    #[test]
+    fn test_unexpected_geometry_types() {
+        let types = |types: &[&str]| types.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        assert!(
+            unexpected_geometry_types(Some(GeometryFilter::Point), &types(&["POINT"])).is_empty()
+        );
+        assert_eq!(
+            unexpected_geometry_types(
+                Some(GeometryFilter::Polygon),
+                &types(&["MULTIPOLYGON", "MULTILINESTRING"])
+            ),
+            types(&["MULTILINESTRING"])
+        );
+        assert!(unexpected_geometry_types(None, &types(&["MULTIPOLYGON"])).is_empty());
+        assert_eq!(
+            unexpected_geometry_types(None, &types(&["POINT", "MULTIPOLYGON"])),
+            types(&["POINT", "MULTIPOLYGON"])
+        );
+    }
+
+    #[test]
+    fn test_postgres_args_tuning() {
+        let vrt = Path::new("./tmp/vrt/n03.vrt");
+        let value_after = |args: &[OsString], flag: &str| {
+            args.iter()
+                .position(|a| a == flag)
+                .map(|pos| args[pos + 1].clone())
+        };
+
+        let args = postgres_args(
+            vrt,
+            "host=localhost",
+            None,
+            None,
+            &Ogr2OgrOptions::default(),
+        );
+        assert_eq!(value_after(&args, "-gt").unwrap(), "65536");
+        assert!(!args.contains(&OsString::from("GDAL_CACHEMAX")));
+        assert!(args.contains(&OsString::from("PG_USE_COPY=YES")));
+
+        let options = Ogr2OgrOptions {
+            gdal_cache_max: Some("512".to_string()),
+            group_transactions: 1000,
+            ..Default::default()
+        };
+        let args = postgres_args(vrt, "host=localhost", None, None, &options);
+        assert_eq!(value_after(&args, "-gt").unwrap(), "1000");
+        assert_eq!(value_after(&args, "GDAL_CACHEMAX").unwrap(), "512");
+        assert!(!args.contains(&OsString::from("-skipfailures")));
+        let pos = args.iter().position(|a| a == "GDAL_CACHEMAX").unwrap();
+        assert_eq!(args[pos - 1], "--config");
+        assert_eq!(args.last().unwrap(), vrt.as_os_str());
+
+        let options = Ogr2OgrOptions {
+            skip_failures: true,
+            ..Default::default()
+        };
+        let args = postgres_args(vrt, "host=localhost", None, None, &options);
+        assert!(args.contains(&OsString::from("-skipfailures")));
+        let args = file_args(
+            vrt,
+            Path::new("n03.fgb"),
+            "FlatGeobuf",
+            None,
+            None,
+            &options,
+        );
+        assert!(args.contains(&OsString::from("-skipfailures")));
+
+        let options = Ogr2OgrOptions {
+            use_copy: false,
+            ..Default::default()
+        };
+        let args = postgres_args(vrt, "host=localhost", None, None, &options);
+        assert!(args.contains(&OsString::from("PG_USE_COPY=NO")));
+        assert!(!args.contains(&OsString::from("PG_USE_COPY=YES")));
+
+        // --ogr2ogr-extra goes before the datasources
+        let options = Ogr2OgrOptions {
+            extra_args: vec!["--config".into(), "OGR_TRUNCATE".into(), "YES".into()],
+            ..Default::default()
+        };
+        let args = postgres_args(vrt, "host=localhost", None, None, &options);
+        assert_eq!(value_after(&args, "OGR_TRUNCATE").unwrap(), "YES");
+        assert_eq!(args.last().unwrap(), vrt.as_os_str());
+        let target = Path::new("n03.fgb");
+        let args = file_args(vrt, target, "FlatGeobuf", None, None, &options);
+        assert_eq!(
+            &args[args.len() - 3..],
+            [OsString::from("YES"), target.into(), vrt.into()]
+        );
+    }
+
+    #[test]
+    fn test_parse_gdal_version() {
+        assert_eq!(
+            parse_gdal_version("GDAL 3.9.2, released 2024/08/13\n"),
+            Some((3, 9, 2))
+        );
+        assert_eq!(
+            parse_gdal_version("GDAL 3.10.0dev-4d7e2e5, released 2024/06/01"),
+            Some((3, 10, 0))
+        );
+        assert_eq!(parse_gdal_version("GDAL 3.4"), Some((3, 4, 0)));
+        assert_eq!(parse_gdal_version("ogrinfo: not found"), None);
+
+        let info = |version| GdalInfo {
+            version_line: String::new(),
+            version,
+            postgres_driver: true,
+        };
+        assert_eq!(info(Some((3, 9, 0))).version_warning(), None);
+        assert_eq!(info(Some((3, 10, 1))).version_warning(), None);
+        assert_eq!(info(None).version_warning(), None);
+        assert!(info(Some((3, 4, 1)))
+            .version_warning()
+            .unwrap()
+            .contains("3.9 以上"));
+    }
+
+    #[test]
+    fn test_has_postgres_driver() {
+        let formats = "Supported Formats: (ro:read-only, rw:read-write, +:update, v:virtual-I/O s:subdatasets)
+  PCIDSK -raster,vector- (rw+v): PCIDSK Database File
+  PostgreSQL -vector- (rw+): PostgreSQL/PostGIS
+  ESRI Shapefile -vector- (rw+v): ESRI Shapefile
+";
+        assert!(has_postgres_driver(formats));
+        assert!(!has_postgres_driver(
+            "  PostGISRaster -raster- (rws): PostGIS Raster driver\n  ESRI Shapefile -vector- (rw+v): ESRI Shapefile\n"
+        ));
+    }
+
+    #[test]
+    fn test_srs_args() {
+        let vrt = Path::new("./tmp/vrt/n03.vrt");
+        let value_after = |args: &[OsString], flag: &str| {
+            args.iter()
+                .position(|a| a == flag)
+                .map(|pos| args[pos + 1].clone())
+        };
+
+        // no reprojection by default
+        let options = Ogr2OgrOptions::default();
+        let args = postgres_args(vrt, "host=localhost", None, None, &options);
+        assert!(["-t_srs", "-s_srs", "-a_srs"]
+            .iter()
+            .all(|flag| !args.contains(&OsString::from(flag))));
+        assert_eq!(options.output_srid(), None);
+
+        let options = Ogr2OgrOptions {
+            target_srs: Some("EPSG:3857".to_string()),
+            ..Default::default()
+        };
+        let args = postgres_args(vrt, "host=localhost", None, None, &options);
+        assert_eq!(value_after(&args, "-t_srs").unwrap(), "EPSG:3857");
+        assert_eq!(options.output_srid(), Some(3857));
+        let args = file_args(
+            vrt,
+            Path::new("n03.fgb"),
+            "FlatGeobuf",
+            None,
+            None,
+            &options,
+        );
+        assert_eq!(value_after(&args, "-t_srs").unwrap(), "EPSG:3857");
+
+        let options = Ogr2OgrOptions {
+            source_srs: Some("EPSG:4612".to_string()),
+            ..options
+        };
+        let args = postgres_args(vrt, "host=localhost", None, None, &options);
+        assert_eq!(value_after(&args, "-s_srs").unwrap(), "EPSG:4612");
+        assert_eq!(value_after(&args, "-t_srs").unwrap(), "EPSG:3857");
+        assert_eq!(options.output_srid(), Some(3857));
+
+        // without a target, the source SRS is assigned instead of being reprojected from
+        let options = Ogr2OgrOptions {
+            target_srs: None,
+            ..options
+        };
+        let args = postgres_args(vrt, "host=localhost", None, None, &options);
+        assert_eq!(value_after(&args, "-a_srs").unwrap(), "EPSG:4612");
+        assert!(!args.contains(&OsString::from("-s_srs")));
+        assert_eq!(options.output_srid(), Some(4612));
+    }
+
+    #[test]
+    fn test_file_args_single_file_layer() {
+        let vrt = Path::new("./tmp/vrt/n03.vrt");
+        let target = Path::new("./output/jpksj.gpkg");
+        let options = Ogr2OgrOptions::default();
+
+        let args = file_args(vrt, target, "GPKG", Some("n03"), None, &options);
+        let pos = args.iter().position(|a| a == "-nln").unwrap();
+        assert_eq!(args[pos + 1], "n03");
+        assert!(args.contains(&OsString::from("-overwrite")));
+        assert_eq!(
+            &args[args.len() - 2..],
+            [target.as_os_str(), vrt.as_os_str()]
+        );
+
+        let args = file_args(vrt, target, "GPKG", None, None, &options);
+        assert!(!args.contains(&OsString::from("-nln")));
+        assert!(!args.contains(&OsString::from("-overwrite")));
+    }
+
+    #[test]
+    fn test_bbox_of_points() {
+        let output = "139.5 35.1\n139.5 35.9\n140.2 35.0\n140.1 36.0\n";
+        assert_eq!(bbox_of_points(output), Some([139.5, 35.0, 140.2, 36.0]));
+        assert_eq!(bbox_of_points(""), None);
+        assert_eq!(bbox_of_points("139.5 35.1\ninf inf\n"), None);
+        assert_eq!(bbox_of_points("ERROR 1: invalid\n"), None);
+    }
 
     #[tokio::test]
     async fn test_detect_encoding() {
@@ -421,10 +1536,172 @@ mod tests {
         assert_eq!(encoding, "CP932");
     }
 
+    #[test]
+    fn test_encoding_of_bytes() {
+        let (cp932, _, _) = SHIFT_JIS.encode("行政区域コード");
+        assert_eq!(encoding_of_bytes(&cp932, "test"), Some("CP932"));
+        assert_eq!(
+            encoding_of_bytes("ｶﾀｶﾅと漢字".as_bytes(), "test"),
+            Some("UTF-8")
+        );
+        assert_eq!(encoding_of_bytes(b"ascii only", "test"), Some("CP932"));
+        // 東京 in EUC-JP is malformed in both CP932 and UTF-8
+        let euc_jp = [0xc5, 0xec, 0xb5, 0xfe];
+        assert_eq!(encoding_of_bytes(&euc_jp, "test"), Some("EUC-JP"));
+        assert_eq!(first_decode_error(SHIFT_JIS, &euc_jp), Some(1));
+        assert_eq!(first_decode_error(UTF_8, b"ok\xff"), Some(2));
+        assert_eq!(encoding_of_bytes(&[0xff, 0xff], "test"), None);
+    }
+
+    #[test]
+    fn test_encoding_sample() {
+        let data = b"OGRFeature(a):0\n  N03_001 (String) = 13101\nOGRFeature(a):1\n";
+        let sample = EncodingSample::new(data, "test");
+        assert_eq!(sample.features, 2);
+        // ASCII only: more features are read, unless these were all of them
+        assert!(!sample.is_conclusive(data, 2));
+        assert!(sample.is_conclusive(data, 100));
+        // decodes as any of them: the first is used
+        assert_eq!(sample.detected, Some("CP932"));
+
+        let (cp932, _, _) = SHIFT_JIS.encode("OGRFeature(a):0\n  名称 (String) = 東京");
+        let sample = EncodingSample::new(&cp932, "test");
+        assert!(sample.is_conclusive(&cp932, 1));
+        assert_eq!(sample.detected, Some("CP932"));
+        assert_eq!(sample.errors[0], ("CP932", None));
+        assert!(sample.errors[1].1.is_some());
+        assert_eq!(
+            sample.describe("a.shp"),
+            format!(
+                "a.shp: 1 件（{} バイト）のフィーチャで判定 [CP932: エラーなし, UTF-8: {} バイト目でエラー, EUC-JP: {} バイト目でエラー] → CP932",
+                cp932.len(),
+                sample.errors[1].1.unwrap(),
+                sample.errors[2].1.unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_unmapped_attributes() {
+        let mut mapping = crate::loader::mapping::ShapefileMetadataBuilder::default();
+        let mapping = mapping
+            .cat1(String::new())
+            .cat2(String::new())
+            .name(String::new())
+            .version(String::new())
+            .data_year(String::new())
+            .identifier("A27".to_string())
+            .original_identifier("A27".to_string())
+            .field_mappings(vec![
+                ("行政区域コード".to_string(), "A27_001".to_string()),
+                ("学校名".to_string(), "A27_002".to_string()),
+            ])
+            .build()
+            .unwrap();
+        let attributes = ["A27_001", "A27_002", "A27_009", "学校名", "備考"]
+            .map(str::to_string)
+            .to_vec();
+        assert_eq!(
+            unmapped_attributes(&attributes, &mapping),
+            vec!["A27_009", "備考"]
+        );
+    }
+
+    #[test]
+    fn test_vrt_field() {
+        assert_eq!(
+            vrt_field("行政区域コード", "A27_001", Some("String")),
+            r#"<Field name="行政区域コード" src="A27_001" type="String" />"#
+        );
+        assert_eq!(
+            vrt_field("児童数", "A27_003", None),
+            r#"<Field name="児童数" src="A27_003" />"#
+        );
+    }
+
+    #[test]
+    fn test_forced_encodings() {
+        let forced: ForcedEncodings = [
+            (None, "utf8".to_string()),
+            (Some("A38".to_string()), "shift_jis".to_string()),
+            (Some("a38b".to_string()), "EUC-JP".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let mapping = |identifier: &str, original_identifier: &str| {
+            let mut mapping = crate::loader::mapping::ShapefileMetadataBuilder::default();
+            mapping
+                .cat1(String::new())
+                .cat2(String::new())
+                .name(String::new())
+                .version(String::new())
+                .data_year(String::new())
+                .field_mappings(vec![])
+                .identifier(identifier.to_string())
+                .original_identifier(original_identifier.to_string());
+            mapping.build().unwrap()
+        };
+        assert_eq!(forced.for_mapping(&mapping("A38b", "A38")), Some("EUC-JP"));
+        assert_eq!(forced.for_mapping(&mapping("A38a", "A38")), Some("CP932"));
+        assert_eq!(forced.for_mapping(&mapping("N03", "N03")), Some("UTF-8"));
+        assert_eq!(
+            ForcedEncodings::default().for_mapping(&mapping("N03", "N03")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_canonical_encoding() {
+        for name in ["SHIFT_JIS", "Shift-JIS", "shift_jis", "CP932", "cp932", "SJIS", "MS932"] {
+            assert_eq!(canonical_encoding(name), "CP932", "{}", name);
+        }
+        for name in ["UTF-8", "utf8", "UTF_8"] {
+            assert_eq!(canonical_encoding(name), "UTF-8", "{}", name);
+        }
+        for name in ["EUC-JP", "euc_jp", "EUCJP"] {
+            assert_eq!(canonical_encoding(name), "EUC-JP", "{}", name);
+        }
+        assert_eq!(canonical_encoding(" iso-8859-1 "), "ISO-8859-1");
+    }
+
+    #[test]
+    fn test_geometry_filter_matches() {
+        assert!(GeometryFilter::Point.matches("Point"));
+        assert!(GeometryFilter::Point.matches("Multi Point"));
+        assert!(GeometryFilter::Line.matches("LineString"));
+        assert!(GeometryFilter::Line.matches("Multi Line String"));
+        assert!(GeometryFilter::Polygon.matches("3D Multi Polygon"));
+        assert!(!GeometryFilter::Point.matches("Polygon"));
+        assert!(!GeometryFilter::Polygon.matches("Point"));
+        assert!(!GeometryFilter::Point.matches("None"));
+    }
+
+    #[tokio::test]
+    async fn test_polygon_shapefile_skipped_for_points() {
+        let shape = std::path::PathBuf::from("./test_data/shp/cp932.shp");
+        let (matches, geometry_type) = matches_geometry_filter(&shape, GeometryFilter::Point)
+            .await
+            .unwrap();
+        assert!(!matches);
+        assert!(GeometryFilter::Polygon.matches(&geometry_type));
+    }
+
     #[tokio::test]
     async fn test_get_attribute_list() {
         let shape = std::path::PathBuf::from("./test_data/shp/cp932.shp");
         let attributes = super::get_attribute_list(&shape).await.unwrap();
         assert_eq!(attributes, vec!["W09_001", "W09_002", "W09_003", "W09_004"]);
     }
+
+    #[tokio::test]
+    async fn test_shape_info_cached() {
+        let shape = std::path::PathBuf::from("./test_data/shp/cp932.shp");
+        let info = super::shape_info(&shape).await.unwrap();
+        assert_eq!(info.schema.fields.len(), 4);
+        // the attributes and the encoding come from the same ogrinfo run
+        assert!(Arc::ptr_eq(
+            &info,
+            &super::shape_info(&shape).await.unwrap()
+        ));
+    }
 }
@@ -1,24 +1,140 @@
 use crate::context;
+use crate::loader::checkpoint::{self, Checkpoint};
 use crate::loader::gdal;
-use crate::loader::{mapping, zip_traversal, OutputTarget};
+use crate::loader::quirks::SkipRules;
+use crate::loader::table_names::{self, TableNames};
+use crate::loader::transform::FieldTransform;
+use crate::loader::{compression, mapping, zip_traversal, OutputTarget};
+use crate::logging;
 use crate::metadata::{self, ColumnSchema, MetadataConnection};
+use crate::report::{Failure, Stage, StageContext};
 use crate::scraper::Dataset;
 use anyhow::{Context, Result};
 use async_channel::unbounded;
-use indicatif::{ProgressBar, ProgressStyle};
-use std::cmp::max;
-use std::path::PathBuf;
+use indicatif::ProgressStyle;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::task;
 
-use super::Loader;
+use super::{LoadReport, LoadedTable, Loader};
+
+/// Options shared by all load workers.
+#[derive(Clone)]
+struct LoadOptions {
+    output: OutputTarget,
+    skip_if_exists: bool,
+    extra_extensions: Vec<String>,
+    primary_keys: HashMap<String, String>,
+    where_filters: HashMap<String, String>,
+    table_prefix: String,
+    add_primary_keys: bool,
+    create_indexes: bool,
+    prefer_utf8_dirs: bool,
+    ogr2ogr: gdal::Ogr2OgrOptions,
+    field_transforms: HashMap<String, Vec<FieldTransform>>,
+    geometry_filter: Option<gdal::GeometryFilter>,
+    forced_encodings: gdal::ForcedEncodings,
+    keep_all_fields: bool,
+    fix_geometries: bool,
+    dedupe: bool,
+    collect_extents: bool,
+    collect_row_counts: bool,
+    /// `ANALYZE` するテーブルを [`LoadReport::tables`] に記録します
+    analyze: bool,
+    fill_gaps: bool,
+    keep_intermediate: bool,
+    delete_zips_after_load: bool,
+    extract_limits: zip_traversal::ExtractLimits,
+    skip_rules: SkipRules,
+    checkpoint: Option<Arc<Checkpoint>>,
+    /// ogr2ogr can't write to the same file from several workers at once
+    single_file_lock: Arc<tokio::sync::Mutex<()>>,
+    /// `--db-write-concurrency`: the ogr2ogr writes to PostgreSQL at once, while the
+    /// workers extract the ZIPs and create the VRTs in parallel
+    db_writes: Option<Arc<tokio::sync::Semaphore>>,
+    table_locks: Arc<TableLocks>,
+}
+
+/// Serializes writes to the same table: the existence check, the DROP/CREATE by
+/// ogr2ogr and the metadata are not atomic, so two workers loading a table of the
+/// same name (a repeated identifier, or a rerun) would otherwise fail each other.
+#[derive(Default)]
+struct TableLocks {
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl TableLocks {
+    /// Locks every table, in sorted order so that two workers never wait on each other.
+    async fn lock_all(&self, tables: &[String]) -> Vec<tokio::sync::OwnedMutexGuard<()>> {
+        let mut tables = tables.to_vec();
+        tables.sort();
+        tables.dedup();
+        let mut guards = Vec::with_capacity(tables.len());
+        for table in tables {
+            let lock = self.locks.lock().unwrap().entry(table).or_default().clone();
+            guards.push(lock.lock_owned().await);
+        }
+        guards
+    }
+}
+
+/// Loads a dataset, with its metadata writes in a transaction of their own:
+/// a failure rolls back only this dataset's metadata.
+async fn load_in_transaction(
+    dataset: &Dataset,
+    options: &LoadOptions,
+    metadata_conn: Option<&MetadataConnection>,
+) -> Result<Vec<LoadedTable>> {
+    // held until the metadata is committed
+    let tables = super::target_tables_for_dataset(dataset, &options.table_prefix)
+        .await
+        .context("when resolving target tables")?;
+    let _table_guards = options.table_locks.lock_all(&tables).await;
+
+    let Some(conn) = metadata_conn else {
+        return load(dataset, options, None).await;
+    };
+    conn.begin().await?;
+    match load(dataset, options, Some(conn)).await {
+        Ok(tables) => {
+            conn.commit().await?;
+            Ok(tables)
+        }
+        Err(e) => {
+            if let Err(rollback_error) = conn.rollback().await {
+                eprintln!("{:#}", rollback_error);
+            }
+            Err(e)
+        }
+    }
+}
 
 async fn load(
     dataset: &Dataset,
-    output: &OutputTarget,
-    skip_if_exists: bool,
+    options: &LoadOptions,
     metadata_conn: Option<&MetadataConnection>,
-) -> Result<()> {
+) -> Result<Vec<LoadedTable>> {
+    let LoadOptions {
+        output,
+        skip_if_exists,
+        primary_keys,
+        where_filters,
+        table_prefix,
+        add_primary_keys,
+        create_indexes,
+        ogr2ogr,
+        field_transforms,
+        geometry_filter,
+        collect_extents,
+        collect_row_counts,
+        analyze,
+        fill_gaps,
+        single_file_lock,
+        ..
+    } = options;
+    let skip_if_exists = *skip_if_exists;
     let tmp = context::tmp();
     let vrt_tmp = tmp.join("vrt");
     tokio::fs::create_dir_all(&vrt_tmp)
@@ -26,6 +142,7 @@ async fn load(
         .with_context(|| format!("when creating tempdir for vrt: {}", &vrt_tmp.display()))?;
 
     let identifier = &dataset.initial_item.identifier;
+    let mut loaded_tables = Vec::new();
 
     // first, let's get the entries for this dataset from the API metadata
     let mappings = mapping::mapping_defs_for_dataset(dataset)
@@ -37,41 +154,99 @@ async fn load(
             )
         })?;
 
-    for mapping in mappings {
-        // overwrite the identifier with the one from the mapping file
-        let identifier = mapping.identifier.clone().to_lowercase();
+    for mut mapping in mappings {
+        // overwrite the identifier with the table name of the mapping
+        let identifier = super::table_name(table_prefix, &mapping.identifier);
+        if let Some(primary_key) = primary_keys
+            .get(&mapping.identifier)
+            .or_else(|| primary_keys.get(&mapping.original_identifier))
+        {
+            mapping.primary_key = Some(primary_key.clone());
+        }
+        if let Some(condition) = where_filters
+            .get(&mapping.identifier)
+            .or_else(|| where_filters.get(&mapping.original_identifier))
+        {
+            mapping.where_clause = Some(condition.clone());
+        }
+        for (field_name, transforms) in field_transforms {
+            mapping.set_field_transforms(field_name, transforms.clone());
+        }
+        mapping.encoding = options
+            .forced_encodings
+            .for_mapping(&mapping)
+            .map(str::to_string);
+        mapping.keep_all_fields = options.keep_all_fields;
         // println!(
         //     "Loading dataset: {} - {} - {} as {}",
         //     mapping.cat1, mapping.cat2, mapping.name, mapping.identifier
         // );
 
-        let mut shapefiles: Vec<PathBuf> = Vec::new();
-        for zip_file_path in &dataset.zip_file_paths {
-            let shapefiles_in_zip =
-                zip_traversal::matching_shapefiles_in_zip(tmp, zip_file_path, &mapping)
-                    .await
-                    .with_context(|| {
-                        format!(
-                            "when looking for matching shapefiles in zip: {}",
-                            &zip_file_path.display()
-                        )
-                    })?;
-            shapefiles.extend(shapefiles_in_zip);
-        }
+        let (shapefiles, extra_files) =
+            matching_shapefiles(&dataset.zip_file_paths, &mapping, options)
+                .await
+                .context(StageContext::new(
+                    Stage::Extract,
+                    &identifier,
+                    "when extracting shapefiles",
+                ))?;
 
         println!("Found {} shapefiles.", shapefiles.len());
+        if shapefiles.is_empty() && mapping.subcategory.is_some() {
+            // not every ZIP has every subcategory's directory
+            println!(
+                "{} のサブディレクトリが見つからないため、スキップします",
+                mapping.identifier
+            );
+            continue;
+        }
+        if let (Some(filter), Some(shape)) = (geometry_filter, shapefiles.first()) {
+            let (matches, geometry_type) = gdal::matches_geometry_filter(shape, *filter)
+                .await
+                .with_context(|| {
+                    StageContext::new(
+                        Stage::Extract,
+                        &identifier,
+                        format!("when reading geometry type of {}", shape.display()),
+                    )
+                })?;
+            if !matches {
+                println!(
+                    "{} のジオメトリ ({}) が --geometry-filter に一致しないため、スキップします",
+                    mapping.identifier, geometry_type
+                );
+                logging::event(format!(
+                    "load skipped (geometry {}): {}",
+                    geometry_type, mapping.identifier
+                ));
+                continue;
+            }
+        }
+        if !extra_files.is_empty() {
+            retain_extra_files(&identifier, &extra_files, output)
+                .await
+                .context(StageContext::new(
+                    Stage::Extract,
+                    &identifier,
+                    "when retaining extra files",
+                ))?;
+        }
 
         let output_path = output.output_path(&identifier);
         let already_exists = if skip_if_exists {
             match output {
-                OutputTarget::Postgres { postgres_url } => {
-                    gdal::has_layer(postgres_url, &mapping.identifier)
+                OutputTarget::Postgres {
+                    postgres_url,
+                    schema,
+                } => gdal::has_layer(postgres_url, schema.as_deref(), &identifier)
+                    .await
+                    .with_context(|| format!("when asking gdal for layer"))?,
+                OutputTarget::File { .. } => match (output_path.as_ref(), output.single_file()) {
+                    (Some(path), Some(_)) => gdal::has_file_layer(path, &identifier)
                         .await
-                        .with_context(|| format!("when asking gdal for layer"))?
-                }
-                OutputTarget::File { .. } => match output_path.as_ref() {
-                    Some(path) => path.exists(),
-                    None => false,
+                        .context("when asking gdal for layer")?,
+                    (Some(path), None) => path.exists(),
+                    (None, _) => false,
                 },
             }
         } else {
@@ -79,14 +254,18 @@ async fn load(
         };
 
         let needs_load = !(skip_if_exists && already_exists);
-        let needs_vrt = needs_load
-            || (matches!(output, OutputTarget::File { .. }) && !shapefiles.is_empty());
+        let needs_vrt =
+            needs_load || (matches!(output, OutputTarget::File { .. }) && !shapefiles.is_empty());
         let mut vrt_path = None;
         if needs_vrt {
             let path = vrt_tmp.join(&identifier).with_extension("vrt");
             gdal::create_vrt(&path, &shapefiles, &mapping)
                 .await
-                .context("when creating VRT")?;
+                .context(StageContext::new(
+                    Stage::Vrt,
+                    &identifier,
+                    "when creating VRT",
+                ))?;
             vrt_path = Some(path);
         }
 
@@ -108,10 +287,24 @@ async fn load(
                 .as_ref()
                 .ok_or_else(|| anyhow::anyhow!("missing VRT path for {}", identifier))?;
             match output {
-                OutputTarget::Postgres { postgres_url } => {
-                    gdal::load_to_postgres(&vrt_path, postgres_url)
-                        .await
-                        .context("when loading to Postgres")?;
+                OutputTarget::Postgres {
+                    postgres_url,
+                    schema,
+                } => {
+                    let _db_write = db_write_permit(options).await?;
+                    gdal::load_to_postgres(
+                        &vrt_path,
+                        postgres_url,
+                        schema.as_deref(),
+                        mapping.geometry_filter,
+                        ogr2ogr,
+                    )
+                    .await
+                    .context(StageContext::new(
+                        Stage::Ogr2ogr,
+                        &identifier,
+                        "when loading to Postgres",
+                    ))?;
                 }
                 OutputTarget::File { .. } => {
                     let output_path = output_path
@@ -120,41 +313,256 @@ async fn load(
                     let driver = output
                         .gdal_driver()
                         .ok_or_else(|| anyhow::anyhow!("missing GDAL driver"))?;
-                    gdal::load_to_file(&vrt_path, &output_path, driver)
-                        .await
-                        .with_context(|| {
+                    let _single_file_guard = match output.single_file() {
+                        Some(_) => Some(single_file_lock.lock().await),
+                        None => None,
+                    };
+                    gdal::load_to_file(
+                        &vrt_path,
+                        &output_path,
+                        driver,
+                        output.compression(),
+                        output.layer_name(&identifier),
+                        mapping.geometry_filter,
+                        ogr2ogr,
+                    )
+                    .await
+                    .with_context(|| {
+                        StageContext::new(
+                            Stage::Ogr2ogr,
+                            &identifier,
                             format!(
                                 "when gdal loading VRT {} to {}",
                                 &vrt_path.display(),
                                 &output_path.display()
-                            )
-                        })?;
+                            ),
+                        )
+                    })?;
+                }
+            }
+        }
+
+        // before the primary key: the filled rows must be unique too
+        let mut filled_areas = Vec::new();
+        if let (true, true, Some(metadata_conn), OutputTarget::Postgres { postgres_url, .. }) =
+            (*fill_gaps, needs_load, metadata_conn, output)
+        {
+            filled_areas = fill_gaps_from_fallbacks(
+                dataset,
+                &mapping,
+                &identifier,
+                postgres_url,
+                metadata_conn,
+                options,
+            )
+            .await
+            .context(StageContext::new(
+                Stage::Ogr2ogr,
+                &identifier,
+                "when filling gaps from older versions",
+            ))?;
+        }
+
+        // before the primary key, which the duplicates would fail
+        let mut removed_duplicates = None;
+        if let (true, true, Some(metadata_conn)) = (options.dedupe, needs_load, metadata_conn) {
+            removed_duplicates = remove_duplicates(&identifier, &mapping, metadata_conn, options)
+                .await
+                .context(StageContext::new(
+                    Stage::Metadata,
+                    &identifier,
+                    "when removing duplicate features",
+                ))?;
+        }
+
+        // before the spatial index, which the UPDATE would have to maintain
+        let mut geometry_check = None;
+        if let (true, Some(metadata_conn)) = (needs_load, metadata_conn) {
+            geometry_check = check_geometries(&identifier, metadata_conn, options)
+                .await
+                .context(StageContext::new(
+                    Stage::Metadata,
+                    &identifier,
+                    "when validating geometries",
+                ))?;
+        }
+
+        if let (true, true, Some(metadata_conn)) = (*create_indexes, needs_load, metadata_conn) {
+            metadata_conn
+                .create_spatial_index(&identifier, &ogr2ogr.geometry_column)
+                .await
+                .context(StageContext::new(
+                    Stage::Metadata,
+                    &identifier,
+                    "when creating the spatial index",
+                ))?;
+        }
+
+        if let (Some(metadata_conn), true) = (metadata_conn, *add_primary_keys) {
+            if let Some(primary_key) = mapping.primary_key.clone() {
+                // a failed constraint only falls back to ogc_fid, without aborting the dataset's transaction
+                let savepoint = format!("{}_primary_key", identifier);
+                metadata_conn.savepoint(&savepoint).await?;
+                let added = match metadata_conn
+                    .add_unique_key(&identifier, &primary_key)
+                    .await
+                {
+                    Ok(added) => {
+                        metadata_conn.release_savepoint(&savepoint).await?;
+                        added
+                    }
+                    Err(e) => {
+                        metadata_conn.rollback_to_savepoint(&savepoint).await?;
+                        eprintln!(
+                            "{} の {} に一意制約を追加できませんでした: {:#}",
+                            identifier, primary_key, e
+                        );
+                        false
+                    }
+                };
+                if !added {
+                    println!(
+                        "{} の {} に重複または NULL があるため、主キーとして採用しません",
+                        identifier, primary_key
+                    );
+                    mapping.primary_key = None;
+                }
+            }
+        }
+
+        let feature_count = match table_row_count(
+            &identifier,
+            output_path.as_deref(),
+            options,
+            metadata_conn,
+        )
+        .await
+        {
+            Ok(count) => count,
+            Err(e) => {
+                eprintln!("{} の行数を取得できませんでした: {:#}", identifier, e);
+                None
+            }
+        };
+        let bbox =
+            match table_extent(&identifier, output_path.as_deref(), options, metadata_conn).await {
+                Ok(bbox) => bbox,
+                Err(e) => {
+                    eprintln!("{} の範囲を取得できませんでした: {:#}", identifier, e);
+                    None
+                }
+            };
+        if needs_load && feature_count == Some(0) {
+            eprintln!(
+                "警告: {} に取り込まれたフィーチャが0件です。シェープファイルの一致やエンコーディングを確認してください",
+                identifier
+            );
+            logging::event(format!("no features loaded: {}", identifier));
+        }
+        if needs_load {
+            // a shapefile of another kind matched, e.g. from another subdirectory
+            match table_geometry_types(&identifier, output_path.as_deref(), options, metadata_conn)
+                .await
+            {
+                Ok(geometry_types) => {
+                    let unexpected =
+                        gdal::unexpected_geometry_types(mapping.geometry_filter, &geometry_types);
+                    if !unexpected.is_empty() {
+                        let expected = match mapping.geometry_filter {
+                            Some(expected) => format!("{:?}", expected),
+                            None => "単一の種別".to_string(),
+                        };
+                        eprintln!(
+                            "警告: {} のジオメトリ ({}) が期待する種別 ({}) と一致しません。別のシェープファイルに一致していないか確認してください",
+                            identifier,
+                            unexpected.join(", "),
+                            expected
+                        );
+                        logging::event(format!(
+                            "unexpected geometry types: {} {}",
+                            identifier,
+                            unexpected.join(",")
+                        ));
+                    }
+                }
+                Err(e) => {
+                    eprintln!(
+                        "{} のジオメトリの種別を取得できませんでした: {:#}",
+                        identifier, e
+                    );
                 }
             }
         }
 
         if let Some(metadata_conn) = metadata_conn {
-            let metadata = metadata_conn
+            let mut metadata = metadata_conn
                 .build_metadata_from_dataset(&identifier, &mapping, dataset)
                 .await
-                .context("when building metadata from dataset")?;
+                .context(StageContext::new(
+                    Stage::Metadata,
+                    &identifier,
+                    "when building metadata from dataset",
+                ))?;
+            metadata::describe_fill_gaps(&mut metadata, &filled_areas);
             // println!("Metadata: {:?}", metadata);
             metadata_conn
                 .create_dataset(&identifier, &metadata)
                 .await
-                .context("when creating dataset metadata")?;
+                .context(StageContext::new(
+                    Stage::Metadata,
+                    &identifier,
+                    "when creating dataset metadata",
+                ))?;
+            metadata_conn
+                .comment_columns(&identifier, &metadata)
+                .await
+                .context(StageContext::new(
+                    Stage::Metadata,
+                    &identifier,
+                    "when commenting on columns",
+                ))?;
+            if let Some(count) = feature_count {
+                metadata_conn
+                    .record_feature_count(&identifier, count, bbox)
+                    .await
+                    .context(StageContext::new(
+                        Stage::Metadata,
+                        &identifier,
+                        "when recording the feature count",
+                    ))?;
+            }
+        } else if !needs_load
+            && keeps_earlier_metadata(
+                &identifier,
+                output_path.as_deref(),
+                vrt_path.is_some(),
+                output,
+            )
+        {
+            // the metadata written with the output is left as it is
         } else if let OutputTarget::File { .. } = output {
             let schema_source = if let Some(vrt_path) = vrt_path.as_ref() {
-                vrt_path.as_path()
+                vrt_path.clone()
             } else {
-                output_path
+                let output_path = output_path
                     .as_ref()
-                    .ok_or_else(|| anyhow::anyhow!("missing output path for {}", identifier))?
-                    .as_path()
+                    .ok_or_else(|| anyhow::anyhow!("missing output path for {}", identifier))?;
+                compression::gdal_readable_path(output_path, output.compression())
+                    .ok_or_else(|| anyhow::anyhow!("GDAL cannot read {}", output_path.display()))?
             };
-            let schema = gdal::layer_schema(schema_source).await.with_context(|| {
-                format!("when reading schema from {}", schema_source.display())
-            })?;
+            let layer = match vrt_path {
+                Some(_) => None,
+                None => output.layer_name(&identifier),
+            };
+            let schema = gdal::layer_schema_of(&schema_source, layer)
+                .await
+                .with_context(|| {
+                    StageContext::new(
+                        Stage::Metadata,
+                        &identifier,
+                        format!("when reading schema from {}", schema_source.display()),
+                    )
+                })?;
 
             let mut columns = Vec::with_capacity(schema.fields.len() + 2);
             columns.push(ColumnSchema {
@@ -168,29 +576,516 @@ async fn load(
                 });
             }
             if let Some(geom_type) = schema.geometry_type {
-                let geom_type = gdal::promote_geometry_type(&geom_type);
-                let srid = schema.geometry_srid.unwrap_or(-1);
+                let geom_type = gdal::output_geometry_type(&geom_type, mapping.geometry_filter);
+                // the schema is read from the VRT, before any reprojection
+                let srid = ogr2ogr.output_srid().or(schema.geometry_srid).unwrap_or(-1);
                 columns.push(ColumnSchema {
-                    name: "geom".to_string(),
+                    name: ogr2ogr.geometry_column.clone(),
                     data_type: format!("geometry({}, {})", geom_type, srid),
                 });
             }
 
             let metadata = metadata::build_metadata_from_columns(&mapping, dataset, columns);
-            let output_path = output_path
-                .as_ref()
+            let metadata_path = output
+                .metadata_path(&identifier)
                 .ok_or_else(|| anyhow::anyhow!("missing output path for {}", identifier))?;
-            let metadata_path = output_path.with_extension("metadata.json");
-            let json = serde_json::to_string_pretty(&metadata)?;
-            tokio::fs::write(&metadata_path, json).await?;
+            let json = serde_json::to_string_pretty(&metadata::FileTableMetadata {
+                metadata: &metadata,
+                feature_count,
+                bbox,
+            })?;
+            tokio::fs::write(&metadata_path, json)
+                .await
+                .with_context(|| {
+                    StageContext::new(
+                        Stage::Metadata,
+                        &identifier,
+                        format!("when writing metadata to {}", metadata_path.display()),
+                    )
+                })?;
+        }
+
+        // with -skipfailures, the features that didn't make it are the difference to the VRT
+        let count_skipped = ogr2ogr.skip_failures && needs_load;
+        let (invalid_geometries, fixed_geometries) = match geometry_check {
+            Some((invalid, fixed)) => (Some(invalid), fixed),
+            None => (None, None),
+        };
+        if *collect_extents
+            || *collect_row_counts
+            || count_skipped
+            || *analyze
+            || invalid_geometries.is_some_and(|invalid| invalid > 0)
+            || removed_duplicates.is_some_and(|removed| removed > 0)
+        {
+            let skipped_features = match (count_skipped, vrt_path.as_ref(), feature_count) {
+                (true, Some(vrt_path), Some(row_count)) => {
+                    match gdal::layer_schema(vrt_path).await {
+                        Ok(schema) => schema
+                            .feature_count
+                            .map(|source_count| source_count.saturating_sub(row_count)),
+                        Err(e) => {
+                            eprintln!(
+                                "{} のフィーチャ数を取得できませんでした: {:#}",
+                                identifier, e
+                            );
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
+            if let Some(skipped) = skipped_features.filter(|skipped| *skipped > 0) {
+                eprintln!(
+                    "{}: 不正なジオメトリなどにより {} 件のフィーチャをスキップしました",
+                    identifier, skipped
+                );
+                logging::event(format!("skipped features: {} {}", identifier, skipped));
+            }
+            loaded_tables.push(LoadedTable {
+                dataset_identifier: dataset.initial_item.identifier.clone(),
+                identifier: identifier.clone(),
+                name: mapping.name.clone(),
+                output_path: output_path.clone(),
+                bbox: bbox.filter(|_| *collect_extents),
+                row_count: feature_count.filter(|_| *collect_row_counts),
+                skipped_features,
+                invalid_geometries,
+                fixed_geometries,
+                removed_duplicates,
+            });
+        }
+    }
+
+    if let Some(path) = output.dataset_metadata_path(&dataset.initial_item.identifier) {
+        let json = serde_json::to_string_pretty(&metadata::DatasetMetadata::from(dataset))?;
+        tokio::fs::write(&path, json)
+            .await
+            .with_context(|| format!("when writing dataset metadata to {}", path.display()))?;
+    }
+    Ok(loaded_tables)
+}
+
+/// The shapefiles (and extra files) of the mapping in the given ZIPs.
+async fn matching_shapefiles(
+    zip_file_paths: &[PathBuf],
+    mapping: &mapping::ShapefileMetadata,
+    options: &LoadOptions,
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut shapefiles: Vec<PathBuf> = Vec::new();
+    let mut extra_files: Vec<PathBuf> = Vec::new();
+    for zip_file_path in zip_file_paths {
+        let contents = zip_traversal::matching_shapefiles_in_zip(
+            &context::tmp(),
+            zip_file_path,
+            mapping,
+            &options.extra_extensions,
+            options.prefer_utf8_dirs,
+            options.extract_limits,
+            &options.skip_rules,
+        )
+        .await
+        .with_context(|| {
+            format!(
+                "when looking for matching shapefiles in zip: {}",
+                &zip_file_path.display()
+            )
+        })?;
+        logging::detail(format!(
+            "{}: {} → {} shapefiles",
+            mapping.identifier,
+            zip_file_path.display(),
+            contents.shapefiles.len()
+        ));
+        for shape in &contents.shapefiles {
+            logging::detail(format!("  - {}", shape.display()));
+        }
+        shapefiles.extend(contents.shapefiles);
+        extra_files.extend(contents.extra_files);
+    }
+    Ok((shapefiles, extra_files))
+}
+
+/// Waits for a turn to write to PostgreSQL, see [`LoadOptions::db_writes`].
+async fn db_write_permit(
+    options: &LoadOptions,
+) -> Result<Option<tokio::sync::OwnedSemaphorePermit>> {
+    match &options.db_writes {
+        Some(db_writes) => Ok(Some(db_writes.clone().acquire_owned().await?)),
+        None => Ok(None),
+    }
+}
+
+/// `--fill-gaps`: loads each older version (newest first) into a staging table, and
+/// copies over the rows of the areas (行政区域コード) the table doesn't have yet.
+/// Returns the filled areas by year.
+async fn fill_gaps_from_fallbacks(
+    dataset: &Dataset,
+    mapping: &mapping::ShapefileMetadata,
+    identifier: &str,
+    postgres_url: &str,
+    metadata_conn: &MetadataConnection,
+    options: &LoadOptions,
+) -> Result<Vec<(u32, Vec<String>)>> {
+    let fallbacks = &dataset.fallback_zip_file_paths;
+    if fallbacks.is_empty() {
+        return Ok(vec![]);
+    }
+    let Some(area_column) = mapping.area_code_column(&dataset.page.metadata) else {
+        println!(
+            "{} に行政区域コードのカラムがないため、古い版からの補完をスキップします",
+            mapping.identifier
+        );
+        return Ok(vec![]);
+    };
+
+    let mut years: Vec<u32> = fallbacks.iter().map(|(year, _)| *year).collect();
+    years.sort_unstable_by(|a, b| b.cmp(a));
+    years.dedup();
+
+    let mut filled = Vec::new();
+    for year in years {
+        let zip_file_paths: Vec<PathBuf> = fallbacks
+            .iter()
+            .filter(|(y, _)| *y == year)
+            .map(|(_, path)| path.clone())
+            .collect();
+        let (shapefiles, _) = matching_shapefiles(&zip_file_paths, mapping, options).await?;
+        if shapefiles.is_empty() {
+            continue;
+        }
+        // the VRT's name is the name of the table ogr2ogr creates
+        let staging_table = format!("{}_fill_{}", identifier, year);
+        let vrt_path = context::tmp()
+            .join("vrt")
+            .join(&staging_table)
+            .with_extension("vrt");
+        gdal::create_vrt(&vrt_path, &shapefiles, mapping)
+            .await
+            .context("when creating VRT")?;
+        let _db_write = db_write_permit(options).await?;
+        gdal::load_to_postgres(
+            &vrt_path,
+            postgres_url,
+            options.output.postgres_schema(),
+            mapping.geometry_filter,
+            &options.ogr2ogr,
+        )
+        .await
+        .with_context(|| format!("when loading {} to Postgres", staging_table))?;
+        let areas = metadata_conn
+            .fill_gaps(identifier, &staging_table, &area_column, year)
+            .await?;
+        if !areas.is_empty() {
+            println!(
+                "{}: {} 件の地域を {}年版から補完しました",
+                identifier,
+                areas.len(),
+                year
+            );
+            logging::event(format!(
+                "filled gaps: {} {} {}",
+                identifier,
+                year,
+                areas.join(",")
+            ));
+        }
+        filled.push((year, areas));
+    }
+    Ok(filled)
+}
+
+/// Whether the metadata of a skipped output GDAL can't read (zstd) is left as it is: the
+/// metadata written with the output still describes it, with its feature count and extent.
+fn keeps_earlier_metadata(
+    identifier: &str,
+    output_path: Option<&Path>,
+    has_vrt: bool,
+    output: &OutputTarget,
+) -> bool {
+    let (Some(output_path), Some(metadata_path)) = (output_path, output.metadata_path(identifier))
+    else {
+        return false;
+    };
+    if compression::gdal_readable_path(output_path, output.compression()).is_some() {
+        return false;
+    }
+    if metadata_path.exists() {
+        return true;
+    }
+    if has_vrt {
+        // written from the VRT instead, without the feature count and extent
+        return false;
+    }
+    eprintln!(
+        "警告: {} は GDAL で読めず、{} もないため、メタデータを書き出せません",
+        output_path.display(),
+        metadata_path.display()
+    );
+    true
+}
+
+/// The WGS84 extent of a loaded table, from PostGIS or the output file.
+async fn table_extent(
+    identifier: &str,
+    output_path: Option<&Path>,
+    options: &LoadOptions,
+    metadata_conn: Option<&MetadataConnection>,
+) -> Result<Option<[f64; 4]>> {
+    if let Some(metadata_conn) = metadata_conn {
+        return metadata_conn
+            .table_extent(identifier, &options.ogr2ogr.geometry_column)
+            .await;
+    }
+    let Some(output_path) = output_path else {
+        return Ok(None);
+    };
+    // not read back from zstd outputs
+    let Some(path) = compression::gdal_readable_path(output_path, options.output.compression())
+    else {
+        return Ok(None);
+    };
+    let layer = options.output.layer_name(identifier);
+    let schema = gdal::layer_schema_of(&path, layer).await?;
+    match (schema.extent, schema.geometry_srid) {
+        (Some(extent), Some(srid)) if srid != 4326 => {
+            gdal::transform_extent(extent, srid).await.map(Some)
+        }
+        (extent, _) => Ok(extent),
+    }
+}
+
+/// `--dedupe`: removes the features loaded more than once (e.g. by `--fill-gaps`, or a
+/// nationwide file next to the prefectures'), keyed by the primary key and the geometry.
+/// Returns the number of removed features, or `None` if they couldn't be removed.
+async fn remove_duplicates(
+    identifier: &str,
+    mapping: &mapping::ShapefileMetadata,
+    metadata_conn: &MetadataConnection,
+    options: &LoadOptions,
+) -> Result<Option<u64>> {
+    // a missing key column only leaves the duplicates, without aborting the dataset's transaction
+    let savepoint = format!("{}_dedupe", identifier);
+    metadata_conn.savepoint(&savepoint).await?;
+    match metadata_conn
+        .remove_duplicates(
+            identifier,
+            &options.ogr2ogr.geometry_column,
+            mapping.primary_key.as_deref(),
+        )
+        .await
+    {
+        Ok(removed) => {
+            metadata_conn.release_savepoint(&savepoint).await?;
+            if removed > 0 {
+                println!(
+                    "{}: 重複する {} 件のフィーチャを削除しました",
+                    identifier, removed
+                );
+                logging::event(format!("removed duplicates: {} {}", identifier, removed));
+            }
+            Ok(Some(removed))
+        }
+        Err(e) => {
+            metadata_conn.rollback_to_savepoint(&savepoint).await?;
+            eprintln!("{} の重複を削除できませんでした: {:#}", identifier, e);
+            Ok(None)
+        }
+    }
+}
+
+/// Counts the features that `ST_IsValid` rejects, and with `--fix-geometries` repairs them.
+/// Returns the number of invalid features and, if fixed, the number of fixed features.
+/// A failed repair (e.g. a GEOS error) leaves the table as loaded, without aborting the dataset.
+async fn check_geometries(
+    identifier: &str,
+    metadata_conn: &MetadataConnection,
+    options: &LoadOptions,
+) -> Result<Option<(u64, Option<u64>)>> {
+    let geometry_column = &options.ogr2ogr.geometry_column;
+    let invalid = metadata_conn
+        .invalid_geometry_count(identifier, geometry_column)
+        .await?;
+    if invalid == 0 {
+        return Ok(Some((0, None)));
+    }
+    if !options.fix_geometries {
+        eprintln!(
+            "警告: {} に不正なジオメトリが {} 件あります（--fix-geometries で修復できます）",
+            identifier, invalid
+        );
+        logging::event(format!("invalid geometries: {} {}", identifier, invalid));
+        return Ok(Some((invalid, None)));
+    }
+
+    let savepoint = format!("{}_fix_geometries", identifier);
+    metadata_conn.savepoint(&savepoint).await?;
+    match metadata_conn
+        .fix_geometries(identifier, geometry_column)
+        .await
+    {
+        Ok((fixed, emptied)) => {
+            metadata_conn.release_savepoint(&savepoint).await?;
+            println!(
+                "{}: 不正なジオメトリ {} 件を ST_MakeValid で修復しました",
+                identifier, fixed
+            );
+            if emptied > 0 {
+                eprintln!(
+                    "警告: {} の {} 件は修復後に元の種別の図形が残らず、空のジオメトリになりました",
+                    identifier, emptied
+                );
+            }
+            logging::event(format!("fixed geometries: {} {}", identifier, fixed));
+            Ok(Some((invalid, Some(fixed))))
+        }
+        Err(e) => {
+            metadata_conn.rollback_to_savepoint(&savepoint).await?;
+            eprintln!(
+                "{} の不正なジオメトリを修復できませんでした: {:#}",
+                identifier, e
+            );
+            Ok(Some((invalid, None)))
+        }
+    }
+}
+
+/// The geometry types of the loaded table. A file layer has only the one type of its schema.
+async fn table_geometry_types(
+    identifier: &str,
+    output_path: Option<&Path>,
+    options: &LoadOptions,
+    metadata_conn: Option<&MetadataConnection>,
+) -> Result<Vec<String>> {
+    if let Some(metadata_conn) = metadata_conn {
+        return metadata_conn
+            .geometry_types(identifier, &options.ogr2ogr.geometry_column)
+            .await;
+    }
+    let Some(output_path) = output_path else {
+        return Ok(vec![]);
+    };
+    let Some(path) = compression::gdal_readable_path(output_path, options.output.compression())
+    else {
+        return Ok(vec![]);
+    };
+    let layer = options.output.layer_name(identifier);
+    Ok(gdal::layer_schema_of(&path, layer)
+        .await?
+        .geometry_type
+        .into_iter()
+        .collect())
+}
+
+/// The number of rows of a loaded table, from PostgreSQL or the output file.
+async fn table_row_count(
+    identifier: &str,
+    output_path: Option<&Path>,
+    options: &LoadOptions,
+    metadata_conn: Option<&MetadataConnection>,
+) -> Result<Option<u64>> {
+    if let Some(metadata_conn) = metadata_conn {
+        return metadata_conn.row_count(identifier).await.map(Some);
+    }
+    let Some(output_path) = output_path else {
+        return Ok(None);
+    };
+    let Some(path) = compression::gdal_readable_path(output_path, options.output.compression())
+    else {
+        return Ok(None);
+    };
+    let layer = options.output.layer_name(identifier);
+    Ok(gdal::layer_schema_of(&path, layer).await?.feature_count)
+}
+
+/// Keeps auxiliary files (csv, txt, ...) next to the output.
+/// For file outputs they are copied to `<output_dir>/<identifier>_files/`, under their path
+/// relative to `<tmp>/shp` (the ZIP file name, then the directories inside the ZIP) so that
+/// same-named files of different ZIPs or directories are all kept.
+/// Otherwise they stay in the extraction directory.
+async fn retain_extra_files(
+    identifier: &str,
+    extra_files: &[PathBuf],
+    output: &OutputTarget,
+) -> Result<()> {
+    let Some(output_dir) = output.output_dir() else {
+        for path in extra_files {
+            println!("Extracted extra file: {}", path.display());
+        }
+        return Ok(());
+    };
+    let files_dir = output_dir.join(format!("{}_files", identifier));
+    tokio::fs::create_dir_all(&files_dir)
+        .await
+        .with_context(|| format!("when creating {}", files_dir.display()))?;
+    let shp_tmp = context::tmp().join("shp");
+    for path in extra_files {
+        let relative = path
+            .strip_prefix(&shp_tmp)
+            .with_context(|| format!("{} is not in {}", path.display(), shp_tmp.display()))?;
+        let dest = files_dir.join(relative);
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("when creating {}", parent.display()))?;
+        }
+        tokio::fs::copy(path, &dest)
+            .await
+            .with_context(|| format!("when copying {} to {}", path.display(), dest.display()))?;
+    }
+    println!(
+        "Copied {} extra files to {}",
+        extra_files.len(),
+        files_dir.display()
+    );
+    Ok(())
+}
+
+/// Frees the tmp directory of a dataset once it has been loaded: the shapefiles extracted
+/// from its ZIP files (unless `--keep-intermediate`), and, after a successful load with
+/// `--delete-zips-after-load`, the ZIP files themselves.
+async fn clean_up(dataset: &Dataset, options: &LoadOptions, loaded: bool) -> Result<()> {
+    let zip_file_paths = dataset
+        .zip_file_paths
+        .iter()
+        .chain(dataset.fallback_zip_file_paths.iter().map(|(_, path)| path));
+    // without a file output, the extra files are kept in the extraction directory
+    let extra_files_in_tmp =
+        !options.extra_extensions.is_empty() && options.output.output_dir().is_none();
+    let shp_tmp = context::tmp().join("shp");
+    for zip_file_path in zip_file_paths {
+        if !options.keep_intermediate && !extra_files_in_tmp {
+            let dir = zip_traversal::extraction_dir(&shp_tmp, zip_file_path);
+            remove_if_exists(&dir).await?;
+        }
+        if loaded && options.delete_zips_after_load {
+            remove_if_exists(zip_file_path).await?;
+            let mut meta_path = zip_file_path.clone().into_os_string();
+            meta_path.push(".meta.json");
+            remove_if_exists(Path::new(&meta_path)).await?;
         }
     }
     Ok(())
 }
 
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    let result = if path.is_dir() {
+        tokio::fs::remove_dir_all(path).await
+    } else {
+        tokio::fs::remove_file(path).await
+    };
+    match result {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("when removing {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
 struct PBStatusUpdateMsg {
     added: u64,
     finished: u64,
+    failed: u64,
     msg: Option<String>,
 }
 
@@ -199,60 +1094,162 @@ pub struct LoadQueue {
     sender: Option<async_channel::Sender<Dataset>>,
 
     set: Option<task::JoinSet<()>>,
+    report: Arc<Mutex<LoadReport>>,
+    checkpoint: Option<Arc<Checkpoint>>,
+    /// the tables of the datasets pushed so far, see [`TableNames`]
+    /// (`None` when the datasets were checked before they were pushed)
+    table_names: Option<tokio::sync::Mutex<TableNames>>,
 }
 
 impl LoadQueue {
-    pub async fn new(loader: &Loader) -> Result<Self> {
+    pub async fn new(loader: &Loader, table_names: Option<TableNames>) -> Result<Self> {
         let Loader {
             output,
             skip_if_exists,
+            extra_extensions,
+            primary_keys,
+            where_filters,
+            table_prefix,
+            add_primary_keys,
+            create_indexes,
+            prefer_utf8_dirs,
+            ogr2ogr,
+            field_transforms,
+            geometry_filter,
+            forced_encodings,
+            keep_all_fields,
+            fix_geometries,
+            dedupe,
+            collect_extents,
+            collect_row_counts,
+            analyze,
+            fill_gaps,
+            keep_intermediate,
+            delete_zips_after_load,
+            extract_limits,
+            skip_rules,
+            checkpoint,
+            restart_checkpoint,
+            load_concurrency,
+            db_write_concurrency,
             ..
         } = loader;
+        let checkpoint = match checkpoint {
+            Some(path) => Some(Arc::new(Checkpoint::open(
+                path,
+                &checkpoint::target_key(output, table_prefix),
+                *restart_checkpoint,
+            )?)),
+            None => None,
+        };
+        let options = LoadOptions {
+            output: output.clone(),
+            skip_if_exists: *skip_if_exists,
+            extra_extensions: extra_extensions.clone(),
+            primary_keys: primary_keys.clone(),
+            where_filters: where_filters.clone(),
+            table_prefix: table_prefix.clone(),
+            add_primary_keys: *add_primary_keys,
+            create_indexes: *create_indexes,
+            prefer_utf8_dirs: *prefer_utf8_dirs,
+            ogr2ogr: ogr2ogr.clone(),
+            field_transforms: field_transforms.clone(),
+            geometry_filter: *geometry_filter,
+            forced_encodings: forced_encodings.clone(),
+            keep_all_fields: *keep_all_fields,
+            fix_geometries: *fix_geometries,
+            dedupe: *dedupe,
+            collect_extents: *collect_extents,
+            collect_row_counts: *collect_row_counts,
+            analyze: analyze.is_some(),
+            fill_gaps: *fill_gaps,
+            keep_intermediate: *keep_intermediate,
+            delete_zips_after_load: *delete_zips_after_load,
+            extract_limits: *extract_limits,
+            skip_rules: skip_rules.clone(),
+            checkpoint: checkpoint.clone(),
+            single_file_lock: Arc::new(tokio::sync::Mutex::new(())),
+            db_writes: db_write_concurrency
+                .map(|permits| Arc::new(tokio::sync::Semaphore::new(permits.max(1)))),
+            table_locks: Arc::new(TableLocks::default()),
+        };
 
         if let Some(output_dir) = output.output_dir() {
             tokio::fs::create_dir_all(output_dir).await?;
         }
 
-        let metadata_conn = if let Some(postgres_url) = output.postgres_url() {
-            Some(MetadataConnection::new(postgres_url).await?)
-        } else {
-            None
-        };
+        if let Some(postgres_url) = output.postgres_url() {
+            MetadataConnection::new(postgres_url, output.postgres_schema()).await?;
+        }
 
+        let report = Arc::new(Mutex::new(LoadReport::default()));
         let (pb_status_sender, pb_status_receiver) = unbounded::<PBStatusUpdateMsg>();
         let (sender, receiver) = unbounded::<Dataset>();
         let mut set = task::JoinSet::new();
-        let size = max(num_cpus::get() - 1, 1);
+        let size = load_concurrency.unwrap_or(num_cpus::get() - 1).max(1);
         for _i in 0..size {
             let receiver = receiver.clone();
             let pb_sender = pb_status_sender.clone();
-            let output = output.clone();
-            let skip_if_exists = *skip_if_exists;
-            let metadata_conn = metadata_conn.clone();
+            let options = options.clone();
+            // each worker has a connection of its own
+            let metadata_conn = match output.postgres_url() {
+                Some(postgres_url) => {
+                    Some(MetadataConnection::connect(postgres_url, output.postgres_schema()).await?)
+                }
+                None => None,
+            };
+            let report = report.clone();
             set.spawn(async move {
                 while let Ok(item) = receiver.recv().await {
                     // println!("processor {} loading", _i);
+                    let identifier = item.initial_item.identifier.clone();
                     pb_sender
                         .send(PBStatusUpdateMsg {
                             added: 0,
                             finished: 0,
-                            msg: Some(item.initial_item.identifier.clone()),
+                            failed: 0,
+                            msg: Some(identifier.clone()),
                         })
                         .await
                         .unwrap();
-                    let result = load(&item, &output, skip_if_exists, metadata_conn.as_ref()).await;
-                    if let Err(e) = result {
-                        let identifier = item.initial_item.identifier.clone();
+                    logging::event(format!("load start: {}", identifier));
+                    let result = load_in_transaction(&item, &options, metadata_conn.as_ref()).await;
+                    let failed = if let Err(e) = &result {
                         eprintln!(
                             "Error in loading dataset {}, skipping... {:?}",
                             identifier, e
                         );
+                        logging::event(format!("load error: {}: {:#}", identifier, e));
+                        report.lock().unwrap().failed.push(Failure::new(
+                            &identifier,
+                            Stage::Load,
+                            e,
+                        ));
+                        1
+                    } else {
+                        logging::event(format!("load end: {}", identifier));
+                        if let Some(checkpoint) = &options.checkpoint {
+                            if let Err(e) = checkpoint.record(&item) {
+                                eprintln!("チェックポイントを記録できませんでした: {:#}", e);
+                            }
+                        }
+                        let mut report = report.lock().unwrap();
+                        report.succeeded.push(identifier.clone());
+                        report.tables.extend(result.unwrap_or_default());
+                        0
+                    };
+                    if let Err(e) = clean_up(&item, &options, failed == 0).await {
+                        eprintln!(
+                            "{} の中間ファイルを削除できませんでした: {:#}",
+                            identifier, e
+                        );
                     }
                     pb_sender
                         .send(PBStatusUpdateMsg {
                             added: 0,
                             finished: 1,
-                            msg: Some(item.initial_item.identifier.clone()),
+                            failed,
+                            msg: Some(identifier),
                         })
                         .await
                         .unwrap();
@@ -261,7 +1258,7 @@ impl LoadQueue {
         }
 
         set.spawn(async move {
-            let pb = ProgressBar::new(0);
+            let pb = logging::progress_bar();
             pb.set_style(
                 ProgressStyle::with_template(
                     "{spinner:.green} [{msg}] [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len}",
@@ -272,9 +1269,11 @@ impl LoadQueue {
             pb.enable_steady_tick(Duration::from_millis(300));
             let mut length = 0;
             let mut position = 0;
+            let mut failed = 0;
             while let Ok(msg) = pb_status_receiver.recv().await {
                 length += msg.added;
                 position += msg.finished;
+                failed += msg.failed;
                 pb.set_length(length);
                 pb.set_position(position);
                 if let Some(msg) = msg.msg {
@@ -283,12 +1282,19 @@ impl LoadQueue {
             }
             pb.finish();
             println!("取り込みが終了しました。");
+            logging::event(format!(
+                "load summary: {} datasets, {} failed",
+                position, failed
+            ));
         });
 
         Ok(Self {
             pb_status_sender: Some(pb_status_sender),
             sender: Some(sender),
             set: Some(set),
+            report,
+            checkpoint,
+            table_names: table_names.map(tokio::sync::Mutex::new),
         })
     }
 
@@ -299,10 +1305,46 @@ impl LoadQueue {
         let Some(pb_status_sender) = &self.pb_status_sender else {
             return Err(anyhow::anyhow!("LoadQueue is already closed"));
         };
+        if let Some(checkpoint) = &self.checkpoint {
+            if checkpoint.is_completed(item) {
+                let identifier = &item.initial_item.identifier;
+                println!(
+                    "{} は前回の実行で取り込み済みのため、スキップします（--resume）",
+                    identifier
+                );
+                logging::event(format!("load skipped (checkpoint): {}", identifier));
+                self.report
+                    .lock()
+                    .unwrap()
+                    .succeeded
+                    .push(identifier.clone());
+                return Ok(());
+            }
+        }
+        // before loading the dataset: it would overwrite the tables of another one
+        if let Some(table_names) = &self.table_names {
+            let problems = table_names.lock().await.claim_or_warn(item).await;
+            if !problems.is_empty() {
+                let identifier = &item.initial_item.identifier;
+                let error = table_names::dataset_error(&problems);
+                eprintln!("{}: {:#}", identifier, error);
+                logging::event(format!(
+                    "load skipped (table names): {}: {:#}",
+                    identifier, error
+                ));
+                self.report.lock().unwrap().failed.push(Failure::new(
+                    identifier,
+                    Stage::Load,
+                    &error,
+                ));
+                return Ok(());
+            }
+        }
         pb_status_sender
             .send(PBStatusUpdateMsg {
                 added: 1,
                 finished: 0,
+                failed: 0,
                 msg: None,
             })
             .await?;
@@ -310,7 +1352,7 @@ impl LoadQueue {
         Ok(())
     }
 
-    pub async fn close(&mut self) -> Result<()> {
+    pub async fn close(&mut self) -> Result<LoadReport> {
         let Some(_) = self.sender.take() else {
             return Err(anyhow::anyhow!("LoadQueue is already closed"));
         };
@@ -321,6 +1363,217 @@ impl LoadQueue {
             return Err(anyhow::anyhow!("LoadQueue is already closed"));
         };
         set.join_all().await;
-        Ok(())
+        Ok(std::mem::take(&mut *self.report.lock().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn test_options(extra_extensions: Vec<String>) -> LoadOptions {
+        LoadOptions {
+            output: OutputTarget::Postgres {
+                postgres_url: String::new(),
+                schema: None,
+            },
+            skip_if_exists: false,
+            extra_extensions,
+            primary_keys: HashMap::new(),
+            where_filters: HashMap::new(),
+            table_prefix: String::new(),
+            add_primary_keys: false,
+            create_indexes: true,
+            prefer_utf8_dirs: false,
+            ogr2ogr: gdal::Ogr2OgrOptions::default(),
+            field_transforms: HashMap::new(),
+            geometry_filter: None,
+            forced_encodings: Default::default(),
+            keep_all_fields: false,
+            fix_geometries: false,
+            dedupe: false,
+            collect_extents: false,
+            collect_row_counts: false,
+            analyze: false,
+            fill_gaps: false,
+            keep_intermediate: false,
+            delete_zips_after_load: false,
+            extract_limits: Default::default(),
+            skip_rules: SkipRules::default(),
+            checkpoint: None,
+            single_file_lock: Arc::new(tokio::sync::Mutex::new(())),
+            db_writes: None,
+            table_locks: Arc::new(TableLocks::default()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clean_up() {
+        let tmp = context::tmp();
+        let zip = tmp.join("clean_up_test-20_GML.zip");
+        let meta = tmp.join("clean_up_test-20_GML.zip.meta.json");
+        let extracted = tmp.join("shp").join("clean_up_test-20_GML");
+        let prepare = || {
+            std::fs::create_dir_all(extracted.join("sub")).unwrap();
+            std::fs::write(extracted.join("sub").join("a.shp"), "").unwrap();
+            std::fs::write(&zip, "").unwrap();
+            std::fs::write(&meta, "{}").unwrap();
+        };
+        let mut dataset = crate::test_helpers::fixture_dataset("A99", vec![]);
+        dataset.zip_file_paths = vec![zip.clone()];
+
+        // the extra files stay in the extraction directory for PostgreSQL
+        prepare();
+        let options = test_options(vec!["csv".to_string()]);
+        clean_up(&dataset, &options, true).await.unwrap();
+        assert!(extracted.exists());
+
+        let mut options = test_options(vec![]);
+        clean_up(&dataset, &options, true).await.unwrap();
+        assert!(!extracted.exists());
+        assert!(zip.exists());
+
+        prepare();
+        options.keep_intermediate = true;
+        options.delete_zips_after_load = true;
+        clean_up(&dataset, &options, false).await.unwrap();
+        assert!(extracted.exists());
+        assert!(zip.exists());
+        clean_up(&dataset, &options, true).await.unwrap();
+        assert!(extracted.exists());
+        assert!(!zip.exists());
+        assert!(!meta.exists());
+
+        std::fs::remove_dir_all(&extracted).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retain_extra_files_keeps_same_names() {
+        let shp_tmp = context::tmp().join("shp");
+        let extra_files = [
+            shp_tmp.join("retain_test-01").join("readme.txt"),
+            shp_tmp.join("retain_test-02").join("readme.txt"),
+            shp_tmp
+                .join("retain_test-02")
+                .join("sub")
+                .join("readme.txt"),
+        ];
+        for (i, path) in extra_files.iter().enumerate() {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, i.to_string()).unwrap();
+        }
+        let output_dir = context::tmp().join("test_retain_extra_files");
+        let output = OutputTarget::File {
+            output_dir: output_dir.clone(),
+            gdal_driver: "GeoJSON".to_string(),
+            file_extension: "geojson".to_string(),
+            compression: None,
+            single_file: None,
+        };
+
+        retain_extra_files("A99", &extra_files, &output)
+            .await
+            .unwrap();
+        let files_dir = output_dir.join("A99_files");
+        for (i, relative) in [
+            "retain_test-01/readme.txt",
+            "retain_test-02/readme.txt",
+            "retain_test-02/sub/readme.txt",
+        ]
+        .iter()
+        .enumerate()
+        {
+            let copied = std::fs::read_to_string(files_dir.join(relative)).unwrap();
+            assert_eq!(copied, i.to_string(), "{}", relative);
+        }
+
+        std::fs::remove_dir_all(&output_dir).unwrap();
+        std::fs::remove_dir_all(shp_tmp.join("retain_test-01")).unwrap();
+        std::fs::remove_dir_all(shp_tmp.join("retain_test-02")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_zstd_output_is_not_read_back() {
+        let output_dir = context::tmp().join("test_zstd_output");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let output = |compression| OutputTarget::File {
+            output_dir: output_dir.clone(),
+            gdal_driver: "GeoJSON".to_string(),
+            file_extension: "geojson".to_string(),
+            compression: Some(compression),
+            single_file: None,
+        };
+        let mut options = test_options(vec![]);
+        options.output = output(compression::Compression::Zstd);
+        let output_path = options.output.output_path("A99").unwrap();
+        let metadata_path = options.output.metadata_path("A99").unwrap();
+        let _ = std::fs::remove_file(&metadata_path);
+
+        // the probes skip it instead of failing
+        let path = Some(output_path.as_path());
+        assert_eq!(
+            table_row_count("A99", path, &options, None).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            table_extent("A99", path, &options, None).await.unwrap(),
+            None
+        );
+        assert!(table_geometry_types("A99", path, &options, None)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // without earlier metadata, it is written from the VRT if there is one
+        assert!(!keeps_earlier_metadata("A99", path, true, &options.output));
+        assert!(keeps_earlier_metadata("A99", path, false, &options.output));
+        std::fs::write(&metadata_path, "{}").unwrap();
+        assert!(keeps_earlier_metadata("A99", path, true, &options.output));
+
+        // gzip is read through /vsigzip/
+        options.output = output(compression::Compression::Gzip);
+        assert!(!keeps_earlier_metadata("A99", path, true, &options.output));
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_table_locks_serialize_same_table() {
+        let locks = Arc::new(TableLocks::default());
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let mut set = task::JoinSet::new();
+        for i in 0..4 {
+            let locks = locks.clone();
+            let active = active.clone();
+            let peak = peak.clone();
+            // every worker writes a27, in a different order than the others
+            let tables = if i % 2 == 0 {
+                vec!["a27".to_string(), format!("t{}", i)]
+            } else {
+                vec![format!("t{}", i), "a27".to_string(), "a27".to_string()]
+            };
+            set.spawn(async move {
+                let _guards = locks.lock_all(&tables).await;
+                let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                active.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+        while let Some(result) = set.join_next().await {
+            result.unwrap();
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 1);
+
+        // different tables don't wait for each other
+        let _a = locks.lock_all(&["a27".to_string()]).await;
+        let _b = tokio::time::timeout(
+            Duration::from_millis(100),
+            locks.lock_all(&["p29".to_string()]),
+        )
+        .await
+        .unwrap();
     }
 }
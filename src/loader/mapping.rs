@@ -2,9 +2,15 @@ use anyhow::{Context, Result};
 use derive_builder::Builder;
 use regex::Regex;
 
-use crate::scraper::data_page::{DataPageMetadata, VariantMetadata};
+use std::collections::BTreeMap;
+
+use crate::metadata::shorten_identifier;
+use crate::scraper::data_page::{DataPageMetadata, VariantAttribute, VariantMetadata};
 use crate::scraper::Dataset;
 
+use super::gdal::GeometryFilter;
+use super::transform::{self, FieldTransform};
+
 #[derive(Builder, Clone, Debug)]
 #[builder(derive(Debug))]
 pub struct ShapefileMetadata {
@@ -34,6 +40,81 @@ pub struct ShapefileMetadata {
     pub original_identifier: String,
     /// インポート識別子
     pub identifier: String,
+
+    /// 自然キーとなるカラム名（例: 学校コード）
+    /// 指定がない場合は ogr2ogr が生成する `ogc_fid` が主キーになります
+    #[builder(default)]
+    pub primary_key: Option<String>,
+
+    /// 取り込み時にカラムの値に適用する変換（カラム名, 変換）
+    #[builder(default)]
+    pub field_transforms: Vec<(String, Vec<FieldTransform>)>,
+
+    /// サブカテゴリ（ZIP 内のサブディレクトリのプレフィックス）
+    /// 例: A31a の `20_想定最大規模/` の場合は `20`。指定がある場合、そのサブディレクトリ内のシェープファイルのみを取り込みます
+    #[builder(default)]
+    pub subcategory: Option<String>,
+
+    /// 取り込むジオメトリの種別
+    /// 指定がある場合、ZIP 内のシェープファイルのうちジオメトリが一致するものだけを取り込みます
+    #[builder(default)]
+    pub geometry_filter: Option<GeometryFilter>,
+
+    /// シェープファイルの文字コード（`--force-encoding`）。指定がない場合は自動検出します
+    #[builder(default)]
+    pub encoding: Option<String>,
+
+    /// VRT で型を明示するカラム（カラム名, OGR の型）
+    /// コードリストや文字列型の属性は、先頭のゼロが落ちないよう `String` として取り込みます
+    #[builder(default)]
+    pub field_types: Vec<(String, String)>,
+
+    /// マッピングにない属性も元のカラム名で取り込みます（`--keep-all-fields`）
+    #[builder(default)]
+    pub keep_all_fields: bool,
+
+    /// 取り込むフィーチャの条件（`--where`、元のシェープファイルの属性名で記述）
+    #[builder(default)]
+    pub where_clause: Option<String>,
+}
+
+impl ShapefileMetadata {
+    /// Overrides (or adds) the transforms of a field. An empty list disables them.
+    pub fn set_field_transforms(&mut self, field_name: &str, transforms: Vec<FieldTransform>) {
+        self.field_transforms.retain(|(name, _)| name != field_name);
+        if !transforms.is_empty() {
+            self.field_transforms
+                .push((field_name.to_string(), transforms));
+        }
+    }
+
+    pub fn transforms_for(&self, field_name: &str) -> &[FieldTransform] {
+        self.field_transforms
+            .iter()
+            .find(|(name, _)| name == field_name)
+            .map(|(_, transforms)| transforms.as_slice())
+            .unwrap_or(&[])
+    }
+
+    pub fn field_type(&self, field_name: &str) -> Option<&str> {
+        self.field_types
+            .iter()
+            .find(|(name, _)| name == field_name)
+            .map(|(_, field_type)| field_type.as_str())
+    }
+
+    /// 行政区域コードのカラム名（取り込み後の名前）。`--fill-gaps` で補完する地域の単位です
+    pub fn area_code_column(&self, metadata: &DataPageMetadata) -> Option<String> {
+        self.field_mappings
+            .iter()
+            .find(|(_, shape_name)| {
+                metadata.attribute.get(shape_name).is_some_and(|attr| {
+                    attr.name.contains("行政区域コード")
+                        || attr.attr_type.contains("行政区域コード")
+                })
+            })
+            .map(|(field_name, _)| shorten_identifier(field_name).into_owned())
+    }
 }
 
 fn format_name(name: &str) -> String {
@@ -106,17 +187,70 @@ impl ShapefileMetadataBuilder {
 }
 
 /// Splits and normalizes a shapefile matcher string into a vector of strings.
-fn split_shapefile_matcher(s: &str) -> Vec<String> {
-    s.replace("\r\n", "\n")
-        // A38 variants use A38a/b/c in hints, but files are named A38-YY_#.shp.
-        .replace("A38a-", "A38-")
-        .replace("A38b-", "A38-")
-        .replace("A38c-", "A38-")
+/// Hints of lettered variants (`A38a-YY_1.shp` for `A38a`) also match files named after
+/// the dataset itself (`A38-YY_1.shp`), since some datasets only use the letter in the hint.
+fn split_shapefile_matcher(s: &str, original_identifier: &str) -> Vec<String> {
+    let mut matchers = Vec::new();
+    for line in s
+        .replace("\r\n", "\n")
         .replace("A38-YY_PP_", "A38-YY_") // 医療圏のshapefile名が間違っている
         .split('\n')
-        .map(|s| s.trim().to_string())
+        .map(str::trim)
         .filter(|s| !s.is_empty())
-        .collect()
+    {
+        matchers.push(line.to_string());
+        if let Some(rest) = line.strip_prefix(original_identifier) {
+            let mut chars = rest.chars();
+            if chars.next().is_some_and(|c| c.is_ascii_lowercase())
+                && chars.as_str().starts_with('-')
+            {
+                let unlettered = format!("{}{}", original_identifier, chars.as_str())
+                    .replace("A38-YY_PP_", "A38-YY_");
+                if !matchers.contains(&unlettered) {
+                    matchers.push(unlettered);
+                }
+            }
+        }
+    }
+    matchers
+}
+
+/// 自然キーが分かっているデータセットの主キー候補
+/// 取り込み時に一意でないことが分かった場合は採用されません（`--no-index` 参照）
+fn primary_key_hint(original_identifier: &str) -> Option<&'static str> {
+    match original_identifier {
+        "A27" => Some("学校コード"),
+        "P29" => Some("学校コード"),
+        _ => None,
+    }
+}
+
+/// 同じ ZIP に種別の違うジオメトリが含まれるデータセットの、取り込むジオメトリ
+/// A33 はポイントとポリゴンのシェープファイルが同梱されていて、shapefile_hint にも一致しません
+fn geometry_filter_hint(original_identifier: &str) -> Option<GeometryFilter> {
+    match original_identifier {
+        "A33" => Some(GeometryFilter::Polygon),
+        _ => None,
+    }
+}
+
+/// API のジオメトリの説明（例: 「ポリゴン」）から取り込むジオメトリを推定します。
+/// 複数の種別が書かれている場合は絞り込みません。
+fn geometry_filter_from_description(description: &str) -> Option<GeometryFilter> {
+    let description = description.to_ascii_lowercase();
+    let kinds = [
+        (GeometryFilter::Point, ["ポイント", "point"]),
+        (GeometryFilter::Line, ["ライン", "line"]),
+        (GeometryFilter::Polygon, ["ポリゴン", "polygon"]),
+    ]
+    .into_iter()
+    .filter(|(_, words)| words.iter().any(|word| description.contains(word)))
+    .map(|(kind, _)| kind)
+    .collect::<Vec<_>>();
+    match kinds.as_slice() {
+        [kind] => Some(*kind),
+        _ => None,
+    }
 }
 
 struct MultiOutputRule {
@@ -176,6 +310,71 @@ fn apply_multi_output_rules(metadata: ShapefileMetadata) -> Vec<ShapefileMetadat
     vec![metadata]
 }
 
+/// A dataset whose ZIP files keep shapefiles with different meanings in numbered
+/// subdirectories (e.g. `10_計画規模/`, `20_想定最大規模/`), which can't be unioned into one table.
+struct SubcategoryRule {
+    original_identifier: &'static str,
+    /// (サブディレクトリのプレフィックス, 名称)
+    subcategories: &'static [(&'static str, &'static str)],
+}
+
+static SUBCATEGORY_RULES: &[SubcategoryRule] = &[SubcategoryRule {
+    original_identifier: "A31a",
+    subcategories: &[
+        ("10", "計画規模"),
+        ("20", "想定最大規模"),
+        ("30", "浸水継続時間"),
+        ("41", "家屋倒壊等氾濫想定区域_氾濫流"),
+        ("42", "家屋倒壊等氾濫想定区域_河岸侵食"),
+    ],
+}];
+
+/// Splits a mapping into one table per subcategory, e.g. `A31a` into `A31a_10`, `A31a_20`, ...
+fn apply_subcategory_rules(metadata: ShapefileMetadata) -> Vec<ShapefileMetadata> {
+    let Some(rule) = SUBCATEGORY_RULES.iter().find(|rule| {
+        rule.original_identifier
+            .eq_ignore_ascii_case(&metadata.original_identifier)
+    }) else {
+        return vec![metadata];
+    };
+
+    rule.subcategories
+        .iter()
+        .map(|(prefix, name)| {
+            let mut metadata = metadata.clone();
+            metadata.identifier = format!("{}_{}", metadata.identifier, prefix);
+            metadata.name = format!("{} {}", metadata.name, name);
+            metadata.subcategory = Some(prefix.to_string());
+            metadata
+        })
+        .collect()
+}
+
+/// The OGR type a column is declared with in the VRT, from the attribute type of the data page.
+/// Only strings are forced: codes like `01000` would lose their leading zero as numbers.
+/// Other types are left to GDAL.
+fn vrt_field_type(attr_type: &str) -> Option<&'static str> {
+    let attr_type = attr_type.to_ascii_lowercase();
+    ["コード", "文字列", "codelist", "characterstring"]
+        .iter()
+        .any(|word| attr_type.contains(word))
+        .then_some("String")
+}
+
+fn field_types(
+    field_mappings: &[(String, String)],
+    metadata: &DataPageMetadata,
+) -> Vec<(String, String)> {
+    field_mappings
+        .iter()
+        .filter_map(|(field_name, shape_name)| {
+            let attr = metadata.attribute.get(shape_name)?;
+            let field_type = vrt_field_type(&attr.attr_type)?;
+            Some((field_name.clone(), field_type.to_string()))
+        })
+        .collect()
+}
+
 fn field_mappings_from_variant(variant: &VariantMetadata) -> Vec<(String, String)> {
     variant
         .attributes
@@ -214,13 +413,72 @@ fn fallback_variant(dataset: &Dataset) -> VariantMetadata {
         variant_name: dataset.initial_item.name.clone(),
         variant_identifier: dataset.initial_item.identifier.clone(),
         shapefile_hint: None,
+        geometry_description: None,
         attributes: vec![],
     }
 }
 
+/// Returns the attribute-id prefix (e.g. `A38a` for `A38a_001`) if it belongs to the dataset.
+fn attribute_prefix<'a>(attribute_id: &'a str, original_identifier: &str) -> Option<&'a str> {
+    let (prefix, _) = attribute_id.trim().rsplit_once('_')?;
+    let matches_dataset = prefix.len() >= original_identifier.len()
+        && prefix
+            .get(..original_identifier.len())
+            .is_some_and(|p| p.eq_ignore_ascii_case(original_identifier));
+    matches_dataset.then_some(prefix)
+}
+
+/// 属性IDのプレフィックス（例: A38a_001, A38b_001）が複数ある場合、プレフィックスごとにバリアントを推定します。
+/// API がバリアントを返さないデータセットでも、ジオメトリ種別ごとにテーブルを分割できるようにするためです。
+fn variants_from_attribute_prefixes(dataset: &Dataset) -> Vec<VariantMetadata> {
+    let original_identifier = dataset.initial_item.identifier.as_str();
+    let mut by_prefix: BTreeMap<String, Vec<VariantAttribute>> = BTreeMap::new();
+    for (attribute_id, attr) in dataset.page.metadata.attribute.iter() {
+        let Some(prefix) = attribute_prefix(attribute_id, original_identifier) else {
+            continue;
+        };
+        by_prefix
+            .entry(prefix.to_string())
+            .or_default()
+            .push(VariantAttribute {
+                readable_name: attr.name.clone(),
+                attribute_name: attribute_id.clone(),
+            });
+    }
+    if by_prefix.len() < 2 {
+        return vec![];
+    }
+    by_prefix
+        .into_iter()
+        .map(|(prefix, mut attributes)| {
+            attributes.sort_by(|a, b| a.attribute_name.cmp(&b.attribute_name));
+            VariantMetadata {
+                variant_name: dataset.initial_item.name.clone(),
+                variant_identifier: prefix,
+                shapefile_hint: None,
+                geometry_description: None,
+                attributes,
+            }
+        })
+        .collect()
+}
+
+/// Matches shapefiles named after the attribute prefix, e.g. `P23a-12_38.shp`.
+fn prefix_shapefile_regex(prefix: &str) -> Result<Regex> {
+    Ok(Regex::new(&format!(
+        r"(?:^|/){}[-_][^/]*(?i:(?:\.shp|\.cpg|\.dbf|\.prj|\.qmd|\.shx))$",
+        regex::escape(prefix)
+    ))?)
+}
+
 pub async fn mapping_defs_for_dataset(dataset: &Dataset) -> Result<Vec<ShapefileMetadata>> {
     let original_identifier = dataset.initial_item.identifier.clone();
     let mut variants = dataset.page.variants.clone();
+    let mut prefix_variants = false;
+    if variants.is_empty() {
+        variants = variants_from_attribute_prefixes(dataset);
+        prefix_variants = !variants.is_empty();
+    }
     if variants.is_empty() {
         variants.push(fallback_variant(dataset));
     }
@@ -243,6 +501,9 @@ pub async fn mapping_defs_for_dataset(dataset: &Dataset) -> Result<Vec<Shapefile
             original_identifier.clone()
         } else if variant_identifier.eq_ignore_ascii_case(original_identifier.as_str()) {
             original_identifier.clone()
+        } else if prefix_variants {
+            // prefix variants already contain the dataset identifier (A38a, P23b, ...)
+            variant_identifier.to_string()
         } else {
             format!("{}_{}", original_identifier, variant_identifier)
         };
@@ -255,23 +516,46 @@ pub async fn mapping_defs_for_dataset(dataset: &Dataset) -> Result<Vec<Shapefile
             "{}-{}",
             dataset.page.version.start_year, dataset.page.version.end_year
         ));
-        builder.data_year(dataset.page.version.end_year.to_string());
+        builder.data_year(
+            dataset
+                .year_suffix
+                .unwrap_or(dataset.page.version.end_year)
+                .to_string(),
+        );
         builder.original_identifier(original_identifier.clone());
         builder.identifier(identifier);
+        builder.primary_key(primary_key_hint(&original_identifier).map(str::to_string));
+        builder.geometry_filter(geometry_filter_hint(&original_identifier).or_else(|| {
+            variant
+                .geometry_description
+                .as_deref()
+                .and_then(geometry_filter_from_description)
+        }));
+        builder.field_transforms(
+            field_mappings
+                .iter()
+                .map(|(name, _)| (name.clone(), transform::default_transforms(name)))
+                .filter(|(_, transforms)| !transforms.is_empty())
+                .collect(),
+        );
+        builder.field_types(field_types(&field_mappings, &dataset.page.metadata));
         builder.field_mappings(field_mappings);
 
         if let Some(matchers) = variant
             .shapefile_hint
             .as_ref()
-            .map(|s| split_shapefile_matcher(s))
+            .map(|s| split_shapefile_matcher(s, &original_identifier))
             .filter(|m| !m.is_empty())
         {
             builder.shapefile_matcher(matchers);
         }
 
-        let metadata = builder
+        let mut metadata = builder
             .build()
             .with_context(|| "when building shapefile metadata from API")?;
+        if prefix_variants {
+            metadata.shapefile_name_regex = vec![prefix_shapefile_regex(variant_identifier)?];
+        }
         mappings.push(metadata);
     }
 
@@ -280,6 +564,17 @@ pub async fn mapping_defs_for_dataset(dataset: &Dataset) -> Result<Vec<Shapefile
     } else {
         mappings
     };
+    let mappings = mappings
+        .into_iter()
+        .flat_map(apply_subcategory_rules)
+        .map(|mut mapping| {
+            // --suffix-year: a table for each year, e.g. n03_2024
+            if let Some(year) = dataset.year_suffix {
+                mapping.identifier = format!("{}_{}", mapping.identifier, year);
+            }
+            mapping
+        })
+        .collect();
 
     Ok(mappings)
 }
@@ -288,23 +583,246 @@ pub async fn mapping_defs_for_dataset(dataset: &Dataset) -> Result<Vec<Shapefile
 mod tests {
     use super::*;
     use crate::scraper::{data_page, initial};
+    use crate::test_helpers::{fixture_dataset, setup_mock_server};
     use std::sync::Arc;
 
+    #[tokio::test]
+    async fn test_area_code_column() {
+        let dataset = fixture_dataset(
+            "A29",
+            vec![
+                ("A29_001", "行政区域コード", "コードリスト"),
+                ("A29_002", "用途地域", "文字列型"),
+            ],
+        );
+        let mappings = mapping_defs_for_dataset(&dataset).await.unwrap();
+        assert_eq!(
+            mappings[0]
+                .area_code_column(&dataset.page.metadata)
+                .as_deref(),
+            Some("行政区域コード")
+        );
+
+        let dataset = fixture_dataset("P29", vec![("P29_001", "名称", "文字列型")]);
+        let mappings = mapping_defs_for_dataset(&dataset).await.unwrap();
+        assert_eq!(mappings[0].area_code_column(&dataset.page.metadata), None);
+    }
+
+    #[tokio::test]
+    async fn test_year_suffix() {
+        let mut dataset = fixture_dataset("A27", vec![("A27_001", "名称", "文字列型")]);
+        dataset.year_suffix = Some(2019);
+        let mappings = mapping_defs_for_dataset(&dataset).await.unwrap();
+        assert_eq!(mappings[0].identifier, "A27_2019");
+        assert_eq!(mappings[0].original_identifier, "A27");
+        assert_eq!(mappings[0].data_year, "2019");
+    }
+
+    #[test]
+    fn test_split_shapefile_matcher() {
+        assert_eq!(
+            split_shapefile_matcher("A38a-YY_PP_1.shp\r\nA38a-YY_PP_2.shp\n", "A38"),
+            vec![
+                "A38a-YY_PP_1.shp",
+                "A38-YY_1.shp",
+                "A38a-YY_PP_2.shp",
+                "A38-YY_2.shp"
+            ]
+        );
+        assert_eq!(
+            split_shapefile_matcher("P23b-YY_PP.shp", "P23"),
+            vec!["P23b-YY_PP.shp", "P23-YY_PP.shp"]
+        );
+        // the dataset identifier itself ends with a letter
+        assert_eq!(
+            split_shapefile_matcher("A31a-YY_PP.shp", "A31a"),
+            vec!["A31a-YY_PP.shp"]
+        );
+    }
+
+    #[test]
+    fn test_geometry_filter_from_description() {
+        assert_eq!(
+            geometry_filter_from_description("ポリゴン"),
+            Some(GeometryFilter::Polygon)
+        );
+        assert_eq!(
+            geometry_filter_from_description("土砂災害警戒区域（Polygon）"),
+            Some(GeometryFilter::Polygon)
+        );
+        assert_eq!(
+            geometry_filter_from_description("ポイント"),
+            Some(GeometryFilter::Point)
+        );
+        assert_eq!(geometry_filter_from_description("ポイント・ライン"), None);
+        assert_eq!(geometry_filter_from_description("医療圏"), None);
+    }
+
+    #[tokio::test]
+    async fn test_mapping_defs_geometry_filter() {
+        let dataset = fixture_dataset("A33", vec![("A33_001", "区域区分", "コードリスト")]);
+        let mappings = mapping_defs_for_dataset(&dataset).await.unwrap();
+        assert_eq!(mappings[0].geometry_filter, Some(GeometryFilter::Polygon));
+
+        let mut dataset =
+            fixture_dataset("A27", vec![("A27_001", "行政区域コード", "コードリスト")]);
+        let mappings = mapping_defs_for_dataset(&dataset).await.unwrap();
+        assert_eq!(mappings[0].geometry_filter, None);
+
+        let mut page = (*dataset.page).clone();
+        page.variants = vec![VariantMetadata {
+            variant_name: "学校".to_string(),
+            variant_identifier: "A27".to_string(),
+            shapefile_hint: None,
+            geometry_description: Some("ポイント".to_string()),
+            attributes: vec![],
+        }];
+        dataset.page = Arc::new(page);
+        let mappings = mapping_defs_for_dataset(&dataset).await.unwrap();
+        assert_eq!(mappings[0].geometry_filter, Some(GeometryFilter::Point));
+    }
+
+    #[tokio::test]
+    async fn test_field_types() {
+        assert_eq!(
+            vrt_field_type("コードリスト「設置主体コード」"),
+            Some("String")
+        );
+        assert_eq!(
+            vrt_field_type("文字列型（CharacterString）"),
+            Some("String")
+        );
+        assert_eq!(vrt_field_type("CodeList"), Some("String"));
+        assert_eq!(vrt_field_type("整数型（Integer）"), None);
+        assert_eq!(vrt_field_type("実数型"), None);
+
+        let dataset = fixture_dataset(
+            "A27",
+            vec![
+                ("A27_001", "行政区域コード", "コードリスト"),
+                ("A27_002", "学校名", "文字列型"),
+                ("A27_003", "児童数", "整数型"),
+            ],
+        );
+        let mappings = mapping_defs_for_dataset(&dataset).await.unwrap();
+        assert_eq!(mappings[0].field_type("行政区域コード"), Some("String"));
+        assert_eq!(mappings[0].field_type("学校名"), Some("String"));
+        assert_eq!(mappings[0].field_type("児童数"), None);
+    }
+
+    #[tokio::test]
+    async fn test_mapping_defs_split_by_attribute_prefix() {
+        let dataset = fixture_dataset(
+            "P23",
+            vec![
+                ("P23a_001", "行政区域コード", "コードリスト"),
+                ("P23a_002", "海岸保全区域名", "文字列型"),
+                ("P23b_001", "行政区域コード", "コードリスト"),
+                ("P23b_002", "施設名", "文字列型"),
+            ],
+        );
+        let mappings = mapping_defs_for_dataset(&dataset).await.unwrap();
+        let identifiers = mappings
+            .iter()
+            .map(|m| m.identifier.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(identifiers, vec!["P23a", "P23b"]);
+        assert_eq!(
+            mappings[1].field_mappings,
+            vec![
+                ("行政区域コード".to_string(), "P23b_001".to_string()),
+                ("施設名".to_string(), "P23b_002".to_string()),
+            ]
+        );
+        assert!(mappings[0].shapefile_name_regex[0].is_match("P23-12_38_GML/P23a-12_38.shp"));
+        assert!(!mappings[0].shapefile_name_regex[0].is_match("P23-12_38_GML/P23b-12_38.shp"));
+        assert!(mappings.iter().all(|m| m.original_identifier == "P23"));
+    }
+
+    #[tokio::test]
+    async fn test_mapping_defs_single_prefix_is_not_split() {
+        let dataset = fixture_dataset(
+            "A27",
+            vec![
+                ("A27_001", "行政区域コード", "コードリスト"),
+                ("A27_002", "設置主体", "コードリスト"),
+            ],
+        );
+        let mappings = mapping_defs_for_dataset(&dataset).await.unwrap();
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].identifier, "A27");
+        assert_eq!(mappings[0].field_mappings.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mapping_defs_split_by_subcategory() {
+        let dataset = fixture_dataset(
+            "A31a",
+            vec![
+                ("A31a_101", "浸水深ランク", "コードリスト"),
+                ("A31a_102", "河川名", "文字列型"),
+            ],
+        );
+        let mappings = mapping_defs_for_dataset(&dataset).await.unwrap();
+        let identifiers = mappings
+            .iter()
+            .map(|m| m.identifier.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            identifiers,
+            vec!["A31a_10", "A31a_20", "A31a_30", "A31a_41", "A31a_42"]
+        );
+        assert_eq!(mappings[1].subcategory.as_deref(), Some("20"));
+        assert_eq!(mappings[1].name, "A31a 想定最大規模");
+        assert!(mappings.iter().all(|m| m.field_mappings.len() == 2));
+        assert!(mappings.iter().all(|m| m.original_identifier == "A31a"));
+    }
+
     #[tokio::test]
     async fn test_mapping_defs_for_dataset() {
+        setup_mock_server().await;
+        let page = data_page::scrape("N03", Some(&[2024])).await.unwrap();
+        let mut dataset = fixture_dataset("N03", vec![]);
+        dataset.page = Arc::new(page);
+
+        let mappings = mapping_defs_for_dataset(&dataset).await.unwrap();
+        let identifiers = mappings
+            .iter()
+            .map(|m| m.identifier.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(identifiers, vec!["N03", "N03_prefecture"]);
+        let shape_names = mappings[0]
+            .field_mappings
+            .iter()
+            .map(|(_, shape_name)| shape_name.as_str())
+            .collect::<Vec<_>>();
+        assert!(shape_names.contains(&"N03_001"), "{:?}", shape_names);
+        assert!(shape_names.contains(&"N03_007"), "{:?}", shape_names);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires network"]
+    async fn test_mapping_defs_for_dataset_live() {
         let initial = initial::scrape().await.unwrap();
         let data_item = initial
             .data
             .into_iter()
             .find(|item| item.identifier == "N03")
             .unwrap();
-        let page = data_page::scrape(&data_item.identifier, Some(2024))
+        let page = data_page::scrape(&data_item.identifier, Some(&[2024]))
             .await
             .unwrap();
         let dataset = Dataset {
+            listing_fingerprint: crate::scraper::listing_fingerprint(
+                &data_item,
+                &[],
+                Some(&[2024]),
+            ),
             initial_item: data_item,
             page: Arc::new(page),
             zip_file_paths: vec![],
+            fallback_zip_file_paths: vec![],
+            year_suffix: None,
         };
 
         let mappings = mapping_defs_for_dataset(&dataset).await.unwrap();
@@ -1,56 +1,324 @@
 // The loader module is responsible for loading data from ZIP files and into the output destination.
 
-use crate::scraper::Dataset;
-use anyhow::Result;
+use crate::metadata::MetadataConnection;
+use crate::report::Failure;
+use crate::scraper::{self, data_page, initial, Dataset};
+use anyhow::{Context, Result};
 use derive_builder::Builder;
+use futures_util::{stream, Stream, StreamExt};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 mod admini_boundary;
+mod checkpoint;
+mod compression;
 mod gdal;
 mod load_queue;
 pub mod mapping;
+mod quirks;
+mod table_names;
+pub mod transform;
 mod xslx_helpers;
 mod zip_traversal;
 
-pub async fn check_gdal_tools() -> Result<()> {
-    gdal::check_gdal_tools().await
+pub use admini_boundary::ADMINI_BOUNDARY_URL;
+pub use checkpoint::default_checkpoint_path;
+pub use compression::Compression;
+pub use gdal::{
+    set_debug_encoding, set_ogrinfo_extra_args, ForcedEncodings, GdalInfo, GeometryFilter,
+    Ogr2OgrOptions, DEFAULT_GROUP_TRANSACTIONS,
+};
+pub use quirks::SkipRules;
+pub use zip_traversal::{ExtractLimits, DEFAULT_MAX_ZIP_DEPTH};
+
+/// `--analyze` の同時実行数のデフォルト
+pub const DEFAULT_ANALYZE_CONCURRENCY: usize = 2;
+
+pub async fn check_gdal_tools(require_postgres: bool) -> Result<GdalInfo> {
+    gdal::check_gdal_tools(require_postgres).await
+}
+
+/// The table a mapping is loaded into: `--table-prefix` and the lowercased mapping identifier,
+/// shortened to PostgreSQL's identifier limit like column names.
+pub fn table_name(prefix: &str, identifier: &str) -> String {
+    let name = format!("{}{}", prefix, identifier).to_lowercase();
+    crate::metadata::shorten_identifier(&name).into_owned()
+}
+
+/// Table names the dataset will create, e.g. `a38a`, `a38b`, `a38c` for A38.
+pub async fn target_tables_for_dataset(dataset: &Dataset, prefix: &str) -> Result<Vec<String>> {
+    let mappings = mapping::mapping_defs_for_dataset(dataset).await?;
+    Ok(mappings
+        .into_iter()
+        .map(|mapping| table_name(prefix, &mapping.identifier))
+        .collect())
+}
+
+/// Table names the latest version of the dataset will create, without downloading any ZIP files.
+pub async fn target_tables(identifier: &str, prefix: &str) -> Result<Vec<String>> {
+    let listing = initial::scrape().await?;
+    let initial_item = listing
+        .data
+        .into_iter()
+        .find(|item| item.identifier == identifier)
+        .with_context(|| format!("unknown dataset identifier: {}", identifier))?;
+    let page = data_page::scrape(identifier, None)
+        .await
+        .with_context(|| format!("when fetching dataset {}", identifier))?;
+    let dataset = Dataset {
+        listing_fingerprint: scraper::listing_fingerprint(&initial_item, &[], None),
+        initial_item,
+        page: Arc::new(page),
+        zip_file_paths: vec![],
+        fallback_zip_file_paths: vec![],
+        year_suffix: None,
+    };
+    target_tables_for_dataset(&dataset, prefix).await
 }
 
 #[derive(Builder)]
 pub struct Loader {
+    /// [`Loader::load_all`] で取り込むデータセット（[`Loader::load_stream`] では使いません）
+    #[builder(default)]
     datasets: Vec<Dataset>,
     output: OutputTarget,
     skip_if_exists: bool,
+    #[builder(default)]
+    extra_extensions: Vec<String>,
+    /// 識別子ごとの主キーの上書き
+    #[builder(default)]
+    primary_keys: HashMap<String, String>,
+    /// 識別子ごとの取り込むフィーチャの条件（`--where`）
+    #[builder(default)]
+    where_filters: HashMap<String, String>,
+    /// テーブル名の前に付けるプレフィックス（例: `ksj_`）
+    #[builder(default)]
+    table_prefix: String,
+    #[builder(default)]
+    add_primary_keys: bool,
+    /// 取り込み後、不正なジオメトリを `ST_MakeValid` で修復します（PostgreSQL のみ）
+    /// ポリゴンがジオメトリコレクションになる場合は、元の種別の図形のみを残します
+    #[builder(default)]
+    fix_geometries: bool,
+    /// 取り込み後、主キー（なければジオメトリ）が重複するフィーチャを削除します（PostgreSQL のみ）
+    #[builder(default)]
+    dedupe: bool,
+    /// 取り込み後にジオメトリカラムの空間インデックス（GiST）を作成します
+    #[builder(default = "true")]
+    create_indexes: bool,
+    /// `sjis/` と `utf8/` の重複ディレクトリがある場合、`utf8/` を優先します
+    #[builder(default)]
+    prefer_utf8_dirs: bool,
+    #[builder(default)]
+    ogr2ogr: Ogr2OgrOptions,
+    /// カラム名ごとの変換の上書き
+    #[builder(default)]
+    field_transforms: HashMap<String, Vec<transform::FieldTransform>>,
+    /// 指定した種別のジオメトリのみ取り込みます
+    #[builder(default)]
+    geometry_filter: Option<GeometryFilter>,
+    /// シェープファイルの文字コードの指定（指定がないものは自動検出します）
+    #[builder(default)]
+    forced_encodings: ForcedEncodings,
+    /// マッピングにない属性も元のカラム名で取り込みます
+    #[builder(default)]
+    keep_all_fields: bool,
+    /// 取り込み後、この同時実行数で全テーブルに `ANALYZE` を実行します（PostgreSQL のみ）
+    #[builder(default)]
+    analyze: Option<usize>,
+    /// 取り込んだテーブルの範囲を調べて [`LoadReport::tables`] に記録します
+    #[builder(default)]
+    collect_extents: bool,
+    /// 取り込んだテーブルの行数を調べて [`LoadReport::tables`] に記録します
+    #[builder(default)]
+    collect_row_counts: bool,
+    /// 最新版に欠けている地域（行政区域コード）を古い版で補完します（PostgreSQL のみ）
+    #[builder(default)]
+    fill_gaps: bool,
+    /// 取り込み後も展開したシェープファイルを残します
+    #[builder(default)]
+    keep_intermediate: bool,
+    /// 取り込みに成功したデータセットの ZIP ファイルを削除します
+    #[builder(default)]
+    delete_zips_after_load: bool,
+    /// ネストした ZIP の深さと展開後の合計サイズの上限
+    #[builder(default)]
+    extract_limits: ExtractLimits,
+    /// ZIP 内で展開しないファイルのパターン（識別子ごと）
+    #[builder(default)]
+    skip_rules: SkipRules,
+    /// 取り込みに成功したデータセットを記録するファイル。記録済みのデータセットはスキップします（`--resume`）
+    #[builder(default)]
+    checkpoint: Option<PathBuf>,
+    /// 既存のチェックポイントを使わずに記録し直します（`--force`）
+    #[builder(default)]
+    restart_checkpoint: bool,
+    /// 同時に取り込むデータセットの数（ワーカー数）。指定がない場合は CPU 数 - 1 です
+    #[builder(default)]
+    load_concurrency: Option<usize>,
+    /// PostgreSQL へ同時に書き込む ogr2ogr の数。指定がない場合はワーカー数と同じです
+    /// ZIP の展開と VRT の作成はワーカー数の並列度のまま行います
+    #[builder(default)]
+    db_write_concurrency: Option<usize>,
 }
 
 impl Loader {
-    pub async fn load_all(self) -> Result<()> {
-        let mut load_queue = load_queue::LoadQueue::new(&self).await?;
-        for dataset in self.datasets {
+    pub async fn load_all(mut self) -> Result<LoadReport> {
+        let datasets = std::mem::take(&mut self.datasets);
+        // all the problems at once, before loading anything
+        let mut table_names = self.table_names();
+        let mut problems = Vec::new();
+        for dataset in &datasets {
+            problems.extend(table_names.claim_or_warn(dataset).await);
+        }
+        table_names::ensure_valid(&problems)?;
+        self.load_from(stream::iter(datasets), None).await
+    }
+
+    /// Loads the datasets as they arrive, e.g. from [`scraper::Scraper::download_each`] while
+    /// the rest is still downloading. Returns when the stream ends and everything is loaded.
+    /// The table names of each dataset are checked as it arrives: a dataset with a problem
+    /// fails, and the others are loaded.
+    pub async fn load_stream(self, datasets: impl Stream<Item = Dataset>) -> Result<LoadReport> {
+        let table_names = self.table_names();
+        self.load_from(datasets, Some(table_names)).await
+    }
+
+    fn table_names(&self) -> table_names::TableNames {
+        table_names::TableNames::new(&self.table_prefix, self.output.postgres_url().is_some())
+    }
+
+    /// `table_names` checks each dataset before it's loaded (`None` when they were all
+    /// checked beforehand).
+    async fn load_from(
+        self,
+        datasets: impl Stream<Item = Dataset>,
+        table_names: Option<table_names::TableNames>,
+    ) -> Result<LoadReport> {
+        let mut load_queue = load_queue::LoadQueue::new(&self, table_names).await?;
+        let mut datasets = std::pin::pin!(datasets);
+        while let Some(dataset) = datasets.next().await {
             load_queue.push(&dataset).await?;
         }
-        load_queue.close().await?;
-        if let OutputTarget::Postgres { postgres_url } = &self.output {
-            admini_boundary::load_admini_boundary(postgres_url).await?;
+        let mut report = load_queue.close().await?;
+        if let OutputTarget::Postgres {
+            postgres_url,
+            schema,
+        } = &self.output
+        {
+            let schema = schema.as_deref();
+            admini_boundary::load_admini_boundary(postgres_url, schema).await?;
+            if let Some(concurrency) = self.analyze {
+                let mut tables: Vec<String> = report
+                    .tables
+                    .iter()
+                    .map(|table| table.identifier.clone())
+                    .collect();
+                tables.push("admini_boundary_cd".to_string());
+                tables.sort();
+                tables.dedup();
+                report.analyze_duration =
+                    Some(analyze_tables(postgres_url, schema, &tables, concurrency).await);
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Runs `ANALYZE` on the tables, `concurrency` at a time. A failure is only reported,
+/// the tables are usable without fresh statistics.
+async fn analyze_tables(
+    postgres_url: &str,
+    schema: Option<&str>,
+    tables: &[String],
+    concurrency: usize,
+) -> Duration {
+    let started = Instant::now();
+    let results: Vec<(&String, Result<()>)> = stream::iter(tables)
+        .map(|table| async move {
+            let result = async {
+                let conn = MetadataConnection::connect(postgres_url, schema).await?;
+                conn.analyze(table).await
+            }
+            .await;
+            (table, result)
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+    for (table, result) in results {
+        if let Err(e) = result {
+            eprintln!("{} の ANALYZE に失敗しました: {:#}", table, e);
         }
-        Ok(())
     }
+    started.elapsed()
+}
+
+/// データセットごとの取り込み結果
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    pub succeeded: Vec<String>,
+    pub failed: Vec<Failure>,
+    /// 取り込んだテーブル（`collect_extents`、`collect_row_counts`、`skip_failures`、`analyze` のいずれかを指定した場合と、不正なジオメトリや重複があった場合のみ）
+    pub tables: Vec<LoadedTable>,
+    /// `analyze` の所要時間
+    pub analyze_duration: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadedTable {
+    /// データセットの識別子
+    pub dataset_identifier: String,
+    /// テーブル名（小文字）
+    pub identifier: String,
+    pub name: String,
+    /// ファイル出力の場合の出力先
+    pub output_path: Option<PathBuf>,
+    /// `[minx, miny, maxx, maxy]`
+    pub bbox: Option<[f64; 4]>,
+    /// 行数（`collect_row_counts` を指定した場合のみ）
+    pub row_count: Option<u64>,
+    /// `-skipfailures` によりスキップされたフィーチャ数（[`Ogr2OgrOptions::skip_failures`] を指定した場合のみ）
+    pub skipped_features: Option<u64>,
+    /// 取り込み後に `ST_IsValid` で不正と判定されたフィーチャ数（PostgreSQL のみ）
+    pub invalid_geometries: Option<u64>,
+    /// `fix_geometries` により `ST_MakeValid` で修復したフィーチャ数
+    pub fixed_geometries: Option<u64>,
+    /// `dedupe` により削除した重複フィーチャ数
+    pub removed_duplicates: Option<u64>,
 }
 
 #[derive(Debug, Clone)]
 pub enum OutputTarget {
-    Postgres { postgres_url: String },
+    Postgres {
+        postgres_url: String,
+        /// 書き込み先のスキーマ（`--pg-schema`）。指定がない場合は `public` です
+        schema: Option<String>,
+    },
     File {
         output_dir: PathBuf,
         gdal_driver: String,
         file_extension: String,
+        compression: Option<Compression>,
+        /// 全テーブルをレイヤとして書き出す1つのファイル（例: `./output/jpksj.gpkg`）
+        /// 指定がない場合はテーブルごとに `<output_dir>/<識別子>.<拡張子>` に書き出します
+        single_file: Option<PathBuf>,
     },
 }
 
 impl OutputTarget {
     pub fn postgres_url(&self) -> Option<&str> {
         match self {
-            Self::Postgres { postgres_url } => Some(postgres_url.as_str()),
+            Self::Postgres { postgres_url, .. } => Some(postgres_url.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn postgres_schema(&self) -> Option<&str> {
+        match self {
+            Self::Postgres { schema, .. } => schema.as_deref(),
             _ => None,
         }
     }
@@ -78,9 +346,189 @@ impl OutputTarget {
         }
     }
 
+    pub fn compression(&self) -> Option<Compression> {
+        match self {
+            Self::File { compression, .. } => *compression,
+            _ => None,
+        }
+    }
+
+    pub fn single_file(&self) -> Option<&Path> {
+        match self {
+            Self::File { single_file, .. } => single_file.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The layer to write to (or read from) in the output file, when it holds more than one table.
+    pub fn layer_name<'a>(&self, identifier: &'a str) -> Option<&'a str> {
+        self.single_file().map(|_| identifier)
+    }
+
     pub fn output_path(&self, identifier: &str) -> Option<PathBuf> {
+        if let Some(single_file) = self.single_file() {
+            return Some(single_file.to_path_buf());
+        }
         let output_dir = self.output_dir()?;
-        let extension = self.file_extension()?;
+        let extension = match (self.file_extension()?, self.compression()) {
+            (extension, Some(compression)) => format!("{}.{}", extension, compression.suffix()),
+            (extension, None) => extension.to_string(),
+        };
         Some(output_dir.join(identifier).with_extension(extension))
     }
+
+    /// メタデータのサイドカーファイル（`<識別子>.metadata.json`）
+    pub fn metadata_path(&self, identifier: &str) -> Option<PathBuf> {
+        let output_dir = self.output_dir()?;
+        Some(output_dir.join(identifier).with_extension("metadata.json"))
+    }
+
+    /// データセット単位のメタデータ（[`crate::metadata::DatasetMetadata`]）の出力先: `<output_dir>/<identifier>.dataset.json`
+    pub fn dataset_metadata_path(&self, dataset_identifier: &str) -> Option<PathBuf> {
+        let output_dir = self.output_dir()?;
+        Some(
+            output_dir
+                .join(dataset_identifier.to_lowercase())
+                .with_extension("dataset.json"),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::fixture_dataset;
+
+    #[tokio::test]
+    async fn test_target_tables_for_dataset() {
+        let dataset = fixture_dataset(
+            "A38",
+            vec![
+                ("A38a_001", "都道府県名", "文字列型"),
+                ("A38b_001", "二次医療圏名", "文字列型"),
+                ("A38c_001", "三次医療圏名", "文字列型"),
+            ],
+        );
+        let tables = target_tables_for_dataset(&dataset, "").await.unwrap();
+        assert_eq!(tables, vec!["a38a", "a38b", "a38c"]);
+        let tables = target_tables_for_dataset(&dataset, "ksj_").await.unwrap();
+        assert_eq!(tables, vec!["ksj_a38a", "ksj_a38b", "ksj_a38c"]);
+
+        let dataset = fixture_dataset("N03", vec![("N03_001", "都道府県名", "文字列型")]);
+        let tables = target_tables_for_dataset(&dataset, "").await.unwrap();
+        assert_eq!(tables, vec!["n03", "n03_prefecture"]);
+    }
+
+    #[tokio::test]
+    async fn test_table_name_problems() {
+        let datasets = || {
+            let attributes = vec![
+                ("A38a_001", "都道府県名", "文字列型"),
+                ("A38b_001", "二次医療圏名", "文字列型"),
+            ];
+            vec![
+                fixture_dataset("A38", attributes),
+                // the table of A38's first variant
+                fixture_dataset("A38a", vec![]),
+                fixture_dataset("A 99", vec![]),
+            ]
+        };
+        let output_dir = crate::context::tmp().join("test_table_name_problems");
+        let loader = || {
+            LoaderBuilder::default()
+                .output(OutputTarget::File {
+                    output_dir: output_dir.clone(),
+                    gdal_driver: "GeoJSON".to_string(),
+                    file_extension: "geojson".to_string(),
+                    compression: None,
+                    single_file: None,
+                })
+                .skip_if_exists(false)
+                .load_concurrency(Some(1))
+                .clone()
+        };
+
+        // all of them before loading anything
+        let error = loader()
+            .datasets(datasets())
+            .build()
+            .unwrap()
+            .load_all()
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("A38a: a38a は A38 のテーブルと重複します"),
+            "{}",
+            error
+        );
+        assert!(error.contains("A 99: a 99 に使用できない文字"), "{}", error);
+
+        // as they arrive, only the datasets with a problem fail
+        let report = loader()
+            .build()
+            .unwrap()
+            .load_stream(stream::iter(datasets()))
+            .await
+            .unwrap();
+        let failure = |identifier: &str| {
+            report
+                .failed
+                .iter()
+                .find(|failure| failure.identifier == identifier)
+                .unwrap()
+                .error
+                .clone()
+        };
+        // without shapefiles, A38 is loaded and fails later
+        assert!(!failure("A38").contains("テーブル名"));
+        assert!(failure("A38a").contains("a38a は A38 のテーブルと重複します"));
+        assert!(failure("A 99").contains("に使用できない文字"));
+
+        let _ = std::fs::remove_dir_all(&output_dir);
+    }
+
+    #[test]
+    fn test_table_name() {
+        assert_eq!(table_name("", "A38a"), "a38a");
+        assert_eq!(table_name("KSJ_", "N03"), "ksj_n03");
+
+        let long_prefix = "jpksj_".repeat(10);
+        let name = table_name(&long_prefix, "N03_prefecture");
+        assert!(name.len() <= 63);
+        assert!(name.starts_with(&long_prefix[..30]));
+        assert_ne!(name, table_name(&long_prefix, "N03"));
+    }
+
+    #[test]
+    fn test_single_file_output_path() {
+        let gpkg_output = |single_file: Option<&str>| OutputTarget::File {
+            output_dir: PathBuf::from("./output"),
+            gdal_driver: "GPKG".to_string(),
+            file_extension: "gpkg".to_string(),
+            compression: None,
+            single_file: single_file.map(PathBuf::from),
+        };
+        let output = gpkg_output(Some("./output/jpksj.gpkg"));
+        assert_eq!(
+            output.output_path("n03").unwrap(),
+            PathBuf::from("./output/jpksj.gpkg")
+        );
+        assert_eq!(output.layer_name("n03"), Some("n03"));
+        assert_eq!(
+            output.metadata_path("n03").unwrap(),
+            PathBuf::from("./output/n03.metadata.json")
+        );
+        assert_eq!(
+            output.dataset_metadata_path("N03").unwrap(),
+            PathBuf::from("./output/n03.dataset.json")
+        );
+
+        let output = gpkg_output(None);
+        assert_eq!(
+            output.output_path("n03").unwrap(),
+            PathBuf::from("./output/n03.gpkg")
+        );
+        assert_eq!(output.layer_name("n03"), None);
+    }
 }
@@ -0,0 +1,185 @@
+// Per-dataset workarounds for irregularities in the published ZIP files.
+// Keeping them here (instead of inline in the traversal/loading code) makes it
+// easy to see which datasets need special treatment, and why.
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// データセット固有の回避策
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DatasetQuirks {
+    /// ZIP 内のパス（`/` 区切り）がこの正規表現に一致するファイルは展開しません
+    pub skip_patterns: &'static [&'static str],
+}
+
+/// 元データの識別子ごとの回避策
+static QUIRKS: &[(&str, DatasetQuirks)] = &[(
+    "N08",
+    DatasetQuirks {
+        // duplicate of the sjis/ directory, and contains malformed UTF-8
+        skip_patterns: &["^N08-21_GML/utf8/"],
+    },
+)];
+
+/// Entries never extracted from the ZIP files, by original identifier: the built-in
+/// [`QUIRKS`] plus the rules of a `--skip-rules` file.
+#[derive(Debug, Clone)]
+pub struct SkipRules {
+    rules: Vec<(String, Regex)>,
+}
+
+impl Default for SkipRules {
+    fn default() -> Self {
+        let rules = QUIRKS
+            .iter()
+            .flat_map(|(identifier, quirks)| {
+                quirks.skip_patterns.iter().map(|pattern| {
+                    (
+                        identifier.to_string(),
+                        Regex::new(pattern).expect("built-in skip pattern"),
+                    )
+                })
+            })
+            .collect();
+        Self { rules }
+    }
+}
+
+impl SkipRules {
+    /// Adds the rules of a JSON file: `{"N08": ["^N08-21_GML/utf8/"], ...}`.
+    pub fn with_file(mut self, path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("when reading {}", path.display()))?;
+        self.rules.extend(
+            parse_skip_rules(&json).with_context(|| format!("when parsing {}", path.display()))?,
+        );
+        Ok(self)
+    }
+
+    pub fn patterns_for(&self, original_identifier: &str) -> Vec<Regex> {
+        self.rules
+            .iter()
+            .filter(|(identifier, _)| identifier.eq_ignore_ascii_case(original_identifier))
+            .map(|(_, pattern)| pattern.clone())
+            .collect()
+    }
+}
+
+fn parse_skip_rules(json: &str) -> Result<Vec<(String, Regex)>> {
+    let rules: BTreeMap<String, Vec<String>> = serde_json::from_str(json)?;
+    let mut out = Vec::new();
+    for (identifier, patterns) in rules {
+        for pattern in patterns {
+            let regex = Regex::new(&pattern)
+                .with_context(|| format!("invalid skip pattern for {}: {}", identifier, pattern))?;
+            out.push((identifier.clone(), regex));
+        }
+    }
+    Ok(out)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EncodingDir {
+    Sjis,
+    Utf8,
+}
+
+fn encoding_dir(name: &str) -> Option<EncodingDir> {
+    match name.to_ascii_lowercase().as_str() {
+        "sjis" | "shift_jis" | "shift-jis" | "shiftjis" | "cp932" => Some(EncodingDir::Sjis),
+        "utf8" | "utf-8" | "utf_8" => Some(EncodingDir::Utf8),
+        _ => None,
+    }
+}
+
+/// Finds directories that duplicate a sibling directory in another encoding
+/// (e.g. `X_GML/utf8/` next to `X_GML/sjis/`), and returns the prefixes of the ones
+/// that should not be extracted. `sjis/` is kept unless `prefer_utf8` is set.
+pub fn duplicate_encoding_dirs<'a>(
+    entry_names: impl IntoIterator<Item = &'a str>,
+    prefer_utf8: bool,
+) -> Vec<String> {
+    let skipped = if prefer_utf8 {
+        EncodingDir::Sjis
+    } else {
+        EncodingDir::Utf8
+    };
+    // (parent, encoding dir name, kind)
+    let mut dirs: Vec<(String, String, EncodingDir)> = Vec::new();
+    for name in entry_names {
+        let components = name.split('/').collect::<Vec<_>>();
+        // the last component is the file name
+        for i in 0..components.len().saturating_sub(1) {
+            let Some(kind) = encoding_dir(components[i]) else {
+                continue;
+            };
+            let parent = components[..i].join("/");
+            let dir = components[i].to_string();
+            if !dirs.iter().any(|(p, d, _)| *p == parent && *d == dir) {
+                dirs.push((parent, dir, kind));
+            }
+        }
+    }
+    dirs.iter()
+        .filter(|(parent, _, kind)| {
+            *kind == skipped && dirs.iter().any(|(p, _, k)| p == parent && *k != skipped)
+        })
+        .map(|(parent, dir, _)| {
+            if parent.is_empty() {
+                format!("{}/", dir)
+            } else {
+                format!("{}/{}/", parent, dir)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicate_encoding_dirs() {
+        let names = [
+            "N08-21_GML/utf8/N08-21.shp",
+            "N08-21_GML/utf8/N08-21.dbf",
+            "N08-21_GML/sjis/N08-21.shp",
+            "N08-21_GML/sjis/N08-21.dbf",
+            "other/UTF-8/only.shp",
+        ];
+        assert_eq!(
+            duplicate_encoding_dirs(names, false),
+            vec!["N08-21_GML/utf8/".to_string()]
+        );
+        assert_eq!(
+            duplicate_encoding_dirs(names, true),
+            vec!["N08-21_GML/sjis/".to_string()]
+        );
+        assert!(duplicate_encoding_dirs(["a/utf8/x.shp", "b/sjis/x.shp"], false).is_empty());
+    }
+
+    #[test]
+    fn test_skip_rules() {
+        let rules = SkipRules::default();
+        let n08 = rules.patterns_for("N08");
+        assert_eq!(n08.len(), 1);
+        assert!(n08[0].is_match("N08-21_GML/utf8/N08-21.shp"));
+        assert!(!n08[0].is_match("N08-21_GML/sjis/N08-21.shp"));
+        assert!(rules.patterns_for("N03").is_empty());
+
+        let mut rules = SkipRules::default();
+        rules.rules.extend(
+            parse_skip_rules(r#"{"n03": ["_prefecture\\.", "^old/"], "N08": ["\\.xml$"]}"#)
+                .unwrap(),
+        );
+        assert_eq!(rules.patterns_for("N03").len(), 2);
+        assert_eq!(rules.patterns_for("N08").len(), 2);
+        assert!(rules.patterns_for("N03")[0].is_match("N03-20240101_prefecture.shp"));
+
+        let error = parse_skip_rules(r#"{"N03": ["("]}"#).unwrap_err();
+        assert!(format!("{:#}", error).contains("invalid skip pattern for N03"));
+        assert!(parse_skip_rules(r#"["N03"]"#).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+// Checks the table names before anything is loaded: a name used by two datasets, or one
+// that PostgreSQL or the file system can't take, would otherwise only fail (or overwrite
+// another table) at the end of a long run.
+// `Loader::load_all` checks every dataset up front; when the datasets are loaded as they are
+// downloaded, each one is checked as it arrives and only the datasets with problems fail.
+
+use anyhow::Result;
+use std::collections::HashMap;
+
+use crate::logging;
+use crate::metadata::MAX_IDENTIFIER_BYTES;
+use crate::scraper::Dataset;
+
+/// The tables jpksj-to-sql creates in PostgreSQL next to the datasets' tables.
+const RESERVED_TABLES: &[&str] = &[
+    "datasets",
+    "admini_boundary_cd",
+    "feature_counts",
+    "schema_migrations",
+];
+
+/// What is wrong with a table name on its own, if anything.
+fn table_name_problem(name: &str) -> Option<String> {
+    if name.is_empty() {
+        return Some("テーブル名が空です".to_string());
+    }
+    if name.len() > MAX_IDENTIFIER_BYTES {
+        return Some(format!(
+            "{} は {} バイトを超えています",
+            name, MAX_IDENTIFIER_BYTES
+        ));
+    }
+    // also the file name of file outputs
+    if let Some(c) = name
+        .chars()
+        .find(|c| c.is_control() || c.is_whitespace() || matches!(c, '/' | '\\' | '"' | '\''))
+    {
+        return Some(format!(
+            "{} に使用できない文字 {:?} が含まれています",
+            name, c
+        ));
+    }
+    None
+}
+
+/// The datasets that claimed each table name so far.
+pub struct TableNames {
+    table_prefix: String,
+    postgres: bool,
+    owners: HashMap<String, String>,
+}
+
+/// The identifier, and the year with `--suffix-year`: loading the same dataset twice
+/// writes the same tables, which is not a conflict.
+fn owner(dataset: &Dataset) -> String {
+    match dataset.year_suffix {
+        Some(year) => format!("{} ({}年)", dataset.initial_item.identifier, year),
+        None => dataset.initial_item.identifier.clone(),
+    }
+}
+
+impl TableNames {
+    pub fn new(table_prefix: &str, postgres: bool) -> Self {
+        Self {
+            table_prefix: table_prefix.to_string(),
+            postgres,
+            owners: HashMap::new(),
+        }
+    }
+
+    /// Claims the tables of the dataset, and returns the problems with their names.
+    pub async fn claim(&mut self, dataset: &Dataset) -> Result<Vec<String>> {
+        let tables = super::target_tables_for_dataset(dataset, &self.table_prefix).await?;
+        Ok(self.claim_tables(&owner(dataset), &tables))
+    }
+
+    /// [`TableNames::claim`], but a dataset whose tables can't be listed has no problems here:
+    /// the same error fails it (and is reported) when it's loaded.
+    pub async fn claim_or_warn(&mut self, dataset: &Dataset) -> Vec<String> {
+        match self.claim(dataset).await {
+            Ok(problems) => problems,
+            Err(e) => {
+                eprintln!(
+                    "{} のテーブル名を確認できませんでした: {:#}",
+                    owner(dataset),
+                    e
+                );
+                logging::event(format!(
+                    "table names unchecked: {}: {:#}",
+                    owner(dataset),
+                    e
+                ));
+                Vec::new()
+            }
+        }
+    }
+
+    /// The tables are only claimed when there is no problem with any of them, so that a
+    /// dataset which isn't loaded doesn't take names from the ones after it.
+    fn claim_tables(&mut self, owner: &str, tables: &[String]) -> Vec<String> {
+        let mut problems = Vec::new();
+        for table in tables {
+            if let Some(problem) = table_name_problem(table) {
+                problems.push(format!("{}: {}", owner, problem));
+            }
+            if self.postgres && RESERVED_TABLES.contains(&table.as_str()) {
+                problems.push(format!(
+                    "{}: {} は jpksj-to-sql のテーブルと重複します",
+                    owner, table
+                ));
+            }
+            match self.owners.get(table) {
+                Some(other) if other != owner => problems.push(format!(
+                    "{}: {} は {} のテーブルと重複します",
+                    owner, table, other
+                )),
+                _ => {}
+            }
+        }
+        if problems.is_empty() {
+            for table in tables {
+                self.owners.insert(table.clone(), owner.to_string());
+            }
+        }
+        problems
+    }
+}
+
+/// Fails with every problem at once, if there are any.
+pub fn ensure_valid(problems: &[String]) -> Result<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    anyhow::bail!(
+        "テーブル名に問題があるため、取り込みを中止します:\n  {}",
+        problems.join("\n  ")
+    )
+}
+
+/// The error of a single dataset that isn't loaded because of its table names.
+pub fn dataset_error(problems: &[String]) -> anyhow::Error {
+    anyhow::anyhow!(
+        "テーブル名に問題があるため、このデータセットは取り込みません:\n  {}",
+        problems.join("\n  ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_claim_tables() {
+        let tables = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let mut names = TableNames::new("", true);
+        assert!(names
+            .claim_tables("A38", &tables(&["a38a", "a38b"]))
+            .is_empty());
+        // the same dataset again
+        assert!(names.claim_tables("A38", &tables(&["a38a"])).is_empty());
+        assert_eq!(
+            names.claim_tables("A99", &tables(&["a38a", "datasets", "a 99"])),
+            vec![
+                "A99: a38a は A38 のテーブルと重複します",
+                "A99: datasets は jpksj-to-sql のテーブルと重複します",
+                "A99: a 99 に使用できない文字 ' ' が含まれています",
+            ]
+        );
+        // a dataset with a problem claims none of its tables
+        assert_eq!(
+            names.claim_tables("A97", &tables(&["a38b", "a97"])).len(),
+            1
+        );
+        assert!(names.claim_tables("A96", &tables(&["a97"])).is_empty());
+        // only PostgreSQL has the metadata tables
+        assert!(TableNames::new("", false)
+            .claim_tables("A99", &tables(&["datasets"]))
+            .is_empty());
+
+        assert!(table_name_problem(&"a".repeat(64)).is_some());
+        assert!(table_name_problem("n03_prefecture").is_none());
+        assert!(ensure_valid(&[]).is_ok());
+        assert!(ensure_valid(&["A99: a38a".to_string()]).is_err());
+    }
+}
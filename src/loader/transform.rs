@@ -0,0 +1,221 @@
+// Per-field value transforms, applied while reading the shapefiles through the VRT
+// (an SQLite-dialect `<SrcSQL>` on each source layer).
+//
+// Supported transforms:
+// - `trim`: strips leading/trailing whitespace
+// - `upper`: converts to upper case
+// - `zero_pad:N`: left-pads with `0` to N characters (longer values are left as is)
+// - `none`: disables the built-in transforms of a field
+
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldTransform {
+    Trim,
+    Upper,
+    ZeroPad(usize),
+}
+
+impl fmt::Display for FieldTransform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Trim => write!(f, "trim"),
+            Self::Upper => write!(f, "upper"),
+            Self::ZeroPad(width) => write!(f, "zero_pad:{}", width),
+        }
+    }
+}
+
+impl FieldTransform {
+    pub fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        match s.split_once(':') {
+            None if s == "trim" => Ok(Self::Trim),
+            None if s == "upper" => Ok(Self::Upper),
+            Some(("zero_pad", width)) => {
+                let width = width
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| anyhow!("invalid zero_pad width: {}", s))?;
+                if width == 0 || width > 32 {
+                    anyhow::bail!("zero_pad width must be between 1 and 32: {}", s);
+                }
+                Ok(Self::ZeroPad(width))
+            }
+            _ => Err(anyhow!(
+                "unknown field transform: {} (supported: trim, upper, zero_pad:N)",
+                s
+            )),
+        }
+    }
+
+    /// Parses a comma-separated list, e.g. `trim,zero_pad:5`. `none` disables all transforms.
+    pub fn parse_list(s: &str) -> Result<Vec<Self>> {
+        if s.trim() == "none" {
+            return Ok(vec![]);
+        }
+        s.split(',')
+            .filter(|t| !t.trim().is_empty())
+            .map(Self::parse)
+            .collect()
+    }
+
+    fn apply_sql(&self, expr: &str) -> String {
+        match self {
+            Self::Trim => format!("trim({})", expr),
+            Self::Upper => format!("upper({})", expr),
+            Self::ZeroPad(width) => format!(
+                "CASE WHEN length({expr}) < {width} THEN substr('{zeros}' || {expr}, -{width}, {width}) ELSE {expr} END",
+                expr = expr,
+                width = width,
+                zeros = "0".repeat(*width),
+            ),
+        }
+    }
+}
+
+fn quote_sql_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// SQLite expression applying `transforms` (in order) to the source column.
+pub fn sql_expression(src_column: &str, transforms: &[FieldTransform]) -> String {
+    let mut expr = format!("CAST({} AS TEXT)", quote_sql_ident(src_column));
+    for transform in transforms {
+        expr = transform.apply_sql(&expr);
+    }
+    expr
+}
+
+/// Name of the column holding the transformed value of `src_column`.
+pub fn transformed_column(src_column: &str) -> String {
+    format!("{}__transformed", src_column)
+}
+
+/// `SELECT *, <expr> AS "<col>__transformed", ... FROM "<layer>" [WHERE (<condition>)]`
+pub fn src_sql(
+    layer_name: &str,
+    columns: &[(&str, &[FieldTransform])],
+    where_clause: Option<&str>,
+) -> String {
+    let mut select = vec!["*".to_string()];
+    for (src_column, transforms) in columns {
+        select.push(format!(
+            "{} AS {}",
+            sql_expression(src_column, transforms),
+            quote_sql_ident(&transformed_column(src_column))
+        ));
+    }
+    let mut sql = format!(
+        "SELECT {} FROM {}",
+        select.join(", "),
+        quote_sql_ident(layer_name)
+    );
+    if let Some(condition) = where_clause {
+        sql.push_str(&format!(" WHERE ({})", condition));
+    }
+    sql
+}
+
+/// 組み込みの変換（カラム名ごと）
+/// 年度によって桁数が揃っていない行政区域コードは、結合できるように5桁にします
+pub fn default_transforms(field_name: &str) -> Vec<FieldTransform> {
+    match field_name {
+        "行政区域コード" => vec![FieldTransform::Trim, FieldTransform::ZeroPad(5)],
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(value: &str, transforms: &[FieldTransform]) -> String {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.query_row(
+            &format!(
+                "SELECT {} FROM (SELECT ?1 AS \"N03_007\")",
+                sql_expression("N03_007", transforms)
+            ),
+            [value],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_field_transforms() {
+        assert_eq!(
+            FieldTransform::parse_list("trim, zero_pad:5").unwrap(),
+            vec![FieldTransform::Trim, FieldTransform::ZeroPad(5)]
+        );
+        assert_eq!(
+            FieldTransform::parse("upper").unwrap(),
+            FieldTransform::Upper
+        );
+        assert!(FieldTransform::parse_list("none").unwrap().is_empty());
+        assert!(FieldTransform::parse("zero_pad:x").is_err());
+        assert!(FieldTransform::parse("lower").is_err());
+        assert_eq!(FieldTransform::ZeroPad(5).to_string(), "zero_pad:5");
+    }
+
+    #[test]
+    fn test_zero_pad_code() {
+        let transforms = default_transforms("行政区域コード");
+        assert_eq!(eval("1101", &transforms), "01101");
+        assert_eq!(eval(" 1101 ", &transforms), "01101");
+        assert_eq!(eval("13101", &transforms), "13101");
+        assert_eq!(eval("131010", &transforms), "131010");
+        assert_eq!(eval("ab", &[FieldTransform::Upper]), "AB");
+    }
+
+    #[test]
+    fn test_src_sql() {
+        let transforms = [FieldTransform::ZeroPad(5)];
+        let sql = src_sql("N03-20240101", &[("N03_007", &transforms)], None);
+        assert_eq!(
+            sql,
+            "SELECT *, CASE WHEN length(CAST(\"N03_007\" AS TEXT)) < 5 THEN substr('00000' || CAST(\"N03_007\" AS TEXT), -5, 5) ELSE CAST(\"N03_007\" AS TEXT) END AS \"N03_007__transformed\" FROM \"N03-20240101\""
+        );
+
+        // the SQL runs as is on SQLite
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE \"N03-20240101\" (\"N03_007\" INTEGER); INSERT INTO \"N03-20240101\" VALUES (1101);",
+        )
+        .unwrap();
+        let padded: String = conn
+            .query_row(
+                &format!(
+                    "SELECT \"N03_007__transformed\" FROM ({})",
+                    src_sql("N03-20240101", &[("N03_007", &transforms)], None)
+                ),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(padded, "01101");
+
+        // --where
+        assert_eq!(
+            src_sql("A31-27", &[], Some("A31_001='27100'")),
+            "SELECT * FROM \"A31-27\" WHERE (A31_001='27100')"
+        );
+        conn.execute_batch(
+            "CREATE TABLE \"A31-27\" (\"A31_001\" TEXT); INSERT INTO \"A31-27\" VALUES ('27100'), ('27200');",
+        )
+        .unwrap();
+        let count: i64 = conn
+            .query_row(
+                &format!(
+                    "SELECT count(*) FROM ({})",
+                    src_sql("A31-27", &[], Some("A31_001='27100'"))
+                ),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+}
@@ -1,23 +1,160 @@
 // the module responsible for opening ZIP files and traversing them.
 // sometimes, zip files are inside zip files, so when a zip file is encountered, we have to recursively traverse it.
 // only extracts shapefiles, to a temporary directory, so ogr2ogr can load them directly to the database.
+// optionally, files with extra extensions (csv, txt, ...) are extracted too, but returned separately
+// so they are never passed to ogr2ogr.
 
+use super::gdal;
 use super::mapping::ShapefileMetadata;
-use anyhow::{Context, Result};
+use super::quirks::{self, SkipRules};
+use crate::logging;
+use anyhow::{bail, Context, Result};
+use bytesize::ByteSize;
 use regex::Regex;
-use std::{fs::File, path::PathBuf};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use zip::ZipArchive;
 
+/// Files extracted from a ZIP archive.
+#[derive(Debug, Default)]
+pub struct ZipContents {
+    /// `.shp` files to be passed to ogr2ogr
+    pub shapefiles: Vec<PathBuf>,
+    /// files matching the extra extension allowlist
+    pub extra_files: Vec<PathBuf>,
+}
+
+/// Entries that are never extracted, regardless of the matchers.
+#[derive(Clone)]
+struct EntryFilter {
+    /// entries whose path matches one of these (`--skip-rules` and the built-in quirks)
+    skip_patterns: Vec<Regex>,
+    prefer_utf8_dirs: bool,
+    /// only entries under a directory named `<subcategory>_...` are extracted
+    subcategory: Option<String>,
+}
+
+/// Whether one of the directories of `file_name` is the subcategory's, e.g. `20_想定最大規模/`.
+fn in_subcategory(file_name: &str, subcategory: &str) -> bool {
+    let prefix = format!("{}_", subcategory);
+    let mut dirs = file_name.split('/').rev().skip(1);
+    dirs.any(|dir| dir.starts_with(&prefix))
+}
+
+/// Guards against ZIP bombs and runaway nesting (`--max-zip-depth`, `--max-extracted-size`).
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractLimits {
+    /// how deep ZIP files inside ZIP files are followed; the downloaded ZIP is depth 0
+    pub max_depth: usize,
+    /// the total bytes written when extracting one downloaded ZIP, nested ZIPs included
+    pub max_total_bytes: u64,
+}
+
+pub const DEFAULT_MAX_ZIP_DEPTH: usize = 5;
+pub const DEFAULT_MAX_EXTRACTED_BYTES: u64 = 20 * 1024 * 1024 * 1024;
+
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: DEFAULT_MAX_ZIP_DEPTH,
+            max_total_bytes: DEFAULT_MAX_EXTRACTED_BYTES,
+        }
+    }
+}
+
+/// What has been used of the limits while extracting one downloaded ZIP.
+struct ExtractBudget {
+    limits: ExtractLimits,
+    depth: usize,
+    written: u64,
+}
+
+impl ExtractBudget {
+    fn new(limits: ExtractLimits) -> Self {
+        Self {
+            limits,
+            depth: 0,
+            written: 0,
+        }
+    }
+
+    /// Copies an entry to `dest`, failing once the total would exceed the limit.
+    fn copy(&mut self, entry: &mut impl Read, dest: &Path, zip_path: &Path) -> Result<()> {
+        let remaining = self.limits.max_total_bytes.saturating_sub(self.written);
+        let written = std::io::copy(&mut entry.take(remaining + 1), &mut File::create(dest)?)?;
+        self.written += written;
+        if self.written > self.limits.max_total_bytes {
+            bail!(
+                "extracting {} (nesting depth {}) exceeds the limit of {} in total (--max-extracted-size)",
+                zip_path.display(),
+                self.depth,
+                ByteSize(self.limits.max_total_bytes)
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Extracted {
+    matched: Vec<PathBuf>,
+    extra: Vec<PathBuf>,
+}
+
+fn extra_extensions_regex(extra_extensions: &[String]) -> Result<Option<Regex>> {
+    let extensions = extra_extensions
+        .iter()
+        .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+        .filter(|e| !e.is_empty())
+        .map(|e| regex::escape(&e))
+        .collect::<Vec<_>>();
+    if extensions.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(Regex::new(&format!(
+        r"(?i)\.(?:{})$",
+        extensions.join("|")
+    ))?))
+}
+
+/// The directory a ZIP file is extracted to: `<outdir>/<zip file name without .zip>`.
+pub fn extraction_dir(outdir: &Path, zip_path: &Path) -> PathBuf {
+    let zip_filename = zip_path.file_name().unwrap().to_str().unwrap();
+    outdir.join(zip_filename).with_extension("")
+}
+
 fn extract_zip(
     outdir: &PathBuf,
     zip_path: &PathBuf,
     matchers: &Vec<Regex>,
-) -> Result<Vec<PathBuf>> {
-    let mut out = vec![];
+    extra_matcher: Option<&Regex>,
+    filter: &EntryFilter,
+    budget: &mut ExtractBudget,
+) -> Result<Extracted> {
+    if budget.depth > budget.limits.max_depth {
+        bail!(
+            "{} is nested {} ZIP files deep, more than the limit of {} (--max-zip-depth)",
+            zip_path.display(),
+            budget.depth,
+            budget.limits.max_depth
+        );
+    }
+    let mut out = Extracted::default();
     let file = File::open(zip_path)?;
-    let zip_filename = zip_path.file_name().unwrap().to_str().unwrap();
-    let outdir = outdir.join(zip_filename).with_extension("");
+    let outdir = extraction_dir(outdir, zip_path);
     let mut zip = ZipArchive::new(file)?;
+    let skipped_by_rule = |name: &str| filter.skip_patterns.iter().any(|re| re.is_match(name));
+    // entries skipped by a rule don't count as duplicates of their siblings
+    let entry_names = zip
+        .file_names()
+        .map(|name| name.replace("\\", "/"))
+        .filter(|name| !skipped_by_rule(name))
+        .collect::<Vec<_>>();
+    let skip_prefixes = quirks::duplicate_encoding_dirs(
+        entry_names.iter().map(String::as_str),
+        filter.prefer_utf8_dirs,
+    );
     // println!("Matchers: {:?}", matchers);
     for i in 0..zip.len() {
         let mut file = zip.by_index(i)?;
@@ -25,23 +162,49 @@ fn extract_zip(
         let file_name = file.name().to_string().replace("\\", "/");
         let dest_path = outdir.join(&file_name);
         let basedir = dest_path.parent().unwrap();
+        if skipped_by_rule(&file_name) || skip_prefixes.iter().any(|p| file_name.starts_with(p)) {
+            continue;
+        }
 
         // println!("Extracting: {}", file_name);
         if file_name.ends_with(".zip") {
             std::fs::create_dir_all(&basedir)?;
-            std::io::copy(&mut file, &mut File::create(&dest_path)?)?;
-            out.extend(
-                extract_zip(&outdir, &dest_path, &matchers)
-                    .with_context(|| format!("when extracting nested {}", dest_path.display()))?,
-            );
+            budget.copy(&mut file, &dest_path, zip_path)?;
+            // a nested zip inside the subcategory's directory belongs to it entirely
+            let nested_filter = match &filter.subcategory {
+                Some(subcategory) if in_subcategory(&file_name, subcategory) => EntryFilter {
+                    subcategory: None,
+                    ..filter.clone()
+                },
+                _ => filter.clone(),
+            };
+            budget.depth += 1;
+            let nested = extract_zip(
+                &outdir,
+                &dest_path,
+                &matchers,
+                extra_matcher,
+                &nested_filter,
+                budget,
+            )
+            .with_context(|| format!("when extracting nested {}", dest_path.display()))?;
+            budget.depth -= 1;
+            out.matched.extend(nested.matched);
+            out.extra.extend(nested.extra);
+        } else if filter
+            .subcategory
+            .as_ref()
+            .is_some_and(|subcategory| !in_subcategory(&file_name, subcategory))
+        {
+            continue;
         } else if matchers.iter().any(|r| r.is_match(&file_name)) {
-            if file_name.starts_with("N08-21_GML/utf8/") {
-                // skip this file, it's a duplicate and contains malformed UTF8
-                continue;
-            }
             std::fs::create_dir_all(&basedir)?;
-            std::io::copy(&mut file, &mut File::create(&dest_path)?)?;
-            out.push(dest_path);
+            budget.copy(&mut file, &dest_path, zip_path)?;
+            out.matched.push(dest_path);
+        } else if extra_matcher.is_some_and(|r| r.is_match(&file_name)) {
+            std::fs::create_dir_all(&basedir)?;
+            budget.copy(&mut file, &dest_path, zip_path)?;
+            out.extra.push(dest_path);
         }
     }
     Ok(out)
@@ -51,58 +214,105 @@ pub async fn matching_shapefiles_in_zip(
     tmp: &PathBuf,
     zip_path: &PathBuf,
     mapping: &ShapefileMetadata,
-) -> Result<Vec<PathBuf>> {
+    extra_extensions: &[String],
+    prefer_utf8_dirs: bool,
+    limits: ExtractLimits,
+    skip_rules: &SkipRules,
+) -> Result<ZipContents> {
     let shp_tmp = tmp.join("shp");
     tokio::fs::create_dir_all(&shp_tmp).await?;
     let matchers = mapping.shapefile_name_regex.clone();
     let zip_path = zip_path.clone();
+    let extra_matcher = extra_extensions_regex(extra_extensions)?;
+    let filter = std::sync::Arc::new(EntryFilter {
+        skip_patterns: skip_rules.patterns_for(&mapping.original_identifier),
+        prefer_utf8_dirs,
+        subcategory: mapping.subcategory.clone(),
+    });
 
-    let mut all_paths = {
+    let mut extracted = {
+        let filter = filter.clone();
         let shp_tmp = shp_tmp.clone();
         let zip_path = zip_path.clone();
-        if mapping.original_identifier == "A33" {
-            // A33 shapefiles don't match the regex, so we'll skip this part and
-            // fall back to the expanded matchers, focusing on Polygon files only
-            let expanded_matchers = vec![Regex::new(
-                r"Po?lygon(?i:(?:\.shp|\.cpg|\.dbf|\.prj|\.qmd|\.shx))$",
-            )?];
-
-            tokio::task::spawn_blocking(move || {
-                extract_zip(&shp_tmp, &zip_path, &expanded_matchers)
-                    .with_context(|| format!("when extracting {}", zip_path.display()))
-            })
-            .await??
-        } else {
-            tokio::task::spawn_blocking(move || {
-                extract_zip(&shp_tmp, &zip_path, &matchers)
-                    .with_context(|| format!("when extracting {}", zip_path.display()))
-            })
-            .await??
-        }
+        let extra_matcher = extra_matcher.clone();
+        tokio::task::spawn_blocking(move || {
+            extract_zip(
+                &shp_tmp,
+                &zip_path,
+                &matchers,
+                extra_matcher.as_ref(),
+                &filter,
+                &mut ExtractBudget::new(limits),
+            )
+            .with_context(|| format!("when extracting {}", zip_path.display()))
+        })
+        .await??
     };
 
-    if all_paths.is_empty() {
+    if extracted.matched.is_empty() {
         println!("No shapefiles found in zip file, expanding matchers...");
+        logging::detail(format!(
+            "warning: {} の matcher ({}) に一致するシェープファイルが {} にないため、すべてのシェープファイルを対象にします",
+            mapping.identifier,
+            mapping
+                .shapefile_name_regex
+                .iter()
+                .map(|re| re.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            zip_path.display()
+        ));
         // since we didn't get any shapefiles this time, let's expand the matchers to see if we can find any
         let expanded_matchers = vec![Regex::new(
             r"(?i:(?:\.shp|\.cpg|\.dbf|\.prj|\.qmd|\.shx))$",
         )?];
 
-        all_paths = tokio::task::spawn_blocking(move || {
-            extract_zip(&shp_tmp, &zip_path, &expanded_matchers)
-                .with_context(|| format!("when extracting {}", zip_path.display()))
+        let extra_files = extracted.extra;
+        extracted = tokio::task::spawn_blocking(move || {
+            extract_zip(
+                &shp_tmp,
+                &zip_path,
+                &expanded_matchers,
+                None,
+                &filter,
+                &mut ExtractBudget::new(limits),
+            )
+            .with_context(|| format!("when extracting {}", zip_path.display()))
         })
         .await??;
+        extracted.extra = extra_files;
     }
 
     // at this point, we have decompressed all shapefiles (and accompanying files)
     // however, we only need the `.shp` files for passing to ogr2ogr
-    let shapefile_paths = all_paths
+    let mut shapefile_paths = extracted
+        .matched
         .iter()
         .filter(|p| p.extension().unwrap() == "shp")
         .cloned()
         .collect::<Vec<_>>();
 
+    if let Some(geometry_filter) = mapping.geometry_filter {
+        let mut kept = Vec::with_capacity(shapefile_paths.len());
+        for shape in shapefile_paths {
+            let (matches, geometry_type) = gdal::matches_geometry_filter(&shape, geometry_filter)
+                .await
+                .with_context(|| format!("when checking the geometry of {}", shape.display()))?;
+            if matches {
+                kept.push(shape);
+            } else {
+                logging::detail(format!(
+                    "{}: ジオメトリが {:?} ではない ({}) ため、{} をスキップします",
+                    mapping.identifier,
+                    geometry_filter,
+                    geometry_type,
+                    shape.display()
+                ));
+            }
+        }
+        shapefile_paths = kept;
+    }
+
     // println!(
     //     "Found {} shapefiles: \n{}",
     //     shapefile_paths.len(),
@@ -113,7 +323,10 @@ pub async fn matching_shapefiles_in_zip(
     //         .join("\n")
     // );
 
-    Ok(shapefile_paths)
+    Ok(ZipContents {
+        shapefiles: shapefile_paths,
+        extra_files: extracted.extra,
+    })
 }
 
 #[cfg(test)]
@@ -135,16 +348,80 @@ mod tests {
             field_mappings: vec![],
             original_identifier: "original_identifier".to_string(),
             identifier: "identifier".to_string(),
+            primary_key: None,
+            field_transforms: vec![],
+            subcategory: None,
+            geometry_filter: None,
+            encoding: None,
+            field_types: vec![],
+            keep_all_fields: false,
+            where_clause: None,
             shapefile_name_regex: vec![Regex::new(
                 r"A30a5-\d{2}_\d{4}_SedimentDisasterAndSnowslide(?i:(?:\.shp|\.cpg|\.dbf|\.prj|\.qmd|\.shx))$",
             )
             .unwrap()],
         };
-        let result = matching_shapefiles_in_zip(&tmp, &zip, &mapping).await;
+        let result = matching_shapefiles_in_zip(
+            &tmp,
+            &zip,
+            &mapping,
+            &[],
+            false,
+            ExtractLimits::default(),
+            &SkipRules::default(),
+        )
+        .await;
         assert!(result.is_ok());
         let _ = result.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_matching_shapefiles_in_zip_extra_extensions() {
+        let tmp = PathBuf::from("./tmp");
+        let zip = PathBuf::from("./test_data/zip/P23-12_38_GML.zip");
+        let mapping = ShapefileMetadata {
+            cat1: "cat1".to_string(),
+            cat2: "cat2".to_string(),
+            name: "name".to_string(),
+            version: "version".to_string(),
+            data_year: "data_year".to_string(),
+            shapefile_matcher: vec!["P23a-YY_PP.shp".to_string()],
+            field_mappings: vec![],
+            original_identifier: "original_identifier".to_string(),
+            identifier: "identifier".to_string(),
+            primary_key: None,
+            field_transforms: vec![],
+            subcategory: None,
+            geometry_filter: None,
+            encoding: None,
+            field_types: vec![],
+            keep_all_fields: false,
+            where_clause: None,
+            shapefile_name_regex: vec![Regex::new(
+                r"(?:^|/)P23a-\d{2}_\d{2}(?i:(?:\.shp|\.cpg|\.dbf|\.prj|\.qmd|\.shx))$",
+            )
+            .unwrap()],
+        };
+        let contents = matching_shapefiles_in_zip(
+            &tmp,
+            &zip,
+            &mapping,
+            &[".XML".to_string()],
+            false,
+            ExtractLimits::default(),
+            &SkipRules::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(contents.shapefiles.len(), 1);
+        assert!(contents.shapefiles[0].ends_with("P23a-12_38.shp"));
+        assert_eq!(contents.extra_files.len(), 2);
+        assert!(contents
+            .extra_files
+            .iter()
+            .all(|p| p.extension().unwrap() == "xml" && p.exists()));
+    }
+
     #[tokio::test]
     async fn test_matching_shapefiles_in_zip_subdir() {
         let tmp = PathBuf::from("./tmp");
@@ -159,13 +436,295 @@ mod tests {
             field_mappings: vec![],
             original_identifier: "original_identifier".to_string(),
             identifier: "identifier".to_string(),
+            primary_key: None,
+            field_transforms: vec![],
+            subcategory: None,
+            geometry_filter: None,
+            encoding: None,
+            field_types: vec![],
+            keep_all_fields: false,
+            where_clause: None,
             shapefile_name_regex: vec![Regex::new(
                 r"(?:^|/)P23a-\d{2}_\d{2}(?i:(?:\.shp|\.cpg|\.dbf|\.prj|\.qmd|\.shx))$",
             )
             .unwrap()],
         };
-        let result = matching_shapefiles_in_zip(&tmp, &zip, &mapping).await;
+        let result = matching_shapefiles_in_zip(
+            &tmp,
+            &zip,
+            &mapping,
+            &[],
+            false,
+            ExtractLimits::default(),
+            &SkipRules::default(),
+        )
+        .await;
         assert!(result.is_ok());
         let _ = result.unwrap();
     }
+
+    fn write_fixture_zip(path: &std::path::Path, entries: &[&str]) {
+        use std::io::Write;
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        for entry in entries {
+            zip.start_file(*entry, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(b"dummy").unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn zip_bytes(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        use std::io::Write;
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, contents) in entries {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn fixture_mapping(original_identifier: &str, pattern: &str) -> ShapefileMetadata {
+        ShapefileMetadata {
+            cat1: "cat1".to_string(),
+            cat2: "cat2".to_string(),
+            name: "name".to_string(),
+            version: "version".to_string(),
+            data_year: "data_year".to_string(),
+            shapefile_matcher: vec![],
+            field_mappings: vec![],
+            original_identifier: original_identifier.to_string(),
+            identifier: original_identifier.to_string(),
+            primary_key: None,
+            field_transforms: vec![],
+            subcategory: None,
+            geometry_filter: None,
+            encoding: None,
+            field_types: vec![],
+            keep_all_fields: false,
+            where_clause: None,
+            shapefile_name_regex: vec![Regex::new(pattern).unwrap()],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_matching_shapefiles_in_zip_limits() {
+        let tmp = PathBuf::from("./tmp/test_zip_limits");
+        let zip = tmp.join("X01-21_GML.zip");
+        // X01-21_GML.zip > inner1.zip > inner2.zip > X01-21.shp
+        let mut bytes = zip_bytes(&[("X01-21.shp", b"dummy")]);
+        for name in ["inner2.zip", "inner1.zip"] {
+            bytes = zip_bytes(&[(name, &bytes)]);
+        }
+        std::fs::create_dir_all(&tmp).unwrap();
+        std::fs::write(&zip, bytes).unwrap();
+        let mapping = fixture_mapping("X01", r"X01-\d{2}\.shp$");
+
+        let contents = matching_shapefiles_in_zip(
+            &tmp,
+            &zip,
+            &mapping,
+            &[],
+            false,
+            ExtractLimits::default(),
+            &SkipRules::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(contents.shapefiles.len(), 1);
+
+        let limits = ExtractLimits {
+            max_depth: 1,
+            ..ExtractLimits::default()
+        };
+        let error = matching_shapefiles_in_zip(
+            &tmp,
+            &zip,
+            &mapping,
+            &[],
+            false,
+            limits,
+            &SkipRules::default(),
+        )
+        .await
+        .unwrap_err();
+        let error = format!("{:#}", error);
+        assert!(
+            error.contains("inner2.zip is nested 2 ZIP files deep"),
+            "{}",
+            error
+        );
+
+        let limits = ExtractLimits {
+            max_total_bytes: 100,
+            ..ExtractLimits::default()
+        };
+        let error = matching_shapefiles_in_zip(
+            &tmp,
+            &zip,
+            &mapping,
+            &[],
+            false,
+            limits,
+            &SkipRules::default(),
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains("--max-extracted-size"));
+    }
+
+    #[tokio::test]
+    async fn test_matching_shapefiles_in_zip_duplicate_encoding_dirs() {
+        let tmp = PathBuf::from("./tmp/test_encoding_dirs");
+        let zip = tmp.join("W09-21_GML.zip");
+        write_fixture_zip(
+            &zip,
+            &[
+                "W09-21_GML/sjis/W09-21.shp",
+                "W09-21_GML/sjis/W09-21.dbf",
+                "W09-21_GML/utf8/W09-21.shp",
+                "W09-21_GML/utf8/W09-21.dbf",
+            ],
+        );
+        let mapping = fixture_mapping("W09", r"W09-\d{2}(?i:(?:\.shp|\.dbf))$");
+
+        let contents = matching_shapefiles_in_zip(
+            &tmp,
+            &zip,
+            &mapping,
+            &[],
+            false,
+            ExtractLimits::default(),
+            &SkipRules::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(contents.shapefiles.len(), 1);
+        assert!(contents.shapefiles[0].ends_with("sjis/W09-21.shp"));
+
+        let contents = matching_shapefiles_in_zip(
+            &tmp,
+            &zip,
+            &mapping,
+            &[],
+            true,
+            ExtractLimits::default(),
+            &SkipRules::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(contents.shapefiles.len(), 1);
+        assert!(contents.shapefiles[0].ends_with("utf8/W09-21.shp"));
+    }
+
+    #[tokio::test]
+    async fn test_matching_shapefiles_in_zip_n08_quirk() {
+        let tmp = PathBuf::from("./tmp/test_n08_quirk");
+        let zip = tmp.join("N08-21_GML.zip");
+        write_fixture_zip(
+            &zip,
+            &["N08-21_GML/sjis/N08-21.shp", "N08-21_GML/utf8/N08-21.shp"],
+        );
+        let mapping = fixture_mapping("N08", r"N08-\d{2}\.shp$");
+
+        // the N08-21 utf8/ directory is malformed, so it is skipped even when utf8/ is preferred
+        let contents = matching_shapefiles_in_zip(
+            &tmp,
+            &zip,
+            &mapping,
+            &[],
+            true,
+            ExtractLimits::default(),
+            &SkipRules::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(contents.shapefiles.len(), 1);
+        assert!(contents.shapefiles[0].ends_with("sjis/N08-21.shp"));
+    }
+
+    #[tokio::test]
+    async fn test_matching_shapefiles_in_zip_subcategory() {
+        let tmp = PathBuf::from("./tmp/test_subcategory");
+        let zip = tmp.join("A31a-24_81_8101_SHP.zip");
+        let other = tmp.join("A31a-24_81_8102_SHP.zip");
+        write_fixture_zip(&other, &["41_家屋倒壊等氾濫想定区域_氾濫流/A31a-41.shp"]);
+        write_fixture_zip(
+            &zip,
+            &[
+                "A31a-24_81_8101_SHP/10_計画規模/A31a-10.shp",
+                "A31a-24_81_8101_SHP/20_想定最大規模/A31a-20.shp",
+                "A31a-24_81_8101_SHP/30_浸水継続時間/A31a-30.shp",
+            ],
+        );
+        let mut mapping = fixture_mapping("A31a", r"A31a-\d{2}\.shp$");
+
+        mapping.subcategory = Some("20".to_string());
+        let contents = matching_shapefiles_in_zip(
+            &tmp,
+            &zip,
+            &mapping,
+            &[],
+            false,
+            ExtractLimits::default(),
+            &SkipRules::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(contents.shapefiles.len(), 1);
+        assert!(contents.shapefiles[0].ends_with("20_想定最大規模/A31a-20.shp"));
+
+        // the expanded matchers don't fall back to the other subdirectories
+        mapping.subcategory = Some("41".to_string());
+        let contents = matching_shapefiles_in_zip(
+            &tmp,
+            &zip,
+            &mapping,
+            &[],
+            false,
+            ExtractLimits::default(),
+            &SkipRules::default(),
+        )
+        .await
+        .unwrap();
+        assert!(contents.shapefiles.is_empty());
+
+        let contents = matching_shapefiles_in_zip(
+            &tmp,
+            &other,
+            &mapping,
+            &[],
+            false,
+            ExtractLimits::default(),
+            &SkipRules::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(contents.shapefiles.len(), 1);
+
+        mapping.subcategory = None;
+        let contents = matching_shapefiles_in_zip(
+            &tmp,
+            &zip,
+            &mapping,
+            &[],
+            false,
+            ExtractLimits::default(),
+            &SkipRules::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(contents.shapefiles.len(), 3);
+    }
+
+    #[test]
+    fn test_in_subcategory() {
+        assert!(in_subcategory("X_SHP/10_計画規模/a.shp", "10"));
+        assert!(!in_subcategory("X_SHP/10_計画規模/a.shp", "20"));
+        assert!(!in_subcategory("X_SHP/a.shp", "10"));
+        // only directories count, not the file name itself
+        assert!(!in_subcategory("X_SHP/10_a.shp", "10"));
+    }
 }
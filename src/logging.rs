@@ -0,0 +1,216 @@
+// Persistent run log, written alongside (not instead of) the terminal progress bars.
+// Nothing is written unless `--log-file` was given.
+
+use anyhow::{Context, Result};
+use indicatif::{MultiProgress, ProgressBar};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock, PoisonError, RwLock};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+pub struct Logger {
+    file: Mutex<File>,
+}
+
+impl Logger {
+    /// ログファイルを開きます。
+    /// `append` が false の場合、既存のファイルは `<path>.1` にローテーションされます。
+    pub fn open(path: &Path, append: bool) -> Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("when creating log directory {}", parent.display()))?;
+        }
+        if !append && path.exists() {
+            let rotated = rotated_path(path);
+            std::fs::rename(path, &rotated).with_context(|| {
+                format!("when rotating {} to {}", path.display(), rotated.display())
+            })?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("when opening log file {}", path.display()))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn write(&self, message: &str) {
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        let mut file = self.file.lock().unwrap();
+        // a failing log write should never abort the run
+        let _ = writeln!(file, "{} {}", timestamp, message);
+    }
+}
+
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+pub fn init(path: &Path, append: bool) -> Result<()> {
+    let logger = Logger::open(path, append)?;
+    if LOGGER.set(logger).is_err() {
+        anyhow::bail!("log file is already initialized");
+    }
+    Ok(())
+}
+
+/// ログファイルにイベントを1行書き込みます。`--log-file` 未指定の場合は何もしません。
+pub fn event(message: impl AsRef<str>) {
+    if let Some(logger) = LOGGER.get() {
+        logger.write(message.as_ref());
+    }
+}
+
+static VERBOSE: RwLock<bool> = RwLock::new(false);
+/// `--verbose`: 詳細な診断メッセージを出力します（複数回設定できます）
+pub fn set_verbose(verbose: bool) {
+    *VERBOSE.write().unwrap_or_else(PoisonError::into_inner) = verbose;
+}
+pub fn is_verbose() -> bool {
+    *VERBOSE.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+static PROGRESS: OnceLock<MultiProgress> = OnceLock::new();
+/// 新しいプログレスバー。ダウンロードと取り込みを並行して行う場合も、バーは重ならずに表示されます
+pub fn progress_bar() -> ProgressBar {
+    PROGRESS
+        .get_or_init(MultiProgress::new)
+        .add(ProgressBar::new(0))
+}
+
+/// 診断メッセージを出力します（ログファイルにも書き込みます）。`--verbose` 未指定の場合は何もしません。
+pub fn detail(message: impl AsRef<str>) {
+    if is_verbose() {
+        println!("{}", message.as_ref());
+        event(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_verbose_more_than_once() {
+        // other tests run with the default concurrently, so it is only set to it
+        set_verbose(false);
+        set_verbose(false);
+        assert!(!is_verbose());
+    }
+
+    /// A mock run: a dataset whose download is not a ZIP fails, one with an existing output is
+    /// loaded (skipped), and the summary is written at the end.
+    #[tokio::test]
+    async fn test_log_file_events() {
+        use crate::loader::{LoaderBuilder, OutputTarget};
+        use crate::report::RunSummary;
+        use crate::test_helpers::{fixture_dataset, spawn_http_server};
+
+        let dir = PathBuf::from("./tmp/test_logging_events");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run.log");
+        // the only test with a log file; the events of the tests running alongside end up in it too
+        init(&path, false).unwrap();
+
+        let base = spawn_http_server(vec![("/LOGNG.zip", 200, b"not a zip".to_vec())]).await;
+        let url = base.join("LOGNG.zip").unwrap();
+        let downloaded =
+            crate::downloader::download_to_tmp_from(&url, &["127.0.0.1".to_string()], &|_| {})
+                .await
+                .unwrap();
+        let mut failing = fixture_dataset("LOGNG", vec![]);
+        failing.zip_file_paths = vec![downloaded.path.clone()];
+
+        let output = OutputTarget::File {
+            output_dir: dir.join("out"),
+            gdal_driver: "GeoJSON".to_string(),
+            file_extension: "geojson".to_string(),
+            compression: Some(crate::loader::Compression::Zstd),
+            single_file: None,
+        };
+        // already loaded by an earlier run (as its table name), which needs no GDAL
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        std::fs::write(output.output_path("logok").unwrap(), "").unwrap();
+        std::fs::write(output.metadata_path("logok").unwrap(), "{}").unwrap();
+        let loaded = fixture_dataset("LOGOK", vec![]);
+
+        let report = LoaderBuilder::default()
+            .output(output)
+            .skip_if_exists(true)
+            .load_concurrency(Some(1))
+            .build()
+            .unwrap()
+            .load_stream(futures_util::stream::iter(vec![loaded, failing]))
+            .await
+            .unwrap();
+        RunSummary {
+            succeeded: report.succeeded,
+            failed: report.failed,
+            ..Default::default()
+        }
+        .log();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let position = |event: &str| {
+            contents
+                .lines()
+                .position(|line| line.contains(event))
+                .unwrap_or_else(|| panic!("{:?} is not in the log:\n{}", event, contents))
+        };
+        assert!(position("load start: LOGOK") < position("load end: LOGOK"));
+        assert!(position("load start: LOGNG") < position("load error: LOGNG: "));
+        assert!(position("load error: LOGNG: ") < position("summary: ロード成功 1 データセット"));
+        position("summary: ロード失敗 1 データセット（LOGNG）");
+        position("summary: --filter-identifiers LOGNG");
+
+        let (_, meta_path) = crate::downloader::path_for_url(&url);
+        let _ = std::fs::remove_file(&downloaded.path);
+        let _ = std::fs::remove_file(meta_path);
+    }
+
+    #[test]
+    fn test_log_file_rotation() {
+        let dir = PathBuf::from("./tmp/test_logging");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run.log");
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(rotated_path(&path));
+
+        let logger = Logger::open(&path, false).unwrap();
+        logger.write("first run");
+        logger.write("first run again");
+        logger.write("first run end");
+        drop(logger);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        // appending keeps the previous run
+        let logger = Logger::open(&path, true).unwrap();
+        logger.write("second run");
+        drop(logger);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 4);
+
+        // without append, the previous log is rotated away
+        let logger = Logger::open(&path, false).unwrap();
+        logger.write("third run");
+        drop(logger);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert_eq!(
+            std::fs::read_to_string(rotated_path(&path))
+                .unwrap()
+                .lines()
+                .count(),
+            4
+        );
+    }
+}
@@ -1,68 +1,439 @@
-#![warn(unused_extern_crates)]
-
 use anyhow::{Context, Result};
-use std::path::PathBuf;
+use jpksj_to_sql::{
+    circuit_breaker, context, doctor, downloader, export, list, loader, logging, plan, report,
+    scraper, stac,
+};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 mod cli;
-mod context;
-mod downloader;
-mod loader;
-mod metadata;
-mod scraper;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = cli::main();
-    loader::check_gdal_tools()
-        .await
-        .context("while checking GDAL tools")?;
-    if let Some(tmp) = args.tmp_dir {
+    let mut args = cli::main();
+    if let Some(log_file) = &args.log_file {
+        logging::init(log_file, args.log_append).context("while opening log file")?;
+    }
+    logging::set_verbose(args.verbose);
+    context::set_http_client(context::build_http_client(
+        args.user_agent.as_deref(),
+        context::https_proxy_from_env().as_deref(),
+    )?);
+    // `list` and `--print-tables` read the cached dataset list from here too
+    if let Some(tmp) = args.tmp_dir.clone() {
         context::set_tmp(tmp);
     }
-    tokio::fs::create_dir_all(context::tmp()).await?;
+    scraper::initial::set_cache_max_age(args.use_cached_index.then(|| {
+        args.index_cache_max_age
+            .map(|hours| Duration::from_secs_f64(hours * 3600.0))
+            .unwrap_or(scraper::initial::DEFAULT_CACHE_MAX_AGE)
+    }));
+    if args.command == Some(cli::Command::Doctor) {
+        // PostgreSQL is only checked when a connection string is available
+        let postgres_url = if is_postgres_format(&normalize_format(&args.output_format)) {
+            args.resolve_output_destination(true).ok()
+        } else {
+            None
+        };
+        return doctor::run(postgres_url.as_deref()).await;
+    }
+    if let Some(cli::Command::List { json }) = args.command {
+        return list::run(json).await;
+    }
+    if let Some(identifier) = &args.print_tables {
+        let prefix = args.table_prefix.as_deref().unwrap_or_default();
+        let tables = loader::target_tables(identifier, prefix)
+            .await
+            .with_context(|| format!("while resolving tables of {}", identifier))?;
+        for table in tables {
+            println!("{}", table);
+        }
+        return Ok(());
+    }
+    // --print-plan and --dry-run only scrape the catalog
+    let plan_only = args.print_plan.is_some() || args.dry_run;
+    // resolve the destination before spending time on downloads
+    let output_destination = if !plan_only {
+        let is_postgres = is_postgres_format(&normalize_format(&args.output_format));
+        if args.fill_gaps && !is_postgres {
+            anyhow::bail!("--fill-gaps is only supported for PostgreSQL outputs");
+        }
+        Some(args.resolve_output_destination(is_postgres)?)
+    } else {
+        None
+    };
+    if !plan_only {
+        let is_postgres = is_postgres_format(&normalize_format(&args.output_format));
+        let gdal = loader::check_gdal_tools(is_postgres)
+            .await
+            .context("while checking GDAL tools")?;
+        if let Some(warning) = gdal.version_warning() {
+            eprintln!("警告: {}", warning);
+        }
+    }
+    // a broken rules file should fail before the downloads
+    let skip_rules = match &args.skip_rules {
+        Some(path) => loader::SkipRules::default()
+            .with_file(path)
+            .context("while reading skip rules")?,
+        None => loader::SkipRules::default(),
+    };
+    if !args.dry_run {
+        tokio::fs::create_dir_all(context::tmp()).await?;
+    }
+    downloader::set_per_host_limit(args.parallel_downloads_per_host);
+    downloader::set_max_rate(args.max_download_rate.map(|rate| rate.as_u64()));
+    downloader::set_verify(!args.no_verify);
+    if let Some(hosts) = &args.allowed_hosts {
+        downloader::set_allowed_hosts(hosts.clone());
+    }
+    loader::set_ogrinfo_extra_args(args.ogrinfo_extra.clone().unwrap_or_default().0);
+    loader::set_debug_encoding(args.debug_encoding);
+
+    let mut years = args.years();
+    if let Some(path) = &args.retry_failed {
+        let previous = report::RunSummary::read(path)?;
+        let failed = previous.failed_identifiers();
+        if failed.is_empty() {
+            println!("{} に失敗したデータセットはありません。", path.display());
+            return Ok(());
+        }
+        println!(
+            "失敗した {} 件のデータセットを再実行します: {}",
+            failed.len(),
+            failed.join(", ")
+        );
+        args.filter_identifiers = Some(failed);
+        years = years.or(previous.config.year);
+    }
+    let run_config = report::RunConfig {
+        output_format: args.output_format.clone(),
+        year: years.clone(),
+        filter_identifiers: args.filter_identifiers.clone(),
+    };
+
+    let cached_pages = match &args.incremental_from {
+        Some(path) => export::read_cached_pages(path)
+            .with_context(|| format!("while reading catalog {}", path.display()))?,
+        None => Default::default(),
+    };
+
+    let output = output_destination
+        .as_deref()
+        .map(|destination| {
+            parse_output_target(
+                &args.output_format,
+                destination,
+                args.compress_output,
+                args.pg_schema.clone(),
+            )
+        })
+        .transpose()
+        .context("while parsing output settings")?;
 
-    // Download all files first
     let scraper = scraper::ScraperBuilder::default()
-        .skip_dl(args.skip_download)
+        .skip_dl(args.skip_download || plan_only)
         .filter_identifiers(args.filter_identifiers.clone())
-        .year(args.year)
+        .filter_category1(args.filter_category1.clone())
+        .filter_category2(args.filter_category2.clone())
+        .include_non_commercial(args.include_non_commercial)
+        .year(years.clone())
+        .fill_gaps(args.fill_gaps)
+        .suffix_year(args.suffix_year)
+        .download_concurrency(args.download_concurrency)
+        .breaker(circuit_breaker::BreakerConfig {
+            failure_threshold: args.breaker_threshold.max(1),
+            window: Duration::from_secs(args.breaker_window),
+            cooldown: Duration::from_secs(args.breaker_cooldown),
+            ..Default::default()
+        })
+        .cached_pages(cached_pages)
         .build()
         .context("while building scraper")?;
-    let datasets = scraper
-        .download_all()
-        .await
-        .with_context(|| format!("while downloading initial data"))?;
+    // datasets are loaded as soon as their files are downloaded, unless --sequential
+    // (--print-plan and --dry-run need the whole catalog, and load nothing)
+    let (scraped, pipelined_report) = match &output {
+        Some(output) if !args.sequential => {
+            let loader = loader_builder(&args, output, skip_rules.clone())
+                .build()
+                .context("while building loader")?;
+            let (ready_sender, ready) = async_channel::unbounded();
+            let (scraped, load_report) = tokio::try_join!(
+                async {
+                    scraper
+                        .download_each(Some(ready_sender))
+                        .await
+                        .context("while downloading initial data")
+                },
+                async {
+                    loader
+                        .load_stream(ready)
+                        .await
+                        .context("while loading datasets")
+                },
+            )?;
+            (scraped, Some(load_report))
+        }
+        _ => {
+            let scraped = scraper
+                .download_all()
+                .await
+                .with_context(|| format!("while downloading initial data"))?;
+            (scraped, None)
+        }
+    };
+    let scraper::Scraped {
+        datasets,
+        failures,
+        downloads,
+    } = scraped;
 
-    let output = parse_output_target(&args.output_format, &args.output_destination)
-        .context("while parsing output settings")?;
+    if args.dry_run {
+        let plans = plan::build_plan(&datasets)
+            .await
+            .context("while building plan")?;
+        print!("{}", plan::render_dry_run(&plans));
+        return Ok(());
+    }
 
-    let loader = loader::LoaderBuilder::default()
-        .datasets(datasets)
-        .output(output)
-        .skip_if_exists(args.skip_if_exists)
-        .build()
-        .context("while building loader")?;
-    loader
-        .load_all()
-        .await
-        .with_context(|| "while loading datasets")?;
+    if let Some(path) = &args.export_attributes {
+        export::write_attribute_catalog(&datasets, path)
+            .with_context(|| format!("while exporting attributes to {}", path.display()))?;
+    }
+    if let Some(path) = &args.catalog_sqlite {
+        export::write_sqlite_catalog(&datasets, path)
+            .with_context(|| format!("while writing SQLite catalog to {}", path.display()))?;
+    }
+
+    if let Some(format) = args.print_plan {
+        let plans = plan::build_plan(&datasets)
+            .await
+            .context("while building plan")?;
+        print!("{}", plan::render(&plans, format)?);
+        return Ok(());
+    }
+
+    let output = output.context("OUTPUT_DESTINATION is required")?;
+    let load_report = match pipelined_report {
+        Some(load_report) => load_report,
+        None => loader_builder(&args, &output, skip_rules)
+            .datasets(datasets.clone())
+            .build()
+            .context("while building loader")?
+            .load_all()
+            .await
+            .with_context(|| "while loading datasets")?,
+    };
+
+    if let Some(dir) = &args.stac {
+        stac::write_stac_catalog(dir, &datasets, &load_report.tables, &output)
+            .with_context(|| format!("while writing STAC catalog to {}", dir.display()))?;
+    }
+    let skipped = load_report
+        .tables
+        .iter()
+        .filter_map(|table| Some((table, table.skipped_features.filter(|n| *n > 0)?)))
+        .collect::<Vec<_>>();
+    if !skipped.is_empty() {
+        eprintln!("スキップされたフィーチャ:");
+        for (table, count) in skipped {
+            eprintln!(
+                "  {} ({}) {} 件",
+                table.identifier, table.dataset_identifier, count
+            );
+        }
+    }
+
+    let invalid = load_report
+        .tables
+        .iter()
+        .filter_map(|table| Some((table, table.invalid_geometries.filter(|n| *n > 0)?)))
+        .collect::<Vec<_>>();
+    if !invalid.is_empty() {
+        eprintln!("不正なジオメトリ:");
+        for (table, count) in invalid {
+            let fixed = match table.fixed_geometries {
+                Some(fixed) => format!("（{} 件修復）", fixed),
+                None => String::new(),
+            };
+            eprintln!(
+                "  {} ({}) {} 件{}",
+                table.identifier, table.dataset_identifier, count, fixed
+            );
+        }
+    }
+
+    let deduped = load_report
+        .tables
+        .iter()
+        .filter_map(|table| Some((table, table.removed_duplicates.filter(|n| *n > 0)?)))
+        .collect::<Vec<_>>();
+    if !deduped.is_empty() {
+        println!("削除した重複フィーチャ:");
+        for (table, count) in deduped {
+            println!(
+                "  {} ({}) {} 件",
+                table.identifier, table.dataset_identifier, count
+            );
+        }
+    }
+
+    if let Some(duration) = load_report.analyze_duration {
+        println!("ANALYZE の所要時間: {:.1} 秒", duration.as_secs_f64());
+    }
+
+    if args.row_counts {
+        println!("テーブルごとの行数:");
+        for table in &load_report.tables {
+            let count = table
+                .row_count
+                .map(|count| count.to_string())
+                .unwrap_or_else(|| "-".to_string());
+            println!("  {} {}", table.identifier, count);
+        }
+    }
+
+    let mut failed = failures;
+    failed.extend(load_report.failed);
+    let summary = report::RunSummary {
+        config: run_config,
+        succeeded: load_report.succeeded,
+        failed,
+        downloads,
+        analyze_seconds: load_report
+            .analyze_duration
+            .map(|duration| duration.as_secs_f64()),
+    };
+    print!("{}", summary.render());
+    summary.log();
+    logging::event("run finished");
+
+    if let Some(path) = &args.summary {
+        summary
+            .write(path)
+            .with_context(|| format!("while writing summary to {}", path.display()))?;
+    }
+    if let Some(path) = &args.error_report {
+        report::write_error_report(&summary.failed, path)
+            .with_context(|| format!("while writing error report to {}", path.display()))?;
+    }
+
+    if args.fail_on_error && !summary.failed.is_empty() {
+        anyhow::bail!(
+            "{} 件のデータセットが失敗しました: {}",
+            summary.failed_identifiers().len(),
+            summary.failed_identifiers().join(",")
+        );
+    }
+    if let (Some(min_rate), Some(rate)) = (args.min_success_rate, summary.success_rate()) {
+        if rate < min_rate {
+            anyhow::bail!(
+                "成功率 {:.1}% が --min-success-rate の {:.1}% を下回りました",
+                rate * 100.0,
+                min_rate * 100.0
+            );
+        }
+    }
 
     Ok(())
 }
 
-fn parse_output_target(format: &str, destination: &str) -> Result<loader::OutputTarget> {
+/// The loader settings from the command line, without the datasets.
+fn loader_builder(
+    args: &cli::Cli,
+    output: &loader::OutputTarget,
+    skip_rules: loader::SkipRules,
+) -> loader::LoaderBuilder {
+    let mut builder = loader::LoaderBuilder::default();
+    builder
+        .output(output.clone())
+        .skip_if_exists(args.skip_if_exists)
+        .extra_extensions(args.extra_extensions.clone().unwrap_or_default())
+        .primary_keys(args.primary_key.iter().cloned().collect())
+        .where_filters(args.where_filter.iter().cloned().collect())
+        .table_prefix(args.table_prefix.clone().unwrap_or_default())
+        .add_primary_keys(!args.no_index)
+        .create_indexes(!args.no_index)
+        .prefer_utf8_dirs(args.prefer_utf8_dirs)
+        .field_transforms(args.field_transform.iter().cloned().collect())
+        .geometry_filter(args.geometry_filter.clone())
+        .forced_encodings(args.force_encoding.iter().cloned().collect())
+        .keep_all_fields(args.keep_all_fields)
+        .fix_geometries(args.fix_geometries)
+        .dedupe(args.dedupe)
+        .collect_extents(args.stac.is_some())
+        .collect_row_counts(args.row_counts)
+        .analyze(args.analyze.then_some(args.analyze_concurrency))
+        .fill_gaps(args.fill_gaps)
+        .keep_intermediate(args.keep_intermediate)
+        .delete_zips_after_load(args.delete_zips_after_load)
+        .extract_limits(loader::ExtractLimits {
+            max_depth: args.max_zip_depth,
+            max_total_bytes: args.max_extracted_size.as_u64(),
+        })
+        .skip_rules(skip_rules)
+        .checkpoint(args.resume.then(loader::default_checkpoint_path))
+        .restart_checkpoint(args.force)
+        .load_concurrency(args.load_concurrency)
+        .db_write_concurrency(args.db_write_concurrency)
+        .ogr2ogr(loader::Ogr2OgrOptions {
+            geometry_column: args.geometry_column_name.clone(),
+            gdal_cache_max: args.gdal_cache_max.clone(),
+            group_transactions: args.ogr_group_transactions,
+            target_srs: args.target_srs.clone(),
+            source_srs: args.source_srs.clone(),
+            skip_failures: args.skip_invalid_geometries,
+            use_copy: !args.no_copy,
+            extra_args: args.ogr2ogr_extra.clone().unwrap_or_default().0,
+        });
+    builder
+}
+
+fn parse_output_target(
+    format: &str,
+    destination: &str,
+    compression: Option<loader::Compression>,
+    schema: Option<String>,
+) -> Result<loader::OutputTarget> {
     let normalized = normalize_format(format);
     if is_postgres_format(&normalized) {
+        if compression.is_some() {
+            anyhow::bail!("--compress-output is only supported for file outputs");
+        }
         return Ok(loader::OutputTarget::Postgres {
             postgres_url: destination.to_string(),
+            schema,
         });
     }
+    if schema.is_some() {
+        anyhow::bail!("--pg-schema is only supported for PostgreSQL output");
+    }
 
     let extension = file_extension_for_format(&normalized);
+    // `--format GPKG ./output/jpksj.gpkg` writes every table as a layer of that file
+    let destination = PathBuf::from(destination);
+    let single_file = (normalized == "gpkg"
+        && destination
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case(&extension)))
+    .then(|| destination.clone());
+    if single_file.is_some() && compression.is_some() {
+        anyhow::bail!("--compress-output is not supported when writing a single GeoPackage");
+    }
+    let output_dir = match &single_file {
+        Some(path) => path
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from(".")),
+        None => destination,
+    };
     Ok(loader::OutputTarget::File {
-        output_dir: PathBuf::from(destination),
+        output_dir,
         gdal_driver: format.to_string(),
         file_extension: extension,
+        compression,
+        single_file,
     })
 }
 
@@ -82,6 +453,7 @@ fn file_extension_for_format(normalized: &str) -> String {
         "geoparquet" | "parquet" => "parquet".to_string(),
         "geojson" | "geojsonseq" => "geojson".to_string(),
         "flatgeobuf" => "fgb".to_string(),
+        "gpkg" => "gpkg".to_string(),
         _ => normalize_extension(normalized),
     }
 }
@@ -1,13 +1,56 @@
+use crate::scraper::{data_page::DataPage, initial::DataItem};
 use crate::{loader::mapping::ShapefileMetadata, scraper::Dataset};
 use anyhow::{Context, Result};
 use km_to_sql::{
     metadata::{ColumnEnumDetails, ColumnForeignKeyDetails, ColumnMetadata, TableMetadata},
     postgres::{init_schema, upsert},
 };
-use std::sync::Arc;
+use serde::Serialize;
+use std::borrow::Cow;
 use tokio_postgres::{Client, NoTls};
+use url::Url;
 
-const INIT_SQL: &str = include_str!("../data/schema.sql");
+/// `data/migrations` の SQL（バージョン、名前、内容）。バージョン順に追加してください
+const MIGRATIONS: &[(i32, &str, &str)] = &[
+    (
+        1,
+        "001_init",
+        include_str!("../data/migrations/001_init.sql"),
+    ),
+    (
+        2,
+        "002_feature_counts",
+        include_str!("../data/migrations/002_feature_counts.sql"),
+    ),
+    (
+        3,
+        "003_table_bbox",
+        include_str!("../data/migrations/003_table_bbox.sql"),
+    ),
+];
+
+/// Serializes the migrations of concurrent workers (`pg_advisory_xact_lock`).
+const MIGRATION_LOCK_KEY: i64 = 0x6a706b736a;
+
+/// The migrations not yet in `applied`. A database migrated by a newer version of
+/// jpksj-to-sql is refused, as this version doesn't know its schema.
+fn pending_migrations(applied: &[i32]) -> Result<Vec<&'static (i32, &'static str, &'static str)>> {
+    let latest = MIGRATIONS.last().map_or(0, |(version, _, _)| *version);
+    if let Some(unknown) = applied.iter().find(|version| **version > latest) {
+        anyhow::bail!(
+            "データベースのスキーマ（バージョン {}）はこのバージョンの jpksj-to-sql（バージョン {} まで）より新しいため、更新できません。jpksj-to-sql を更新してください",
+            unknown,
+            latest
+        );
+    }
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|(version, _, _)| !applied.contains(version))
+        .collect())
+}
+
+/// 国土数値情報の利用約款。各テーブルのメタデータの `license_url` に記録します
+pub const KSJ_LICENSE_URL: &str = "https://nlftp.mlit.go.jp/ksj/other/agreement.html";
 
 #[derive(Clone, Debug)]
 pub struct ColumnSchema {
@@ -15,6 +58,193 @@ pub struct ColumnSchema {
     pub data_type: String,
 }
 
+/// データセット単位の完全なメタデータ（一覧のエントリとデータページ）
+/// ファイル出力の場合に `<identifier>.dataset.json` として書き出します。
+/// 属性の日本語名・説明やコードリストを、QGIS などで後から参照するためのものです
+#[derive(Debug, Serialize)]
+pub struct DatasetMetadata<'a> {
+    pub data_item: &'a DataItem,
+    pub data_page: &'a DataPage,
+}
+
+impl<'a> From<&'a Dataset> for DatasetMetadata<'a> {
+    fn from(dataset: &'a Dataset) -> Self {
+        DatasetMetadata {
+            data_item: &dataset.initial_item,
+            data_page: &dataset.page,
+        }
+    }
+}
+
+/// ファイル出力の `*.metadata.json`: テーブルのメタデータと、取り込んだフィーチャの件数・範囲
+#[derive(Debug, Serialize)]
+pub struct FileTableMetadata<'a> {
+    #[serde(flatten)]
+    pub metadata: &'a TableMetadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feature_count: Option<u64>,
+    /// WGS84 の `[minx, miny, maxx, maxy]`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bbox: Option<[f64; 4]>,
+}
+
+/// Quotes a PostgreSQL identifier.
+pub fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// Quotes a PostgreSQL string literal (for statements that take no parameters, like `COMMENT ON`).
+fn quote_literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+/// カラムのコメントに含めるコードリストの最大件数
+const MAX_COMMENT_ENUM_VALUES: usize = 50;
+
+/// `COMMENT ON COLUMN` の内容: 説明と、コードリスト・列挙値の対応表
+fn column_comment(column: &ColumnMetadata) -> Option<String> {
+    let mut parts: Vec<String> = Vec::new();
+    if let Some(desc) = column.desc.as_ref().filter(|desc| !desc.trim().is_empty()) {
+        parts.push(desc.trim().to_string());
+    }
+    if let Some(values) = column
+        .enum_values
+        .as_ref()
+        .filter(|values| !values.is_empty())
+    {
+        let mut values: Vec<&ColumnEnumDetails> = values.iter().collect();
+        values.sort_by(|a, b| a.value.cmp(&b.value));
+        let mut entries: Vec<String> = values
+            .iter()
+            .take(MAX_COMMENT_ENUM_VALUES)
+            .map(|value| match &value.desc {
+                Some(desc) => format!("{}={}", value.value, desc),
+                None => value.value.clone(),
+            })
+            .collect();
+        if values.len() > MAX_COMMENT_ENUM_VALUES {
+            entries.push(format!("…（全 {} 件）", values.len()));
+        }
+        let label = if values.iter().any(|value| value.desc.is_some()) {
+            "コード"
+        } else {
+            "値"
+        };
+        parts.push(format!("{}: {}", label, entries.join(", ")));
+    }
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join("\n"))
+    }
+}
+
+/// PostgreSQL の識別子の最大長（バイト数、`NAMEDATALEN - 1`）
+pub const MAX_IDENTIFIER_BYTES: usize = 63;
+
+/// Shortens identifiers longer than [`MAX_IDENTIFIER_BYTES`] (UTF-8) to a prefix and
+/// a hash of the full name, e.g. `長い…名称_1a2b3c4d`. PostgreSQL would otherwise
+/// truncate them silently, and names sharing a long prefix would collide.
+pub fn shorten_identifier(ident: &str) -> Cow<'_, str> {
+    use sha2::{Digest, Sha256};
+
+    if ident.len() <= MAX_IDENTIFIER_BYTES {
+        return Cow::Borrowed(ident);
+    }
+    let hash = format!("{:x}", Sha256::digest(ident.as_bytes()));
+    let suffix = &hash[..8];
+    let mut end = MAX_IDENTIFIER_BYTES - suffix.len() - 1;
+    while !ident.is_char_boundary(end) {
+        end -= 1;
+    }
+    Cow::Owned(format!("{}_{}", &ident[..end], suffix))
+}
+
+/// `--fill-gaps` で古い版から補完した行に、その版の年を記録するカラム
+pub const FILL_GAPS_YEAR_COLUMN: &str = "source_year";
+
+fn unique_constraint_name(table_name: &str, column: &str) -> String {
+    shorten_identifier(&format!("{}_{}_key", table_name, column)).into_owned()
+}
+
+fn unique_constraint_sql(table_name: &str, column: &str) -> String {
+    format!(
+        "ALTER TABLE {} ADD CONSTRAINT {} UNIQUE ({})",
+        quote_ident(table_name),
+        quote_ident(&unique_constraint_name(table_name, column)),
+        quote_ident(column)
+    )
+}
+
+/// ogr2ogr が付ける名前と同じ、ジオメトリカラムの空間インデックス名
+fn spatial_index_name(table_name: &str, geometry_column: &str) -> String {
+    shorten_identifier(&format!("{}_{}_geom_idx", table_name, geometry_column)).into_owned()
+}
+
+fn spatial_index_sql(table_name: &str, geometry_column: &str) -> String {
+    format!(
+        "CREATE INDEX IF NOT EXISTS {} ON {} USING GIST ({})",
+        quote_ident(&spatial_index_name(table_name, geometry_column)),
+        quote_ident(table_name),
+        quote_ident(geometry_column)
+    )
+}
+
+fn invalid_geometry_count_sql(table_name: &str, geometry_column: &str) -> String {
+    format!(
+        "SELECT count(*) FROM {} WHERE NOT ST_IsValid({})",
+        quote_ident(table_name),
+        quote_ident(geometry_column)
+    )
+}
+
+/// `ST_MakeValid` can turn a polygon into a collection of polygons and lines (or points):
+/// only the parts of the original dimension are kept, as a multi geometry, so that the
+/// result still fits the `geometry(MultiPolygon, ...)` column written by ogr2ogr.
+/// A feature without any such part left becomes an empty geometry.
+fn fix_geometries_sql(table_name: &str, geometry_column: &str) -> String {
+    let column = quote_ident(geometry_column);
+    format!(
+        "UPDATE {table} SET {column} = ST_Multi(ST_CollectionExtract(ST_MakeValid({column}), ST_Dimension({column}) + 1)) WHERE NOT ST_IsValid({column}) RETURNING ST_IsEmpty({column})",
+        table = quote_ident(table_name),
+        column = column
+    )
+}
+
+/// `--dedupe`: deletes the features whose key (the primary key column if there is one, and
+/// the geometry) was already loaded, keeping the first one: the latest version comes before
+/// the rows added by `--fill-gaps`. Features without a geometry are never removed.
+fn dedupe_sql(table_name: &str, geometry_column: &str, key_column: Option<&str>) -> String {
+    let geometry_hash = format!("md5(ST_AsBinary({}))", quote_ident(geometry_column));
+    let key = match key_column {
+        Some(column) => format!("{}, {}", quote_ident(column), geometry_hash),
+        None => geometry_hash,
+    };
+    format!(
+        "DELETE FROM {table} t USING (SELECT ogc_fid, row_number() OVER (PARTITION BY {key} ORDER BY ogc_fid) AS n FROM {table} WHERE {geometry} IS NOT NULL) d WHERE t.ogc_fid = d.ogc_fid AND d.n > 1",
+        table = quote_ident(table_name),
+        key = key,
+        geometry = quote_ident(geometry_column)
+    )
+}
+
+/// `--pg-schema`: unqualified table names (including `datasets`) resolve to `schema`,
+/// and PostGIS is still found in `public`.
+fn search_path_sql(schema: &str) -> String {
+    format!("SET search_path TO {}, public", quote_ident(schema))
+}
+
+/// Points the connection at the schema (see [`search_path_sql`]).
+pub async fn set_search_path(client: &Client, schema: Option<&str>) -> Result<()> {
+    if let Some(schema) = schema {
+        client
+            .batch_execute(&search_path_sql(schema))
+            .await
+            .with_context(|| format!("when setting search_path to {}", schema))?;
+    }
+    Ok(())
+}
+
 pub fn build_metadata_from_columns(
     metadata: &ShapefileMetadata,
     dataset: &Dataset,
@@ -25,6 +255,13 @@ pub fn build_metadata_from_columns(
 
     let dp_col_vec: Vec<_> = data_page.metadata.attribute.clone().into_values().collect();
 
+    let primary_key = metadata
+        .primary_key
+        .as_ref()
+        .filter(|pk| columns.iter().any(|c| &c.name == *pk))
+        .cloned()
+        .unwrap_or_else(|| "ogc_fid".to_string());
+
     let mut out_columns: Vec<ColumnMetadata> = vec![];
     for column in columns {
         let column_name = column.name.clone();
@@ -36,8 +273,22 @@ pub fn build_metadata_from_columns(
             enum_values: None,
         };
 
-        if let Some(column) = dp_col_vec.iter().find(|c| c.name == column_name) {
-            column_metadata.desc = Some(column.description.clone());
+        if column_name == FILL_GAPS_YEAR_COLUMN {
+            column_metadata.desc = Some(
+                "--fill-gaps で古い版から補完した行の元データの年（最新版の行は NULL）".to_string(),
+            );
+        }
+
+        // long names were shortened in the VRT, see `shorten_identifier`
+        if let Some(column) = dp_col_vec
+            .iter()
+            .find(|c| shorten_identifier(&c.name) == column_name)
+        {
+            column_metadata.desc = Some(if column.name == column_name {
+                column.description.clone()
+            } else {
+                format!("{}（元のカラム名: {}）", column.description, column.name)
+            });
 
             if column.attr_type.contains("行政区域コード") {
                 column_metadata.foreign_key = Some(ColumnForeignKeyDetails {
@@ -48,14 +299,6 @@ pub fn build_metadata_from_columns(
 
             use crate::scraper::data_page::RefType;
             column_metadata.enum_values = match &column.r#ref {
-                Some(RefType::Code(map)) => Some(
-                    map.iter()
-                        .map(|(key, value)| ColumnEnumDetails {
-                            value: key.clone(),
-                            desc: Some(value.clone()),
-                        })
-                        .collect(),
-                ),
                 Some(RefType::Enum(vec)) => Some(
                     vec.iter()
                         .map(|value| ColumnEnumDetails {
@@ -64,6 +307,14 @@ pub fn build_metadata_from_columns(
                         })
                         .collect(),
                 ),
+                Some(codes) => codes.flat_codes().map(|map| {
+                    map.into_iter()
+                        .map(|(key, value)| ColumnEnumDetails {
+                            value: key,
+                            desc: Some(value),
+                        })
+                        .collect()
+                }),
                 None => None,
             }
         }
@@ -79,6 +330,27 @@ pub fn build_metadata_from_columns(
             Some(trimmed.to_string())
         }
     });
+    // TableMetadata has no field for it, so the base year goes at the start of the description
+    let desc = if metadata.data_year.is_empty() {
+        desc
+    } else {
+        let year = format!("データ基準年: {}年", metadata.data_year);
+        Some(match desc {
+            Some(desc) => format!("{}\n{}", year, desc),
+            None => year,
+        })
+    };
+    // `--where`: the table holds only part of the dataset
+    let desc = match &metadata.where_clause {
+        Some(condition) => {
+            let note = format!("取り込み条件: {}", condition);
+            Some(match desc {
+                Some(desc) => format!("{}\n\n{}", desc, note),
+                None => note,
+            })
+        }
+        None => desc,
+    };
 
     TableMetadata {
         name: metadata.name.clone(),
@@ -90,19 +362,106 @@ pub fn build_metadata_from_columns(
         } else {
             Some(data_item.usage.clone())
         },
-        license_url: None,
-        primary_key: Some("ogc_fid".to_string()),
+        license_url: Some(Url::parse(KSJ_LICENSE_URL).unwrap()),
+        primary_key: Some(primary_key),
         columns: out_columns,
     }
 }
 
-#[derive(Clone)]
+/// `--fill-gaps` で補完した地域（行政区域コード）を年ごとにテーブルの説明に追記します
+pub fn describe_fill_gaps(metadata: &mut TableMetadata, filled: &[(u32, Vec<String>)]) {
+    let lines: Vec<String> = filled
+        .iter()
+        .filter(|(_, areas)| !areas.is_empty())
+        .map(|(year, areas)| format!("{}年版から補完: {}", year, areas.join(", ")))
+        .collect();
+    if lines.is_empty() {
+        return;
+    }
+    let note = format!(
+        "最新版に含まれない地域を古い版から補完しています（{} カラムで判別できます）\n{}",
+        FILL_GAPS_YEAR_COLUMN,
+        lines.join("\n")
+    );
+    metadata.desc = Some(match metadata.desc.take() {
+        Some(desc) => format!("{}\n\n{}", desc, note),
+        None => note,
+    });
+}
+
+/// A PostgreSQL connection for metadata writes.
+/// Each load worker owns its own connection, so the transaction control below
+/// (plain `BEGIN` / `SAVEPOINT` statements) never interleaves with other workers.
 pub struct MetadataConnection {
-    client: Arc<Client>,
+    client: Client,
 }
 
 impl MetadataConnection {
-    pub async fn new(connection_str: &str) -> Result<Self> {
+    /// Connects and initializes the metadata schema, creating `schema` if it doesn't exist.
+    pub async fn new(connection_str: &str, schema: Option<&str>) -> Result<Self> {
+        let conn = Self::connect(connection_str, None).await?;
+        if let Some(schema) = schema {
+            conn.client
+                .batch_execute(&format!(
+                    "CREATE SCHEMA IF NOT EXISTS {}",
+                    quote_ident(schema)
+                ))
+                .await
+                .with_context(|| format!("when creating schema {}", schema))?;
+            set_search_path(&conn.client, Some(schema)).await?;
+        }
+        conn.begin().await?;
+        match conn.migrate().await {
+            Ok(()) => conn.commit().await?,
+            Err(e) => {
+                conn.rollback().await?;
+                return Err(e.context("when initializing PostgreSQL schema"));
+            }
+        }
+        init_schema(&conn.client).await?;
+        Ok(conn)
+    }
+
+    /// Applies the pending migrations, in the caller's transaction.
+    async fn migrate(&self) -> Result<()> {
+        self.client
+            .batch_execute(&format!(
+                "SELECT pg_advisory_xact_lock({});
+                CREATE TABLE IF NOT EXISTS schema_migrations (
+                    version INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                )",
+                MIGRATION_LOCK_KEY
+            ))
+            .await
+            .context("when creating schema_migrations")?;
+        let applied: Vec<i32> = self
+            .client
+            .query("SELECT version FROM schema_migrations", &[])
+            .await
+            .context("when reading schema_migrations")?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        for (version, name, sql) in pending_migrations(&applied)? {
+            self.client
+                .batch_execute(sql)
+                .await
+                .with_context(|| format!("when applying migration {}", name))?;
+            self.client
+                .execute(
+                    "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+                    &[version, name],
+                )
+                .await
+                .with_context(|| format!("when recording migration {}", name))?;
+        }
+        Ok(())
+    }
+
+    /// Connects without initializing the schema (see [`MetadataConnection::new`]).
+    pub async fn connect(connection_str: &str, schema: Option<&str>) -> Result<Self> {
         let (client, connection) = tokio_postgres::connect(connection_str, NoTls)
             .await
             .with_context(|| "when connecting to PostgreSQL")?;
@@ -111,16 +470,51 @@ impl MetadataConnection {
                 panic!("PostgreSQL connection error: {}", e);
             }
         });
+        set_search_path(&client, schema).await?;
+        Ok(MetadataConnection { client })
+    }
 
-        client
-            .batch_execute(INIT_SQL)
+    /// データセット単位のトランザクションを開始します
+    pub async fn begin(&self) -> Result<()> {
+        self.client
+            .batch_execute("BEGIN")
             .await
-            .with_context(|| "when initializing PostgreSQL schema")?;
-        init_schema(&client).await?;
+            .context("when starting transaction")
+    }
 
-        Ok(MetadataConnection {
-            client: Arc::new(client),
-        })
+    pub async fn commit(&self) -> Result<()> {
+        self.client
+            .batch_execute("COMMIT")
+            .await
+            .context("when committing transaction")
+    }
+
+    pub async fn rollback(&self) -> Result<()> {
+        self.client
+            .batch_execute("ROLLBACK")
+            .await
+            .context("when rolling back transaction")
+    }
+
+    pub async fn savepoint(&self, name: &str) -> Result<()> {
+        self.client
+            .batch_execute(&format!("SAVEPOINT {}", quote_ident(name)))
+            .await
+            .with_context(|| format!("when creating savepoint {}", name))
+    }
+
+    pub async fn release_savepoint(&self, name: &str) -> Result<()> {
+        self.client
+            .batch_execute(&format!("RELEASE SAVEPOINT {}", quote_ident(name)))
+            .await
+            .with_context(|| format!("when releasing savepoint {}", name))
+    }
+
+    pub async fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        self.client
+            .batch_execute(&format!("ROLLBACK TO SAVEPOINT {}", quote_ident(name)))
+            .await
+            .with_context(|| format!("when rolling back to savepoint {}", name))
     }
 
     pub async fn build_metadata_from_dataset(
@@ -144,7 +538,7 @@ impl MetadataConnection {
                     ON gc.f_table_schema = cols.table_schema
                     AND gc.f_table_name = cols.table_name
                     AND gc.f_geometry_column = cols.column_name
-                WHERE cols.table_schema = 'public'
+                WHERE cols.table_schema = current_schema()
                 AND cols.table_name = $1
                 ORDER BY cols.ordinal_position
                 "#,
@@ -178,9 +572,720 @@ impl MetadataConnection {
         Ok(build_metadata_from_columns(metadata, dataset, columns))
     }
 
+    /// 自然キーのカラムに一意制約を追加します。
+    /// NULL または重複する値がある場合は制約を追加せず `false` を返します。
+    pub async fn add_unique_key(&self, table_name: &str, column: &str) -> Result<bool> {
+        let table_name = table_name.to_lowercase();
+        let constraint_name = unique_constraint_name(&table_name, column);
+        let existing = self
+            .client
+            .query_opt(
+                "SELECT 1 FROM pg_constraint WHERE conname = $1 AND conrelid = to_regclass($2)",
+                &[&constraint_name, &quote_ident(&table_name)],
+            )
+            .await
+            .with_context(|| "when checking existing constraints")?;
+        if existing.is_some() {
+            return Ok(true);
+        }
+
+        let row = self
+            .client
+            .query_one(
+                &format!(
+                    "SELECT count(*) = count(DISTINCT {col}) AND count(*) = count({col}) FROM {table}",
+                    col = quote_ident(column),
+                    table = quote_ident(&table_name)
+                ),
+                &[],
+            )
+            .await
+            .with_context(|| format!("when checking uniqueness of {}.{}", table_name, column))?;
+        let is_unique: bool = row.get(0);
+        if !is_unique {
+            return Ok(false);
+        }
+
+        self.client
+            .batch_execute(&unique_constraint_sql(&table_name, column))
+            .await
+            .with_context(|| format!("when adding unique constraint to {}", table_name))?;
+        Ok(true)
+    }
+
+    /// ジオメトリカラムに GiST の空間インデックスを作成します（既にある場合は何もしません）
+    pub async fn create_spatial_index(
+        &self,
+        table_name: &str,
+        geometry_column: &str,
+    ) -> Result<()> {
+        let table_name = table_name.to_lowercase();
+        self.client
+            .batch_execute(&spatial_index_sql(&table_name, geometry_column))
+            .await
+            .with_context(|| format!("when creating the spatial index of {}", table_name))?;
+        Ok(())
+    }
+
+    /// 重複するフィーチャを削除し、削除した件数を返します（[`dedupe_sql`] を参照）
+    pub async fn remove_duplicates(
+        &self,
+        table_name: &str,
+        geometry_column: &str,
+        key_column: Option<&str>,
+    ) -> Result<u64> {
+        let table_name = table_name.to_lowercase();
+        self.client
+            .execute(&dedupe_sql(&table_name, geometry_column, key_column), &[])
+            .await
+            .with_context(|| format!("when removing duplicates from {}", table_name))
+    }
+
+    /// `ST_IsValid` で不正と判定されるジオメトリの件数
+    pub async fn invalid_geometry_count(
+        &self,
+        table_name: &str,
+        geometry_column: &str,
+    ) -> Result<u64> {
+        let table_name = table_name.to_lowercase();
+        let row = self
+            .client
+            .query_one(
+                &invalid_geometry_count_sql(&table_name, geometry_column),
+                &[],
+            )
+            .await
+            .with_context(|| format!("when validating the geometries of {}", table_name))?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    /// 不正なジオメトリを `ST_MakeValid` で修復します（[`fix_geometries_sql`] を参照）
+    /// 修復したフィーチャ数と、そのうち空のジオメトリになったフィーチャ数を返します
+    pub async fn fix_geometries(
+        &self,
+        table_name: &str,
+        geometry_column: &str,
+    ) -> Result<(u64, u64)> {
+        let table_name = table_name.to_lowercase();
+        let rows = self
+            .client
+            .query(&fix_geometries_sql(&table_name, geometry_column), &[])
+            .await
+            .with_context(|| format!("when fixing the geometries of {}", table_name))?;
+        let emptied = rows.iter().filter(|row| row.get::<_, bool>(0)).count();
+        Ok((rows.len() as u64, emptied as u64))
+    }
+
+    /// プランナ統計を更新します（`ANALYZE`）
+    pub async fn analyze(&self, table_name: &str) -> Result<()> {
+        let table_name = table_name.to_lowercase();
+        self.client
+            .batch_execute(&format!("ANALYZE {}", quote_ident(&table_name)))
+            .await
+            .with_context(|| format!("when analyzing {}", table_name))?;
+        Ok(())
+    }
+
+    /// テーブルに含まれるジオメトリの種別（例: `MULTIPOLYGON`）
+    pub async fn geometry_types(
+        &self,
+        table_name: &str,
+        geometry_column: &str,
+    ) -> Result<Vec<String>> {
+        let rows = self
+            .client
+            .query(
+                &format!(
+                    "SELECT DISTINCT GeometryType({0}) FROM {1} WHERE {0} IS NOT NULL ORDER BY 1",
+                    quote_ident(geometry_column),
+                    quote_ident(&table_name.to_lowercase())
+                ),
+                &[],
+            )
+            .await
+            .with_context(|| format!("when querying the geometry types of {}", table_name))?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// テーブルの範囲（WGS84 の `[minx, miny, maxx, maxy]`）。空のテーブルは `None`
+    pub async fn table_extent(
+        &self,
+        table_name: &str,
+        geometry_column: &str,
+    ) -> Result<Option<[f64; 4]>> {
+        let row = self
+            .client
+            .query_one(
+                &format!(
+                    "SELECT ST_XMin(e), ST_YMin(e), ST_XMax(e), ST_YMax(e) FROM (SELECT ST_Extent(ST_Transform({}, 4326)) AS e FROM {}) t",
+                    quote_ident(geometry_column),
+                    quote_ident(&table_name.to_lowercase())
+                ),
+                &[],
+            )
+            .await
+            .with_context(|| format!("when querying the extent of {}", table_name))?;
+        let values: [Option<f64>; 4] = [row.get(0), row.get(1), row.get(2), row.get(3)];
+        Ok(match values {
+            [Some(minx), Some(miny), Some(maxx), Some(maxy)] => Some([minx, miny, maxx, maxy]),
+            _ => None,
+        })
+    }
+
+    pub async fn row_count(&self, table_name: &str) -> Result<u64> {
+        let row = self
+            .client
+            .query_one(
+                &format!(
+                    "SELECT count(*) FROM {}",
+                    quote_ident(&table_name.to_lowercase())
+                ),
+                &[],
+            )
+            .await
+            .with_context(|| format!("when counting rows of {}", table_name))?;
+        let count: i64 = row.get(0);
+        Ok(count as u64)
+    }
+
+    /// `--fill-gaps`: `staging_table`（古い版）の行のうち、`table` にない行政区域コードの行を
+    /// [`FILL_GAPS_YEAR_COLUMN`] に `year` を入れて挿入し、`staging_table` を削除します。
+    /// 補完した行政区域コードを返します
+    pub async fn fill_gaps(
+        &self,
+        table_name: &str,
+        staging_table: &str,
+        area_column: &str,
+        year: u32,
+    ) -> Result<Vec<String>> {
+        let table_name = table_name.to_lowercase();
+        let staging_table = staging_table.to_lowercase();
+        self.client
+            .batch_execute(&format!(
+                "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} integer",
+                quote_ident(&table_name),
+                quote_ident(FILL_GAPS_YEAR_COLUMN)
+            ))
+            .await
+            .with_context(|| format!("when adding {} to {}", FILL_GAPS_YEAR_COLUMN, table_name))?;
+
+        // older versions may have a different set of attributes
+        let rows = self
+            .client
+            .query(
+                r#"
+                SELECT column_name::text
+                FROM information_schema.columns
+                WHERE table_schema = current_schema()
+                AND table_name = $1
+                AND column_name NOT IN ('ogc_fid', $3)
+                AND column_name IN (
+                    SELECT column_name
+                    FROM information_schema.columns
+                    WHERE table_schema = current_schema() AND table_name = $2
+                )
+                ORDER BY ordinal_position
+                "#,
+                &[&table_name, &staging_table, &FILL_GAPS_YEAR_COLUMN],
+            )
+            .await
+            .with_context(|| format!("when querying columns of {}", staging_table))?;
+        let columns: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+        if !columns.iter().any(|column| column == area_column) {
+            anyhow::bail!("{} has no column {}", staging_table, area_column);
+        }
+        let column_list = columns
+            .iter()
+            .map(|column| quote_ident(column))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let rows = self
+            .client
+            .query(
+                &format!(
+                    "WITH inserted AS (
+                        INSERT INTO {table} ({columns}, {year_column})
+                        SELECT {columns}, $1::integer FROM {staging} s
+                        WHERE s.{area} IS NOT NULL
+                        AND NOT EXISTS (SELECT 1 FROM {table} t WHERE t.{area} = s.{area})
+                        RETURNING {area}
+                    )
+                    SELECT DISTINCT {area}::text FROM inserted ORDER BY 1",
+                    table = quote_ident(&table_name),
+                    staging = quote_ident(&staging_table),
+                    columns = column_list,
+                    year_column = quote_ident(FILL_GAPS_YEAR_COLUMN),
+                    area = quote_ident(area_column),
+                ),
+                &[&(year as i32)],
+            )
+            .await
+            .with_context(|| {
+                format!("when filling gaps of {} from {}", table_name, staging_table)
+            })?;
+        let filled = rows.iter().map(|row| row.get(0)).collect();
+
+        self.client
+            .batch_execute(&format!("DROP TABLE {}", quote_ident(&staging_table)))
+            .await
+            .with_context(|| format!("when dropping {}", staging_table))?;
+        Ok(filled)
+    }
+
+    /// 属性の説明とコードリストを `COMMENT ON COLUMN` でカラムに付けます（psql の `\d+` で見えます）
+    pub async fn comment_columns(&self, table_name: &str, metadata: &TableMetadata) -> Result<()> {
+        let table_name = table_name.to_lowercase();
+        let mut sql = String::new();
+        for column in &metadata.columns {
+            let Some(comment) = column_comment(column) else {
+                continue;
+            };
+            sql.push_str(&format!(
+                "COMMENT ON COLUMN {}.{} IS {};\n",
+                quote_ident(&table_name),
+                quote_ident(&column.name),
+                quote_literal(&comment)
+            ));
+        }
+        if sql.is_empty() {
+            return Ok(());
+        }
+        self.client
+            .batch_execute(&sql)
+            .await
+            .with_context(|| format!("when commenting on columns of {}", table_name))
+    }
+
     pub async fn create_dataset(&self, identifier: &str, dataset: &TableMetadata) -> Result<()> {
         let lowercase_identifier = identifier.to_lowercase();
         upsert(&self.client, &lowercase_identifier, dataset).await?;
         Ok(())
     }
+
+    /// 取り込んだフィーチャの件数と WGS84 の範囲を `feature_counts` テーブルに記録します
+    pub async fn record_feature_count(
+        &self,
+        table_name: &str,
+        count: u64,
+        bbox: Option<[f64; 4]>,
+    ) -> Result<()> {
+        let bbox = bbox.map(|bbox| bbox.to_vec());
+        self.client
+            .execute(
+                "INSERT INTO feature_counts (table_name, feature_count, bbox) VALUES ($1, $2, $3)
+                ON CONFLICT (table_name) DO UPDATE
+                SET feature_count = EXCLUDED.feature_count, bbox = EXCLUDED.bbox, counted_at = now()",
+                &[&table_name.to_lowercase(), &(count as i64), &bbox],
+            )
+            .await
+            .with_context(|| format!("when recording the feature count of {}", table_name))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::mapping::ShapefileMetadataBuilder;
+    use crate::test_helpers::fixture_dataset;
+
+    fn shapefile_metadata(primary_key: Option<&str>) -> ShapefileMetadata {
+        ShapefileMetadataBuilder::default()
+            .cat1("cat1".to_string())
+            .cat2("cat2".to_string())
+            .name("学校区".to_string())
+            .version("2023-2023".to_string())
+            .data_year("2023".to_string())
+            .field_mappings(vec![])
+            .original_identifier("A27".to_string())
+            .identifier("A27".to_string())
+            .primary_key(primary_key.map(str::to_string))
+            .build()
+            .unwrap()
+    }
+
+    fn columns() -> Vec<ColumnSchema> {
+        ["ogc_fid", "学校コード", "名称"]
+            .iter()
+            .map(|name| ColumnSchema {
+                name: name.to_string(),
+                data_type: "varchar".to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_primary_key_from_mapping() {
+        let dataset = fixture_dataset("A27", vec![("A27_003", "学校コード", "文字列型")]);
+        let metadata =
+            build_metadata_from_columns(&shapefile_metadata(Some("学校コード")), &dataset, columns());
+        assert_eq!(metadata.primary_key.as_deref(), Some("学校コード"));
+    }
+
+    #[test]
+    fn test_primary_key_falls_back_to_ogc_fid() {
+        let dataset = fixture_dataset("A27", vec![]);
+        let metadata = build_metadata_from_columns(&shapefile_metadata(None), &dataset, columns());
+        assert_eq!(metadata.primary_key.as_deref(), Some("ogc_fid"));
+
+        // the hinted column doesn't exist in the table
+        let metadata =
+            build_metadata_from_columns(&shapefile_metadata(Some("存在しない")), &dataset, columns());
+        assert_eq!(metadata.primary_key.as_deref(), Some("ogc_fid"));
+    }
+
+    #[test]
+    fn test_dataset_metadata_json() {
+        use crate::scraper::data_page::RefType;
+
+        let mut dataset = fixture_dataset("A27", vec![("A27_002", "設置主体", "コードリスト")]);
+        let mut page = (*dataset.page).clone();
+        page.metadata.attribute.get_mut("A27_002").unwrap().r#ref = Some(RefType::Code(
+            [("1".to_string(), "国".to_string())].into_iter().collect(),
+        ));
+        dataset.page = std::sync::Arc::new(page);
+
+        let json = serde_json::to_value(DatasetMetadata::from(&dataset)).unwrap();
+        assert_eq!(json["data_item"]["identifier"], "A27");
+        let attribute = &json["data_page"]["metadata"]["attribute"]["A27_002"];
+        assert_eq!(attribute["name"], "設置主体");
+        assert_eq!(attribute["description"], "設置主体の説明");
+        assert_eq!(attribute["ref"]["Code"]["1"], "国");
+    }
+
+    #[test]
+    fn test_column_comment() {
+        let column =
+            |desc: Option<&str>, enum_values: Option<Vec<ColumnEnumDetails>>| ColumnMetadata {
+                name: "設置主体".to_string(),
+                desc: desc.map(str::to_string),
+                data_type: "varchar".to_string(),
+                foreign_key: None,
+                enum_values,
+            };
+        let value = |value: &str, desc: Option<&str>| ColumnEnumDetails {
+            value: value.to_string(),
+            desc: desc.map(str::to_string),
+        };
+
+        assert_eq!(column_comment(&column(None, None)), None);
+        assert_eq!(
+            column_comment(&column(Some("学校の設置主体"), None)).as_deref(),
+            Some("学校の設置主体")
+        );
+        let codes = vec![value("2", Some("都道府県")), value("1", Some("国"))];
+        assert_eq!(
+            column_comment(&column(Some("学校の設置主体"), Some(codes))).as_deref(),
+            Some("学校の設置主体\nコード: 1=国, 2=都道府県")
+        );
+        let values = vec![value("住宅", None), value("商業", None)];
+        assert_eq!(
+            column_comment(&column(None, Some(values))).as_deref(),
+            Some("値: 住宅, 商業")
+        );
+
+        let many = (0..60).map(|i| value(&format!("{:02}", i), None)).collect();
+        let comment = column_comment(&column(None, Some(many))).unwrap();
+        assert!(comment.starts_with("値: 00, 01,"));
+        assert!(comment.ends_with("49, …（全 60 件）"));
+        assert_eq!(quote_literal("国'の"), "'国''の'");
+    }
+
+    #[test]
+    fn test_license_records_usage() {
+        let mut dataset = fixture_dataset("A99", vec![]);
+        let metadata = build_metadata_from_columns(&shapefile_metadata(None), &dataset, columns());
+        assert_eq!(metadata.license, None);
+
+        dataset.initial_item.usage = crate::scraper::NON_COMMERCIAL.to_string();
+        let metadata = build_metadata_from_columns(&shapefile_metadata(None), &dataset, columns());
+        assert_eq!(metadata.license.as_deref(), Some("非商用"));
+        assert_eq!(
+            metadata.license_url.as_ref().map(Url::as_str),
+            Some(KSJ_LICENSE_URL)
+        );
+    }
+
+    #[test]
+    fn test_base_year_in_description() {
+        let mut dataset = fixture_dataset("A27", vec![]);
+        let mut page = (*dataset.page).clone();
+        page.metadata
+            .fundamental
+            .insert("内容".to_string(), "学校区".to_string());
+        dataset.page = std::sync::Arc::new(page);
+        let metadata = build_metadata_from_columns(&shapefile_metadata(None), &dataset, columns());
+        assert_eq!(
+            metadata.desc.as_deref(),
+            Some("データ基準年: 2023年\n学校区")
+        );
+    }
+
+    #[test]
+    fn test_where_clause_in_description() {
+        let dataset = fixture_dataset("A27", vec![]);
+        let mut mapping = shapefile_metadata(None);
+        mapping.where_clause = Some("A27_005='13101'".to_string());
+        let metadata = build_metadata_from_columns(&mapping, &dataset, columns());
+        assert!(metadata
+            .desc
+            .unwrap()
+            .ends_with("\n\n取り込み条件: A27_005='13101'"));
+    }
+
+    #[test]
+    fn test_dedupe_sql() {
+        assert_eq!(
+            dedupe_sql("n03", "geom", None),
+            r#"DELETE FROM "n03" t USING (SELECT ogc_fid, row_number() OVER (PARTITION BY md5(ST_AsBinary("geom")) ORDER BY ogc_fid) AS n FROM "n03" WHERE "geom" IS NOT NULL) d WHERE t.ogc_fid = d.ogc_fid AND d.n > 1"#
+        );
+        assert!(dedupe_sql("a27", "geom", Some("学校コード"))
+            .contains(r#"PARTITION BY "学校コード", md5(ST_AsBinary("geom"))"#));
+    }
+
+    #[test]
+    fn test_fix_geometries_sql() {
+        assert_eq!(
+            invalid_geometry_count_sql("a31", "geom"),
+            r#"SELECT count(*) FROM "a31" WHERE NOT ST_IsValid("geom")"#
+        );
+        assert_eq!(
+            fix_geometries_sql("a31", "geom"),
+            r#"UPDATE "a31" SET "geom" = ST_Multi(ST_CollectionExtract(ST_MakeValid("geom"), ST_Dimension("geom") + 1)) WHERE NOT ST_IsValid("geom") RETURNING ST_IsEmpty("geom")"#
+        );
+    }
+
+    #[test]
+    fn test_unique_constraint_sql() {
+        assert_eq!(
+            unique_constraint_sql("a27", "学校コード"),
+            r#"ALTER TABLE "a27" ADD CONSTRAINT "a27_学校コード_key" UNIQUE ("学校コード")"#
+        );
+        assert_eq!(quote_ident(r#"a"b"#), r#""a""b""#);
+    }
+
+    #[test]
+    fn test_search_path_sql() {
+        assert_eq!(
+            search_path_sql("jpksj_2024"),
+            r#"SET search_path TO "jpksj_2024", public"#
+        );
+    }
+
+    #[test]
+    fn test_spatial_index_sql() {
+        assert_eq!(
+            spatial_index_sql("n03", "geom"),
+            r#"CREATE INDEX IF NOT EXISTS "n03_geom_geom_idx" ON "n03" USING GIST ("geom")"#
+        );
+        assert!(spatial_index_name(&"a".repeat(60), "geom").len() <= MAX_IDENTIFIER_BYTES);
+    }
+
+    #[test]
+    fn test_shorten_identifier() {
+        assert_eq!(shorten_identifier("学校コード"), "学校コード");
+
+        // 27 characters, 81 bytes
+        let long = "土砂災害警戒区域内の要配慮者利用施設の避難確保計画作成";
+        let other = "土砂災害警戒区域内の要配慮者利用施設の避難確保計画作成日";
+        assert!(long.len() > MAX_IDENTIFIER_BYTES);
+        let shortened = shorten_identifier(long);
+        assert!(shortened.len() <= MAX_IDENTIFIER_BYTES);
+        assert_eq!(shortened, "土砂災害警戒区域内の要配慮者利用施設_43ff62b4");
+        // stable, and unique even though the names share the kept prefix
+        assert_eq!(shorten_identifier(long), shortened);
+        assert_ne!(shorten_identifier(other), shortened);
+
+        let dataset = fixture_dataset("A33", vec![("A33_009", long, "文字列型")]);
+        let columns = vec![ColumnSchema {
+            name: shortened.to_string(),
+            data_type: "varchar".to_string(),
+        }];
+        let metadata = build_metadata_from_columns(&shapefile_metadata(None), &dataset, columns);
+        assert_eq!(metadata.columns[0].name, shortened);
+        assert_eq!(
+            metadata.columns[0].desc.as_deref(),
+            Some(format!("{}の説明（元のカラム名: {}）", long, long).as_str())
+        );
+    }
+
+    async fn table_exists(conn: &MetadataConnection, table: &str) -> bool {
+        conn.client
+            .query_one("SELECT to_regclass($1) IS NOT NULL", &[&table])
+            .await
+            .unwrap()
+            .get(0)
+    }
+
+    /// Needs a scratch database: `JPKSJ_TEST_POSTGRES_URL="host=127.0.0.1 dbname=jpksj_test"`
+    #[tokio::test]
+    async fn test_worker_transactions_are_isolated() {
+        let Ok(url) = std::env::var("JPKSJ_TEST_POSTGRES_URL") else {
+            eprintln!("JPKSJ_TEST_POSTGRES_URL is not set, skipping");
+            return;
+        };
+        let worker_a = MetadataConnection::connect(&url, None).await.unwrap();
+        let worker_b = MetadataConnection::connect(&url, None).await.unwrap();
+        worker_a
+            .client
+            .batch_execute("DROP TABLE IF EXISTS test_worker_a, test_worker_b, test_worker_c")
+            .await
+            .unwrap();
+
+        worker_a.begin().await.unwrap();
+        worker_b.begin().await.unwrap();
+        worker_a
+            .client
+            .batch_execute("CREATE TABLE test_worker_a (id int)")
+            .await
+            .unwrap();
+        worker_b
+            .client
+            .batch_execute("CREATE TABLE test_worker_b (id int)")
+            .await
+            .unwrap();
+
+        // a failure in a savepoint only rolls back to the savepoint
+        worker_b.savepoint("test_worker_b_key").await.unwrap();
+        assert!(worker_b
+            .add_unique_key("test_worker_missing", "id")
+            .await
+            .is_err());
+        worker_b
+            .rollback_to_savepoint("test_worker_b_key")
+            .await
+            .unwrap();
+        assert!(table_exists(&worker_b, "test_worker_b").await);
+
+        // worker b's dataset fails as a whole, worker a commits
+        worker_b.rollback().await.unwrap();
+        worker_a.commit().await.unwrap();
+        assert!(table_exists(&worker_b, "test_worker_a").await);
+        assert!(!table_exists(&worker_a, "test_worker_b").await);
+
+        worker_a
+            .client
+            .batch_execute("DROP TABLE test_worker_a")
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_pending_migrations() {
+        for (i, (version, name, _)) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(*version, i as i32 + 1);
+            assert!(name.starts_with(&format!("{:03}_", version)));
+        }
+        let latest = MIGRATIONS.len() as i32;
+
+        assert_eq!(pending_migrations(&[]).unwrap().len(), MIGRATIONS.len());
+        assert!(pending_migrations(&(1..=latest).collect::<Vec<_>>())
+            .unwrap()
+            .is_empty());
+        // written by a newer jpksj-to-sql
+        assert!(pending_migrations(&[1, latest + 1]).is_err());
+    }
+
+    #[test]
+    fn test_file_table_metadata_json() {
+        let dataset = fixture_dataset("A27", vec![]);
+        let metadata = build_metadata_from_columns(&shapefile_metadata(None), &dataset, columns());
+        let json = serde_json::to_value(FileTableMetadata {
+            metadata: &metadata,
+            feature_count: Some(0),
+            bbox: Some([139.0, 35.0, 140.0, 36.0]),
+        })
+        .unwrap();
+        assert_eq!(json["name"], "学校区");
+        assert_eq!(json["feature_count"], 0);
+        assert_eq!(json["bbox"], serde_json::json!([139.0, 35.0, 140.0, 36.0]));
+
+        let json = serde_json::to_value(FileTableMetadata {
+            metadata: &metadata,
+            feature_count: None,
+            bbox: None,
+        })
+        .unwrap();
+        assert!(json.get("feature_count").is_none());
+        assert!(json.get("bbox").is_none());
+    }
+
+    #[test]
+    fn test_describe_fill_gaps() {
+        let dataset = fixture_dataset("A29", vec![]);
+        let mut metadata =
+            build_metadata_from_columns(&shapefile_metadata(None), &dataset, columns());
+        metadata.desc = Some("用途地域".to_string());
+        describe_fill_gaps(&mut metadata, &[(2015, vec![]), (2011, vec![])]);
+        assert_eq!(metadata.desc.as_deref(), Some("用途地域"));
+
+        describe_fill_gaps(
+            &mut metadata,
+            &[
+                (2015, vec![]),
+                (2011, vec!["27100".to_string(), "27140".to_string()]),
+            ],
+        );
+        assert_eq!(
+            metadata.desc.as_deref(),
+            Some(
+                "用途地域\n\n最新版に含まれない地域を古い版から補完しています（source_year カラムで判別できます）\n2011年版から補完: 27100, 27140"
+            )
+        );
+    }
+
+    /// Needs a scratch database: `JPKSJ_TEST_POSTGRES_URL="host=127.0.0.1 dbname=jpksj_test"`
+    #[tokio::test]
+    async fn test_fill_gaps() {
+        let Ok(url) = std::env::var("JPKSJ_TEST_POSTGRES_URL") else {
+            eprintln!("JPKSJ_TEST_POSTGRES_URL is not set, skipping");
+            return;
+        };
+        let conn = MetadataConnection::connect(&url, None).await.unwrap();
+        conn.client
+            .batch_execute(
+                r#"
+                DROP TABLE IF EXISTS test_fill, test_fill_fill_2011;
+                CREATE TABLE test_fill (ogc_fid serial, "行政区域コード" varchar, "名称" varchar);
+                INSERT INTO test_fill ("行政区域コード", "名称") VALUES ('27102', '新');
+                CREATE TABLE test_fill_fill_2011 (ogc_fid serial, "行政区域コード" varchar, "名称" varchar, "旧属性" varchar);
+                INSERT INTO test_fill_fill_2011 ("行政区域コード", "名称", "旧属性") VALUES
+                    ('27102', '旧', 'x'), ('27100', '大阪市', 'x'), ('27100', '大阪市2', 'x'), (NULL, '不明', 'x');
+                "#,
+            )
+            .await
+            .unwrap();
+
+        let filled = conn
+            .fill_gaps("test_fill", "test_fill_fill_2011", "行政区域コード", 2011)
+            .await
+            .unwrap();
+        assert_eq!(filled, vec!["27100"]);
+        assert!(!table_exists(&conn, "test_fill_fill_2011").await);
+
+        let rows = conn
+            .client
+            .query(
+                r#"SELECT "名称", source_year FROM test_fill ORDER BY ogc_fid"#,
+                &[],
+            )
+            .await
+            .unwrap();
+        let rows: Vec<(String, Option<i32>)> =
+            rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("新".to_string(), None),
+                ("大阪市".to_string(), Some(2011)),
+                ("大阪市2".to_string(), Some(2011)),
+            ]
+        );
+
+        conn.client
+            .batch_execute("DROP TABLE test_fill")
+            .await
+            .unwrap();
+    }
 }
@@ -0,0 +1,208 @@
+// `--print-plan`: the resolved pipeline (dataset → version → ZIPs → tables → matchers),
+// assembled from the scraper and mapping layers without downloading anything.
+// `--dry-run` prints a shorter summary of the same plan.
+
+use crate::loader::mapping::mapping_defs_for_dataset;
+use crate::scraper::data_page::DataPageVersion;
+use crate::scraper::Dataset;
+use anyhow::Result;
+use bytesize::ByteSize;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PlanFormat {
+    Tree,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DatasetPlan {
+    pub identifier: String,
+    pub name: String,
+    pub version: DataPageVersion,
+    pub zip_urls: Vec<String>,
+    /// ダウンロードする ZIP の合計バイト数
+    pub total_bytes: u64,
+    pub tables: Vec<TablePlan>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TablePlan {
+    pub identifier: String,
+    pub name: String,
+    pub primary_key: Option<String>,
+    pub matchers: Vec<String>,
+    pub field_count: usize,
+}
+
+pub async fn build_plan(datasets: &[Dataset]) -> Result<Vec<DatasetPlan>> {
+    let mut plans = Vec::with_capacity(datasets.len());
+    for dataset in datasets {
+        let mappings = mapping_defs_for_dataset(dataset).await?;
+        plans.push(DatasetPlan {
+            identifier: dataset.initial_item.identifier.clone(),
+            name: dataset.initial_item.name.clone(),
+            version: dataset.page.version.clone(),
+            zip_urls: dataset
+                .page
+                .items
+                .iter()
+                .map(|item| item.file_url.to_string())
+                .collect(),
+            total_bytes: dataset.page.items.iter().map(|item| item.bytes).sum(),
+            tables: mappings
+                .into_iter()
+                .map(|mapping| TablePlan {
+                    matchers: mapping
+                        .shapefile_name_regex
+                        .iter()
+                        .map(|re| re.as_str().to_string())
+                        .collect(),
+                    field_count: mapping.field_mappings.len(),
+                    identifier: mapping.identifier,
+                    name: mapping.name,
+                    primary_key: mapping.primary_key,
+                })
+                .collect(),
+        });
+    }
+    Ok(plans)
+}
+
+/// 計画をインデント付きのツリーとして整形します
+pub fn render_tree(plans: &[DatasetPlan]) -> String {
+    let mut out = String::new();
+    for plan in plans {
+        let _ = writeln!(out, "{} {}", plan.identifier, plan.name);
+        let _ = writeln!(
+            out,
+            "  version {} ({}-{})",
+            plan.version.id, plan.version.start_year, plan.version.end_year
+        );
+        let _ = writeln!(out, "  zips ({})", plan.zip_urls.len());
+        for url in &plan.zip_urls {
+            let _ = writeln!(out, "    {}", url);
+        }
+        let _ = writeln!(out, "  tables ({})", plan.tables.len());
+        for table in &plan.tables {
+            let _ = write!(
+                out,
+                "    {} {} [{} fields",
+                table.identifier, table.name, table.field_count
+            );
+            if let Some(pk) = &table.primary_key {
+                let _ = write!(out, ", pk={}", pk);
+            }
+            let _ = writeln!(out, "]");
+            if table.matchers.is_empty() {
+                let _ = writeln!(out, "      (no matchers)");
+            }
+            for matcher in &table.matchers {
+                let _ = writeln!(out, "      {}", matcher);
+            }
+        }
+    }
+    out
+}
+
+/// `--dry-run`: データセットごとの版、ZIP の件数と合計サイズ、作成されるテーブル
+pub fn render_dry_run(plans: &[DatasetPlan]) -> String {
+    let mut out = String::new();
+    for plan in plans {
+        let _ = writeln!(
+            out,
+            "{} {} version {} ({}-{}): {} ファイル, {}",
+            plan.identifier,
+            plan.name,
+            plan.version.id,
+            plan.version.start_year,
+            plan.version.end_year,
+            plan.zip_urls.len(),
+            ByteSize(plan.total_bytes)
+        );
+        let tables: Vec<String> = plan
+            .tables
+            .iter()
+            .map(|table| table.identifier.to_lowercase())
+            .collect();
+        let _ = writeln!(out, "  → {}", tables.join(", "));
+    }
+    let _ = writeln!(
+        out,
+        "合計: {} データセット, {} ファイル, {}, {} テーブル",
+        plans.len(),
+        plans.iter().map(|plan| plan.zip_urls.len()).sum::<usize>(),
+        ByteSize(plans.iter().map(|plan| plan.total_bytes).sum()),
+        plans.iter().map(|plan| plan.tables.len()).sum::<usize>()
+    );
+    out
+}
+
+pub fn render(plans: &[DatasetPlan], format: PlanFormat) -> Result<String> {
+    Ok(match format {
+        PlanFormat::Tree => render_tree(plans),
+        PlanFormat::Json => serde_json::to_string_pretty(plans)? + "\n",
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scraper::data_page::DataItem;
+    use crate::test_helpers::fixture_dataset;
+    use std::sync::Arc;
+    use url::Url;
+
+    #[tokio::test]
+    async fn test_print_plan_tree() {
+        let mut p23 = fixture_dataset(
+            "P23",
+            vec![
+                ("P23a_001", "行政区域コード", "コードリスト"),
+                ("P23b_001", "施設名", "文字列型"),
+            ],
+        );
+        let mut page = Arc::try_unwrap(p23.page).unwrap();
+        page.items.push(DataItem {
+            area: "愛媛".to_string(),
+            crs: "JGD2011".to_string(),
+            bytes: 1024,
+            year: Some("2012".to_string()),
+            nendo: None,
+            file_url: Url::parse(
+                "https://nlftp.mlit.go.jp/ksj/gml/data/P23/P23-12/P23-12_38_GML.zip",
+            )
+            .unwrap(),
+        });
+        p23.page = Arc::new(page);
+
+        let plans = build_plan(&[p23]).await.unwrap();
+        let tree = render_tree(&plans);
+        assert_eq!(
+            tree,
+            r"P23 P23
+  version 2024 (2024-2024)
+  zips (1)
+    https://nlftp.mlit.go.jp/ksj/gml/data/P23/P23-12/P23-12_38_GML.zip
+  tables (2)
+    P23a P23 [1 fields]
+      (?:^|/)P23a[-_][^/]*(?i:(?:\.shp|\.cpg|\.dbf|\.prj|\.qmd|\.shx))$
+    P23b P23 [1 fields]
+      (?:^|/)P23b[-_][^/]*(?i:(?:\.shp|\.cpg|\.dbf|\.prj|\.qmd|\.shx))$
+"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render(&plans, PlanFormat::Json).unwrap()).unwrap();
+        assert_eq!(json[0]["tables"][1]["identifier"], "P23b");
+        assert_eq!(json[0]["version"]["id"], "2024");
+        assert_eq!(json[0]["total_bytes"], 1024);
+
+        assert_eq!(
+            render_dry_run(&plans),
+            "P23 P23 version 2024 (2024-2024): 1 ファイル, 1.0 KB\n  → p23a, p23b\n合計: 1 データセット, 1 ファイル, 1.0 KB, 2 テーブル\n"
+        );
+    }
+}
@@ -0,0 +1,435 @@
+// Machine-readable summary of a run (`--summary`), which can be fed back
+// with `--retry-failed` to run only the datasets that failed.
+
+use crate::logging;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Write};
+use std::path::Path;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    Scrape,
+    Download,
+    /// 取り込みのうち、以下のいずれにも当てはまらないもの
+    Load,
+    /// ZIP の展開とシェープファイルの検索
+    Extract,
+    Vrt,
+    Ogr2ogr,
+    /// メタデータ・インデックス・制約の作成
+    Metadata,
+}
+
+impl Stage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Scrape => "scrape",
+            Self::Download => "download",
+            Self::Load => "load",
+            Self::Extract => "extract",
+            Self::Vrt => "vrt",
+            Self::Ogr2ogr => "ogr2ogr",
+            Self::Metadata => "metadata",
+        }
+    }
+
+    /// 取り込み（`load_queue`）の段階
+    pub fn is_load(&self) -> bool {
+        !matches!(self, Self::Scrape | Self::Download)
+    }
+}
+
+/// An error context recording the stage and table a load error happened in, picked up by
+/// [`Failure::new`]. It displays as its message, so the error chain reads like a plain context.
+#[derive(Debug)]
+pub struct StageContext {
+    stage: Stage,
+    mapping: String,
+    message: String,
+}
+
+impl StageContext {
+    pub fn new(stage: Stage, mapping: &str, message: impl Into<String>) -> Self {
+        Self {
+            stage,
+            mapping: mapping.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for StageContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+pub fn timestamp() -> String {
+    OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Failure {
+    pub identifier: String,
+    pub stage: Stage,
+    pub error: String,
+    /// 失敗したテーブル（マッピングの識別子）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mapping: Option<String>,
+    /// エラーの原因を外側から順に
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chain: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+}
+
+impl Failure {
+    /// `stage` is overridden by a [`StageContext`] in the error's chain.
+    pub fn new(identifier: impl Into<String>, stage: Stage, error: &anyhow::Error) -> Self {
+        let context = error.downcast_ref::<StageContext>();
+        Self {
+            identifier: identifier.into(),
+            stage: context.map_or(stage, |context| context.stage),
+            error: format!("{:#}", error),
+            mapping: context.map(|context| context.mapping.clone()),
+            chain: error.chain().map(|cause| cause.to_string()).collect(),
+            timestamp: Some(timestamp()),
+        }
+    }
+}
+
+/// Quotes a CSV field when it needs to be (RFC 4180).
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// `--error-report`: the failures as JSON, or as CSV when the path ends with `.csv`.
+pub fn write_error_report(failures: &[Failure], path: &Path) -> Result<()> {
+    let is_csv = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    let content = if is_csv {
+        let mut csv = String::from("timestamp,identifier,mapping,stage,error\n");
+        for failure in failures {
+            let fields = [
+                failure.timestamp.as_deref().unwrap_or_default(),
+                &failure.identifier,
+                failure.mapping.as_deref().unwrap_or_default(),
+                failure.stage.as_str(),
+                &failure.error,
+            ];
+            let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+            writeln!(csv, "{}", fields.join(",")).unwrap();
+        }
+        csv
+    } else {
+        serde_json::to_string_pretty(failures)?
+    };
+    std::fs::write(path, content)
+        .with_context(|| format!("when writing error report {}", path.display()))
+}
+
+/// 実行時の設定（`--retry-failed` で再利用されます）
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunConfig {
+    pub output_format: String,
+    /// 以前のサマリーでは単一の年（数値）で記録されていたため、どちらも読み込めます
+    #[serde(default, deserialize_with = "deserialize_years")]
+    pub year: Option<Vec<u32>>,
+    pub filter_identifiers: Option<Vec<String>>,
+}
+
+fn deserialize_years<'de, D>(deserializer: D) -> Result<Option<Vec<u32>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Years {
+        One(u32),
+        Many(Vec<u32>),
+    }
+    Ok(match Option::<Years>::deserialize(deserializer)? {
+        Some(Years::One(year)) => Some(vec![year]),
+        Some(Years::Many(years)) => Some(years),
+        None => None,
+    })
+}
+
+/// ダウンロードしたファイル数
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadCounts {
+    pub completed: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub config: RunConfig,
+    pub succeeded: Vec<String>,
+    pub failed: Vec<Failure>,
+    /// `--skip-download` の場合は記録されません
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downloads: Option<DownloadCounts>,
+    /// `--analyze` の所要時間（秒）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub analyze_seconds: Option<f64>,
+}
+
+impl RunSummary {
+    pub fn read(path: &Path) -> Result<Self> {
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("when reading summary {}", path.display()))?;
+        serde_json::from_str(&json)
+            .with_context(|| format!("when parsing summary {}", path.display()))
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)
+            .with_context(|| format!("when writing summary {}", path.display()))
+    }
+
+    /// 失敗したデータセットの識別子（重複なし、記録順）
+    pub fn failed_identifiers(&self) -> Vec<String> {
+        let mut identifiers: Vec<String> = Vec::new();
+        for failure in &self.failed {
+            if !identifiers.contains(&failure.identifier) {
+                identifiers.push(failure.identifier.clone());
+            }
+        }
+        identifiers
+    }
+
+    /// 失敗が1件もなかったデータセットの割合（データセットがない場合は `None`）
+    pub fn success_rate(&self) -> Option<f64> {
+        let failed = self.failed_identifiers();
+        let succeeded = self
+            .succeeded
+            .iter()
+            .filter(|identifier| !failed.contains(identifier))
+            .count();
+        let total = succeeded + failed.len();
+        (total > 0).then(|| succeeded as f64 / total as f64)
+    }
+
+    /// 実行の最後に表示するサマリ。失敗したデータセットは `--filter-identifiers` に
+    /// そのまま渡せる形で出力します
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "実行結果:").unwrap();
+        if let Some(downloads) = self.downloads {
+            writeln!(
+                out,
+                "  ダウンロード済み {}/{} ファイル",
+                downloads.completed, downloads.total
+            )
+            .unwrap();
+        }
+        let mut load_failed: Vec<&str> = Vec::new();
+        for failure in self.failed.iter().filter(|f| f.stage.is_load()) {
+            if !load_failed.contains(&failure.identifier.as_str()) {
+                load_failed.push(&failure.identifier);
+            }
+        }
+        writeln!(out, "  ロード成功 {} データセット", self.succeeded.len()).unwrap();
+        if load_failed.is_empty() {
+            writeln!(out, "  ロード失敗 0 データセット").unwrap();
+        } else {
+            writeln!(
+                out,
+                "  ロード失敗 {} データセット（{}）",
+                load_failed.len(),
+                load_failed.join(", ")
+            )
+            .unwrap();
+        }
+        let failed = self.failed_identifiers();
+        if !failed.is_empty() {
+            writeln!(
+                out,
+                "失敗したデータセット（取得・ダウンロードの失敗を含む）を再実行するには:"
+            )
+            .unwrap();
+            writeln!(out, "  --filter-identifiers {}", failed.join(",")).unwrap();
+        }
+        out
+    }
+
+    /// [`RunSummary::render`] をログファイルに1行ずつ書き込みます（`--log-file`）
+    pub fn log(&self) {
+        for line in self.render().lines() {
+            logging::event(format!("summary: {}", line.trim()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_failed_from_summary() {
+        let json = r#"{
+            "config": {"output_format": "postgresql", "year": 2020, "filter_identifiers": null},
+            "succeeded": ["N03", "A38a"],
+            "failed": [
+                {"identifier": "A27", "stage": "load", "error": "ogr2ogr failed"},
+                {"identifier": "P23", "stage": "scrape", "error": "timeout"},
+                {"identifier": "A27", "stage": "load", "error": "ogr2ogr failed again"}
+            ]
+        }"#;
+        let path = std::path::PathBuf::from("./tmp/test_report/summary.json");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, json).unwrap();
+
+        let summary = RunSummary::read(&path).unwrap();
+        assert_eq!(summary.failed_identifiers(), vec!["A27", "P23"]);
+        assert_eq!(summary.config.year, Some(vec![2020]));
+        assert_eq!(summary.failed[1].stage, Stage::Scrape);
+
+        // round-trips through write
+        summary.write(&path).unwrap();
+        let reread = RunSummary::read(&path).unwrap();
+        assert_eq!(reread.failed, summary.failed);
+        assert_eq!(reread.succeeded, vec!["N03", "A38a"]);
+        assert_eq!(reread.config.year, Some(vec![2020]));
+    }
+
+    #[test]
+    fn test_render() {
+        let failure = |identifier: &str, stage| Failure {
+            identifier: identifier.to_string(),
+            stage,
+            error: "error".to_string(),
+            mapping: None,
+            chain: vec![],
+            timestamp: None,
+        };
+        let summary = RunSummary {
+            succeeded: vec!["N03".to_string(), "A38".to_string()],
+            failed: vec![
+                failure("A27", Stage::Load),
+                failure("P23", Stage::Download),
+                failure("A27", Stage::Ogr2ogr),
+            ],
+            downloads: Some(DownloadCounts {
+                completed: 9,
+                total: 10,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            summary.render(),
+            "実行結果:
+  ダウンロード済み 9/10 ファイル
+  ロード成功 2 データセット
+  ロード失敗 1 データセット（A27）
+失敗したデータセット（取得・ダウンロードの失敗を含む）を再実行するには:
+  --filter-identifiers A27,P23
+"
+        );
+
+        let summary = RunSummary {
+            succeeded: vec!["N03".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            summary.render(),
+            "実行結果:\n  ロード成功 1 データセット\n  ロード失敗 0 データセット\n"
+        );
+    }
+
+    #[test]
+    fn test_success_rate() {
+        let failure = |identifier: &str, stage| Failure {
+            identifier: identifier.to_string(),
+            stage,
+            error: "error".to_string(),
+            mapping: None,
+            chain: vec![],
+            timestamp: None,
+        };
+        assert_eq!(RunSummary::default().success_rate(), None);
+
+        // A38 was loaded, but one of its ZIP files failed to download
+        let summary = RunSummary {
+            succeeded: vec!["N03".to_string(), "A38".to_string(), "P23".to_string()],
+            failed: vec![failure("A27", Stage::Load), failure("A38", Stage::Download)],
+            ..Default::default()
+        };
+        assert_eq!(summary.success_rate(), Some(0.5));
+    }
+
+    #[test]
+    fn test_failure_stage_from_context() {
+        let error = Err::<(), _>(anyhow::anyhow!("ogr2ogr failed: bad geometry"))
+            .context(StageContext::new(
+                Stage::Ogr2ogr,
+                "a38a",
+                "when loading to Postgres",
+            ))
+            .context("when loading A38")
+            .unwrap_err();
+        let failure = Failure::new("A38", Stage::Load, &error);
+        assert_eq!(failure.stage, Stage::Ogr2ogr);
+        assert_eq!(failure.mapping.as_deref(), Some("a38a"));
+        assert_eq!(
+            failure.chain,
+            vec![
+                "when loading A38",
+                "when loading to Postgres",
+                "ogr2ogr failed: bad geometry"
+            ]
+        );
+        assert_eq!(
+            failure.error,
+            "when loading A38: when loading to Postgres: ogr2ogr failed: bad geometry"
+        );
+        assert!(failure.timestamp.is_some());
+
+        // without a stage context, the given stage is kept
+        let failure = Failure::new("A38", Stage::Load, &anyhow::anyhow!("no mappings"));
+        assert_eq!(failure.stage, Stage::Load);
+        assert_eq!(failure.mapping, None);
+    }
+
+    #[test]
+    fn test_write_error_report() {
+        let failures = vec![Failure {
+            identifier: "A27".to_string(),
+            stage: Stage::Vrt,
+            error: "when creating VRT: no fields, \"A27_001\" missing".to_string(),
+            mapping: Some("a27".to_string()),
+            chain: vec![],
+            timestamp: Some("2024-01-01T00:00:00Z".to_string()),
+        }];
+        let dir = std::path::PathBuf::from("./tmp/test_report");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let csv_path = dir.join("errors.csv");
+        write_error_report(&failures, &csv_path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&csv_path).unwrap(),
+            "timestamp,identifier,mapping,stage,error
+2024-01-01T00:00:00Z,A27,a27,vrt,\"when creating VRT: no fields, \"\"A27_001\"\" missing\"
+"
+        );
+
+        let json_path = dir.join("errors.json");
+        write_error_report(&failures, &json_path).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&json_path).unwrap()).unwrap();
+        assert_eq!(json[0]["stage"], "vrt");
+        assert_eq!(json[0]["mapping"], "a27");
+    }
+}
@@ -3,6 +3,8 @@ use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use url::Url;
 
+use crate::context;
+
 pub const API_BASE_URL: &str = "https://jpksj-api.kmproj.com/";
 
 #[derive(Debug, Clone, Deserialize)]
@@ -98,13 +100,30 @@ pub async fn fetch_dataset_version(id: &str, version_id: &str) -> Result<Dataset
 }
 
 fn api_url(path: &str) -> Result<Url> {
+    #[cfg(test)]
+    if let Some(base) = MOCK_BASE_URL.with(|base| base.borrow().clone()) {
+        return base.join(path).context("when building JPKSJ API url");
+    }
     Url::parse(API_BASE_URL)?
         .join(path)
         .context("when building JPKSJ API url")
 }
 
+#[cfg(test)]
+thread_local! {
+    static MOCK_BASE_URL: std::cell::RefCell<Option<Url>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Sends the API requests made on this thread to `base` (see `test_helpers::setup_mock_server`).
+#[cfg(test)]
+pub fn set_mock_base_url(base: Url) {
+    MOCK_BASE_URL.with(|mock| *mock.borrow_mut() = Some(base));
+}
+
 async fn fetch_json<T: DeserializeOwned>(url: Url) -> Result<T> {
-    let response = reqwest::get(url.clone())
+    let response = context::http_client()
+        .get(url.clone())
+        .send()
         .await
         .with_context(|| format!("when requesting {}", url))?
         .error_for_status()
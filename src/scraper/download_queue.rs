@@ -1,18 +1,82 @@
+use crate::circuit_breaker::CircuitBreaker;
 use crate::downloader;
+use crate::logging;
 use anyhow::Result;
 use async_channel::unbounded;
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use indicatif::{ProgressState, ProgressStyle};
 use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::task;
+use url::Url;
 
 use super::data_page::DataItem;
 
-const DL_QUEUE_SIZE: usize = 15;
+/// ダウンロードの同時実行数のデフォルト
+pub const DEFAULT_CONCURRENCY: usize = 15;
+/// 同時実行数の上限（MLIT のサーバーに負荷をかけすぎないため）
+pub const MAX_CONCURRENCY: usize = 64;
+const MAX_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileStatus {
+    Completed,
+    Failed(String),
+}
+
+/// ファイルごとのダウンロード結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEvent {
+    pub url: Url,
+    pub status: FileStatus,
+}
 
 struct PBStatusUpdateMsg {
-    added: u64,
-    finished: u64,
+    /// キューに追加したファイルの推定サイズ、または完了時の実際のサイズとの差
+    length_delta: i64,
+    /// 書き込んだバイト数（完了時は、書き込み済みと実際のサイズとの差）
+    position_delta: i64,
+    file: Option<FileEvent>,
+}
+
+/// バイト単位の進捗。推定サイズで始め、ファイルの完了時に実際のサイズで補正します
+/// （失敗したファイルは除きます）
+#[derive(Debug, Default)]
+struct ByteProgress {
+    length: i64,
+    position: i64,
+}
+
+impl ByteProgress {
+    fn apply(&mut self, msg: &PBStatusUpdateMsg) {
+        self.length += msg.length_delta;
+        self.position += msg.position_delta;
+    }
+
+    fn length(&self) -> u64 {
+        self.length.max(0) as u64
+    }
+
+    fn position(&self) -> u64 {
+        self.position.max(0) as u64
+    }
+}
+
+/// The message sent when a file is done: replaces its estimated size with the actual one
+/// (nothing for a failed file), and the bytes written so far with it.
+fn finished_msg(
+    estimate: u64,
+    written: u64,
+    actual: Option<u64>,
+    event: FileEvent,
+) -> PBStatusUpdateMsg {
+    let actual = actual.unwrap_or(0) as i64;
+    PBStatusUpdateMsg {
+        length_delta: actual - estimate as i64,
+        position_delta: actual - written as i64,
+        file: Some(event),
+    }
 }
 
 pub struct DownloadQueue {
@@ -20,56 +84,145 @@ pub struct DownloadQueue {
     sender: Option<async_channel::Sender<DataItem>>,
 
     set: Option<task::JoinSet<()>>,
+    events: Arc<Mutex<Vec<FileEvent>>>,
+}
+
+/// Clamps the requested concurrency to `1..=MAX_CONCURRENCY`.
+pub fn clamp_concurrency(concurrency: usize) -> usize {
+    let clamped = concurrency.clamp(1, MAX_CONCURRENCY);
+    if clamped != concurrency {
+        eprintln!(
+            "ダウンロードの同時実行数 {} は範囲外のため、{} を使用します（1〜{}）",
+            concurrency, clamped, MAX_CONCURRENCY
+        );
+    }
+    clamped
 }
 
 impl DownloadQueue {
-    pub fn new() -> Self {
+    /// `notify` receives each [`FileEvent`] as soon as the file is done (all of them are also
+    /// returned by [`DownloadQueue::close`]). Files outside of `allowed_hosts` fail right away.
+    pub fn new(
+        breaker: Arc<CircuitBreaker>,
+        concurrency: usize,
+        notify: Option<async_channel::Sender<FileEvent>>,
+        allowed_hosts: Vec<String>,
+    ) -> Self {
+        let allowed_hosts = Arc::new(allowed_hosts);
         let (pb_status_sender, pb_status_receiver) = unbounded::<PBStatusUpdateMsg>();
         let (sender, receiver) = unbounded::<DataItem>();
         let mut set = task::JoinSet::new();
-        for _i in 0..DL_QUEUE_SIZE {
+        for _i in 0..clamp_concurrency(concurrency) {
             let receiver = receiver.clone();
             let pb_sender = pb_status_sender.clone();
+            let breaker = breaker.clone();
+            let allowed_hosts = allowed_hosts.clone();
             set.spawn(async move {
                 while let Ok(item) = receiver.recv().await {
                     // println!("processor {} loading: {}", i, item.file_url);
                     // println!("Downloading: {}", url);
                     let url = item.file_url;
-                    // TODO: retry the download if it fails
-                    downloader::download_to_tmp(&url).await.unwrap();
+                    logging::event(format!("download start: {}", url));
+                    // across all attempts
+                    let written = AtomicU64::new(0);
+                    let on_chunk = |bytes: u64| {
+                        written.fetch_add(bytes, Ordering::Relaxed);
+                        let _ = pb_sender.try_send(PBStatusUpdateMsg {
+                            length_delta: 0,
+                            position_delta: bytes as i64,
+                            file: None,
+                        });
+                    };
+                    let mut attempt = 1;
+                    let (status, actual) = loop {
+                        // not retried, and not counted by the circuit breaker
+                        if let Err(e) = downloader::ensure_allowed_host(&url, &allowed_hosts) {
+                            eprintln!("{}", e);
+                            logging::event(format!("download skipped: {}", url));
+                            break (FileStatus::Failed(format!("{:#}", e)), None);
+                        }
+                        breaker.wait_until_closed().await;
+                        match downloader::download_to_tmp_from(&url, &allowed_hosts, &on_chunk)
+                            .await
+                        {
+                            Ok(file) => {
+                                logging::event(format!("download end: {}", url));
+                                break (FileStatus::Completed, Some(file.bytes));
+                            }
+                            Err(e) => {
+                                logging::event(format!(
+                                    "download error (attempt {}): {}: {:#}",
+                                    attempt, url, e
+                                ));
+                                breaker.report_failure();
+                                if attempt >= MAX_ATTEMPTS {
+                                    eprintln!("Error in downloading {}, skipping... {:?}", url, e);
+                                    break (FileStatus::Failed(format!("{:#}", e)), None);
+                                }
+                                tokio::time::sleep(breaker.retry_delay(attempt)).await;
+                                attempt += 1;
+                            }
+                        }
+                    };
+                    let written = written.load(Ordering::Relaxed);
+                    let event = FileEvent { url, status };
                     pb_sender
-                        .send(PBStatusUpdateMsg {
-                            added: 0,
-                            finished: item.bytes,
-                        })
+                        .send(finished_msg(item.bytes, written, actual, event))
                         .await
                         .unwrap();
                 }
             });
         }
 
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let pb_events = events.clone();
         set.spawn(async move {
-            let pb = ProgressBar::new(0);
+            let pb = logging::progress_bar();
             pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
                 .unwrap()
                 .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
                 .progress_chars("=>-"));
             pb.enable_steady_tick(Duration::from_millis(300));
-            let mut length = 0;
-            let mut position = 0;
+            let mut progress = ByteProgress::default();
+            let mut completed = 0;
+            let mut failed = 0;
             while let Ok(msg) = pb_status_receiver.recv().await {
-                length += msg.added;
-                position += msg.finished;
-                pb.set_length(length);
-                pb.set_position(position);
+                progress.apply(&msg);
+                pb.set_length(progress.length());
+                pb.set_position(progress.position());
+                if let Some(event) = msg.file {
+                    // the byte bar stays at the bottom; finished files are listed above it
+                    match &event.status {
+                        FileStatus::Completed => {
+                            completed += 1;
+                            pb.println(format!("完了: {}", event.url));
+                        }
+                        FileStatus::Failed(_) => {
+                            failed += 1;
+                            pb.println(format!("失敗: {}", event.url));
+                        }
+                    }
+                    if let Some(notify) = &notify {
+                        // the receiver may have stopped listening, e.g. after an error
+                        let _ = notify.send(event.clone()).await;
+                    }
+                    pb_events.lock().unwrap().push(event);
+                }
             }
             pb.finish();
             println!("ダウンロードが終了しました。");
+            logging::event(format!(
+                "download summary: {} bytes, {} files completed, {} failed",
+                progress.position(),
+                completed,
+                failed
+            ));
         });
         Self {
             pb_status_sender: Some(pb_status_sender),
             sender: Some(sender),
             set: Some(set),
+            events,
         }
     }
 
@@ -82,15 +235,17 @@ impl DownloadQueue {
         };
         pb_status_sender
             .send(PBStatusUpdateMsg {
-                added: item.bytes,
-                finished: 0,
+                length_delta: item.bytes as i64,
+                position_delta: 0,
+                file: None,
             })
             .await?;
         sender.send(item).await?;
         Ok(())
     }
 
-    pub async fn close(&mut self) -> Result<()> {
+    /// Waits for all downloads to finish, and returns the result of each file.
+    pub async fn close(&mut self) -> Result<Vec<FileEvent>> {
         let Some(_) = self.sender.take() else {
             return Err(anyhow::anyhow!("DownloadQueue is already closed"));
         };
@@ -101,6 +256,124 @@ impl DownloadQueue {
             return Err(anyhow::anyhow!("DownloadQueue is already closed"));
         };
         set.join_all().await;
-        Ok(())
+        Ok(std::mem::take(&mut *self.events.lock().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit_breaker::BreakerConfig;
+    use crate::test_helpers::spawn_http_server;
+
+    fn data_item(file_url: Url) -> DataItem {
+        DataItem {
+            area: "全国".to_string(),
+            crs: String::new(),
+            bytes: 4,
+            year: None,
+            nendo: None,
+            file_url,
+        }
+    }
+
+    #[test]
+    fn test_clamp_concurrency() {
+        assert_eq!(clamp_concurrency(0), 1);
+        assert_eq!(clamp_concurrency(1), 1);
+        assert_eq!(clamp_concurrency(DEFAULT_CONCURRENCY), DEFAULT_CONCURRENCY);
+        assert_eq!(clamp_concurrency(10_000), MAX_CONCURRENCY);
+    }
+
+    #[test]
+    fn test_byte_progress() {
+        let event = |status| FileEvent {
+            url: Url::parse("https://example.com/a.zip").unwrap(),
+            status,
+        };
+        let mut progress = ByteProgress::default();
+        // two files estimated at 10MB each
+        for _ in 0..2 {
+            progress.apply(&PBStatusUpdateMsg {
+                length_delta: 10_000_000,
+                position_delta: 0,
+                file: None,
+            });
+        }
+        progress.apply(&PBStatusUpdateMsg {
+            length_delta: 0,
+            position_delta: 3_000,
+            file: None,
+        });
+        assert_eq!(
+            (progress.length(), progress.position()),
+            (20_000_000, 3_000)
+        );
+
+        // the first one had 4,000 bytes, of which 1,000 were already there (resumed)
+        progress.apply(&finished_msg(
+            10_000_000,
+            3_000,
+            Some(4_000),
+            event(FileStatus::Completed),
+        ));
+        assert_eq!(
+            (progress.length(), progress.position()),
+            (10_004_000, 4_000)
+        );
+
+        // the second one failed halfway
+        progress.apply(&PBStatusUpdateMsg {
+            length_delta: 0,
+            position_delta: 500,
+            file: None,
+        });
+        progress.apply(&finished_msg(
+            10_000_000,
+            500,
+            None,
+            event(FileStatus::Failed("error".to_string())),
+        ));
+        assert_eq!((progress.length(), progress.position()), (4_000, 4_000));
+    }
+
+    #[tokio::test]
+    async fn test_download_queue_file_events() {
+        let base = spawn_http_server(vec![("/dl_queue_test.zip", 200, b"data".to_vec())]).await;
+        let ok_url = base.join("dl_queue_test.zip").unwrap();
+        let missing_url = base.join("dl_queue_missing.zip").unwrap();
+        // the same server, by a name that isn't allowed
+        let mut other_host = ok_url.clone();
+        other_host.set_host(Some("localhost")).unwrap();
+
+        let mut queue = DownloadQueue::new(
+            Arc::new(CircuitBreaker::new(BreakerConfig {
+                retry_delay: Duration::from_millis(10),
+                ..Default::default()
+            })),
+            DEFAULT_CONCURRENCY,
+            None,
+            vec!["127.0.0.1".to_string()],
+        );
+        queue.push(data_item(ok_url.clone())).await.unwrap();
+        queue.push(data_item(missing_url.clone())).await.unwrap();
+        queue.push(data_item(other_host.clone())).await.unwrap();
+        let mut events = queue.close().await.unwrap();
+        events.sort_by(|a, b| a.url.as_str().cmp(b.url.as_str()));
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[2].url, other_host);
+        assert!(
+            matches!(&events[2].status, FileStatus::Failed(e) if e.contains("許可されていないホスト"))
+        );
+        assert_eq!(events[0].url, missing_url);
+        assert!(matches!(events[0].status, FileStatus::Failed(_)));
+        assert_eq!(
+            events[1],
+            FileEvent {
+                url: ok_url,
+                status: FileStatus::Completed,
+            }
+        );
     }
 }
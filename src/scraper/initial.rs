@@ -1,10 +1,18 @@
 use anyhow::{Context, Result};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{PoisonError, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use url::Url;
 
+use crate::context;
+use crate::logging;
 use crate::scraper::api;
 
-#[derive(Debug, Clone, Serialize)]
+/// `--use-cached-index` のキャッシュの有効期間のデフォルト
+pub const DEFAULT_CACHE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DataItem {
     pub category1_name: String,
     pub category2_name: String,
@@ -18,19 +26,109 @@ pub struct DataItem {
     pub identifier: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ScrapeResult {
     #[allow(dead_code)]
     pub url: Url,
     pub data: Vec<DataItem>,
 }
 
+/// The last fetched list, in `<tmp>/initial.json`.
+#[derive(Serialize, Deserialize)]
+struct CachedIndex {
+    /// UNIX 時刻（秒）
+    fetched_at: u64,
+    result: ScrapeResult,
+}
+
+static CACHE_MAX_AGE: RwLock<Option<Duration>> = RwLock::new(None);
+/// `--use-cached-index`: reuses the cached dataset list while it is younger than `max_age`.
+/// It can be set more than once.
+pub fn set_cache_max_age(max_age: Option<Duration>) {
+    *CACHE_MAX_AGE
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = max_age;
+}
+fn cache_max_age() -> Option<Duration> {
+    *CACHE_MAX_AGE.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn cache_path() -> PathBuf {
+    context::tmp().join("initial.json")
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The cached list, unless it is missing, unreadable or older than `max_age`.
+fn read_cache(path: &Path, max_age: Duration, now: SystemTime) -> Option<ScrapeResult> {
+    let json = std::fs::read_to_string(path).ok()?;
+    let cached: CachedIndex = match serde_json::from_str(&json) {
+        Ok(cached) => cached,
+        Err(e) => {
+            logging::detail(format!("ignoring {}: {}", path.display(), e));
+            return None;
+        }
+    };
+    let age = unix_seconds(now).saturating_sub(cached.fetched_at);
+    if age > max_age.as_secs() {
+        logging::detail(format!(
+            "{} is {} seconds old, fetching again",
+            path.display(),
+            age
+        ));
+        return None;
+    }
+    Some(cached.result)
+}
+
+fn write_cache(path: &Path, result: &ScrapeResult, now: SystemTime) -> Result<()> {
+    let cached = CachedIndex {
+        fetched_at: unix_seconds(now),
+        result: result.clone(),
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string(&cached)?)
+        .with_context(|| format!("when writing {}", path.display()))?;
+    Ok(())
+}
+
+/// The dataset list, from the cache with `--use-cached-index`. A fetched list is always
+/// cached for the next run.
 pub async fn scrape() -> Result<ScrapeResult> {
+    let path = cache_path();
+    if let Some(max_age) = cache_max_age() {
+        if let Some(cached) = read_cache(&path, max_age, SystemTime::now()) {
+            logging::detail(format!("using the cached dataset list {}", path.display()));
+            return Ok(cached);
+        }
+    }
+    let result = fetch().await?;
+    if let Err(e) = write_cache(&path, &result, SystemTime::now()) {
+        // the list itself was fetched fine
+        logging::detail(format!("could not cache the dataset list: {:#}", e));
+    }
+    Ok(result)
+}
+
+async fn fetch() -> Result<ScrapeResult> {
     let datasets = api::fetch_dataset_list()
         .await
         .context("when requesting dataset list from JPKSJ API")?;
 
-    let data = datasets
+    Ok(ScrapeResult {
+        url: api::dataset_list_url()?,
+        data: data_items(datasets),
+    })
+}
+
+fn data_items(datasets: Vec<api::DatasetListItem>) -> Vec<DataItem> {
+    datasets
         .into_iter()
         .map(|item| DataItem {
             category1_name: item.category1_name,
@@ -43,24 +141,88 @@ pub async fn scrape() -> Result<ScrapeResult> {
             url: item.source_url,
             identifier: item.id,
         })
-        .collect::<Vec<_>>();
-
-    Ok(ScrapeResult {
-        url: api::dataset_list_url()?,
-        data,
-    })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_set_cache_max_age_more_than_once() {
+        // other tests run with the default concurrently, so it is only set to it
+        set_cache_max_age(None);
+        set_cache_max_age(None);
+        assert_eq!(cache_max_age(), None);
+    }
+
+    /// What every dataset list should satisfy, whatever MLIT adds or removes.
+    fn assert_invariants(data: &[DataItem]) {
+        let mut identifiers = HashSet::new();
+        for item in data {
+            assert!(!item.identifier.is_empty(), "{:?}", item);
+            assert!(!item.name.is_empty(), "{:?}", item);
+            assert!(!item.category1_name.is_empty(), "{:?}", item);
+            assert!(!item.category2_name.is_empty(), "{:?}", item);
+            assert_eq!(item.url.scheme(), "https", "{:?}", item);
+            assert!(item.url.host_str().is_some(), "{:?}", item);
+            assert!(
+                identifiers.insert(item.identifier.as_str()),
+                "duplicate identifier {}",
+                item.identifier
+            );
+        }
+        for known in ["N03", "A27"] {
+            assert!(identifiers.contains(known), "{} is missing", known);
+        }
+    }
+
     #[tokio::test]
-    async fn test_scrape() {
-        let result = scrape().await.unwrap();
-        assert_eq!(result.data.len(), 126);
-        let first = result.data.get(0).unwrap();
+    async fn test_fetch() {
+        // `fetch` rather than `scrape`, which would cache the fixture as the real list
+        let base = crate::test_helpers::setup_mock_server().await;
+        let result = fetch().await.unwrap();
+        assert_eq!(result.url, base.join("datasets.json").unwrap());
+        let data = result.data;
+        assert_eq!(data.len(), 4);
+        assert_invariants(&data);
+        let first = &data[0];
         assert_eq!(first.name, "海岸線");
         assert_eq!(first.identifier, "C23");
+        assert_eq!(first.metadata_xml, first.url);
+    }
+
+    #[tokio::test]
+    #[ignore = "requires network"]
+    async fn test_scrape() {
+        let result = scrape().await.unwrap();
+        // the exact number changes as MLIT adds and removes datasets
+        assert!(result.data.len() >= 100, "{}", result.data.len());
+        assert_invariants(&result.data);
+    }
+
+    #[test]
+    fn test_cache() {
+        let path = context::tmp().join("test_initial_cache.json");
+        let result = ScrapeResult {
+            url: Url::parse("https://example.com/datasets").unwrap(),
+            data: vec![crate::test_helpers::fixture_dataset("N03", vec![]).initial_item],
+        };
+        let fetched_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        write_cache(&path, &result, fetched_at).unwrap();
+
+        let hour = Duration::from_secs(3600);
+        let cached = read_cache(&path, DEFAULT_CACHE_MAX_AGE, fetched_at + hour).unwrap();
+        assert_eq!(cached.url, result.url);
+        assert_eq!(cached.data[0].identifier, "N03");
+        // older than the max age
+        assert!(read_cache(&path, DEFAULT_CACHE_MAX_AGE, fetched_at + 25 * hour).is_none());
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(read_cache(&path, DEFAULT_CACHE_MAX_AGE, fetched_at).is_none());
+        std::fs::remove_file(&path).unwrap();
+        assert!(read_cache(&path, DEFAULT_CACHE_MAX_AGE, fetched_at).is_none());
     }
 }
@@ -1,21 +1,146 @@
 // The scraper module is responsible for downloading the data from the API.
 use anyhow::Result;
 use derive_builder::Builder;
-use std::{fmt, path::PathBuf, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    path::PathBuf,
+    sync::Arc,
+};
+use url::Url;
 
-use crate::downloader::path_for_url;
+use crate::circuit_breaker::{BreakerConfig, CircuitBreaker};
+use crate::downloader::{self, path_for_url};
+use crate::logging;
+use crate::report::{self, DownloadCounts, Failure, Stage};
 
-mod api;
+pub mod api;
 pub mod data_page;
 mod download_queue;
 pub mod initial;
 
+pub use download_queue::DEFAULT_CONCURRENCY as DEFAULT_DOWNLOAD_CONCURRENCY;
+
 #[derive(Clone)]
 pub struct Dataset {
     // pub item: data_page::DataItem,
     pub initial_item: initial::DataItem,
     pub page: Arc<data_page::DataPage>,
     pub zip_file_paths: Vec<PathBuf>,
+    /// `--fill-gaps`: 古いバージョンの ZIP と、その年（新しい順）
+    pub fallback_zip_file_paths: Vec<(u32, PathBuf)>,
+    /// see [`listing_fingerprint`]
+    pub listing_fingerprint: String,
+    /// `--suffix-year`: テーブル名に付ける年（例: `n03_2024`）。`None` の場合は付けません
+    pub year_suffix: Option<u32>,
+}
+
+/// 非商用のデータセットの利用区分
+pub const NON_COMMERCIAL: &str = "非商用";
+
+/// 一覧のエントリ、公開されているバージョン（データセットの詳細）と取得条件（年）から作るフィンガープリント
+/// インクリメンタル取得や `--resume` で、前回から変更があったか（新しい版が公開されたか）を判定するために使います
+pub fn listing_fingerprint(
+    item: &initial::DataItem,
+    versions: &[api::DatasetDetailVersion],
+    years: Option<&[u32]>,
+) -> String {
+    let year = years
+        .map(|years| {
+            years
+                .iter()
+                .map(|y| y.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .unwrap_or_default();
+    // a new release is a new version, even if the listing entry stays the same
+    let versions = versions
+        .iter()
+        .map(|v| format!("{}:{}-{}", v.id, v.start_year, v.end_year))
+        .collect::<Vec<_>>()
+        .join(",");
+    [
+        item.identifier.as_str(),
+        item.name.as_str(),
+        item.category1_name.as_str(),
+        item.category2_name.as_str(),
+        item.url.as_str(),
+        versions.as_str(),
+        year.as_str(),
+    ]
+    .join("\t")
+}
+
+/// カテゴリ名の比較。先頭の番号（`3. 地域` の `3. `）はあってもなくても一致します
+fn category_matches(category: &str, filter: &str) -> bool {
+    fn strip_number(name: &str) -> &str {
+        let name = name.trim();
+        let rest = name.trim_start_matches(|c: char| c.is_ascii_digit());
+        match rest.strip_prefix('.') {
+            // at least one digit before the dot
+            Some(rest) if rest.len() < name.len() - 1 => rest.trim_start(),
+            _ => name,
+        }
+    }
+    let filter = filter.trim();
+    category.trim() == filter || strip_number(category) == filter
+}
+
+/// 前回のカタログに保存されたデータページ
+#[derive(Clone)]
+pub struct CachedPage {
+    pub listing_fingerprint: String,
+    pub page: data_page::DataPage,
+}
+
+/// Returns the cached page if the dataset's listing entry hasn't changed since it was cached.
+fn unchanged_page(
+    cache: &mut HashMap<String, CachedPage>,
+    item: &initial::DataItem,
+    fingerprint: &str,
+) -> Option<data_page::DataPage> {
+    if cache
+        .get(&item.identifier)
+        .is_some_and(|cached| cached.listing_fingerprint == fingerprint)
+    {
+        cache.remove(&item.identifier).map(|cached| cached.page)
+    } else {
+        None
+    }
+}
+
+/// `--suffix-year`: one dataset per year of its files, each loaded into tables with the year
+/// appended. Files without a year belong to the last year of the version.
+fn split_by_year(dataset: Dataset) -> Vec<Dataset> {
+    let mut years: BTreeMap<u32, Vec<data_page::DataItem>> = BTreeMap::new();
+    for item in &dataset.page.items {
+        let year = data_page::parse_recency(item).unwrap_or(dataset.page.version.end_year);
+        years.entry(year).or_default().push(item.clone());
+    }
+    if years.is_empty() {
+        years.insert(dataset.page.version.end_year, vec![]);
+    }
+    years
+        .into_iter()
+        .rev()
+        .map(|(year, items)| {
+            let zip_file_paths = items
+                .iter()
+                .map(|item| path_for_url(&item.file_url).0)
+                .collect();
+            let page = data_page::DataPage {
+                items,
+                ..(*dataset.page).clone()
+            };
+            Dataset {
+                page: Arc::new(page),
+                zip_file_paths,
+                year_suffix: Some(year),
+                ..dataset.clone()
+            }
+        })
+        .collect()
 }
 
 impl fmt::Display for Dataset {
@@ -29,52 +154,542 @@ impl fmt::Display for Dataset {
     }
 }
 
+/// Tells when a dataset is ready to be loaded: when none of its files is still downloading.
+#[derive(Default)]
+struct ReadyTracker {
+    /// datasets waiting for some of their files
+    pending: Vec<(Dataset, HashSet<Url>)>,
+    /// files that are done (a file event may arrive before its dataset is added)
+    done: HashSet<Url>,
+}
+
+impl ReadyTracker {
+    /// Returns the dataset if it doesn't have to wait for any of `files`.
+    fn add(&mut self, dataset: Dataset, files: Vec<Url>) -> Option<Dataset> {
+        let files: HashSet<Url> = files
+            .into_iter()
+            .filter(|url| !self.done.contains(url))
+            .collect();
+        if files.is_empty() {
+            return Some(dataset);
+        }
+        self.pending.push((dataset, files));
+        None
+    }
+
+    /// Returns the datasets that were only waiting for `url`.
+    fn file_done(&mut self, url: Url) -> Vec<Dataset> {
+        for (_, files) in &mut self.pending {
+            files.remove(&url);
+        }
+        self.done.insert(url);
+        let (ready, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|(_, files)| files.is_empty());
+        self.pending = pending;
+        ready.into_iter().map(|(dataset, _)| dataset).collect()
+    }
+}
+
+/// Sends the datasets to `ready` as their downloads finish, until both inputs are closed.
+async fn forward_ready_datasets(
+    datasets: async_channel::Receiver<(Dataset, Vec<Url>)>,
+    file_events: async_channel::Receiver<download_queue::FileEvent>,
+    ready: async_channel::Sender<Dataset>,
+) {
+    let mut tracker = ReadyTracker::default();
+    loop {
+        let ready_now = tokio::select! {
+            Ok((dataset, files)) = datasets.recv() => tracker.add(dataset, files).into_iter().collect(),
+            Ok(event) = file_events.recv() => tracker.file_done(event.url),
+            else => break,
+        };
+        for dataset in ready_now {
+            if ready.send(dataset).await.is_err() {
+                return;
+            }
+        }
+    }
+    // e.g. the download queue was closed early: the loader reports the missing files
+    for (dataset, _) in tracker.pending {
+        let _ = ready.send(dataset).await;
+    }
+}
+
+/// スクレイプ結果。取得に失敗したデータセットは `failures` に記録されます
+pub struct Scraped {
+    pub datasets: Vec<Dataset>,
+    pub failures: Vec<Failure>,
+    /// `skip_dl` の場合は `None`
+    pub downloads: Option<DownloadCounts>,
+}
+
 #[derive(Builder)]
 pub struct Scraper {
     skip_dl: bool,
     filter_identifiers: Option<Vec<String>>,
-    year: Option<u32>,
+    /// カテゴリ1の名前での絞り込み（識別子での絞り込みとは AND 条件）
+    #[builder(default)]
+    filter_category1: Option<Vec<String>>,
+    /// カテゴリ2の名前での絞り込み
+    #[builder(default)]
+    filter_category2: Option<Vec<String>>,
+    /// 取得する年（複数可）。`None` の場合は最新
+    year: Option<Vec<u32>>,
+    #[builder(default)]
+    breaker: BreakerConfig,
+    /// 利用区分が「非商用」のデータセットも対象にします
+    #[builder(default)]
+    include_non_commercial: bool,
+    /// 古いバージョンもダウンロードし、最新版に欠けている地域の補完に使います
+    #[builder(default)]
+    fill_gaps: bool,
+    /// 年版ごとに別のデータセット（テーブル名に年を付けたテーブル）に分けます
+    #[builder(default)]
+    suffix_year: bool,
+    #[builder(default = "download_queue::DEFAULT_CONCURRENCY")]
+    download_concurrency: usize,
+    /// インクリメンタル取得: 識別子ごとの前回のデータページ
+    #[builder(default)]
+    cached_pages: HashMap<String, CachedPage>,
 }
 
 impl Scraper {
-    pub async fn download_all(&self) -> Result<Vec<Dataset>> {
-        let mut dl_queue = download_queue::DownloadQueue::new();
-        let initial = initial::scrape().await?;
+    /// Whether the dataset passes the usage, identifier and category filters.
+    fn selects(&self, item: &initial::DataItem) -> bool {
+        if item.usage == NON_COMMERCIAL && !self.include_non_commercial {
+            return false;
+        }
+        if let Some(filter_identifiers) = &self.filter_identifiers {
+            if !filter_identifiers.contains(&item.identifier) {
+                return false;
+            }
+        }
+        for (filter, category) in [
+            (&self.filter_category1, &item.category1_name),
+            (&self.filter_category2, &item.category2_name),
+        ] {
+            if let Some(filter) = filter {
+                if !filter.iter().any(|name| category_matches(category, name)) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// The dataset's page and its [`listing_fingerprint`]. The detail (its versions) is always
+    /// fetched; only the versions' files are taken from the cached page when nothing changed.
+    async fn scrape_page(
+        &mut self,
+        initial_item: &initial::DataItem,
+    ) -> Result<(data_page::DataPage, String)> {
+        let identifier = &initial_item.identifier;
+        let detail = data_page::fetch_detail(identifier).await?;
+        let fingerprint = listing_fingerprint(initial_item, &detail.versions, self.year.as_deref());
+        // cached pages don't have the older versions' files
+        let cached_page = if self.fill_gaps {
+            None
+        } else {
+            unchanged_page(&mut self.cached_pages, initial_item, &fingerprint)
+        };
+        let page = match cached_page {
+            Some(page) => {
+                logging::event(format!("scrape skipped (unchanged): {}", identifier));
+                page
+            }
+            None => {
+                data_page::scrape_versions(
+                    identifier,
+                    &detail,
+                    self.year.as_deref(),
+                    self.fill_gaps,
+                )
+                .await?
+            }
+        };
+        Ok((page, fingerprint))
+    }
+
+    pub async fn download_all(self) -> Result<Scraped> {
+        self.download_each(None).await
+    }
+
+    /// [`Scraper::download_all`], also sending each dataset to `ready` as soon as all of its files
+    /// are downloaded (or have failed), so that it can be loaded while the rest is downloading.
+    pub async fn download_each(
+        mut self,
+        ready: Option<async_channel::Sender<Dataset>>,
+    ) -> Result<Scraped> {
+        let (forwarder, datasets_sender, file_events_sender) = match ready {
+            Some(ready) => {
+                let (datasets_sender, datasets) = async_channel::unbounded();
+                let (file_events_sender, file_events) = async_channel::unbounded();
+                let forwarder = tokio::spawn(forward_ready_datasets(datasets, file_events, ready));
+                (
+                    Some(forwarder),
+                    Some(datasets_sender),
+                    Some(file_events_sender),
+                )
+            }
+            None => (None, None, None),
+        };
+        // shared by the API requests and the downloads, which go to the same servers
+        let breaker = Arc::new(CircuitBreaker::new(self.breaker.clone()));
+        let mut dl_queue = download_queue::DownloadQueue::new(
+            breaker.clone(),
+            self.download_concurrency,
+            file_events_sender,
+            downloader::allowed_hosts(),
+        );
+        breaker.wait_until_closed().await;
+        let initial = initial::scrape()
+            .await
+            .inspect_err(|_| breaker.report_failure())?;
         let data_items = initial.data;
         let mut out: Vec<Dataset> = Vec::new();
+        let mut failures: Vec<Failure> = Vec::new();
         for initial_item in data_items {
-            // TODO: 非商用を対応
-            if initial_item.usage == "非商用" {
+            if !self.selects(&initial_item) {
+                let requested = self
+                    .filter_identifiers
+                    .as_ref()
+                    .is_some_and(|ids| ids.contains(&initial_item.identifier));
+                if requested && initial_item.usage == NON_COMMERCIAL {
+                    println!(
+                        "{} は非商用のデータセットのため、スキップします（--include-non-commercial で取り込めます）",
+                        initial_item.identifier
+                    );
+                }
                 continue;
             }
-            if let Some(filter_identifiers) = &self.filter_identifiers {
-                if !filter_identifiers.contains(&initial_item.identifier) {
+
+            breaker.wait_until_closed().await;
+            let scraped = self.scrape_page(&initial_item).await;
+            if scraped.is_err() {
+                breaker.report_failure();
+            }
+            let (page, fingerprint) = match scraped {
+                Ok(scraped) => scraped,
+                Err(err) => {
+                    println!("[ERROR, skipping...] {:?}", err);
+                    logging::event(format!(
+                        "scrape error: {}: {:#}",
+                        initial_item.identifier, err
+                    ));
+                    failures.push(Failure::new(&initial_item.identifier, Stage::Scrape, &err));
                     continue;
                 }
-            }
+            };
+            let page = Arc::new(page);
 
-            let page_res = data_page::scrape(&initial_item.identifier, self.year).await;
-            if let Err(err) = page_res {
-                println!("[ERROR, skipping...] {:?}", err);
-                continue;
-            }
-            let page = Arc::new(page_res.unwrap());
-
-            let mut zip_file_paths: Vec<PathBuf> = Vec::new();
-            for item in &page.items {
-                let expected_path = path_for_url(&item.file_url);
-                zip_file_paths.push(expected_path.0);
-                if !self.skip_dl {
-                    dl_queue.push(item.clone()).await?;
+            // registered with the forwarder before the downloads start
+            let downloads: Vec<data_page::DataItem> = if self.skip_dl {
+                vec![]
+            } else if self.fill_gaps {
+                page.items
+                    .iter()
+                    .chain(
+                        page.fallback_items
+                            .iter()
+                            .filter(|item| data_page::parse_recency(item).is_some()),
+                    )
+                    .cloned()
+                    .collect()
+            } else {
+                page.items.clone()
+            };
+            let zip_file_paths: Vec<PathBuf> = page
+                .items
+                .iter()
+                .map(|item| path_for_url(&item.file_url).0)
+                .collect();
+            let mut fallback_zip_file_paths: Vec<(u32, PathBuf)> = Vec::new();
+            if self.fill_gaps {
+                for item in &page.fallback_items {
+                    let Some(year) = data_page::parse_recency(item) else {
+                        continue;
+                    };
+                    fallback_zip_file_paths.push((year, path_for_url(&item.file_url).0));
                 }
             }
-            out.push(Dataset {
+            let dataset = Dataset {
                 initial_item,
                 page,
                 zip_file_paths,
-            });
+                fallback_zip_file_paths,
+                listing_fingerprint: fingerprint,
+                year_suffix: None,
+            };
+            let datasets = if self.suffix_year {
+                split_by_year(dataset)
+            } else {
+                vec![dataset]
+            };
+            if let Some(sender) = &datasets_sender {
+                for dataset in &datasets {
+                    let urls = downloads
+                        .iter()
+                        .filter(|item| {
+                            dataset
+                                .page
+                                .items
+                                .iter()
+                                .chain(&dataset.page.fallback_items)
+                                .any(|own| own.file_url == item.file_url)
+                        })
+                        .map(|item| item.file_url.clone())
+                        .collect();
+                    sender.send((dataset.clone(), urls)).await?;
+                }
+            }
+            for item in downloads {
+                dl_queue.push(item).await?;
+            }
+            out.extend(datasets);
+        }
+        drop(datasets_sender);
+        let file_events = dl_queue.close().await?;
+        if let Some(forwarder) = forwarder {
+            forwarder.await?;
+        }
+        let downloads = (!self.skip_dl).then(|| DownloadCounts {
+            completed: file_events
+                .iter()
+                .filter(|event| event.status == download_queue::FileStatus::Completed)
+                .count(),
+            total: file_events.len(),
+        });
+        for event in file_events {
+            let download_queue::FileStatus::Failed(error) = event.status else {
+                continue;
+            };
+            if let Some(dataset) = out.iter().find(|d| {
+                d.page
+                    .items
+                    .iter()
+                    .chain(&d.page.fallback_items)
+                    .any(|item| item.file_url == event.url)
+            }) {
+                failures.push(Failure {
+                    identifier: dataset.initial_item.identifier.clone(),
+                    stage: Stage::Download,
+                    error: format!("{}: {}", event.url, error),
+                    mapping: None,
+                    chain: vec![],
+                    timestamp: Some(report::timestamp()),
+                });
+            }
         }
-        dl_queue.close().await?;
-        Ok(out)
+        Ok(Scraped {
+            datasets: out,
+            failures,
+            downloads,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::fixture_dataset;
+
+    #[test]
+    fn test_unchanged_dataset_is_not_rescraped() {
+        let versions = |ids: &[&str]| -> Vec<api::DatasetDetailVersion> {
+            ids.iter()
+                .map(|id| api::DatasetDetailVersion {
+                    id: id.to_string(),
+                    start_year: 2024,
+                    end_year: 2024,
+                    most_recent: false,
+                    source_url: Url::parse("https://nlftp.mlit.go.jp/ksj/").unwrap(),
+                })
+                .collect()
+        };
+        let released = versions(&["2024"]);
+        let mut cache = HashMap::new();
+        for identifier in ["N03", "A27", "C23"] {
+            let dataset = fixture_dataset(identifier, vec![]);
+            cache.insert(
+                identifier.to_string(),
+                CachedPage {
+                    listing_fingerprint: listing_fingerprint(
+                        &dataset.initial_item,
+                        &released,
+                        None,
+                    ),
+                    page: Arc::try_unwrap(dataset.page).unwrap(),
+                },
+            );
+        }
+
+        // unchanged listing entry: the cached page is reused
+        let n03 = fixture_dataset("N03", vec![]).initial_item;
+        let page = unchanged_page(
+            &mut cache,
+            &n03,
+            &listing_fingerprint(&n03, &released, None),
+        );
+        assert_eq!(page.unwrap().version.id, "2024");
+
+        // renamed on the listing: scraped again
+        let mut a27 = fixture_dataset("A27", vec![]).initial_item;
+        a27.name = "学校（改訂）".to_string();
+        assert!(unchanged_page(
+            &mut cache,
+            &a27,
+            &listing_fingerprint(&a27, &released, None)
+        )
+        .is_none());
+
+        // a different year is a different request
+        let a27 = fixture_dataset("A27", vec![]).initial_item;
+        assert!(unchanged_page(
+            &mut cache,
+            &a27,
+            &listing_fingerprint(&a27, &released, Some(&[2019]))
+        )
+        .is_none());
+
+        // a new version, with the same listing entry
+        let c23 = fixture_dataset("C23", vec![]).initial_item;
+        let fingerprint = listing_fingerprint(&c23, &versions(&["2024", "2025"]), None);
+        assert!(unchanged_page(&mut cache, &c23, &fingerprint).is_none());
+
+        // not in the catalog at all
+        let p23 = fixture_dataset("P23", vec![]).initial_item;
+        assert!(unchanged_page(
+            &mut cache,
+            &p23,
+            &listing_fingerprint(&p23, &released, None)
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_ready_tracker() {
+        let url = |name: &str| Url::parse(&format!("https://example.com/{}.zip", name)).unwrap();
+        let identifiers = |datasets: Vec<Dataset>| -> Vec<String> {
+            datasets
+                .into_iter()
+                .map(|d| d.initial_item.identifier)
+                .collect()
+        };
+        let mut tracker = ReadyTracker::default();
+
+        // nothing to download (e.g. --skip-download)
+        let ready = tracker.add(fixture_dataset("P23", vec![]), vec![]);
+        assert_eq!(ready.unwrap().initial_item.identifier, "P23");
+
+        assert!(tracker
+            .add(
+                fixture_dataset("N03", vec![]),
+                vec![url("n03_a"), url("n03_b")]
+            )
+            .is_none());
+        assert!(tracker
+            .add(fixture_dataset("A27", vec![]), vec![url("a27")])
+            .is_none());
+        assert!(tracker.file_done(url("n03_a")).is_empty());
+        assert_eq!(identifiers(tracker.file_done(url("a27"))), vec!["A27"]);
+        assert_eq!(identifiers(tracker.file_done(url("n03_b"))), vec!["N03"]);
+
+        // the file was done before the dataset was added
+        assert!(tracker.file_done(url("c23")).is_empty());
+        let ready = tracker.add(fixture_dataset("C23", vec![]), vec![url("c23")]);
+        assert_eq!(ready.unwrap().initial_item.identifier, "C23");
+        assert!(tracker.pending.is_empty());
+    }
+
+    #[test]
+    fn test_split_by_year() {
+        let item = |year: Option<u32>, name: &str| data_page::DataItem {
+            area: "全国".to_string(),
+            crs: String::new(),
+            bytes: 0,
+            year: year.map(|y| format!("{}年", y)),
+            nendo: None,
+            file_url: Url::parse(&format!("https://example.com/{}.zip", name)).unwrap(),
+        };
+        let mut dataset = fixture_dataset("N03", vec![]);
+        let mut page = (*dataset.page).clone();
+        page.items = vec![
+            item(Some(2019), "n03_2019"),
+            item(Some(2024), "n03_2024"),
+            item(None, "n03_undated"),
+        ];
+        dataset.page = Arc::new(page);
+
+        let datasets = split_by_year(dataset);
+        let years: Vec<_> = datasets.iter().map(|d| d.year_suffix).collect();
+        assert_eq!(years, vec![Some(2024), Some(2019)]);
+        // the version of the fixture ends in 2024
+        assert_eq!(datasets[0].page.items.len(), 2);
+        assert_eq!(datasets[0].zip_file_paths.len(), 2);
+        assert_eq!(
+            datasets[1].page.items[0].file_url.as_str(),
+            "https://example.com/n03_2019.zip"
+        );
+        assert!(datasets.iter().all(|d| d.initial_item.identifier == "N03"));
+
+        // without files, the version's year
+        let datasets = split_by_year(fixture_dataset("A27", vec![]));
+        assert_eq!(datasets.len(), 1);
+        assert_eq!(datasets[0].year_suffix, Some(2024));
+    }
+
+    #[test]
+    fn test_non_commercial_is_opt_in() {
+        let mut non_commercial = fixture_dataset("A99", vec![]).initial_item;
+        non_commercial.usage = NON_COMMERCIAL.to_string();
+        let commercial = fixture_dataset("N03", vec![]).initial_item;
+        let scraper = |include_non_commercial: bool| {
+            ScraperBuilder::default()
+                .skip_dl(true)
+                .filter_identifiers(None)
+                .year(None)
+                .include_non_commercial(include_non_commercial)
+                .build()
+                .unwrap()
+        };
+
+        assert!(!scraper(false).selects(&non_commercial));
+        assert!(scraper(false).selects(&commercial));
+        assert!(scraper(true).selects(&non_commercial));
+    }
+
+    #[test]
+    fn test_category_filters() {
+        let mut n03 = fixture_dataset("N03", vec![]).initial_item;
+        n03.category1_name = "3. 地域".to_string();
+        n03.category2_name = "行政区域".to_string();
+        let scraper = |identifiers: Option<&[&str]>,
+                       category1: Option<&[&str]>,
+                       category2: Option<&[&str]>| {
+            let strings = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+            ScraperBuilder::default()
+                .skip_dl(true)
+                .filter_identifiers(identifiers.map(strings))
+                .filter_category1(category1.map(strings))
+                .filter_category2(category2.map(strings))
+                .year(None)
+                .build()
+                .unwrap()
+        };
+
+        assert!(scraper(None, Some(&["3. 地域"]), None).selects(&n03));
+        assert!(scraper(None, Some(&["地域"]), None).selects(&n03));
+        assert!(scraper(None, Some(&["2. 国土", "地域"]), None).selects(&n03));
+        assert!(!scraper(None, Some(&["国土"]), None).selects(&n03));
+        assert!(scraper(None, None, Some(&["行政区域"])).selects(&n03));
+        assert!(!scraper(None, Some(&["地域"]), Some(&["交通"])).selects(&n03));
+        // AND with the identifier filter
+        assert!(scraper(Some(&["N03"]), Some(&["地域"]), None).selects(&n03));
+        assert!(!scraper(Some(&["A27"]), Some(&["地域"]), None).selects(&n03));
+
+        assert!(category_matches("1. 国土", "1. 国土"));
+        assert!(!category_matches("1. 国土", "2. 国土"));
+        assert!(category_matches("2020年", "2020年"));
     }
 }
@@ -0,0 +1,336 @@
+// `--stac <dir>`: a minimal static STAC (SpatioTemporal Asset Catalog) of the loaded tables.
+// `catalog.json` links a collection per dataset (`<identifier>/collection.json`), which links
+// an item per table (`<identifier>/<table>.json`). All links are relative, so the directory can be moved.
+
+use crate::loader::{LoadedTable, OutputTarget};
+use crate::scraper::Dataset;
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use url::Url;
+
+pub const STAC_VERSION: &str = "1.0.0";
+const CATALOG_ID: &str = "jpksj";
+
+/// Bounding box used when no table extent is known.
+const WORLD_BBOX: [f64; 4] = [-180.0, -90.0, 180.0, 90.0];
+
+fn temporal_interval(dataset: &Dataset) -> (String, String) {
+    let version = &dataset.page.version;
+    (
+        format!("{:04}-01-01T00:00:00Z", version.start_year),
+        format!("{:04}-12-31T23:59:59Z", version.end_year),
+    )
+}
+
+fn description(dataset: &Dataset) -> String {
+    dataset
+        .page
+        .metadata
+        .fundamental
+        .get("内容")
+        .cloned()
+        .unwrap_or_else(|| dataset.initial_item.name.clone())
+}
+
+fn union_bbox(tables: &[&LoadedTable]) -> Option<[f64; 4]> {
+    tables.iter().filter_map(|table| table.bbox).reduce(|a, b| {
+        [
+            a[0].min(b[0]),
+            a[1].min(b[1]),
+            a[2].max(b[2]),
+            a[3].max(b[3]),
+        ]
+    })
+}
+
+fn media_type(path: &Path) -> Option<&'static str> {
+    match path.extension()?.to_str()? {
+        "geojson" => Some("application/geo+json"),
+        "parquet" => Some("application/vnd.apache.parquet"),
+        "fgb" => Some("application/vnd.flatgeobuf"),
+        "gpkg" => Some("application/geopackage+sqlite3"),
+        "csv" => Some("text/csv"),
+        _ => None,
+    }
+}
+
+fn asset(table: &LoadedTable, output: &OutputTarget) -> Value {
+    match &table.output_path {
+        Some(path) => {
+            let absolute = path.canonicalize().unwrap_or_else(|_| path.clone());
+            let href = Url::from_file_path(&absolute)
+                .map(|url| url.to_string())
+                .unwrap_or_else(|_| absolute.display().to_string());
+            let mut asset = json!({
+                "href": href,
+                "title": table.name,
+                "roles": ["data"],
+            });
+            if let Some(media_type) = media_type(path) {
+                asset["type"] = json!(media_type);
+            }
+            asset
+        }
+        None => {
+            let qualified = format!(
+                "{}.{}",
+                output.postgres_schema().unwrap_or("public"),
+                table.identifier
+            );
+            json!({
+                "href": format!("postgresql:{}", qualified),
+                "title": table.name,
+                "description": match output {
+                    OutputTarget::Postgres { .. } => format!("PostgreSQL テーブル {}", qualified),
+                    _ => table.identifier.clone(),
+                },
+                "roles": ["data"],
+            })
+        }
+    }
+}
+
+fn item(dataset: &Dataset, table: &LoadedTable, output: &OutputTarget) -> Value {
+    let (start, end) = temporal_interval(dataset);
+    let mut item = json!({
+        "type": "Feature",
+        "stac_version": STAC_VERSION,
+        "id": table.identifier,
+        "geometry": null,
+        "properties": {
+            "title": table.name,
+            "datetime": null,
+            "start_datetime": start,
+            "end_datetime": end,
+        },
+        "links": [
+            {"rel": "root", "href": "../catalog.json", "type": "application/json"},
+            {"rel": "parent", "href": "./collection.json", "type": "application/json"},
+            {"rel": "collection", "href": "./collection.json", "type": "application/json"},
+        ],
+        "assets": {"data": asset(table, output)},
+        "collection": dataset.initial_item.identifier,
+    });
+    if let Some([minx, miny, maxx, maxy]) = table.bbox {
+        item["bbox"] = json!([minx, miny, maxx, maxy]);
+        item["geometry"] = json!({
+            "type": "Polygon",
+            "coordinates": [[
+                [minx, miny],
+                [maxx, miny],
+                [maxx, maxy],
+                [minx, maxy],
+                [minx, miny],
+            ]],
+        });
+    }
+    item
+}
+
+fn collection(dataset: &Dataset, tables: &[&LoadedTable]) -> Value {
+    let (start, end) = temporal_interval(dataset);
+    let mut links = vec![
+        json!({"rel": "root", "href": "../catalog.json", "type": "application/json"}),
+        json!({"rel": "parent", "href": "../catalog.json", "type": "application/json"}),
+        json!({"rel": "via", "href": dataset.page.url.as_str(), "type": "text/html"}),
+    ];
+    for table in tables {
+        links.push(json!({
+            "rel": "item",
+            "href": format!("./{}.json", table.identifier),
+            "type": "application/geo+json",
+        }));
+    }
+    json!({
+        "type": "Collection",
+        "stac_version": STAC_VERSION,
+        "id": dataset.initial_item.identifier,
+        "title": dataset.initial_item.name,
+        "description": description(dataset),
+        "license": "proprietary",
+        "extent": {
+            "spatial": {"bbox": [union_bbox(tables).unwrap_or(WORLD_BBOX)]},
+            "temporal": {"interval": [[start, end]]},
+        },
+        "links": links,
+    })
+}
+
+fn catalog(collection_ids: &[&str]) -> Value {
+    let mut links =
+        vec![json!({"rel": "root", "href": "./catalog.json", "type": "application/json"})];
+    for id in collection_ids {
+        links.push(json!({
+            "rel": "child",
+            "href": format!("./{}/collection.json", id),
+            "type": "application/json",
+        }));
+    }
+    json!({
+        "type": "Catalog",
+        "stac_version": STAC_VERSION,
+        "id": CATALOG_ID,
+        "title": "国土数値情報",
+        "description": "jpksj-to-sql で取り込んだ国土数値情報のデータセット",
+        "links": links,
+    })
+}
+
+/// The STAC documents, with their paths relative to the catalog directory.
+pub fn build_stac(
+    datasets: &[Dataset],
+    tables: &[LoadedTable],
+    output: &OutputTarget,
+) -> Vec<(PathBuf, Value)> {
+    let mut documents = Vec::new();
+    let mut collection_ids = Vec::new();
+    for dataset in datasets {
+        let identifier = dataset.initial_item.identifier.as_str();
+        let dataset_tables = tables
+            .iter()
+            .filter(|table| table.dataset_identifier == identifier)
+            .collect::<Vec<_>>();
+        if dataset_tables.is_empty() {
+            continue;
+        }
+        let dir = PathBuf::from(identifier);
+        documents.push((
+            dir.join("collection.json"),
+            collection(dataset, &dataset_tables),
+        ));
+        for table in dataset_tables {
+            documents.push((
+                dir.join(format!("{}.json", table.identifier)),
+                item(dataset, table, output),
+            ));
+        }
+        collection_ids.push(identifier);
+    }
+    documents.insert(0, (PathBuf::from("catalog.json"), catalog(&collection_ids)));
+    documents
+}
+
+/// 取り込んだテーブルの STAC カタログを `dir` に書き出します
+pub fn write_stac_catalog(
+    dir: &Path,
+    datasets: &[Dataset],
+    tables: &[LoadedTable],
+    output: &OutputTarget,
+) -> Result<()> {
+    for (path, document) in build_stac(datasets, tables, output) {
+        let path = dir.join(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("when creating {}", parent.display()))?;
+        }
+        std::fs::write(&path, serde_json::to_string_pretty(&document)?)
+            .with_context(|| format!("when writing {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::fixture_dataset;
+
+    fn schema_errors(schema_file: &str, document: &Value) -> Vec<String> {
+        let schema: Value = serde_json::from_str(
+            &std::fs::read_to_string(format!("./test_data/stac/{}", schema_file)).unwrap(),
+        )
+        .unwrap();
+        let compiled = jsonschema::JSONSchema::compile(&schema).unwrap();
+        let result = match compiled.validate(document) {
+            Ok(()) => vec![],
+            Err(errors) => errors.map(|e| e.to_string()).collect(),
+        };
+        result
+    }
+
+    fn validate(schema_file: &str, document: &Value) {
+        let errors = schema_errors(schema_file, document);
+        assert!(
+            errors.is_empty(),
+            "invalid against {}: {:?}\n{:#}",
+            schema_file,
+            errors,
+            document
+        );
+    }
+
+    #[test]
+    fn test_stac_catalog_is_valid() {
+        let dataset = fixture_dataset("P23", vec![("P23a_001", "行政区域コード", "コードリスト")]);
+        let output = OutputTarget::File {
+            output_dir: PathBuf::from("./tmp/test_stac/out"),
+            gdal_driver: "GeoJSON".to_string(),
+            file_extension: "geojson".to_string(),
+            compression: None,
+            single_file: None,
+        };
+        let tables = vec![
+            LoadedTable {
+                dataset_identifier: "P23".to_string(),
+                identifier: "p23a".to_string(),
+                name: "海岸保全施設".to_string(),
+                output_path: output.output_path("p23a"),
+                bbox: Some([139.0, 35.0, 140.0, 36.0]),
+                row_count: None,
+                skipped_features: None,
+                invalid_geometries: None,
+                fixed_geometries: None,
+                removed_duplicates: None,
+            },
+            LoadedTable {
+                dataset_identifier: "P23".to_string(),
+                identifier: "p23b".to_string(),
+                name: "海岸保全施設".to_string(),
+                output_path: output.output_path("p23b"),
+                bbox: None,
+                row_count: None,
+                skipped_features: None,
+                invalid_geometries: None,
+                fixed_geometries: None,
+                removed_duplicates: None,
+            },
+        ];
+
+        let dir = PathBuf::from("./tmp/test_stac/catalog");
+        let _ = std::fs::remove_dir_all(&dir);
+        write_stac_catalog(&dir, &[dataset], &tables, &output).unwrap();
+
+        let read = |path: &str| -> Value {
+            serde_json::from_str(&std::fs::read_to_string(dir.join(path)).unwrap()).unwrap()
+        };
+        let catalog = read("catalog.json");
+        validate("catalog.json", &catalog);
+        assert_eq!(catalog["links"][1]["href"], "./P23/collection.json");
+
+        let collection = read("P23/collection.json");
+        validate("collection.json", &collection);
+        assert_eq!(
+            collection["extent"]["spatial"]["bbox"][0],
+            json!([139.0, 35.0, 140.0, 36.0])
+        );
+        assert_eq!(
+            collection["extent"]["temporal"]["interval"][0][0],
+            "2024-01-01T00:00:00Z"
+        );
+        let mut without_license = collection.clone();
+        without_license.as_object_mut().unwrap().remove("license");
+        assert!(!schema_errors("collection.json", &without_license).is_empty());
+
+        let item = read("P23/p23a.json");
+        validate("item.json", &item);
+        assert_eq!(item["assets"]["data"]["type"], "application/geo+json");
+        assert!(item["assets"]["data"]["href"]
+            .as_str()
+            .unwrap()
+            .ends_with("/p23a.geojson"));
+        // an item without a known extent has no geometry
+        let item = read("P23/p23b.json");
+        validate("item.json", &item);
+        assert!(item["geometry"].is_null());
+    }
+}
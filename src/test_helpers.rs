@@ -0,0 +1,134 @@
+// Shared fixtures for unit tests.
+
+use crate::scraper::data_page::{AttributeMetadata, DataPage, DataPageMetadata, DataPageVersion};
+use crate::scraper::initial::DataItem;
+use crate::scraper::{api, listing_fingerprint, Dataset};
+use std::path::Path;
+use std::sync::Arc;
+use url::Url;
+
+/// Builds a dataset with the given `(attribute_id, name, attr_type)` attributes, without touching the network.
+pub fn fixture_dataset(identifier: &str, attributes: Vec<(&str, &str, &str)>) -> Dataset {
+    let url = Url::parse(&format!(
+        "https://nlftp.mlit.go.jp/ksj/gml/datalist/KsjTmplt-{}.html",
+        identifier
+    ))
+    .unwrap();
+    let mut metadata = DataPageMetadata::default();
+    for (attribute_id, name, attr_type) in attributes {
+        metadata.attribute.insert(
+            attribute_id.to_string(),
+            AttributeMetadata {
+                name: name.to_string(),
+                description: format!("{}の説明", name),
+                attr_type: attr_type.to_string(),
+                ref_url: None,
+                r#ref: None,
+            },
+        );
+    }
+    let initial_item = DataItem {
+        category1_name: "cat1".to_string(),
+        category2_name: "cat2".to_string(),
+        name: identifier.to_string(),
+        data_source: String::new(),
+        data_accuracy: String::new(),
+        metadata_xml: url.clone(),
+        usage: String::new(),
+        url: url.clone(),
+        identifier: identifier.to_string(),
+    };
+    Dataset {
+        listing_fingerprint: listing_fingerprint(&initial_item, &[], None),
+        initial_item,
+        page: Arc::new(DataPage {
+            url,
+            items: vec![],
+            metadata,
+            variants: vec![],
+            version: DataPageVersion {
+                id: "2024".to_string(),
+                start_year: 2024,
+                end_year: 2024,
+            },
+            fallback_items: vec![],
+        }),
+        zip_file_paths: vec![],
+        fallback_zip_file_paths: vec![],
+        year_suffix: None,
+    }
+}
+
+/// Starts a minimal HTTP server on localhost, answering `GET <path>` with the given status and body
+/// (404 for unknown paths). Returns the base URL.
+pub async fn spawn_http_server(routes: Vec<(impl Into<String>, u16, Vec<u8>)>) -> Url {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let routes = Arc::new(
+        routes
+            .into_iter()
+            .map(|(path, status, body)| (path.into(), status, body))
+            .collect::<Vec<(String, u16, Vec<u8>)>>(),
+    );
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let routes = routes.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let path = request.split_whitespace().nth(1).unwrap_or("/");
+                let (status, body) = routes
+                    .iter()
+                    .find(|(p, _, _)| *p == path)
+                    .map(|(_, status, body)| (*status, body.clone()))
+                    .unwrap_or((404, b"not found".to_vec()));
+                let head = format!(
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes()).await;
+                let _ = stream.write_all(&body).await;
+            });
+        }
+    });
+    Url::parse(&format!("http://{}/", addr)).unwrap()
+}
+
+/// Serves the JSON files under `test_data/api` as the JPKSJ API (`test_data/api/datasets.json` is
+/// `GET /datasets.json`), and points the API requests made on this thread at it.
+/// The live API is only used by the `#[ignore]`d tests (`cargo test -- --ignored`).
+pub async fn setup_mock_server() -> Url {
+    fn collect(dir: &Path, root: &Path, routes: &mut Vec<(String, u16, Vec<u8>)>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                collect(&path, root, routes);
+            } else {
+                let route = path
+                    .strip_prefix(root)
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .replace('\\', "/");
+                routes.push((format!("/{}", route), 200, std::fs::read(&path).unwrap()));
+            }
+        }
+    }
+
+    let root = Path::new("./test_data/api");
+    let mut routes = Vec::new();
+    collect(root, root, &mut routes);
+    let base = spawn_http_server(routes).await;
+    api::set_mock_base_url(base.clone());
+    base
+}